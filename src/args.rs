@@ -1,5 +1,9 @@
 #![allow(dead_code, unused_imports, unused_variables, unused_must_use, unexpected_cfgs, deprecated)]
+use crate::bip353;
 use crate::cli::LdkUserInfo;
+use crate::lnurl::{LnurlServerConfig, LnurlUser};
+use crate::offers::Bolt12InvoicePolicy;
+use crate::probing::ProbingConfig;
 use bitcoin::network::Network;
 use lightning::ln::msgs::SocketAddress;
 use std::collections::HashMap;
@@ -7,6 +11,7 @@ use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 pub(crate) fn get_config_info() -> LdkUserInfo {
 	let bitcoind_rpc_username = "polaruser".to_string();
@@ -39,19 +44,35 @@ pub(crate) fn get_config_info() -> LdkUserInfo {
 		ldk_announced_listen_addr,
 		ldk_announced_node_name,
 		network,
+		sweep_descriptor: None,
+		rgs_source: None,
+		probing: None,
+		accepted_tlv_types: Vec::new(),
+		bolt12_invoice_policy: None,
+		exchange_rate_source: None,
+		lnurl_server: None,
+		dns_resolver: None,
 	};
 }
 
 pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
-	if env::args().nth(1).map_or(false, |arg| arg == "workshop") {
-		return Ok(get_config_info())
+	// Optional `--flag=value` settings may appear anywhere on the command line, so split them out
+	// before walking the positional arguments.
+	let (flag_args, args): (Vec<String>, Vec<String>) =
+		env::args().partition(|arg| arg.starts_with("--"));
+	let flags = parse_startup_flags(&flag_args)?;
+
+	if args.get(1).is_some_and(|arg| arg == "workshop") {
+		let mut config = get_config_info();
+		apply_startup_flags(&mut config, &flags)?;
+		return Ok(config);
 	}
 
-	if env::args().len() < 3 {
-		println!("ldk-tutorial-node requires at least 2 arguments: `cargo run [<bitcoind-rpc-username>:<bitcoind-rpc-password>@]<bitcoind-rpc-host>:<bitcoind-rpc-port> ldk_storage_directory_path [<ldk-incoming-peer-listening-port>] [bitcoin-network] [announced-node-name announced-listen-addr*]`");
+	if args.len() < 3 {
+		println!("ldk-tutorial-node requires at least 2 arguments: `cargo run [<bitcoind-rpc-username>:<bitcoind-rpc-password>@]<bitcoind-rpc-host>:<bitcoind-rpc-port> ldk_storage_directory_path [<ldk-incoming-peer-listening-port>] [bitcoin-network] [announced-node-name announced-listen-addr*] [--option=value]*`");
 		return Err(());
 	}
-	let bitcoind_rpc_info = env::args().skip(1).next().unwrap();
+	let bitcoind_rpc_info = args[1].clone();
 	let bitcoind_rpc_info_parts: Vec<&str> = bitcoind_rpc_info.rsplitn(2, "@").collect();
 
 	// Parse rpc auth after getting network for default .cookie location
@@ -63,10 +84,10 @@ pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
 	let bitcoind_rpc_host = bitcoind_rpc_path[0].to_string();
 	let bitcoind_rpc_port = bitcoind_rpc_path[1].parse::<u16>().unwrap();
 
-	let ldk_storage_dir_path = env::args().skip(2).next().unwrap();
+	let ldk_storage_dir_path = args[2].clone();
 
 	let mut ldk_peer_port_set = true;
-	let ldk_peer_listening_port: u16 = match env::args().skip(3).next().map(|p| p.parse()) {
+	let ldk_peer_listening_port: u16 = match args.get(3).map(|p| p.parse()) {
 		Some(Ok(p)) => p,
 		Some(Err(_)) => {
 			ldk_peer_port_set = false;
@@ -82,7 +103,7 @@ pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
 		true => 4,
 		false => 3,
 	};
-	let network: Network = match env::args().skip(arg_idx).next().as_ref().map(String::as_str) {
+	let network: Network = match args.get(arg_idx).map(String::as_str) {
 		Some("testnet") => Network::Testnet,
 		Some("regtest") => Network::Regtest,
 		Some("signet") => Network::Signet,
@@ -108,7 +129,7 @@ pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
 		return Err(());
 	};
 
-	let ldk_announced_node_name = match env::args().skip(arg_idx + 1).next().as_ref() {
+	let ldk_announced_node_name = match args.get(arg_idx + 1) {
 		Some(s) => {
 			if s.len() > 32 {
 				panic!("Node Alias can not be longer than 32 bytes");
@@ -123,7 +144,7 @@ pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
 
 	let mut ldk_announced_listen_addr = Vec::new();
 	loop {
-		match env::args().skip(arg_idx + 1).next().as_ref() {
+		match args.get(arg_idx + 1) {
 			Some(s) => match SocketAddress::from_str(s) {
 				Ok(sa) => {
 					ldk_announced_listen_addr.push(sa);
@@ -135,7 +156,7 @@ pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
 		}
 	}
 
-	Ok(LdkUserInfo {
+	let mut config = LdkUserInfo {
 		bitcoind_rpc_username,
 		bitcoind_rpc_password,
 		bitcoind_rpc_host,
@@ -145,7 +166,145 @@ pub(crate) fn parse_startup_args() -> Result<LdkUserInfo, ()> {
 		ldk_announced_listen_addr,
 		ldk_announced_node_name,
 		network,
-	})
+		sweep_descriptor: None,
+		rgs_source: None,
+		probing: None,
+		accepted_tlv_types: Vec::new(),
+		bolt12_invoice_policy: None,
+		exchange_rate_source: None,
+		lnurl_server: None,
+		dns_resolver: None,
+	};
+	apply_startup_flags(&mut config, &flags)?;
+	Ok(config)
+}
+
+// Optional startup flags, given as `--<name>=<value>`.
+const SWEEP_DESCRIPTOR_FLAG: &str = "sweep-descriptor";
+const RGS_SOURCE_FLAG: &str = "rgs-source";
+const PROBE_INTERVAL_FLAG: &str = "probe-interval";
+const PROBE_AMOUNT_FLAG: &str = "probe-amount-msat";
+const PROBE_MAX_LOCKED_FLAG: &str = "probe-max-locked-msat";
+const ACCEPT_TLV_TYPES_FLAG: &str = "accept-tlv-types";
+const MANUAL_BOLT12_INVOICES_FLAG: &str = "manual-bolt12-invoices";
+const BOLT12_AUTO_PAY_MAX_FLAG: &str = "bolt12-auto-pay-max-msat";
+const BOLT12_TRUSTED_PAYEES_FLAG: &str = "bolt12-trusted-payees";
+const EXCHANGE_RATE_SOURCE_FLAG: &str = "exchange-rate-source";
+const LNURL_LISTEN_FLAG: &str = "lnurl-listen";
+const LNURL_BASE_URL_FLAG: &str = "lnurl-base-url";
+const LNURL_USERS_FLAG: &str = "lnurl-users";
+const DNS_RESOLVER_FLAG: &str = "dns-resolver";
+
+fn parse_startup_flags(flag_args: &[String]) -> Result<HashMap<String, String>, ()> {
+	let mut flags = HashMap::new();
+	for arg in flag_args {
+		let mut parts = arg.trim_start_matches("--").splitn(2, '=');
+		let name = parts.next().unwrap().to_string();
+		// Bare flags such as `--foo` are treated as `--foo=true`.
+		let value = parts.next().unwrap_or("true").to_string();
+		if flags.insert(name.clone(), value).is_some() {
+			println!("ERROR: startup flag --{} was given more than once", name);
+			return Err(());
+		}
+	}
+	Ok(flags)
+}
+
+fn apply_startup_flags(config: &mut LdkUserInfo, flags: &HashMap<String, String>) -> Result<(), ()> {
+	for (name, value) in flags {
+		match name.as_str() {
+			SWEEP_DESCRIPTOR_FLAG => config.sweep_descriptor = Some(value.clone()),
+			RGS_SOURCE_FLAG => config.rgs_source = Some(value.clone()),
+			// Giving any of the probing flags enables the background prober, with defaults for the
+			// rest.
+			PROBE_INTERVAL_FLAG => {
+				let secs = parse_flag_value(name, value)?;
+				config.probing.get_or_insert_with(ProbingConfig::default).interval =
+					Duration::from_secs(secs);
+			},
+			PROBE_AMOUNT_FLAG => {
+				config.probing.get_or_insert_with(ProbingConfig::default).amount_msat =
+					parse_flag_value(name, value)?;
+			},
+			PROBE_MAX_LOCKED_FLAG => {
+				config.probing.get_or_insert_with(ProbingConfig::default).max_locked_msat =
+					parse_flag_value(name, value)?;
+			},
+			ACCEPT_TLV_TYPES_FLAG => {
+				for typ in value.split(',') {
+					config.accepted_tlv_types.push(parse_flag_value(name, typ)?);
+				}
+			},
+			// As with probing, giving any of the BOLT12 policy flags enables manual invoice
+			// handling.
+			MANUAL_BOLT12_INVOICES_FLAG => {
+				if parse_flag_value(name, value)? {
+					config.bolt12_invoice_policy.get_or_insert_with(Bolt12InvoicePolicy::default);
+				}
+			},
+			BOLT12_AUTO_PAY_MAX_FLAG => {
+				config
+					.bolt12_invoice_policy
+					.get_or_insert_with(Bolt12InvoicePolicy::default)
+					.auto_pay_max_msat = Some(parse_flag_value(name, value)?);
+			},
+			BOLT12_TRUSTED_PAYEES_FLAG => {
+				for pubkey in value.split(',') {
+					config
+						.bolt12_invoice_policy
+						.get_or_insert_with(Bolt12InvoicePolicy::default)
+						.trusted_payees
+						.push(parse_flag_value(name, pubkey)?);
+				}
+			},
+			EXCHANGE_RATE_SOURCE_FLAG => config.exchange_rate_source = Some(value.clone()),
+			// Any of the LNURL flags enables the LNURL-pay server, though the base URL and users
+			// must both be given.
+			LNURL_LISTEN_FLAG => {
+				config.lnurl_server.get_or_insert_with(LnurlServerConfig::default).listen_addr =
+					value.clone();
+			},
+			LNURL_BASE_URL_FLAG => {
+				config.lnurl_server.get_or_insert_with(LnurlServerConfig::default).base_url =
+					value.clone();
+			},
+			LNURL_USERS_FLAG => {
+				let lnurl_server =
+					config.lnurl_server.get_or_insert_with(LnurlServerConfig::default);
+				for user in value.split(',') {
+					let user = LnurlUser::parse(user)
+						.map_err(|e| println!("ERROR: invalid value for --{}: {}", name, e))?;
+					lnurl_server.users.push(user);
+				}
+			},
+			DNS_RESOLVER_FLAG => {
+				bip353::parse_resolver(value)
+					.map_err(|e| println!("ERROR: invalid value for --{}: {}", name, e))?;
+				config.dns_resolver = Some(value.clone());
+			},
+			_ => {
+				println!("ERROR: unknown startup flag --{}", name);
+				return Err(());
+			},
+		}
+	}
+	if let Some(lnurl_server) = &config.lnurl_server {
+		if lnurl_server.base_url.is_empty() || lnurl_server.users.is_empty() {
+			println!(
+				"ERROR: the LNURL-pay server requires both --{} and --{}",
+				LNURL_BASE_URL_FLAG, LNURL_USERS_FLAG
+			);
+			return Err(());
+		}
+	}
+	Ok(())
+}
+
+fn parse_flag_value<T: FromStr>(name: &str, value: &str) -> Result<T, ()>
+where
+	T::Err: std::fmt::Display,
+{
+	value.parse().map_err(|e| println!("ERROR: invalid value for --{}: {}", name, e))
 }
 
 // Default datadir relative to home directory
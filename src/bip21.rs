@@ -0,0 +1,512 @@
+use crate::disk::INBOUND_PAYMENTS_FNAME;
+use crate::filesystem_store::FilesystemStore;
+use crate::offers::OfferStorage;
+use crate::{HTLCStatus, InboundPaymentInfoStorage, LdkOnChainWallet};
+use bitcoin::address::NetworkUnchecked;
+use bitcoin::{Address, Amount, Denomination, ScriptBuf};
+use lightning::offers::offer::{Offer, OfferId};
+use lightning::types::payment::PaymentHash;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable};
+use lightning::{impl_writeable_tlv_based, impl_writeable_tlv_based_enum};
+use lightning_invoice::Bolt11Invoice;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub(crate) const UNIFIED_REQUESTS_KEY: &str = "unified_requests";
+
+/// How often the background task checks whether unified payment requests have been paid.
+const RECONCILE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A BIP 21 `bitcoin:` URI, optionally carrying a BOLT11 invoice (`lightning=`) and a BOLT12 offer
+/// (`lno=`) alongside the on-chain address.
+#[derive(Debug)]
+pub(crate) struct UnifiedUri {
+	pub(crate) address: Option<Address<NetworkUnchecked>>,
+	pub(crate) amount: Option<Amount>,
+	pub(crate) label: Option<String>,
+	pub(crate) message: Option<String>,
+	pub(crate) invoice: Option<Bolt11Invoice>,
+	pub(crate) offer: Option<Offer>,
+}
+
+impl UnifiedUri {
+	/// Whether `s` looks like a BIP 21 URI rather than a bare invoice or offer.
+	pub(crate) fn is_uri(s: &str) -> bool {
+		s.len() > 8 && s[..8].eq_ignore_ascii_case("bitcoin:")
+	}
+
+	pub(crate) fn parse(uri: &str) -> Result<Self, String> {
+		if !Self::is_uri(uri) {
+			return Err("not a bitcoin: URI".to_string());
+		}
+		let mut parts = uri[8..].splitn(2, '?');
+		let address = parts.next().unwrap();
+		let address = if address.is_empty() {
+			None
+		} else {
+			let address = Address::from_str(address)
+				.map_err(|e| format!("invalid address {}: {}", address, e))?;
+			Some(address)
+		};
+		let mut parsed = UnifiedUri {
+			address,
+			amount: None,
+			label: None,
+			message: None,
+			invoice: None,
+			offer: None,
+		};
+		for param in parts.next().unwrap_or("").split('&').filter(|param| !param.is_empty()) {
+			let mut kv = param.splitn(2, '=');
+			let key = kv.next().unwrap().to_ascii_lowercase();
+			let value = percent_decode(kv.next().unwrap_or(""))?;
+			match key.as_str() {
+				"amount" => {
+					let amount = Amount::from_str_in(&value, Denomination::Bitcoin)
+						.map_err(|e| format!("invalid amount {}: {}", value, e))?;
+					parsed.amount = Some(amount);
+				},
+				"label" => parsed.label = Some(value),
+				"message" => parsed.message = Some(value),
+				"lightning" => {
+					let invoice = Bolt11Invoice::from_str(&value)
+						.map_err(|e| format!("invalid lightning invoice: {}", e))?;
+					parsed.invoice = Some(invoice);
+				},
+				"lno" => {
+					let offer =
+						Offer::from_str(&value).map_err(|e| format!("invalid offer: {:?}", e))?;
+					parsed.offer = Some(offer);
+				},
+				// BIP 21 says we must refuse URIs with required parameters we don't understand.
+				key if key.starts_with("req-") => {
+					return Err(format!("unsupported required parameter {}", key))
+				},
+				_ => {},
+			}
+		}
+		if parsed.address.is_none() && parsed.invoice.is_none() && parsed.offer.is_none() {
+			return Err("URI has no address, invoice or offer to pay".to_string());
+		}
+		Ok(parsed)
+	}
+
+	pub(crate) fn to_uri(&self) -> String {
+		let mut uri = "bitcoin:".to_string();
+		if let Some(address) = &self.address {
+			uri.push_str(&address.clone().assume_checked().to_string());
+		}
+		let mut params = Vec::new();
+		if let Some(amount) = self.amount {
+			params.push(format!("amount={}", format_btc(amount)));
+		}
+		if let Some(label) = &self.label {
+			params.push(format!("label={}", percent_encode(label)));
+		}
+		if let Some(message) = &self.message {
+			params.push(format!("message={}", percent_encode(message)));
+		}
+		if let Some(invoice) = &self.invoice {
+			params.push(format!("lightning={}", invoice));
+		}
+		if let Some(offer) = &self.offer {
+			params.push(format!("lno={}", offer));
+		}
+		if !params.is_empty() {
+			uri.push('?');
+			uri.push_str(&params.join("&"));
+		}
+		uri
+	}
+}
+
+/// Formats an amount in BTC without trailing zeros, as BIP 21 expects.
+fn format_btc(amount: Amount) -> String {
+	let sats = amount.to_sat();
+	let btc = format!("{}.{:08}", sats / 100_000_000, sats % 100_000_000);
+	btc.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+pub(crate) fn percent_encode(s: &str) -> String {
+	let mut encoded = String::new();
+	for byte in s.bytes() {
+		match byte {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+				encoded.push(byte as char)
+			},
+			_ => encoded.push_str(&format!("%{:02X}", byte)),
+		}
+	}
+	encoded
+}
+
+pub(crate) fn percent_decode(s: &str) -> Result<String, String> {
+	let mut decoded = Vec::new();
+	let mut bytes = s.bytes();
+	while let Some(byte) = bytes.next() {
+		if byte != b'%' {
+			decoded.push(byte);
+			continue;
+		}
+		let hex = [bytes.next().unwrap_or(0), bytes.next().unwrap_or(0)];
+		let hex = std::str::from_utf8(&hex).map_err(|_| format!("invalid escape in {}", s))?;
+		decoded.push(u8::from_str_radix(hex, 16).map_err(|_| format!("invalid escape in {}", s))?);
+	}
+	String::from_utf8(decoded).map_err(|_| format!("{} is not valid UTF-8", s))
+}
+
+/// Which of a unified request's payment methods it was paid with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum PaymentRail {
+	Bolt11,
+	Bolt12,
+	OnChain,
+}
+
+impl PaymentRail {
+	/// How a request was paid on this rail, e.g. "with its invoice".
+	pub(crate) fn description(&self) -> &'static str {
+		match self {
+			PaymentRail::Bolt11 => "with its invoice",
+			PaymentRail::Bolt12 => "with its offer",
+			PaymentRail::OnChain => "on-chain",
+		}
+	}
+}
+
+impl_writeable_tlv_based_enum!(PaymentRail,
+	(0, Bolt11) => {},
+	(2, Bolt12) => {},
+	(4, OnChain) => {},
+);
+
+/// A `getunifiedqr` request, tying together the address, invoice and offer that can each pay it.
+pub(crate) struct UnifiedRequest {
+	pub(crate) uri: String,
+	pub(crate) script_pubkey: ScriptBuf,
+	pub(crate) payment_hash: PaymentHash,
+	pub(crate) offer_id: OfferId,
+	pub(crate) amt_msat: u64,
+	pub(crate) paid_via: Option<PaymentRail>,
+}
+
+impl_writeable_tlv_based!(UnifiedRequest, {
+	(0, uri, required),
+	(2, script_pubkey, required),
+	(4, payment_hash, required),
+	(6, offer_id, required),
+	(8, amt_msat, required),
+	(10, paid_via, option),
+});
+
+#[derive(Default)]
+pub(crate) struct UnifiedRequestStorage {
+	pub(crate) requests: Vec<UnifiedRequest>,
+}
+
+impl_writeable_tlv_based!(UnifiedRequestStorage, {
+	(0, requests, optional_vec),
+});
+
+impl UnifiedRequestStorage {
+	pub(crate) fn persist(&self, fs_store: &FilesystemStore) {
+		fs_store.write("", "", UNIFIED_REQUESTS_KEY, &self.encode()).unwrap();
+	}
+}
+
+pub(crate) fn read_unified_requests(fs_store: &FilesystemStore) -> UnifiedRequestStorage {
+	match fs_store.read("", "", UNIFIED_REQUESTS_KEY) {
+		Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes)).unwrap_or_default(),
+		Err(_) => UnifiedRequestStorage::default(),
+	}
+}
+
+/// Marks unpaid requests that have been paid on any rail, and stops their other payment methods
+/// from being paid too: the invoice is expired and the offer disabled, and on-chain payments are
+/// simply no longer counted. `received_sat` returns how much has been received on-chain to a
+/// script. Returns the index of each newly paid request and the rail it was paid on.
+pub(crate) fn reconcile<F: Fn(&ScriptBuf) -> u64>(
+	requests: &mut UnifiedRequestStorage, inbound_payments: &mut InboundPaymentInfoStorage,
+	offers: &mut OfferStorage, received_sat: F,
+) -> Vec<(usize, PaymentRail)> {
+	let mut newly_paid = Vec::new();
+	for (idx, request) in requests.requests.iter_mut().enumerate() {
+		if let Some(rail) = reconcile_request(request, inbound_payments, offers, &received_sat) {
+			newly_paid.push((idx, rail));
+		}
+	}
+	newly_paid
+}
+
+/// Reconciles the request a Lightning payment that's about to be claimed is for, identified by
+/// its payment hash or offer, so that it's rejected if the request was already paid rather than
+/// waiting for the background task to notice. Returns the rail the request was paid on.
+///
+/// A BOLT 11 payment to an already paid request is only rejected if the request was paid some
+/// other way, as it may be the invoice's own payment being claimed. Each payment to an offer has
+/// its own payment hash though, so any payment to the offer of a paid request is a second one.
+pub(crate) fn paid_on_other_rail<F: Fn(&ScriptBuf) -> u64>(
+	requests: &mut UnifiedRequestStorage, inbound_payments: &mut InboundPaymentInfoStorage,
+	offers: &mut OfferStorage, rail: PaymentRail, payment_hash: &PaymentHash,
+	offer_id: Option<&OfferId>, received_sat: F,
+) -> Option<PaymentRail> {
+	let request = requests.requests.iter_mut().find(|request| match rail {
+		PaymentRail::Bolt11 => request.payment_hash == *payment_hash,
+		PaymentRail::Bolt12 => Some(&request.offer_id) == offer_id,
+		PaymentRail::OnChain => false,
+	})?;
+	reconcile_request(request, inbound_payments, offers, &received_sat);
+	request.paid_via.filter(|paid_via| *paid_via != rail || rail == PaymentRail::Bolt12)
+}
+
+/// Marks a single request as paid if it's been paid on any rail, returning the rail if it was
+/// newly paid.
+fn reconcile_request<F: Fn(&ScriptBuf) -> u64>(
+	request: &mut UnifiedRequest, inbound_payments: &mut InboundPaymentInfoStorage,
+	offers: &mut OfferStorage, received_sat: &F,
+) -> Option<PaymentRail> {
+	if request.paid_via.is_some() {
+		return None;
+	}
+	let invoice_paid = inbound_payments
+		.payments
+		.get(&request.payment_hash)
+		.is_some_and(|payment| matches!(payment.status, HTLCStatus::Succeeded));
+	let offer_paid =
+		offers.offers.get(&request.offer_id.0).is_some_and(|offer| offer.num_payments > 0);
+	let rail = if invoice_paid {
+		PaymentRail::Bolt11
+	} else if offer_paid {
+		PaymentRail::Bolt12
+	} else if received_sat(&request.script_pubkey).saturating_mul(1000) >= request.amt_msat {
+		PaymentRail::OnChain
+	} else {
+		return None;
+	};
+	request.paid_via = Some(rail);
+	if let Some(payment) = inbound_payments.payments.get_mut(&request.payment_hash) {
+		if matches!(payment.status, HTLCStatus::Pending) {
+			payment.status = HTLCStatus::Expired;
+		}
+	}
+	if let Some(offer) = offers.offers.get_mut(&request.offer_id.0) {
+		offer.disabled = true;
+	}
+	Some(rail)
+}
+
+/// Regularly checks whether unified requests have been paid, so that a request paid on one rail
+/// can't also be paid on another.
+pub(crate) async fn run_reconciliation(
+	requests: Arc<Mutex<UnifiedRequestStorage>>,
+	inbound_payments: Arc<Mutex<InboundPaymentInfoStorage>>, offers: Arc<Mutex<OfferStorage>>,
+	on_chain_wallet: Arc<LdkOnChainWallet>, fs_store: Arc<FilesystemStore>,
+) {
+	let mut interval = tokio::time::interval(RECONCILE_INTERVAL);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+	loop {
+		interval.tick().await;
+		let mut requests = requests.lock().unwrap();
+		let mut inbound = inbound_payments.lock().unwrap();
+		let mut offers = offers.lock().unwrap();
+		let newly_paid = reconcile(&mut requests, &mut inbound, &mut offers, |script| {
+			on_chain_wallet.received_to(script).to_sat()
+		});
+		if newly_paid.is_empty() {
+			continue;
+		}
+		for (idx, rail) in newly_paid {
+			let request = &requests.requests[idx];
+			println!(
+				"\nEVENT: unified payment request for {} millisatoshis was paid {}",
+				request.amt_msat,
+				rail.description()
+			);
+			print!("> ");
+			std::io::Write::flush(&mut std::io::stdout()).unwrap();
+		}
+		requests.persist(&fs_store);
+		offers.persist(&fs_store);
+		fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound.encode()).unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::offers::OfferInfo;
+	use crate::{MillisatAmount, PaymentInfo};
+	use std::collections::HashMap;
+
+	const ADDRESS: &str = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+
+	#[test]
+	fn test_uri_round_trip() {
+		let uri = UnifiedUri {
+			address: Some(Address::from_str(ADDRESS).unwrap()),
+			amount: Some(Amount::from_sat(10_000)),
+			label: None,
+			message: Some("two coffees & cake".to_string()),
+			invoice: None,
+			offer: None,
+		};
+		let encoded = uri.to_uri();
+		assert_eq!(
+			encoded,
+			format!("bitcoin:{}?amount=0.0001&message=two%20coffees%20%26%20cake", ADDRESS)
+		);
+		let decoded = UnifiedUri::parse(&encoded).unwrap();
+		assert_eq!(decoded.address, uri.address);
+		assert_eq!(decoded.amount, uri.amount);
+		assert_eq!(decoded.message, uri.message);
+	}
+
+	#[test]
+	fn test_parse_uri() {
+		let uri = format!("BITCOIN:{}?amount=1.5&label=shop&foo=bar", ADDRESS);
+		let parsed = UnifiedUri::parse(&uri).unwrap();
+		assert_eq!(parsed.amount, Some(Amount::from_sat(150_000_000)));
+		assert_eq!(parsed.label.as_deref(), Some("shop"));
+
+		assert!(UnifiedUri::parse(&format!("bitcoin:{}?req-foo=bar", ADDRESS)).is_err());
+		assert!(UnifiedUri::parse(&format!("bitcoin:{}?amount=lots", ADDRESS)).is_err());
+		assert!(UnifiedUri::parse("bitcoin:?amount=1").is_err());
+		assert!(UnifiedUri::parse("bitcoin:notanaddress").is_err());
+		assert!(UnifiedUri::parse("lnbc1").is_err());
+	}
+
+	#[test]
+	fn test_reconcile() {
+		let request = |payment_hash, offer_id| UnifiedRequest {
+			uri: String::new(),
+			script_pubkey: ScriptBuf::from_bytes(vec![payment_hash]),
+			payment_hash: PaymentHash([payment_hash; 32]),
+			offer_id: OfferId([offer_id; 32]),
+			amt_msat: 10_000_000,
+			paid_via: None,
+		};
+		let mut requests =
+			UnifiedRequestStorage { requests: vec![request(1, 1), request(2, 2), request(3, 3)] };
+		let mut inbound =
+			InboundPaymentInfoStorage { payments: HashMap::new(), last_settle_index: 0 };
+		for hash in 1..=3 {
+			inbound.payments.insert(
+				PaymentHash([hash; 32]),
+				PaymentInfo {
+					preimage: None,
+					secret: None,
+					status: if hash == 1 { HTLCStatus::Succeeded } else { HTLCStatus::Pending },
+					amt_msat: MillisatAmount(Some(10_000_000)),
+					custom_tlvs: Vec::new(),
+					bolt11_invoice: None,
+					settle_index: None,
+					payer_note: None,
+					quantity: None,
+					lnurl_user: None,
+					lnurl_comment: None,
+				},
+			);
+		}
+		let mut offers = OfferStorage::default();
+		for id in 1..=3 {
+			offers.offers.insert(
+				[id; 32],
+				OfferInfo {
+					offer: String::new(),
+					single_use: true,
+					disabled: false,
+					created_at: 0,
+					num_payments: 0,
+					total_received_msat: 0,
+					fiat_price: None,
+				},
+			);
+		}
+		// The second request got paid with its offer, the third on-chain.
+		offers.offers.get_mut(&[2; 32]).unwrap().num_payments = 1;
+		let received = |script: &ScriptBuf| if script.as_bytes() == [3] { 10_000 } else { 0 };
+
+		let newly_paid = reconcile(&mut requests, &mut inbound, &mut offers, received);
+		assert_eq!(
+			newly_paid,
+			vec![(0, PaymentRail::Bolt11), (1, PaymentRail::Bolt12), (2, PaymentRail::OnChain)]
+		);
+		assert!(offers.offers.values().all(|offer| offer.disabled));
+		assert!(matches!(inbound.payments[&PaymentHash([1; 32])].status, HTLCStatus::Succeeded));
+		assert!(matches!(inbound.payments[&PaymentHash([3; 32])].status, HTLCStatus::Expired));
+
+		// Paying again on another rail doesn't count a second time.
+		let received = |_: &ScriptBuf| 10_000;
+		assert!(reconcile(&mut requests, &mut inbound, &mut offers, received).is_empty());
+	}
+
+	#[test]
+	fn test_paid_on_other_rail() {
+		let mut requests = UnifiedRequestStorage {
+			requests: (1..=2)
+				.map(|id| UnifiedRequest {
+					uri: String::new(),
+					script_pubkey: ScriptBuf::from_bytes(vec![id]),
+					payment_hash: PaymentHash([id; 32]),
+					offer_id: OfferId([id; 32]),
+					amt_msat: 10_000_000,
+					paid_via: None,
+				})
+				.collect(),
+		};
+		let mut inbound =
+			InboundPaymentInfoStorage { payments: HashMap::new(), last_settle_index: 0 };
+		let mut offers = OfferStorage::default();
+		offers.offers.insert(
+			[2; 32],
+			OfferInfo {
+				offer: String::new(),
+				single_use: false,
+				disabled: false,
+				created_at: 0,
+				num_payments: 0,
+				total_received_msat: 0,
+				fiat_price: None,
+			},
+		);
+		let mut check = |offers: &mut OfferStorage, rail, hash, offer_id: Option<u8>, received| {
+			let offer_id = offer_id.map(|id| OfferId([id; 32]));
+			paid_on_other_rail(
+				&mut requests,
+				&mut inbound,
+				offers,
+				rail,
+				&PaymentHash([hash; 32]),
+				offer_id.as_ref(),
+				|script| if script.as_bytes() == [1] { received } else { 0 },
+			)
+		};
+
+		// Nothing has been paid yet, so the invoice payment can be claimed.
+		assert_eq!(check(&mut offers, PaymentRail::Bolt11, 1, None, 0), None);
+		// Once the address is paid, neither the invoice nor the offer can be, even before the
+		// background task has run.
+		assert_eq!(
+			check(&mut offers, PaymentRail::Bolt11, 1, None, 10_000),
+			Some(PaymentRail::OnChain)
+		);
+		assert_eq!(
+			check(&mut offers, PaymentRail::Bolt12, 2, Some(1), 0),
+			Some(PaymentRail::OnChain)
+		);
+		// Payments that aren't for a unified request are left alone.
+		assert_eq!(check(&mut offers, PaymentRail::Bolt11, 9, None, 10_000), None);
+
+		// The first payment to the second request's offer is claimed. Each offer payment has its
+		// own payment hash, so a later one is turned away even though it's on the same rail.
+		assert_eq!(check(&mut offers, PaymentRail::Bolt12, 3, Some(2), 0), None);
+		offers.offers.get_mut(&[2; 32]).unwrap().num_payments = 1;
+		assert_eq!(
+			check(&mut offers, PaymentRail::Bolt12, 4, Some(2), 0),
+			Some(PaymentRail::Bolt12)
+		);
+	}
+}
@@ -0,0 +1,499 @@
+use crate::bip21::UnifiedUri;
+use crate::decode::Decoded;
+use crate::http;
+use bitcoin::network::Network;
+use lightning::offers::offer::Offer;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// How long we wait for a DNS server to answer.
+const DNS_TIMEOUT: Duration = Duration::from_secs(5);
+
+const DNS_PORT: u16 = 53;
+const DNS_HEADER_LEN: usize = 12;
+const TXT_RECORD_TYPE: u16 = 16;
+const OPT_RECORD_TYPE: u16 = 41;
+const CLASS_IN: u16 = 1;
+
+/// The UDP payload size we advertise with EDNS, large enough for the TXT records offers need.
+const EDNS_UDP_PAYLOAD_SIZE: u16 = 4096;
+
+// DNS header flags.
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_TRUNCATED: u16 = 0x0200;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_AUTHENTIC_DATA: u16 = 0x0020;
+const RCODE_MASK: u16 = 0x000f;
+const RCODE_NXDOMAIN: u16 = 3;
+
+/// The EDNS "DNSSEC OK" flag, asking for DNSSEC records, in the OPT record's TTL field.
+const EDNS_DNSSEC_OK: u32 = 0x0000_8000;
+
+/// A BIP 353 human-readable payment name, `₿user@domain`, whose payment instructions are
+/// published in a DNSSEC-signed TXT record.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct HumanReadableName {
+	pub(crate) user: String,
+	pub(crate) domain: String,
+}
+
+impl HumanReadableName {
+	/// Whether `s` is meant as a human-readable name. The ₿ prefix is what sets these apart from
+	/// Lightning Addresses, which look the same otherwise.
+	pub(crate) fn is_name(s: &str) -> bool {
+		s.starts_with('₿')
+	}
+
+	pub(crate) fn parse(name: &str) -> Result<Self, String> {
+		let mut parts = name.trim_start_matches('₿').splitn(2, '@');
+		let user = parts.next().unwrap().to_ascii_lowercase();
+		let domain = parts.next().ok_or(format!("{} must be given as ₿user@domain", name))?;
+		let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+		let valid_label = |label: &str| {
+			!label.is_empty()
+				&& label.len() <= 63
+				&& label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+		};
+		if !user.split('.').all(valid_label) || !domain.split('.').all(valid_label) {
+			return Err(format!("{} is not a valid human-readable name", name));
+		}
+		let parsed = HumanReadableName { user, domain };
+		// DNS names are limited to 255 bytes on the wire, which is one more than the dotted form.
+		if parsed.dns_name().len() > 254 {
+			return Err(format!("{} is too long", name));
+		}
+		Ok(parsed)
+	}
+
+	/// The DNS name whose TXT record holds the payment instructions.
+	pub(crate) fn dns_name(&self) -> String {
+		format!("{}.user._bitcoin-payment.{}.", self.user, self.domain)
+	}
+}
+
+impl fmt::Display for HumanReadableName {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "₿{}@{}", self.user, self.domain)
+	}
+}
+
+pub(crate) type TxtFuture<'a> =
+	Pin<Box<dyn Future<Output = Result<Vec<String>, String>> + Send + 'a>>;
+
+/// Looks up TXT records for human-readable names. BIP 353 requires the records to be
+/// DNSSEC-signed, and we don't check the signatures ourselves, so implementations must only
+/// return records their upstream resolver has validated.
+pub(crate) trait DnsResolver: Send + Sync {
+	fn resolve_txt<'a>(&'a self, name: &'a str) -> TxtFuture<'a>;
+}
+
+/// Queries a DNS server over UDP, trusting its DNSSEC validation as reported by the "authentic
+/// data" flag. Anyone on the path could set that flag, so the server must be a validating
+/// resolver on this machine.
+pub(crate) struct UdpResolver {
+	pub(crate) server: SocketAddr,
+}
+
+impl DnsResolver for UdpResolver {
+	fn resolve_txt<'a>(&'a self, name: &'a str) -> TxtFuture<'a> {
+		Box::pin(async move {
+			let id: u16 = rand::random();
+			let query = build_query(id, name)?;
+			let local_addr: SocketAddr = match self.server.ip() {
+				IpAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+				IpAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+			};
+			let io_error =
+				|e: std::io::Error| format!("DNS query to {} failed: {}", self.server, e);
+			let socket = UdpSocket::bind(local_addr).await.map_err(io_error)?;
+			socket.connect(self.server).await.map_err(io_error)?;
+			socket.send(&query).await.map_err(io_error)?;
+			let mut response = vec![0; EDNS_UDP_PAYLOAD_SIZE as usize];
+			let len = tokio::time::timeout(DNS_TIMEOUT, socket.recv(&mut response))
+				.await
+				.map_err(|_| format!("DNS server {} didn't answer", self.server))?
+				.map_err(io_error)?;
+			parse_response(id, &response[..len])
+		})
+	}
+}
+
+/// Queries a DNS-over-HTTPS resolver through its JSON API, such as `https://dns.google/resolve`,
+/// trusting its DNSSEC validation as with `UdpResolver`. TLS authenticates the resolver, so it
+/// needn't be local.
+pub(crate) struct DohResolver {
+	pub(crate) url: String,
+}
+
+impl DnsResolver for DohResolver {
+	fn resolve_txt<'a>(&'a self, name: &'a str) -> TxtFuture<'a> {
+		Box::pin(async move {
+			let url = format!("{}?name={}&type=TXT&do=1", self.url, name);
+			parse_doh_response(&http::get_accepting(&url, "application/dns-json").await?)
+		})
+	}
+}
+
+/// Answers from a fixed set of records, taken as already validated. Used in tests.
+pub(crate) struct StaticResolver {
+	pub(crate) records: HashMap<String, Vec<String>>,
+}
+
+impl DnsResolver for StaticResolver {
+	fn resolve_txt<'a>(&'a self, name: &'a str) -> TxtFuture<'a> {
+		Box::pin(
+			async move { self.records.get(name).cloned().ok_or(format!("no such name {}", name)) },
+		)
+	}
+}
+
+/// Builds a resolver from a `--dns-resolver` value: an `https://` DNS-over-HTTPS URL, or the
+/// loopback IP address (and optionally port) of a DNS server. As we don't check DNSSEC
+/// signatures ourselves, we refuse resolvers whose answers could be tampered with on the way.
+pub(crate) fn parse_resolver(resolver: &str) -> Result<Box<dyn DnsResolver>, String> {
+	if resolver.starts_with("https://") {
+		return Ok(Box::new(DohResolver { url: resolver.to_string() }));
+	}
+	if resolver.starts_with("http://") {
+		return Err("DNS-over-HTTPS resolvers must be reached over https://".to_string());
+	}
+	let server = SocketAddr::from_str(resolver)
+		.or_else(|_| IpAddr::from_str(resolver).map(|ip| SocketAddr::new(ip, DNS_PORT)))
+		.map_err(|_| format!("{} is not an https:// URL or a DNS server address", resolver))?;
+	if !server.ip().is_loopback() {
+		return Err(format!(
+			"{} isn't on this machine, so its DNSSEC validation can't be trusted",
+			server
+		));
+	}
+	Ok(Box::new(UdpResolver { server }))
+}
+
+/// Builds a query for `name`'s TXT records, asking for DNSSEC validation.
+fn build_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+	let mut query = Vec::new();
+	query.extend_from_slice(&id.to_be_bytes());
+	query.extend_from_slice(&(FLAG_RECURSION_DESIRED | FLAG_AUTHENTIC_DATA).to_be_bytes());
+	// One question and one additional record, the EDNS OPT record.
+	for count in [1u16, 0, 0, 1].iter() {
+		query.extend_from_slice(&count.to_be_bytes());
+	}
+	for label in name.trim_end_matches('.').split('.') {
+		if label.is_empty() || label.len() > 63 {
+			return Err(format!("invalid DNS name {}", name));
+		}
+		query.push(label.len() as u8);
+		query.extend_from_slice(label.as_bytes());
+	}
+	query.push(0);
+	query.extend_from_slice(&TXT_RECORD_TYPE.to_be_bytes());
+	query.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+	query.push(0);
+	query.extend_from_slice(&OPT_RECORD_TYPE.to_be_bytes());
+	query.extend_from_slice(&EDNS_UDP_PAYLOAD_SIZE.to_be_bytes());
+	query.extend_from_slice(&EDNS_DNSSEC_OK.to_be_bytes());
+	query.extend_from_slice(&0u16.to_be_bytes());
+	Ok(query)
+}
+
+fn read_u16(message: &[u8], pos: usize) -> Result<u16, String> {
+	match message.get(pos..pos + 2) {
+		Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]])),
+		None => Err("DNS response is truncated".to_string()),
+	}
+}
+
+/// Returns the position just past the (possibly compressed) name starting at `pos`.
+fn skip_name(message: &[u8], mut pos: usize) -> Result<usize, String> {
+	loop {
+		match message.get(pos) {
+			None => return Err("DNS response is truncated".to_string()),
+			Some(0) => return Ok(pos + 1),
+			// A pointer to a name elsewhere in the message ends the name.
+			Some(len) if len & 0xc0 == 0xc0 => return Ok(pos + 2),
+			Some(len) => pos += 1 + *len as usize,
+		}
+	}
+}
+
+/// Joins the character-strings of a TXT record, which splits long text into 255-byte pieces.
+fn parse_txt_rdata(rdata: &[u8]) -> Result<String, String> {
+	let mut text = Vec::new();
+	let mut pos = 0;
+	while pos < rdata.len() {
+		let len = rdata[pos] as usize;
+		let piece = rdata.get(pos + 1..pos + 1 + len).ok_or("TXT record is truncated")?;
+		text.extend_from_slice(piece);
+		pos += 1 + len;
+	}
+	String::from_utf8(text).map_err(|_| "TXT record is not valid UTF-8".to_string())
+}
+
+/// Extracts the TXT records from a response to the query with `id`.
+fn parse_response(id: u16, response: &[u8]) -> Result<Vec<String>, String> {
+	if response.len() < DNS_HEADER_LEN || read_u16(response, 0)? != id {
+		return Err("DNS response doesn't match our query".to_string());
+	}
+	let flags = read_u16(response, 2)?;
+	if flags & FLAG_RESPONSE == 0 {
+		return Err("DNS server sent a query rather than a response".to_string());
+	}
+	if flags & FLAG_TRUNCATED != 0 {
+		return Err("DNS response was too large to be sent over UDP".to_string());
+	}
+	match flags & RCODE_MASK {
+		0 => {},
+		RCODE_NXDOMAIN => return Err("no such name".to_string()),
+		rcode => return Err(format!("DNS server returned error code {}", rcode)),
+	}
+	if flags & FLAG_AUTHENTIC_DATA == 0 {
+		return Err("DNS response was not validated with DNSSEC".to_string());
+	}
+
+	let mut pos = DNS_HEADER_LEN;
+	for _ in 0..read_u16(response, 4)? {
+		pos = skip_name(response, pos)? + 4;
+	}
+	let mut records = Vec::new();
+	for _ in 0..read_u16(response, 6)? {
+		pos = skip_name(response, pos)?;
+		let record_type = read_u16(response, pos)?;
+		let rdata_len = read_u16(response, pos + 8)? as usize;
+		pos += 10;
+		let rdata = response.get(pos..pos + rdata_len).ok_or("DNS response is truncated")?;
+		if record_type == TXT_RECORD_TYPE {
+			records.push(parse_txt_rdata(rdata)?);
+		}
+		pos += rdata_len;
+	}
+	Ok(records)
+}
+
+/// Joins the quoted pieces of a TXT record as DNS-over-HTTPS JSON presents them, e.g.
+/// `"bitcoin:?lno=lno1..." "...rest"`.
+fn parse_txt_data(data: &str) -> Result<String, String> {
+	let invalid = || format!("couldn't parse TXT data {}", data);
+	let mut text = String::new();
+	let mut chars = data.trim().chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'"' => loop {
+				match chars.next().ok_or_else(invalid)? {
+					'"' => break,
+					'\\' => text.push(chars.next().ok_or_else(invalid)?),
+					c => text.push(c),
+				}
+			},
+			c if c.is_whitespace() => {},
+			_ => return Err(invalid()),
+		}
+	}
+	Ok(text)
+}
+
+/// Extracts the TXT records from a DNS-over-HTTPS JSON response.
+fn parse_doh_response(json: &[u8]) -> Result<Vec<String>, String> {
+	let value: serde_json::Value = serde_json::from_slice(json)
+		.map_err(|e| format!("couldn't parse DNS-over-HTTPS response: {}", e))?;
+	match value["Status"].as_u64() {
+		Some(0) => {},
+		Some(3) => return Err("no such name".to_string()),
+		Some(rcode) => return Err(format!("DNS server returned error code {}", rcode)),
+		None => return Err("DNS-over-HTTPS response has no status".to_string()),
+	}
+	if value["AD"].as_bool() != Some(true) {
+		return Err("DNS response was not validated with DNSSEC".to_string());
+	}
+	let answers = value["Answer"].as_array().map(Vec::as_slice).unwrap_or(&[]);
+	answers
+		.iter()
+		.filter(|answer| answer["type"].as_u64() == Some(TXT_RECORD_TYPE as u64))
+		.map(|answer| parse_txt_data(answer["data"].as_str().unwrap_or("")))
+		.collect()
+}
+
+/// Resolves `name` to the BIP 21 URI published for it.
+pub(crate) async fn resolve_uri(
+	resolver: &dyn DnsResolver, name: &HumanReadableName,
+) -> Result<UnifiedUri, String> {
+	let records = resolver.resolve_txt(&name.dns_name()).await?;
+	let uris: Vec<&String> = records.iter().filter(|record| UnifiedUri::is_uri(record)).collect();
+	match uris[..] {
+		[] => Err(format!("{} has no payment instructions", name)),
+		[uri] => UnifiedUri::parse(uri).map_err(|e| format!("{} has an invalid URI: {}", name, e)),
+		// BIP 353 has us refuse to pick between several sets of instructions.
+		_ => Err(format!("{} has more than one set of payment instructions", name)),
+	}
+}
+
+/// Resolves `name` to an offer we can pay on `network`.
+pub(crate) async fn resolve_offer(
+	resolver: &dyn DnsResolver, name: &HumanReadableName, network: Network,
+) -> Result<Offer, String> {
+	let uri = resolve_uri(resolver, name).await?;
+	let offer = uri.offer.ok_or(format!("{} has no offer to pay", name))?;
+	if !Decoded::Offer(offer.clone()).is_for_network(network) {
+		return Err(format!("the offer for {} is not valid on our network ({})", name, network));
+	}
+	if offer.is_expired() {
+		return Err(format!("the offer for {} has expired", name));
+	}
+	Ok(offer)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+	use lightning::offers::offer::OfferBuilder;
+
+	fn offer(network: Network) -> Offer {
+		let secp_ctx = Secp256k1::new();
+		let signing_pubkey =
+			PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+		OfferBuilder::new(signing_pubkey)
+			.chain(network)
+			.description("tips".to_string())
+			.build()
+			.unwrap()
+	}
+
+	fn resolver(records: &[(&str, &[&str])]) -> StaticResolver {
+		let records = records
+			.iter()
+			.map(|(name, txts)| {
+				(name.to_string(), txts.iter().map(|txt| txt.to_string()).collect())
+			})
+			.collect();
+		StaticResolver { records }
+	}
+
+	/// A response to `query` carrying `txt` split into two character-strings, as servers do for
+	/// long records.
+	fn response(query: &[u8], flags: u16, txt: &str) -> Vec<u8> {
+		let (first, second) = txt.split_at(txt.len() / 2);
+		let mut response = query[..2].to_vec();
+		response.extend_from_slice(&flags.to_be_bytes());
+		for count in [1u16, 1, 0, 0].iter() {
+			response.extend_from_slice(&count.to_be_bytes());
+		}
+		// The question, without the OPT record that follows it in the query.
+		response.extend_from_slice(&query[DNS_HEADER_LEN..query.len() - 11]);
+		// The answer's name is a pointer back to the question's.
+		response.extend_from_slice(&[0xc0, DNS_HEADER_LEN as u8]);
+		response.extend_from_slice(&TXT_RECORD_TYPE.to_be_bytes());
+		response.extend_from_slice(&CLASS_IN.to_be_bytes());
+		response.extend_from_slice(&3600u32.to_be_bytes());
+		response.extend_from_slice(&((txt.len() + 2) as u16).to_be_bytes());
+		for piece in [first, second].iter() {
+			response.push(piece.len() as u8);
+			response.extend_from_slice(piece.as_bytes());
+		}
+		response
+	}
+
+	#[test]
+	fn test_parse_name() {
+		let name = HumanReadableName::parse("₿Alice@Example.com").unwrap();
+		assert_eq!(name.to_string(), "₿alice@example.com");
+		assert_eq!(name.dns_name(), "alice.user._bitcoin-payment.example.com.");
+		assert!(HumanReadableName::is_name("₿alice@example.com"));
+		assert!(!HumanReadableName::is_name("alice@example.com"));
+
+		assert!(HumanReadableName::parse("₿alice").is_err());
+		assert!(HumanReadableName::parse("₿@example.com").is_err());
+		assert!(HumanReadableName::parse("₿al ice@example.com").is_err());
+		assert!(HumanReadableName::parse("₿alice@example..com").is_err());
+		assert!(HumanReadableName::parse(&format!("₿{}@example.com", "a".repeat(64))).is_err());
+	}
+
+	#[test]
+	fn test_dns_wire_format() {
+		let name = "alice.user._bitcoin-payment.example.com.";
+		let query = build_query(0x1234, name).unwrap();
+		assert_eq!(&query[..4], &[0x12, 0x34, 0x01, 0x20]);
+		assert_eq!(&query[13..18], b"alice");
+		assert!(build_query(1, "alice..example.com").is_err());
+
+		let validated = FLAG_RESPONSE | FLAG_RECURSION_DESIRED | FLAG_AUTHENTIC_DATA;
+		let txt = "bitcoin:?lno=lno1qgsqvgnwgcg35z6ee2h3yczraddm72xrfua9uve2rlrm9deu7xyfzr";
+		let records = parse_response(0x1234, &response(&query, validated, txt)).unwrap();
+		assert_eq!(records, vec![txt.to_string()]);
+
+		let unvalidated = validated & !FLAG_AUTHENTIC_DATA;
+		assert!(parse_response(0x1234, &response(&query, unvalidated, txt)).is_err());
+		let nxdomain = validated | RCODE_NXDOMAIN;
+		let error = parse_response(0x1234, &response(&query, nxdomain, txt)).unwrap_err();
+		assert_eq!(error, "no such name");
+		assert!(parse_response(0x1234, &response(&query, validated | FLAG_TRUNCATED, txt)).is_err());
+		assert!(parse_response(0x4321, &response(&query, validated, txt)).is_err());
+		let truncated = response(&query, validated, txt);
+		assert!(parse_response(0x1234, &truncated[..truncated.len() - 5]).is_err());
+	}
+
+	#[test]
+	fn test_doh_response() {
+		let json = br#"{"Status": 0, "AD": true, "Answer": [
+			{"name": "alice.user._bitcoin-payment.example.com.", "type": 5, "data": "elsewhere."},
+			{"name": "alice.user._bitcoin-payment.example.com.", "type": 16,
+				"data": "\"bitcoin:?lno=lno1qgs\" \"qvgnwgcg35z\""}
+		]}"#;
+		assert_eq!(parse_doh_response(json).unwrap(), vec!["bitcoin:?lno=lno1qgsqvgnwgcg35z"]);
+		assert!(parse_doh_response(br#"{"Status": 0, "AD": false, "Answer": []}"#).is_err());
+		assert_eq!(
+			parse_doh_response(br#"{"Status": 3, "AD": true}"#).unwrap_err(),
+			"no such name"
+		);
+
+		assert_eq!(parse_txt_data(r#""a \"quoted\" word""#).unwrap(), r#"a "quoted" word"#);
+		assert!(parse_txt_data(r#""unterminated"#).is_err());
+	}
+
+	#[test]
+	fn test_parse_resolver() {
+		assert!(parse_resolver("https://dns.google/resolve").is_ok());
+		assert!(parse_resolver("127.0.0.1").is_ok());
+		assert!(parse_resolver("[::1]:5353").is_ok());
+		assert!(parse_resolver("system").is_err());
+		assert!(parse_resolver("dns.google").is_err());
+		assert!(parse_resolver("http://dns.google/resolve").is_err());
+		assert!(parse_resolver("8.8.8.8").is_err());
+		assert!(parse_resolver("192.168.1.1:53").is_err());
+	}
+
+	#[tokio::test]
+	async fn test_resolve_offer() {
+		let regtest_offer = offer(Network::Regtest);
+		let uri = format!("bitcoin:?lno={}", regtest_offer);
+		let testnet_uri = format!("bitcoin:?lno={}", offer(Network::Testnet));
+		let resolver = resolver(&[
+			("alice.user._bitcoin-payment.example.com.", &["v=spf1 -all", uri.as_str()]),
+			("bob.user._bitcoin-payment.example.com.", &[uri.as_str(), uri.as_str()]),
+			(
+				"carol.user._bitcoin-payment.example.com.",
+				&["bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"],
+			),
+			("dave.user._bitcoin-payment.example.com.", &[testnet_uri.as_str()]),
+		]);
+
+		let alice = HumanReadableName::parse("₿alice@example.com").unwrap();
+		let resolved = resolve_offer(&resolver, &alice, Network::Regtest).await;
+		assert_eq!(resolved.unwrap(), regtest_offer);
+
+		async fn resolve_error(resolver: &StaticResolver, name: &str) -> String {
+			let name = HumanReadableName::parse(name).unwrap();
+			resolve_offer(resolver, &name, Network::Regtest).await.unwrap_err()
+		}
+		assert!(resolve_error(&resolver, "₿bob@example.com").await.contains("more than one"));
+		assert!(resolve_error(&resolver, "₿carol@example.com").await.contains("no offer"));
+		assert!(resolve_error(&resolver, "₿dave@example.com").await.contains("network"));
+		assert!(resolve_error(&resolver, "₿erin@example.com").await.contains("no such name"));
+	}
+}
@@ -323,4 +323,15 @@ impl BitcoindClient {
 	pub async fn get_raw_mempool(&self) -> MempoolInfo {
 		self.bitcoind_rpc_client.call_method("getrawmempool", &[]).await.unwrap()
 	}
+
+	/// Broadcasts a transaction, returning bitcoind's error if it rejects it. Unlike
+	/// [`BroadcasterInterface::broadcast_transactions`] this waits for the result.
+	pub async fn send_raw_transaction(&self, tx: &Transaction) -> Result<(), String> {
+		let tx_json = serde_json::json!(encode::serialize_hex(tx));
+		self.bitcoind_rpc_client
+			.call_method::<serde_json::Value>("sendrawtransaction", &[tx_json])
+			.await
+			.map(|_| ())
+			.map_err(|e| e.to_string())
+	}
 }
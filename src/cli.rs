@@ -1,39 +1,65 @@
 #![allow(dead_code, unused_imports, unused_variables, unused_must_use, unexpected_cfgs)]
+use crate::bip21::{UnifiedRequest, UnifiedRequestStorage, UnifiedUri};
+use crate::bip353::{self, DnsResolver, HumanReadableName};
+use crate::bitcoind_client::BitcoindClient;
+use crate::decode::Decoded;
 use crate::disk::{self, INBOUND_PAYMENTS_FNAME, OUTBOUND_PAYMENTS_FNAME};
 use crate::hex_utils;
+use crate::exchange_rate::{self, ExchangeRateSource};
+use crate::http;
+use crate::invoice::{self, InvoiceDescription, InvoiceOptions};
 use crate::filesystem_store::FilesystemStore;
+use crate::graph::{self, ExportFormat, GraphFilter};
+use crate::lnurl::{self, LightningAddress, LnurlServerConfig};
 use crate::logger::FilesystemLogger;
+use crate::messaging::{self, ChatMessage, ConversationLog};
+use crate::offers::{
+	self, Bolt12InvoicePolicy, OfferOptions, OfferStorage, PendingBolt12Invoices, RefundOptions,
+};
+use crate::probing::{Prober, ProbingConfig};
+use crate::qr::{self, QrOptions};
+use crate::rebalance::{self, CircularRouter, RebalanceStorage};
+use crate::rgs::{self, RapidGossipSync, RgsSource};
+use crate::routing::{self, AvoidList, PaymentOptions};
+use crate::sweep::{self, BumpedSweeps, SweepDestination};
 use crate::{
 	ChainMonitor, ChannelManager, HTLCStatus, InboundPaymentInfoStorage, MillisatAmount,
-	NetworkGraph, OutboundPaymentInfoStorage, PaymentInfo, PeerManager, LdkOnChainWallet
+	NetworkGraph, OutboundPaymentInfoStorage, OutputSweeper, PaymentInfo, PeerManager,
+	LdkOnChainWallet, Scorer,
 };
+use bitcoin::address::NetworkUnchecked;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::network::Network;
 use bitcoin::secp256k1::PublicKey;
+use bitcoin::{Address, Amount, Transaction};
+use chrono::Utc;
+use lightning::chain::chaininterface::{BroadcasterInterface, ConfirmationTarget};
 use lightning::chain::channelmonitor::Balance;
 use lightning::ln::bolt11_payment::payment_parameters_from_invoice;
 use lightning::ln::bolt11_payment::payment_parameters_from_zero_amount_invoice;
 use lightning::ln::channelmanager::{PaymentId, RecipientOnionFields, Retry};
-use lightning::ln::invoice_utils as utils;
 use lightning::ln::msgs::SocketAddress;
 use lightning::ln::types::ChannelId;
 use lightning::offers::offer::{self, Offer};
+use lightning::offers::refund::Refund;
 use lightning::routing::gossip::NodeId;
 use lightning::routing::router::{PaymentParameters, RouteParameters};
-use lightning::sign::{EntropySource, KeysManager};
+use lightning::sign::{EntropySource, KeysManager, SpendableOutputDescriptor};
 use lightning::types::payment::{PaymentHash, PaymentPreimage};
 use lightning::util::config::{ChannelHandshakeConfig, ChannelHandshakeLimits, UserConfig};
 use lightning::util::persist::KVStore;
 use lightning::util::ser::Writeable;
-use lightning_invoice::{Bolt11Invoice, Currency};
+use lightning::util::sweep::OutputSpendStatus;
+use lightning_invoice::Bolt11Invoice;
+use std::collections::HashMap;
 use std::env;
 use std::io::Write;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::path::Path;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime};
 
 pub(crate) struct LdkUserInfo {
 	pub(crate) bitcoind_rpc_username: String,
@@ -45,16 +71,88 @@ pub(crate) struct LdkUserInfo {
 	pub(crate) ldk_announced_listen_addr: Vec<SocketAddress>,
 	pub(crate) ldk_announced_node_name: [u8; 32],
 	pub(crate) network: Network,
+	/// An output descriptor (or bare xpub) that swept channel funds are sent to instead of the
+	/// on-chain wallet, e.g. for cold storage.
+	pub(crate) sweep_descriptor: Option<String>,
+	/// A Rapid Gossip Sync snapshot file path or server URL used to bootstrap the network graph.
+	pub(crate) rgs_source: Option<String>,
+	/// Settings for background probing, if enabled.
+	pub(crate) probing: Option<ProbingConfig>,
+	/// Custom TLV types we accept on incoming payments. Others are dropped, or cause the payment
+	/// to be rejected if they're even.
+	pub(crate) accepted_tlv_types: Vec<u64>,
+	/// If set, invoices for offers we pay are checked against this policy before being paid.
+	pub(crate) bolt12_invoice_policy: Option<Bolt12InvoicePolicy>,
+	/// An exchange rate file path or URL, used to price our offers in fiat currencies.
+	pub(crate) exchange_rate_source: Option<String>,
+	/// If set, we serve LNURL-pay so we can be paid at Lightning Addresses on our own domain.
+	pub(crate) lnurl_server: Option<LnurlServerConfig>,
+	/// How BIP 353 human-readable names are resolved: a DNS-over-HTTPS URL, or the address of a
+	/// DNS server on this machine. Names can't be paid without one.
+	pub(crate) dns_resolver: Option<String>,
 }
 
-pub(crate) fn poll_for_user_input(
-	peer_manager: Arc<PeerManager>, channel_manager: Arc<ChannelManager>,
-	chain_monitor: Arc<ChainMonitor>, keys_manager: Arc<KeysManager>,
-	on_chain_wallet: Arc<LdkOnChainWallet>,
-	network_graph: Arc<NetworkGraph>, inbound_payments: Arc<Mutex<InboundPaymentInfoStorage>>,
-	outbound_payments: Arc<Mutex<OutboundPaymentInfoStorage>>, ldk_data_dir: String,
-	network: Network, logger: Arc<FilesystemLogger>, fs_store: Arc<FilesystemStore>,
-) {
+/// Everything the CLI needs to run commands against the node.
+pub(crate) struct CliContext {
+	pub(crate) peer_manager: Arc<PeerManager>,
+	pub(crate) channel_manager: Arc<ChannelManager>,
+	pub(crate) chain_monitor: Arc<ChainMonitor>,
+	pub(crate) keys_manager: Arc<KeysManager>,
+	pub(crate) on_chain_wallet: Arc<LdkOnChainWallet>,
+	pub(crate) network_graph: Arc<NetworkGraph>,
+	pub(crate) scorer: Arc<RwLock<Scorer>>,
+	pub(crate) prober: Arc<Prober>,
+	pub(crate) avoid_list: Arc<Mutex<AvoidList>>,
+	pub(crate) rapid_gossip_sync: Arc<RapidGossipSync>,
+	pub(crate) rgs_source: Option<String>,
+	pub(crate) inbound_payments: Arc<Mutex<InboundPaymentInfoStorage>>,
+	pub(crate) outbound_payments: Arc<Mutex<OutboundPaymentInfoStorage>>,
+	pub(crate) rebalances: Arc<Mutex<RebalanceStorage>>,
+	pub(crate) conversation_log: Arc<Mutex<ConversationLog>>,
+	pub(crate) offers: Arc<Mutex<OfferStorage>>,
+	pub(crate) unified_requests: Arc<Mutex<UnifiedRequestStorage>>,
+	pub(crate) pending_bolt12_invoices: Arc<PendingBolt12Invoices>,
+	pub(crate) exchange_rate_source: Option<Box<dyn ExchangeRateSource>>,
+	pub(crate) dns_resolver: Option<Box<dyn DnsResolver>>,
+	pub(crate) output_sweeper: Arc<OutputSweeper>,
+	pub(crate) sweep_destination: Arc<SweepDestination>,
+	pub(crate) bitcoind_client: Arc<BitcoindClient>,
+	pub(crate) ldk_data_dir: String,
+	pub(crate) network: Network,
+	pub(crate) logger: Arc<FilesystemLogger>,
+	pub(crate) fs_store: Arc<FilesystemStore>,
+}
+
+pub(crate) fn poll_for_user_input(context: CliContext) {
+	let CliContext {
+		peer_manager,
+		channel_manager,
+		chain_monitor,
+		keys_manager,
+		on_chain_wallet,
+		network_graph,
+		scorer,
+		prober,
+		avoid_list,
+		rapid_gossip_sync,
+		rgs_source,
+		inbound_payments,
+		outbound_payments,
+		rebalances,
+		conversation_log,
+		offers,
+		unified_requests,
+		pending_bolt12_invoices,
+		exchange_rate_source,
+		dns_resolver,
+		output_sweeper,
+		sweep_destination,
+		bitcoind_client,
+		ldk_data_dir,
+		network,
+		logger,
+		fs_store,
+	} = context;
 	println!(
 		"LDK startup successful. Enter \"help\" to view available commands. Press Ctrl-D to quit."
 	);
@@ -142,8 +240,17 @@ pub(crate) fn poll_for_user_input(
 					}
 				},
 				"getaddress" => {
+					let mut args: Vec<String> = words.map(|word| word.to_string()).collect();
+					let qr = match QrOptions::extract(&mut args) {
+						Ok(qr) => qr,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
 					let address = on_chain_wallet.get_address();
 					println!("On Chain Wallet Address: {:?}", address);
+					qr::show(&address.to_string(), &qr);
 				},
 				"getbalance" => {
 					let balance = on_chain_wallet.get_balance();
@@ -152,12 +259,22 @@ pub(crate) fn poll_for_user_input(
 				"sendpayment" => {
 					let invoice_str = words.next();
 					if invoice_str.is_none() {
-						println!("ERROR: sendpayment requires an invoice: `sendpayment <invoice>`");
+						println!("ERROR: sendpayment requires an invoice, offer, bitcoin: URI, Lightning Address or human-readable name: `sendpayment <invoice|offer|uri|user@domain|₿user@domain>`");
 						continue;
 					}
 
+					// The payer note may contain spaces, so parse the options from the raw line.
+					let args = line.trim_start().trim_start_matches("sendpayment");
+					let args = match invoice::split_quoted(args) {
+						Ok(args) => args,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
+					let mut args = args.iter().map(String::as_str).skip(1).peekable();
 					let mut user_provided_amt: Option<u64> = None;
-					if let Some(amt_msat_str) = words.next() {
+					if let Some(amt_msat_str) = args.next_if(|arg| !arg.starts_with("--")) {
 						match amt_msat_str.parse() {
 							Ok(amt) => user_provided_amt = Some(amt),
 							Err(e) => {
@@ -166,16 +283,214 @@ pub(crate) fn poll_for_user_input(
 							},
 						};
 					}
+					let mut options = match PaymentOptions::parse(args) {
+						Ok(options) => options,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
+					if let Err(e) = options.resolve_excluded_channels(
+						&channel_manager,
+						&network_graph,
+						&avoid_list.lock().unwrap(),
+					) {
+						println!("ERROR: {}", e);
+						continue;
+					}
+
+					let (hrn_destination, uri_destination);
+					let mut destination = invoice_str.unwrap();
+					let mut resolved_name = None;
+					// Human-readable names resolve to an offer, which is then paid as any other.
+					if HumanReadableName::is_name(destination) {
+						let name = match HumanReadableName::parse(destination) {
+							Ok(name) => name,
+							Err(e) => {
+								println!("ERROR: {}", e);
+								continue;
+							},
+						};
+						let dns_resolver = match &dns_resolver {
+							Some(dns_resolver) => dns_resolver,
+							None => {
+								println!("ERROR: paying human-readable names requires starting the node with --dns-resolver");
+								continue;
+							},
+						};
+						let resolve = bip353::resolve_offer(&**dns_resolver, &name, network);
+						match tokio::runtime::Handle::current().block_on(resolve) {
+							Ok(offer) => {
+								println!("Resolved {} to offer {}", name, offer);
+								hrn_destination = offer.to_string();
+								destination = &hrn_destination;
+								resolved_name = Some(name);
+							},
+							Err(e) => {
+								println!("ERROR: couldn't resolve {}: {}", name, e);
+								continue;
+							},
+						}
+					}
+					if UnifiedUri::is_uri(destination) {
+						let uri = match UnifiedUri::parse(destination) {
+							Ok(uri) => uri,
+							Err(e) => {
+								println!("ERROR: invalid payment URI: {}", e);
+								continue;
+							},
+						};
+						let uri_amt_msat = uri.amount.map(|amt| amt.to_sat() * 1000);
+						// Prefer paying over Lightning, only falling back to an on-chain payment
+						// if the URI offers no other way.
+						if let Some(offer) = &uri.offer {
+							if offer.amount().is_none() {
+								user_provided_amt = user_provided_amt.or(uri_amt_msat);
+							}
+							uri_destination = offer.to_string();
+						} else if let Some(invoice) = &uri.invoice {
+							if invoice.amount_milli_satoshis().is_none() {
+								user_provided_amt = user_provided_amt.or(uri_amt_msat);
+							}
+							uri_destination = invoice.to_string();
+						} else {
+							let amt_sat = match (uri.amount, user_provided_amt) {
+								(Some(amt), _) => amt,
+								(None, Some(amt_msat)) => Amount::from_sat(amt_msat / 1000),
+								(None, None) => {
+									println!("ERROR: this URI has no amount, so one must be given: `sendpayment <uri> <amt_msats>`");
+									continue;
+								},
+							};
+							let (address, tx, fee) = match create_onchain_send(
+								uri.address.unwrap(),
+								amt_sat,
+								network,
+								&on_chain_wallet,
+							) {
+								Ok(send) => send,
+								Err(e) => {
+									println!("ERROR: {}", e);
+									continue;
+								},
+							};
+							loop {
+								println!("Address: {}", address);
+								println!("Amount: {} sats", amt_sat.to_sat());
+								println!("Fee: {} sats", fee.to_sat());
+								print!("Sending on-chain. Continue (Y/N)? >");
+								std::io::stdout().flush().unwrap();
+								line.clear();
+
+								if let Err(e) = std::io::stdin().read_line(&mut line) {
+									println!("ERROR: {}", e);
+									break 'read_command;
+								}
+
+								if line.is_empty() {
+									// We hit EOF / Ctrl-D
+									break 'read_command;
+								}
+
+								if line.starts_with("Y") {
+									break;
+								}
+								if line.starts_with("N") {
+									continue 'read_command;
+								}
+							}
+							bitcoind_client.broadcast_transactions(&[&tx]);
+							println!(
+								"SUCCESS: sent {} sats to {} in transaction {}",
+								amt_sat.to_sat(),
+								address,
+								tx.compute_txid()
+							);
+							continue;
+						}
+						destination = &uri_destination;
+					}
+
+					if let Some(address) = LightningAddress::parse(destination) {
+						if options.offers_only() {
+							println!("ERROR: --quantity and --note only apply to offers");
+							continue;
+						}
+						let amt_msat = match user_provided_amt {
+							Some(amt) => amt,
+							None => {
+								println!("ERROR: paying a Lightning Address requires an amount: `sendpayment <user@domain> <amt_msats> [--comment <text>]`");
+								continue;
+							},
+						};
+						let resolve = lnurl::resolve_invoice(
+							&http::ReqwestClient,
+							&address,
+							amt_msat,
+							options.comment.as_deref(),
+						);
+						match tokio::runtime::Handle::current().block_on(resolve) {
+							Ok(invoice) => {
+								println!("Resolved {} to invoice {}", address, invoice);
+								send_payment(
+									&channel_manager,
+									&invoice,
+									Some(amt_msat),
+									&options,
+									&mut outbound_payments.lock().unwrap(),
+									Arc::clone(&fs_store),
+								);
+							},
+							Err(e) => println!("ERROR: {}", e),
+						}
+						continue;
+					}
+					if options.lightning_address_only() {
+						println!("ERROR: --comment only applies to Lightning Addresses");
+						continue;
+					}
 
-					if let Ok(offer) = Offer::from_str(invoice_str.unwrap()) {
+					if let Ok(offer) = Offer::from_str(destination) {
+						// LDK routes offer payments itself, so only the fee cap and retry strategy
+						// can be passed through. Our router still applies the global avoid-list.
+						if options.unsupported_for_offers() {
+							println!("ERROR: only --max-fee-msat, --max-fee-ppm, --retries and --timeout apply to offers");
+							continue;
+						}
 						let random_bytes = keys_manager.get_secure_random_bytes();
 						let payment_id = PaymentId(random_bytes);
 
+						if offer.expects_quantity() && options.quantity.is_none() {
+							println!("ERROR: this offer requires a quantity: `--quantity <n>`");
+							continue;
+						}
+						if let Some(quantity) = options.quantity {
+							if !offer.is_valid_quantity(quantity) {
+								println!("ERROR: quantity {} is not accepted by this offer", quantity);
+								continue;
+							}
+						}
+						let quantity = options.quantity.unwrap_or(1);
 						let amt_msat = match (offer.amount(), user_provided_amt) {
-							(Some(offer::Amount::Bitcoin { amount_msats }), _) => amount_msats,
-							(_, Some(amt)) => amt,
-							(amt, _) => {
-								println!("ERROR: Cannot process non-Bitcoin-denominated offer value {:?}", amt);
+							(Some(offer::Amount::Bitcoin { amount_msats }), _) => {
+								match amount_msats.checked_mul(quantity) {
+									Some(amt) => amt,
+									None => {
+										println!("ERROR: offer amount overflows for quantity {}", quantity);
+										continue;
+									},
+								}
+							},
+							(Some(offer::Amount::Currency { iso4217_code, .. }), _) => {
+								// LDK 0.0.125 fails to request invoices for offers that aren't priced in
+								// bitcoin, whatever amount we'd offer to pay.
+								let currency = String::from_utf8_lossy(&iso4217_code).to_string();
+								println!("ERROR: this offer is priced in {}, which LDK 0.0.125 can't pay as it only supports offers priced in bitcoin", currency);
+								continue;
+							},
+							(None, Some(amt)) => amt,
+							(None, None) => {
+								println!("ERROR: this offer has no amount, so one must be given: `sendpayment <offer> <amt_msats>`");
 								continue;
 							},
 						};
@@ -184,9 +499,28 @@ pub(crate) fn poll_for_user_input(
 							continue;
 						}
 
-						while user_provided_amt.is_none() {
+						// Always confirm paying a resolved name, so the user can check who the offer
+						// they're paying is from.
+						while user_provided_amt.is_none() || resolved_name.is_some() {
+							if let Some(name) = &resolved_name {
+								println!("Name: {}", name);
+							}
+							match offer.issuer() {
+								Some(issuer) => println!("Issuer: {}", issuer),
+								None if resolved_name.is_some() => {
+									println!("Issuer: not given by the offer")
+								},
+								None => {},
+							}
+							if let Some(description) = offer.description() {
+								println!("Description: {}", description);
+							}
+							if options.quantity.is_some() {
+								println!("Quantity: {}", quantity);
+							}
 							print!("Paying offer for {} msat. Continue (Y/N)? >", amt_msat);
 							std::io::stdout().flush().unwrap();
+							line.clear();
 
 							if let Err(e) = std::io::stdin().read_line(&mut line) {
 								println!("ERROR: {}", e);
@@ -213,25 +547,48 @@ pub(crate) fn poll_for_user_input(
 								secret: None,
 								status: HTLCStatus::Pending,
 								amt_msat: MillisatAmount(Some(amt_msat)),
+								custom_tlvs: Vec::new(),
+								bolt11_invoice: None,
+								settle_index: None,
+								payer_note: options.payer_note.clone(),
+								quantity: options.quantity,
+								lnurl_user: None,
+								lnurl_comment: None,
 							},
 						);
 						fs_store
 							.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.encode())
 							.unwrap();
 
-						let retry = Retry::Timeout(Duration::from_secs(10));
+						let retry = options.retry();
 						let amt = Some(amt_msat);
-						let pay = channel_manager
-							.pay_for_offer(&offer, None, amt, None, payment_id, retry, None);
+						let max_fee = options.max_total_routing_fee_msat(amt_msat);
+						let note = options.payer_note.clone();
+						let pay = channel_manager.pay_for_offer(
+							&offer,
+							options.quantity,
+							amt,
+							note,
+							payment_id,
+							retry,
+							max_fee,
+						);
 						if pay.is_err() {
 							println!("ERROR: Failed to pay: {:?}", pay);
+							// Nothing was sent, so don't leave the payment looking pending.
+							let mut outbound = outbound_payments.lock().unwrap();
+							outbound.payments.remove(&payment_id);
+							fs_store.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound.encode()).unwrap();
 						}
+					} else if options.offers_only() {
+						println!("ERROR: --quantity and --note only apply to offers");
 					} else {
-						match Bolt11Invoice::from_str(invoice_str.unwrap()) {
+						match Bolt11Invoice::from_str(destination) {
 							Ok(invoice) => send_payment(
 								&channel_manager,
 								&invoice,
 								user_provided_amt,
+								&options,
 								&mut outbound_payments.lock().unwrap(),
 								Arc::clone(&fs_store),
 							),
@@ -269,82 +626,680 @@ pub(crate) fn poll_for_user_input(
 							continue;
 						},
 					};
+					let mut options = match PaymentOptions::parse(words.by_ref()) {
+						Ok(options) => options,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
+					if options.offers_only() {
+						println!("ERROR: --quantity and --note only apply to offers");
+						continue;
+					}
+					if options.lightning_address_only() {
+						println!("ERROR: --comment only applies to Lightning Addresses");
+						continue;
+					}
+					if let Err(e) = options.resolve_excluded_channels(
+						&channel_manager,
+						&network_graph,
+						&avoid_list.lock().unwrap(),
+					) {
+						println!("ERROR: {}", e);
+						continue;
+					}
 					keysend(
 						&channel_manager,
 						dest_pubkey,
 						amt_msat,
+						&options,
 						&*keys_manager,
 						&mut outbound_payments.lock().unwrap(),
 						Arc::clone(&fs_store),
 					);
 				},
-				"getoffer" => {
-					let offer_builder = channel_manager.create_offer_builder(None);
-					if let Err(e) = offer_builder {
-						println!("ERROR: Failed to initiate offer building: {:?}", e);
+				"queryroutes" => {
+					let destination = match words.next() {
+						Some(destination) => destination,
+						None => {
+							println!("ERROR: queryroutes requires a destination: `queryroutes <invoice|pubkey amt_msat> [--max-fee <msat>] [--max-cltv <blocks>] [--avoid <node|scid>...]`");
+							continue;
+						},
+					};
+					let mut args = words.by_ref().peekable();
+					let amt_msat = match args.next_if(|arg| !arg.starts_with("--")) {
+						Some(amt) => match amt.parse::<u64>() {
+							Ok(amt) => Some(amt),
+							Err(e) => {
+								println!("ERROR: couldn't parse amount_msat: {}", e);
+								continue;
+							},
+						},
+						None => None,
+					};
+					let mut route_params =
+						match routing::route_params_for_destination(destination, amt_msat) {
+							Ok(route_params) => route_params,
+							Err(e) => {
+								println!("ERROR: {}", e);
+								continue;
+							},
+						};
+					let mut avoid = Vec::new();
+					let mut parse_error = None;
+					while let Some(flag) = args.next() {
+						match (flag, args.next()) {
+							("--max-fee", Some(max_fee)) => match max_fee.parse() {
+								Ok(max_fee) => route_params.max_total_routing_fee_msat = Some(max_fee),
+								Err(e) => parse_error = Some(format!("couldn't parse --max-fee: {}", e)),
+							},
+							("--max-cltv", Some(max_cltv)) => match max_cltv.parse() {
+								Ok(max_cltv) => {
+									route_params.payment_params.max_total_cltv_expiry_delta = max_cltv
+								},
+								Err(e) => {
+									parse_error = Some(format!("couldn't parse --max-cltv: {}", e))
+								},
+							},
+							("--avoid", Some(target)) => {
+								avoid.push(target);
+								while let Some(target) = args.next_if(|arg| !arg.starts_with("--")) {
+									avoid.push(target);
+								}
+							},
+							_ => parse_error = Some(format!("unknown or incomplete option {}", flag)),
+						}
+						if parse_error.is_some() {
+							break;
+						}
+					}
+					if let Some(e) = parse_error {
+						println!("ERROR: {}", e);
 						continue;
 					}
-
-					let amt_str = words.next();
-					let offer = if amt_str.is_some() {
-						let amt_msat: Result<u64, _> = amt_str.unwrap().parse();
-						if amt_msat.is_err() {
-							println!("ERROR: getoffer provided payment amount was not a number");
+					let avoided_channels = {
+						let graph = network_graph.read_only();
+						avoid
+							.iter()
+							.map(|target| routing::channels_to_avoid(&graph, target))
+							.collect::<Result<Vec<_>, _>>()
+					};
+					match avoided_channels {
+						Ok(scids) => route_params
+							.payment_params
+							.previously_failed_channels
+							.extend(scids.into_iter().flatten()),
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					}
+					routing::apply_avoid_list(
+						&channel_manager,
+						&network_graph,
+						&avoid_list.lock().unwrap(),
+						&mut route_params,
+					);
+					match routing::query_route(
+						&channel_manager,
+						&network_graph,
+						&scorer,
+						Arc::clone(&logger),
+						&route_params,
+						&keys_manager.get_secure_random_bytes(),
+					) {
+						Ok(route) => routing::print_route(&route, &network_graph, &scorer),
+						Err(e) => println!("ERROR: no route found: {}", e),
+					}
+				},
+				"probe" => {
+					let destination = match words.next() {
+						Some(destination) => destination,
+						None => {
+							println!("ERROR: probe requires a destination: `probe <invoice|pubkey amt_msat>`");
+							continue;
+						},
+					};
+					let amt_msat = match words.next().map(|amt| amt.parse::<u64>()) {
+						Some(Ok(amt)) => Some(amt),
+						Some(Err(e)) => {
+							println!("ERROR: couldn't parse amount_msat: {}", e);
+							continue;
+						},
+						None => None,
+					};
+					let mut route_params =
+						match routing::route_params_for_destination(destination, amt_msat) {
+							Ok(route_params) => route_params,
+							Err(e) => {
+								println!("ERROR: {}", e);
+								continue;
+							},
+						};
+					routing::apply_avoid_list(
+						&channel_manager,
+						&network_graph,
+						&avoid_list.lock().unwrap(),
+						&mut route_params,
+					);
+					match prober.probe(route_params) {
+						Ok(num_probes) => println!(
+							"SUCCESS: sent {} probe(s), results will be reported as they arrive",
+							num_probes
+						),
+						Err(e) => println!("ERROR: {}", e),
+					}
+				},
+				"avoidlist" => {
+					let mut avoid_list = avoid_list.lock().unwrap();
+					let (command, target) = match (words.next(), words.next()) {
+						(None, _) => {
+							print_avoid_list(&avoid_list, &network_graph);
+							continue;
+						},
+						(Some(command @ ("add" | "remove")), Some(target)) => (command, target),
+						_ => {
+							println!("ERROR: usage: `avoidlist [add|remove <node_pubkey|short_channel_id>]`");
 							continue;
+						},
+					};
+					let changed = if let Some(pubkey) = hex_utils::to_compressed_pubkey(target) {
+						let node_id = NodeId::from_pubkey(&pubkey);
+						match command {
+							"add" => avoid_list.nodes.insert(node_id),
+							_ => avoid_list.nodes.remove(&node_id),
+						}
+					} else if let Some(scid) = graph::parse_scid(target) {
+						match command {
+							"add" => avoid_list.channels.insert(scid),
+							_ => avoid_list.channels.remove(&scid),
 						}
-						offer_builder.unwrap().amount_msats(amt_msat.unwrap()).build()
 					} else {
-						offer_builder.unwrap().build()
+						println!("ERROR: {} is neither a node pubkey nor a short channel id", target);
+						continue;
 					};
-
-					if offer.is_err() {
-						println!("ERROR: Failed to build offer: {:?}", offer.unwrap_err());
-					} else {
-						// Note that unlike BOLT11 invoice creation we don't bother to add a
-						// pending inbound payment here, as offers can be reused and don't
-						// correspond with individual payments.
-						println!("{}", offer.unwrap());
+					if !changed {
+						println!("Avoid-list unchanged");
+						continue;
+					}
+					match avoid_list.persist(&fs_store) {
+						Ok(()) => println!("SUCCESS: updated avoid-list"),
+						Err(e) => println!("ERROR: {}", e),
 					}
 				},
-				"getinvoice" => {
-					let amt_str = words.next();
-					if amt_str.is_none() {
-						println!("ERROR: getinvoice requires an amount in millisatoshis");
+				"rebalance" => {
+					let (from_str, to_str, amt_msat_str) =
+						match (words.next(), words.next(), words.next()) {
+							(Some(from), Some(to), Some(amt)) => (from, to, amt),
+							_ => {
+								println!("ERROR: rebalance requires two channels and an amount: `rebalance <from_channel> <to_channel> <amt_msat> [--max-fee-ppm <ppm>]`");
+								continue;
+							},
+						};
+					let amt_msat: u64 = match amt_msat_str.parse() {
+						Ok(amt) => amt,
+						Err(e) => {
+							println!("ERROR: couldn't parse amount_msat: {}", e);
+							continue;
+						},
+					};
+					let max_fee_ppm = match (words.next(), words.next()) {
+						(None, _) => None,
+						(Some("--max-fee-ppm"), Some(ppm)) => match ppm.parse::<u64>() {
+							Ok(ppm) => Some(ppm),
+							Err(e) => {
+								println!("ERROR: couldn't parse --max-fee-ppm: {}", e);
+								continue;
+							},
+						},
+						_ => {
+							println!("ERROR: rebalance only accepts `--max-fee-ppm <ppm>` after the amount");
+							continue;
+						},
+					};
+					let first_hops = channel_manager.list_usable_channels();
+					let from = match routing::find_first_hop(&first_hops, from_str) {
+						Some(chan) => chan,
+						None => {
+							println!("ERROR: {} is not one of our usable channels", from_str);
+							continue;
+						},
+					};
+					let to = match routing::find_first_hop(&first_hops, to_str) {
+						Some(chan) => chan,
+						None => {
+							println!("ERROR: {} is not one of our usable channels", to_str);
+							continue;
+						},
+					};
+					if from.channel_id == to.channel_id {
+						println!("ERROR: can't rebalance a channel into itself");
 						continue;
 					}
-
-					let amt_msat: Result<u64, _> = amt_str.unwrap().parse();
-					if amt_msat.is_err() {
-						println!("ERROR: getinvoice provided payment amount was not a number");
-						continue;
+					let random_seed_bytes = keys_manager.get_secure_random_bytes();
+					let route = {
+						let avoid_list = avoid_list.lock().unwrap();
+						let router = CircularRouter::new(
+							&channel_manager,
+							&network_graph,
+							&scorer,
+							Arc::clone(&logger),
+							&avoid_list,
+						);
+						router.build_route(from, to, amt_msat, &random_seed_bytes)
+					};
+					let route = match route {
+						Ok(route) => route,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
+					let fee_msat = route.get_total_fees();
+					if let Some(ppm) = max_fee_ppm {
+						let max_fee_msat = (amt_msat as u128 * ppm as u128 / 1_000_000) as u64;
+						if fee_msat > max_fee_msat {
+							println!(
+								"ERROR: cheapest route found costs {} msat, more than the {} msat allowed",
+								fee_msat, max_fee_msat
+							);
+							continue;
+						}
 					}
-
-					let expiry_secs_str = words.next();
-					if expiry_secs_str.is_none() {
-						println!("ERROR: getinvoice requires an expiry in seconds");
-						continue;
+					match rebalance::send_rebalance(
+						&channel_manager,
+						&route,
+						from,
+						to,
+						&mut rebalances.lock().unwrap(),
+						&fs_store,
+						keys_manager.get_secure_random_bytes(),
+					) {
+						Ok(payment_id) => println!(
+							"SUCCESS: initiated rebalance {} of {} msat over {} hops, expected fee {} msat",
+							payment_id,
+							amt_msat,
+							route.paths[0].hops.len(),
+							fee_msat
+						),
+						Err(e) => println!("ERROR: {}", e),
 					}
-
-					let expiry_secs: Result<u32, _> = expiry_secs_str.unwrap().parse();
-					if expiry_secs.is_err() {
-						println!("ERROR: getinvoice provided expiry was not a number");
+				},
+				"listrebalances" => list_rebalances(&rebalances.lock().unwrap()),
+				"sendmessage" => {
+					// The message may contain spaces, so parse it from the raw line.
+					let args = line.trim_start().trim_start_matches("sendmessage").trim_start();
+					let (pubkey_str, args) =
+						args.split_at(args.find(char::is_whitespace).unwrap_or(args.len()));
+					let usage = "`sendmessage <pubkey> <text|\"quoted text\"> [amt_msat]`";
+					let dest_pubkey = match hex_utils::to_compressed_pubkey(pubkey_str) {
+						Some(pubkey) => pubkey,
+						None => {
+							println!("ERROR: sendmessage requires a destination pubkey: {}", usage);
+							continue;
+						},
+					};
+					let (text, amt_msat_str) = match messaging::parse_message_args(args) {
+						Some((text, amt)) if !text.is_empty() => (text, amt),
+						_ => {
+							println!("ERROR: sendmessage requires a message: {}", usage);
+							continue;
+						},
+					};
+					let amt_msat = match amt_msat_str.map(|amt| amt.parse::<u64>()) {
+						Some(Ok(amt)) => amt,
+						Some(Err(e)) => {
+							println!("ERROR: couldn't parse amount_msat: {}", e);
+							continue;
+						},
+						None => messaging::DEFAULT_MESSAGE_AMOUNT_MSAT,
+					};
+					let our_node_id = channel_manager.get_our_node_id();
+					let mut options = PaymentOptions {
+						custom_tlvs: messaging::message_tlvs(&our_node_id, &text),
+						..Default::default()
+					};
+					if let Err(e) = options.resolve_excluded_channels(
+						&channel_manager,
+						&network_graph,
+						&avoid_list.lock().unwrap(),
+					) {
+						println!("ERROR: {}", e);
 						continue;
 					}
+					let payment_id = keysend(
+						&channel_manager,
+						dest_pubkey,
+						amt_msat,
+						&options,
+						&*keys_manager,
+						&mut outbound_payments.lock().unwrap(),
+						Arc::clone(&fs_store),
+					);
+					if let Some(payment_id) = payment_id {
+						let mut conversation_log = conversation_log.lock().unwrap();
+						conversation_log.messages.push(ChatMessage {
+							peer: Some(dest_pubkey),
+							outgoing: true,
+							text,
+							timestamp: Utc::now().timestamp() as u64,
+							amt_msat,
+							payment_id: Some(payment_id),
+						});
+						conversation_log.persist(&fs_store);
+					}
+				},
+				"listmessages" => {
+					let peer = match words.next().map(hex_utils::to_compressed_pubkey) {
+						Some(Some(pubkey)) => Some(pubkey),
+						Some(None) => {
+							println!("ERROR: couldn't parse pubkey: `listmessages [pubkey]`");
+							continue;
+						},
+						None => None,
+					};
+					list_messages(
+						&conversation_log.lock().unwrap(),
+						&outbound_payments.lock().unwrap(),
+						peer,
+					);
+				},
+				"getoffer" | "createoffer" => {
+					// The description and issuer may contain spaces, so parse the options from the
+					// raw line.
+					let args = line.trim_start().trim_start_matches(word);
+					let mut options = match OfferOptions::parse(args) {
+						Ok(options) => options,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
+					if let Some((currency, amount)) = &options.fiat_price {
+						let source = match &exchange_rate_source {
+							Some(source) => source,
+							None => {
+								println!("ERROR: pricing offers in {} requires starting the node with --exchange-rate-source", currency);
+								continue;
+							},
+						};
+						// LDK can't build offers denominated in other currencies, so the offer is
+						// priced in msats at the current rate instead.
+						let rates = tokio::runtime::Handle::current().block_on(source.fetch_rates());
+						match rates.and_then(|rates| rates.to_msat(currency, *amount)) {
+							Ok(amt_msat) => {
+								let amount = exchange_rate::format_fiat_amount(currency, *amount);
+								println!(
+									"Pricing {} {} at {} msat at the current rate. The offer is for this fixed msat amount, not priced in {}.",
+									amount, currency, amt_msat, currency
+								);
+								options.amt_msat = Some(amt_msat);
+							},
+							Err(e) => {
+								println!("ERROR: {}", e);
+								continue;
+							},
+						}
+					}
+					match offers::create_offer(
+						&channel_manager,
+						&options,
+						&mut offers.lock().unwrap(),
+						&fs_store,
+					) {
+						// Note that unlike BOLT11 invoice creation we don't add a pending inbound
+						// payment here, as offers can be reused and don't correspond with
+						// individual payments. Payments are instead attributed to the offer.
+						Ok(offer) => {
+							println!("{}", offer);
+							qr::show(&offer.to_string(), &options.qr);
+						},
+						Err(e) => println!("ERROR: {}", e),
+					}
+				},
+				"listoffers" => offers::list_offers(&offers.lock().unwrap()),
+				"disableoffer" => {
+					let offer_str = match words.next() {
+						Some(offer) => offer,
+						None => {
+							println!("ERROR: disableoffer requires an offer or offer ID: `disableoffer <offer_id|offer>`");
+							continue;
+						},
+					};
+					let mut offers = offers.lock().unwrap();
+					match offers::find_offer(&offers, offer_str) {
+						Some(offer_id) => {
+							offers.offers.get_mut(&offer_id.0).unwrap().disabled = true;
+							offers.persist(&fs_store);
+							println!("SUCCESS: disabled offer {}", hex_utils::hex_str(&offer_id.0));
+						},
+						None => println!("ERROR: no offer found matching {}", offer_str),
+					}
+				},
+				"createrefund" => {
+					// The description may contain spaces, so parse the options from the raw line.
+					let args = line.trim_start().trim_start_matches("createrefund");
+					let options = match RefundOptions::parse(args) {
+						Ok(options) => options,
+						Err(e) => {
+							println!("ERROR: {}: `createrefund <amt_msat> [--description <text>] [--expiry <secs>]`", e);
+							continue;
+						},
+					};
+					create_refund(
+						&channel_manager,
+						&options,
+						PaymentId(keys_manager.get_secure_random_bytes()),
+						&mut outbound_payments.lock().unwrap(),
+						&fs_store,
+					);
+				},
+				"payrefund" => {
+					let refund = match words.next().map(Refund::from_str) {
+						Some(Ok(refund)) => refund,
+						Some(Err(e)) => {
+							println!("ERROR: couldn't parse refund: {:?}", e);
+							continue;
+						},
+						None => {
+							println!("ERROR: payrefund requires a refund: `payrefund <refund>`");
+							continue;
+						},
+					};
+					pay_refund(
+						&channel_manager,
+						&refund,
+						&mut inbound_payments.lock().unwrap(),
+						&fs_store,
+					);
+				},
+				"approveinvoice" | "rejectinvoice" => {
+					let payment_id = match words.next().and_then(hex_utils::to_vec) {
+						Some(id) if id.len() == 32 => {
+							let mut bytes = [0; 32];
+							bytes.copy_from_slice(&id);
+							PaymentId(bytes)
+						},
+						_ => {
+							println!("ERROR: {} requires a payment ID: `{} <payment_id>`", word, word);
+							continue;
+						},
+					};
+					let res = if word == "approveinvoice" {
+						pending_bolt12_invoices.approve(&channel_manager, &payment_id)
+					} else {
+						let pending = outbound_payments
+							.lock()
+							.unwrap()
+							.payments
+							.get(&payment_id)
+							.is_some_and(|payment| matches!(payment.status, HTLCStatus::Pending));
+						if pending {
+							pending_bolt12_invoices.reject(&channel_manager, &payment_id);
+							Ok(())
+						} else {
+							Err(format!("no pending payment {}", payment_id))
+						}
+					};
+					match res {
+						Ok(()) if word == "approveinvoice" => {
+							println!("SUCCESS: paying invoice for payment {}", payment_id)
+						},
+						Ok(()) => println!("SUCCESS: rejected invoice for payment {}", payment_id),
+						Err(e) => println!("ERROR: {}", e),
+					}
+				},
+				"decode" => {
+					let encoded = match words.next() {
+						Some(encoded) => encoded,
+						None => {
+							println!("ERROR: decode requires an invoice, offer or refund: `decode <bolt11|offer|bolt12_invoice_hex|refund>`");
+							continue;
+						},
+					};
+					match Decoded::parse(encoded) {
+						Ok(decoded) => {
+							decoded.print();
+							if !decoded.is_for_network(network) {
+								println!("ERROR: this is not valid on our network ({})", network);
+							}
+						},
+						Err(e) => println!("ERROR: {}", e),
+					}
+				},
+				"getinvoice" => {
+					// The description may contain spaces, so parse the options from the raw line.
+					let args = line.trim_start().trim_start_matches("getinvoice");
+					let options = match InvoiceOptions::parse(args) {
+						Ok(options) => options,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
 
 					let mut inbound_payments = inbound_payments.lock().unwrap();
 					get_invoice(
-						amt_msat.unwrap(),
+						&options,
 						&mut inbound_payments,
 						&channel_manager,
-						Arc::clone(&keys_manager),
+						&keys_manager,
 						network,
-						expiry_secs.unwrap(),
-						Arc::clone(&logger),
 					);
 					fs_store
 						.write("", "", INBOUND_PAYMENTS_FNAME, &inbound_payments.encode())
 						.unwrap();
 				},
+				"getunifiedqr" => {
+					// The description may contain spaces, so parse the arguments from the raw line.
+					let args = line.trim_start().trim_start_matches("getunifiedqr");
+					let mut args = match invoice::split_quoted(args) {
+						Ok(args) => args,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
+					let qr = match QrOptions::extract(&mut args) {
+						Ok(qr) => qr,
+						Err(e) => {
+							println!("ERROR: {}", e);
+							continue;
+						},
+					};
+					let amt_msat: u64 = match args.first().map(|amt| amt.parse()) {
+						Some(Ok(amt)) if amt > 0 => amt,
+						Some(Ok(_)) => {
+							println!("ERROR: getunifiedqr requires a non-zero amount");
+							continue;
+						},
+						Some(Err(e)) => {
+							println!("ERROR: couldn't parse amount_msat: {}", e);
+							continue;
+						},
+						None => {
+							println!("ERROR: getunifiedqr requires an amount in millisatoshis: `getunifiedqr <amt_msats> [description]`");
+							continue;
+						},
+					};
+					let description = match args.len() {
+						1 => invoice::DEFAULT_INVOICE_DESCRIPTION.to_string(),
+						_ => args[1..].join(" "),
+					};
+					// Take the locks in the same order as the reconciliation task.
+					let mut unified_requests = unified_requests.lock().unwrap();
+					let mut inbound_payments = inbound_payments.lock().unwrap();
+					let mut offers = offers.lock().unwrap();
+					match get_unified_request(
+						amt_msat,
+						&description,
+						&on_chain_wallet,
+						&channel_manager,
+						&keys_manager,
+						network,
+						UnifiedRequestStores {
+							unified_requests: &mut unified_requests,
+							inbound_payments: &mut inbound_payments,
+							offers: &mut offers,
+							fs_store: &fs_store,
+						},
+					) {
+						Ok(uri) => {
+							println!("{}", uri);
+							qr::show(&uri, &qr);
+						},
+						Err(e) => println!("ERROR: {}", e),
+					}
+				},
+				"waitinvoice" => {
+					let hash = match words.next().and_then(hex_utils::to_vec) {
+						Some(hash) if hash.len() == 32 => {
+							let mut bytes = [0; 32];
+							bytes.copy_from_slice(&hash);
+							PaymentHash(bytes)
+						},
+						_ => {
+							println!("ERROR: waitinvoice requires a payment hash: `waitinvoice <payment_hash> [--timeout <secs>]`");
+							continue;
+						},
+					};
+					let timeout = match (words.next(), words.next()) {
+						(None, _) => None,
+						(Some("--timeout"), Some(secs)) => match secs.parse() {
+							Ok(secs) => Some(Duration::from_secs(secs)),
+							Err(e) => {
+								println!("ERROR: couldn't parse --timeout: {}", e);
+								continue;
+							},
+						},
+						_ => {
+							println!("ERROR: waitinvoice only accepts `--timeout <secs>` after the payment hash");
+							continue;
+						},
+					};
+					wait_invoice(&inbound_payments, hash, timeout, &fs_store);
+				},
+				"waitanyinvoice" => {
+					let mut since_index = None;
+					let mut timeout = invoice::DEFAULT_WAIT_ANY_INVOICE_TIMEOUT;
+					let mut parse_err = None;
+					while let Some(flag) = words.next() {
+						match (flag, words.next().map(|value| value.parse::<u64>())) {
+							("--since-index", Some(Ok(index))) => since_index = Some(index),
+							("--timeout", Some(Ok(secs))) => timeout = Duration::from_secs(secs),
+							_ => {
+								parse_err = Some(flag);
+								break;
+							},
+						}
+					}
+					if let Some(flag) = parse_err {
+						println!("ERROR: couldn't parse {}: `waitanyinvoice [--since-index <index>] [--timeout <secs>]`", flag);
+						continue;
+					}
+					wait_any_invoice(&inbound_payments, since_index, timeout);
+				},
 				"connectpeer" => {
 					let peer_pubkey_and_ip_addr = words.next();
 					if peer_pubkey_and_ip_addr.is_none() {
@@ -396,11 +1351,65 @@ pub(crate) fn poll_for_user_input(
 						println!("SUCCESS: disconnected from peer {}", peer_pubkey);
 					}
 				},
-				"listchannels" => list_channels(&channel_manager, &network_graph),
-				"listpayments" => list_payments(
-					&inbound_payments.lock().unwrap(),
-					&outbound_payments.lock().unwrap(),
-				),
+				"listchannels" => list_channels(&channel_manager, &network_graph),
+				"listpayments" => list_payments(
+					&inbound_payments.lock().unwrap(),
+					&outbound_payments.lock().unwrap(),
+				),
+				"getpayment" => {
+					let hash = match words.next().and_then(hex_utils::to_vec) {
+						Some(hash) if hash.len() == 32 => {
+							let mut bytes = [0; 32];
+							bytes.copy_from_slice(&hash);
+							bytes
+						},
+						_ => {
+							println!("ERROR: getpayment requires a payment hash or ID: `getpayment <payment_hash|payment_id>`");
+							continue;
+						},
+					};
+					let inbound = inbound_payments.lock().unwrap();
+					let outbound = outbound_payments.lock().unwrap();
+					if let Some(payment) = inbound.payments.get(&PaymentHash(hash)) {
+						print_payment(&hex_utils::hex_str(&hash), "inbound", payment);
+					} else if let Some(payment) = outbound.payments.get(&PaymentId(hash)) {
+						print_payment(&hex_utils::hex_str(&hash), "outbound", payment);
+					} else {
+						println!("ERROR: no payment found with hash or ID {}", hex_utils::hex_str(&hash));
+					}
+				},
+				"abandonpayment" => {
+					let payment_id = match words.next().and_then(hex_utils::to_vec) {
+						Some(id) if id.len() == 32 => {
+							let mut payment_id = [0; 32];
+							payment_id.copy_from_slice(&id);
+							PaymentId(payment_id)
+						},
+						Some(_) | None => {
+							println!("ERROR: abandonpayment requires a payment ID: `abandonpayment <payment_id>`");
+							continue;
+						},
+					};
+					match outbound_payments.lock().unwrap().payments.get(&payment_id) {
+						Some(payment) if matches!(payment.status, HTLCStatus::Pending) => {},
+						Some(_) => {
+							println!("ERROR: payment {} is no longer pending", payment_id);
+							continue;
+						},
+						None => {
+							println!("ERROR: unknown payment ID {}", payment_id);
+							continue;
+						},
+					}
+					// LDK stops retrying and fails back any HTLCs still in flight. The payment
+					// stays pending until the resulting `PaymentFailed` event, as an HTLC that's
+					// already been claimed downstream may still succeed.
+					channel_manager.abandon_payment(payment_id);
+					println!(
+						"SUCCESS: abandoned payment {}, it will be marked failed once its HTLCs resolve",
+						payment_id
+					);
+				},
 				"closechannel" => {
 					let channel_id_str = words.next();
 					if channel_id_str.is_none() {
@@ -476,6 +1485,191 @@ pub(crate) fn poll_for_user_input(
 				"nodeinfo" => {
 					node_info(&channel_manager, &chain_monitor, &peer_manager, &network_graph)
 				},
+				"balances" => list_balances(
+					&channel_manager,
+					&chain_monitor,
+					&on_chain_wallet,
+					&output_sweeper,
+				),
+				"getnodeinfo" => {
+					let node_str = words.next();
+					if node_str.is_none() {
+						println!("ERROR: getnodeinfo requires a node: `getnodeinfo <pubkey|alias>`");
+						continue;
+					}
+					let graph = network_graph.read_only();
+					match graph::find_node(&graph, node_str.unwrap()) {
+						Ok(node_id) => graph::print_node_info(&graph, &node_id),
+						Err(e) => println!("ERROR: {}", e),
+					}
+				},
+				"getchaninfo" => {
+					let scid = match words.next().map(graph::parse_scid) {
+						Some(Some(scid)) => scid,
+						Some(None) => {
+							println!("ERROR: couldn't parse short channel id");
+							continue;
+						},
+						None => {
+							println!("ERROR: getchaninfo requires a short channel id: `getchaninfo <scid>`");
+							continue;
+						},
+					};
+					let graph = network_graph.read_only();
+					match graph.channel(scid) {
+						Some(channel) => graph::print_channel_info(&graph, scid, channel),
+						None => println!("ERROR: channel {} is not in the network graph", scid),
+					}
+				},
+				"describegraph" => {
+					let mut filter = GraphFilter::default();
+					let mut bad_flag = false;
+					for word in words.by_ref() {
+						if word == "--has-address" {
+							filter.has_address = true;
+						} else if let Some(min) = word.strip_prefix("--min-capacity=") {
+							match min.parse() {
+								Ok(min) => filter.min_capacity_sats = Some(min),
+								Err(e) => {
+									println!("ERROR: couldn't parse min capacity: {}", e);
+									bad_flag = true;
+								},
+							}
+						} else {
+							println!("ERROR: unknown describegraph option {}", word);
+							bad_flag = true;
+						}
+					}
+					if !bad_flag {
+						graph::describe_graph(&network_graph.read_only(), &filter);
+					}
+				},
+				"searchnodes" => {
+					let alias_substring = words.collect::<Vec<_>>().join(" ");
+					if alias_substring.is_empty() {
+						println!("ERROR: searchnodes requires part of an alias: `searchnodes <alias>`");
+						continue;
+					}
+					graph::search_nodes(&network_graph.read_only(), &alias_substring);
+				},
+				"exportgraph" => {
+					let format = match words.next() {
+						Some("json") => ExportFormat::Json,
+						Some("dot") => ExportFormat::Dot,
+						_ => {
+							println!("ERROR: exportgraph requires a format and path: `exportgraph <json|dot> <path> [--hops <n>]`");
+							continue;
+						},
+					};
+					let path = match words.next() {
+						Some(path) => path,
+						None => {
+							println!("ERROR: exportgraph requires a format and path: `exportgraph <json|dot> <path> [--hops <n>]`");
+							continue;
+						},
+					};
+					let hops = match (words.next(), words.next()) {
+						(None, _) => None,
+						(Some("--hops"), Some(hops)) => match hops.parse::<u32>() {
+							Ok(hops) => Some(hops),
+							Err(e) => {
+								println!("ERROR: couldn't parse hops: {}", e);
+								continue;
+							},
+						},
+						_ => {
+							println!("ERROR: exportgraph only accepts `--hops <n>` after the path");
+							continue;
+						},
+					};
+					// Render into memory first so the graph lock isn't held while writing the file.
+					let our_node_id = NodeId::from_pubkey(&channel_manager.get_our_node_id());
+					let mut buffer = Vec::new();
+					let (num_nodes, num_channels) = match graph::export_graph(
+						&network_graph.read_only(),
+						format,
+						&mut buffer,
+						hops.map(|hops| (our_node_id, hops)),
+					) {
+						Ok(counts) => counts,
+						Err(e) => {
+							println!("ERROR: failed to export network graph: {}", e);
+							continue;
+						},
+					};
+					match std::fs::write(path, &buffer) {
+						Ok(()) => println!(
+							"SUCCESS: exported {} nodes and {} channels to {}",
+							num_nodes, num_channels, path
+						),
+						Err(e) => println!("ERROR: failed to write {}: {}", path, e),
+					}
+				},
+				"syncrgs" => {
+					let source = match words.next().map(String::from).or(rgs_source.clone()) {
+						Some(source) => RgsSource::parse(&source),
+						None => {
+							println!("ERROR: syncrgs requires a snapshot source when none was configured at startup: `syncrgs <path|url>`");
+							continue;
+						},
+					};
+					let sync = rgs::sync(&rapid_gossip_sync, &source);
+					match tokio::runtime::Handle::current().block_on(sync) {
+						Ok(timestamp) => {
+							let graph = network_graph.read_only();
+							println!(
+								"SUCCESS: applied RGS snapshot with timestamp {} ({} nodes, {} channels)",
+								timestamp,
+								graph.nodes().len(),
+								graph.channels().len()
+							);
+						},
+						Err(e) => println!("ERROR: {}", e),
+					}
+				},
+				"listsweeps" => list_sweeps(&output_sweeper, &sweep_destination, &fs_store),
+				"bumpsweep" => {
+					let feerate_str = words.next();
+					if feerate_str.is_none() {
+						println!("ERROR: bumpsweep requires a feerate: `bumpsweep <sat_per_vbyte>`");
+						continue;
+					}
+					let sat_per_vbyte: u32 = match feerate_str.unwrap().parse() {
+						Ok(feerate) => feerate,
+						Err(e) => {
+							println!("ERROR: couldn't parse sat_per_vbyte: {}", e);
+							continue;
+						},
+					};
+					// One vbyte is four weight units, so 1 sat/vB is 250 sat/kW.
+					match sweep::bump_sweep(
+						&output_sweeper,
+						&keys_manager,
+						&sweep_destination,
+						sat_per_vbyte.saturating_mul(250),
+					) {
+						Ok(tx) => {
+							let broadcast = bitcoind_client.send_raw_transaction(&tx);
+							match tokio::runtime::Handle::current().block_on(broadcast) {
+								Ok(()) => {
+									let txid = tx.compute_txid();
+									// Remember the replacement so listsweeps shows it until
+									// it confirms.
+									if let Err(e) =
+										BumpedSweeps::record(&fs_store, &output_sweeper, tx)
+									{
+										println!("ERROR: {}", e);
+									}
+									println!("SUCCESS: broadcast replacement sweep {}", txid);
+								},
+								Err(e) => {
+									println!("ERROR: failed to broadcast replacement sweep: {}", e)
+								},
+							}
+						},
+						Err(e) => println!("ERROR: failed to bump sweep: {}", e),
+					}
+				},
 				"listpeers" => list_peers(peer_manager.clone()),
 				"signmessage" => {
 					const MSG_STARTPOS: usize = "signmessage".len() + 1;
@@ -518,18 +1712,63 @@ fn help() {
 	println!("      disconnectpeer <peer_pubkey>");
 	println!("      listpeers");
 	println!("\n  Payments:");
-	println!("      sendpayment <invoice|offer> [<amount_msat>]");
-	println!("      keysend <dest_pubkey> <amt_msats>");
+	println!("      sendpayment <invoice|offer|bitcoin:uri|user@domain|₿user@domain> [<amount_msat>] [payment options]");
+	println!("          offers also take [--quantity <n>] [--note <text|\"quoted text\">]");
+	println!("          Lightning Addresses also take [--comment <text|\"quoted text\">]");
+	println!("      keysend <dest_pubkey> <amt_msats> [payment options]");
+	println!("      probe <invoice|pubkey amt_msat>");
+	println!("      queryroutes <invoice|pubkey amt_msat> [--max-fee <msat>] [--max-cltv <blocks>] [--avoid <node|scid>...]");
 	println!("      listpayments");
+	println!("      getpayment <payment_hash|payment_id>");
+	println!("      abandonpayment <payment_id>");
+	println!("      avoidlist [add|remove <node_pubkey|short_channel_id>]");
+	println!("      rebalance <from_channel> <to_channel> <amt_msat> [--max-fee-ppm <ppm>]");
+	println!("      listrebalances");
+	println!("      payment options: [--max-fee-msat <msat>] [--max-fee-ppm <ppm>] [--max-cltv <blocks>]");
+	println!("          [--retries <n> | --timeout <secs>] [--max-paths <n>] [--max-channel-saturation <n>]");
+	println!("          [--via <channel_id|scid>] [--avoid-node <pubkey>]* [--avoid-channel <scid>]*");
+	println!("          [--tlv <type>=<hex>]*");
 	println!("\n  Invoices:");
-	println!("      getinvoice <amt_msats> <expiry_secs>");
-	println!("      getoffer [<amt_msats>]");
+	println!("      getinvoice [<amt_msats>|any] [--description <text|\"quoted text\"> | --description-hash <hex>]");
+	println!("          [--expiry <secs>] [--min-final-cltv <blocks>] [--fallback-address <address>]");
+	println!("          [--private-hints <channel_id|scid>...] [--preimage <hex>]");
+	println!("          [--qr] [--qr-file <path.png|path.svg>]");
+	println!("      getunifiedqr <amt_msats> [description] [--qr] [--qr-file <path.png|path.svg>]");
+	println!("      createoffer [<amt_msats>|any|--currency <code> --amount <decimal>]");
+	println!("          [--description <text|\"quoted text\">] [--issuer <text>]");
+	println!("          [--expiry <secs>] [--quantity-max <n|unbounded>] [--single-use]");
+	println!("          (--currency converts the amount to a fixed msat amount at today's rate)");
+	println!("          [--qr] [--qr-file <path.png|path.svg>]");
+	println!("      getoffer [<amt_msats>]  (alias of createoffer)");
+	println!("      listoffers");
+	println!("      disableoffer <offer_id|offer>");
+	println!("      createrefund <amt_msats> [--description <text|\"quoted text\">] [--expiry <secs>]");
+	println!("      payrefund <refund>");
+	println!("      approveinvoice <payment_id>");
+	println!("      rejectinvoice <payment_id>");
+	println!("      decode <bolt11|offer|bolt12_invoice_hex|refund>");
+	println!("      waitinvoice <payment_hash> [--timeout <secs>]");
+	println!("      waitanyinvoice [--since-index <index>] [--timeout <secs, default 600>]");
+	println!("\n  Messages:");
+	println!("      sendmessage <pubkey> <text|\"quoted text\"> [<amt_msat>]");
+	println!("      listmessages [<pubkey>]");
+	println!("\n  Network Graph:");
+	println!("      getnodeinfo <pubkey|alias>");
+	println!("      getchaninfo <short_channel_id>");
+	println!("      describegraph [--min-capacity=<sats>] [--has-address]");
+	println!("      searchnodes <alias_substring>");
+	println!("      exportgraph <json|dot> <path> [--hops <n>]");
 	println!("\n  On Chain:");
-	println!("      getaddress");
+	println!("      getaddress [--qr] [--qr-file <path.png|path.svg>]");
 	println!("      getbalance");
 	println!("\n  Other:");
 	println!("      signmessage <message>");
 	println!("      nodeinfo");
+	println!("      balances");
+	println!("      syncrgs [<snapshot_path|rgs_url>]");
+	println!("\n  Sweeps:");
+	println!("      listsweeps");
+	println!("      bumpsweep <sat_per_vbyte>");
 }
 
 fn node_info(
@@ -571,6 +1810,232 @@ fn node_info(
 	println!("\t}},");
 }
 
+// Average time between blocks, used to turn a block height into a rough wall-clock estimate.
+const SECS_PER_BLOCK: u64 = 600;
+
+fn spendable_at_str(cur_height: u32, height: u32) -> String {
+	if height <= cur_height {
+		return "now".to_string();
+	}
+	let blocks = (height - cur_height) as u64;
+	let eta = Utc::now() + chrono::Duration::seconds((blocks * SECS_PER_BLOCK) as i64);
+	format!("height {} (~{} blocks, ~{})", height, blocks, eta.format("%Y-%m-%d %H:%M UTC"))
+}
+
+fn list_balances(
+	channel_manager: &Arc<ChannelManager>, chain_monitor: &Arc<ChainMonitor>,
+	on_chain_wallet: &Arc<LdkOnChainWallet>, output_sweeper: &Arc<OutputSweeper>,
+) {
+	let cur_height = channel_manager.current_best_block().height;
+	let mut total_lightning_sats = 0;
+	println!("\t{{");
+	println!("\t\t best_block_height: {},", cur_height);
+	println!("\t\t channels: [");
+	for (funding_txo, channel_id) in chain_monitor.list_monitors() {
+		let monitor = match chain_monitor.get_monitor(funding_txo) {
+			Ok(monitor) => monitor,
+			Err(()) => continue,
+		};
+		let balances = monitor.get_claimable_balances();
+		if balances.is_empty() {
+			continue;
+		}
+		println!("\t\t\t{{");
+		println!("\t\t\t\tchannel_id: {},", channel_id);
+		if let Some(counterparty_node_id) = monitor.get_counterparty_node_id() {
+			println!("\t\t\t\tpeer_pubkey: {},", counterparty_node_id);
+		}
+		println!("\t\t\t\tbalances: [");
+		for balance in balances.iter() {
+			total_lightning_sats += balance.claimable_amount_satoshis();
+			let (kind, amount_sats, spendable_at) = match balance {
+				Balance::ClaimableOnChannelClose {
+					amount_satoshis, transaction_fee_satoshis, ..
+				} => (
+					"claimable_on_channel_close",
+					*amount_satoshis,
+					format!("on close (close fee {} sats)", transaction_fee_satoshis),
+				),
+				Balance::ClaimableAwaitingConfirmations {
+					amount_satoshis, confirmation_height, ..
+				} => (
+					"claimable_awaiting_confirmations",
+					*amount_satoshis,
+					spendable_at_str(cur_height, *confirmation_height),
+				),
+				Balance::ContentiousClaimable {
+					amount_satoshis, timeout_height, payment_hash, ..
+				} => (
+					"contentious_claimable",
+					*amount_satoshis,
+					format!(
+						"once our preimage claim for {} confirms, counterparty may claim from {}",
+						payment_hash,
+						spendable_at_str(cur_height, *timeout_height)
+					),
+				),
+				Balance::MaybeTimeoutClaimableHTLC {
+					amount_satoshis,
+					claimable_height,
+					payment_hash,
+					outbound_payment,
+				} => (
+					if *outbound_payment {
+						"maybe_timeout_claimable_outbound_htlc"
+					} else {
+						"maybe_timeout_claimable_forwarded_htlc"
+					},
+					*amount_satoshis,
+					format!(
+						"{} if {} is not claimed",
+						spendable_at_str(cur_height, *claimable_height),
+						payment_hash
+					),
+				),
+				Balance::MaybePreimageClaimableHTLC {
+					amount_satoshis, expiry_height, payment_hash,
+				} => (
+					"maybe_preimage_claimable_htlc",
+					*amount_satoshis,
+					format!(
+						"only if we learn the preimage for {} before {}",
+						payment_hash,
+						spendable_at_str(cur_height, *expiry_height)
+					),
+				),
+				Balance::CounterpartyRevokedOutputClaimable { amount_satoshis } => {
+					("counterparty_revoked_output_claimable", *amount_satoshis, "now".to_string())
+				},
+			};
+			println!("\t\t\t\t\t{{");
+			println!("\t\t\t\t\t\ttype: {},", kind);
+			println!("\t\t\t\t\t\tamount_sats: {},", amount_sats);
+			println!("\t\t\t\t\t\tspendable: {},", spendable_at);
+			println!("\t\t\t\t\t}},");
+		}
+		println!("\t\t\t\t]");
+		println!("\t\t\t}},");
+	}
+	println!("\t\t ],");
+	println!("\t\t lightning_total_sats: {},", total_lightning_sats);
+
+	let mut total_sweeper_sats = 0;
+	println!("\t\t sweeper_outputs: [");
+	for output in output_sweeper.tracked_spendable_outputs() {
+		let amount_sats = spendable_output_value(&output.descriptor).to_sat();
+		total_sweeper_sats += amount_sats;
+		let spendable_at = match &output.status {
+			OutputSpendStatus::PendingInitialBroadcast { delayed_until_height: Some(height) } => {
+				format!("sweep broadcast at {}", spendable_at_str(cur_height, *height))
+			},
+			OutputSpendStatus::PendingInitialBroadcast { delayed_until_height: None } => {
+				"sweep broadcast pending".to_string()
+			},
+			OutputSpendStatus::PendingFirstConfirmation { .. } => {
+				"awaiting sweep confirmation".to_string()
+			},
+			OutputSpendStatus::PendingThresholdConfirmations { confirmation_height, .. } => {
+				// The sweeper stops tracking an output after ANTI_REORG_DELAY (6) confirmations.
+				format!("in wallet after {}", spendable_at_str(cur_height, confirmation_height + 5))
+			},
+		};
+		println!("\t\t\t{{");
+		if let Some(channel_id) = output.channel_id {
+			println!("\t\t\t\tchannel_id: {},", channel_id);
+		}
+		println!("\t\t\t\tamount_sats: {},", amount_sats);
+		println!("\t\t\t\tspendable: {},", spendable_at);
+		println!("\t\t\t}},");
+	}
+	println!("\t\t ],");
+	println!("\t\t sweeper_total_sats: {},", total_sweeper_sats);
+
+	let wallet_balance = on_chain_wallet.get_balance();
+	println!("\t\t onchain_wallet: {{");
+	println!("\t\t\tconfirmed_sats: {},", wallet_balance.confirmed.to_sat());
+	println!("\t\t\ttrusted_pending_sats: {},", wallet_balance.trusted_pending.to_sat());
+	println!("\t\t\tuntrusted_pending_sats: {},", wallet_balance.untrusted_pending.to_sat());
+	println!("\t\t\timmature_sats: {},", wallet_balance.immature.to_sat());
+	println!("\t\t }},");
+	println!(
+		"\t\t total_sats: {},",
+		total_lightning_sats + total_sweeper_sats + wallet_balance.total().to_sat()
+	);
+	println!("\t}},");
+}
+
+pub(crate) fn spendable_output_value(descriptor: &SpendableOutputDescriptor) -> Amount {
+	match descriptor {
+		SpendableOutputDescriptor::StaticOutput { output, .. } => output.value,
+		SpendableOutputDescriptor::DelayedPaymentOutput(descriptor) => descriptor.output.value,
+		SpendableOutputDescriptor::StaticPaymentOutput(descriptor) => descriptor.output.value,
+	}
+}
+
+fn list_sweeps(
+	output_sweeper: &Arc<OutputSweeper>, sweep_destination: &Arc<SweepDestination>,
+	fs_store: &FilesystemStore,
+) {
+	let cur_height = output_sweeper.current_best_block().height;
+	let tracked_outputs = output_sweeper.tracked_spendable_outputs();
+	let bumped_sweeps = BumpedSweeps::read(fs_store);
+
+	// Every input of a sweep transaction is one of our tracked outputs, so summing their values
+	// gives us the sweep's total input amount and thus its fee.
+	let output_sats = tracked_outputs
+		.iter()
+		.map(|output| {
+			let value = spendable_output_value(&output.descriptor).to_sat();
+			(sweep::spendable_output_outpoint(&output.descriptor), value)
+		})
+		.collect::<HashMap<_, _>>();
+	let fee_sats = |tx: &Transaction| {
+		let input_sats =
+			tx.input.iter().filter_map(|input| output_sats.get(&input.previous_output)).sum::<u64>();
+		input_sats.saturating_sub(tx.output.iter().map(|o| o.value.to_sat()).sum::<u64>())
+	};
+
+	println!("sweep_destination: {}", sweep_destination.describe());
+	print!("[");
+	for output in tracked_outputs.iter() {
+		println!();
+		println!("\t{{");
+		println!("\t\toutpoint: {},", sweep::spendable_output_outpoint(&output.descriptor));
+		if let Some(channel_id) = output.channel_id {
+			println!("\t\tchannel_id: {},", channel_id);
+		}
+		println!("\t\tamount_sats: {},", spendable_output_value(&output.descriptor).to_sat());
+		match &output.status {
+			OutputSpendStatus::PendingInitialBroadcast { delayed_until_height } => {
+				println!("\t\tstate: pending_initial_broadcast,");
+				if let Some(height) = delayed_until_height {
+					println!("\t\tdelayed_until_height: {},", height);
+				}
+			},
+			OutputSpendStatus::PendingFirstConfirmation { latest_broadcast_height, .. } => {
+				println!("\t\tstate: awaiting_confirmation,");
+				println!("\t\tlatest_broadcast_height: {},", latest_broadcast_height);
+			},
+			OutputSpendStatus::PendingThresholdConfirmations { confirmation_height, .. } => {
+				println!("\t\tstate: confirmed,");
+				println!("\t\tconfirmation_height: {},", confirmation_height);
+				println!("\t\tconfirmations: {},", cur_height + 1 - confirmation_height);
+			},
+		}
+		if let Some(tx) = sweep::latest_sweep_tx(output, &bumped_sweeps, fee_sats) {
+			println!("\t\tsweep_txid: {},", tx.compute_txid());
+			let fee_sats = fee_sats(tx);
+			println!("\t\tsweep_fee_sats: {},", fee_sats);
+			println!(
+				"\t\tsweep_feerate_sat_per_vbyte: {:.1},",
+				fee_sats as f64 / tx.weight().to_vbytes_ceil() as f64
+			);
+		}
+		println!("\t}},");
+	}
+	println!("]");
+}
+
 fn list_peers(peer_manager: Arc<PeerManager>) {
 	println!("\t{{");
 	for peer_details in peer_manager.list_peers() {
@@ -620,6 +2085,142 @@ fn list_channels(channel_manager: &Arc<ChannelManager>, network_graph: &Arc<Netw
 	println!("]");
 }
 
+fn print_avoid_list(avoid_list: &AvoidList, network_graph: &NetworkGraph) {
+	let graph = network_graph.read_only();
+	println!("\t{{");
+	println!("\t\tnodes: [");
+	for node_id in avoid_list.nodes.iter() {
+		match graph.node(node_id).and_then(graph::node_alias) {
+			Some(alias) => println!("\t\t\t{} ({}),", node_id, alias),
+			None => println!("\t\t\t{},", node_id),
+		}
+	}
+	println!("\t\t],");
+	println!("\t\tchannels: [");
+	for scid in avoid_list.channels.iter() {
+		println!("\t\t\t{},", graph::scid_str(*scid));
+	}
+	println!("\t\t],");
+	println!("\t}},");
+}
+
+fn list_rebalances(rebalances: &RebalanceStorage) {
+	let mut total_fees_msat = 0;
+	print!("[");
+	for (payment_id, rebalance) in rebalances.rebalances.iter() {
+		println!();
+		println!("\t{{");
+		println!("\t\tpayment_id: {},", payment_id);
+		println!("\t\tfrom_channel: {},", rebalance.from_channel);
+		println!("\t\tto_channel: {},", rebalance.to_channel);
+		println!("\t\tamount_millisatoshis: {},", rebalance.amt_msat);
+		match rebalance.fee_paid_msat {
+			Some(fee) => {
+				total_fees_msat += fee;
+				println!("\t\tfee_paid_msat: {},", fee);
+			},
+			None => println!("\t\texpected_fee_msat: {},", rebalance.expected_fee_msat),
+		}
+		println!(
+			"\t\tstatus: {},",
+			match rebalance.status {
+				HTLCStatus::Pending => "pending",
+				HTLCStatus::Succeeded => "succeeded",
+				HTLCStatus::Failed => "failed",
+				HTLCStatus::Expired => "expired",
+			}
+		);
+		println!("\t}},");
+	}
+	println!("]");
+	println!("total_fees_paid_msat: {}", total_fees_msat);
+}
+
+fn print_payment(payment_hash: &str, direction: &str, payment: &PaymentInfo) {
+	println!("\t{{");
+	println!("\t\tamount_millisatoshis: {},", payment.amt_msat);
+	println!("\t\tpayment_hash: {},", payment_hash);
+	println!("\t\thtlc_direction: {},", direction);
+	println!(
+		"\t\thtlc_status: {},",
+		match payment.status {
+			HTLCStatus::Pending => "pending",
+			HTLCStatus::Succeeded => "succeeded",
+			HTLCStatus::Failed => "failed",
+			HTLCStatus::Expired => "expired",
+		}
+	);
+	if let Some(preimage) = payment.preimage {
+		println!("\t\tpayment_preimage: {},", preimage);
+	}
+	if let Some(invoice) = &payment.bolt11_invoice {
+		println!("\t\tinvoice: {},", invoice);
+	}
+	if let Some(settle_index) = payment.settle_index {
+		println!("\t\tsettle_index: {},", settle_index);
+	}
+	if let Some(quantity) = payment.quantity {
+		println!("\t\tquantity: {},", quantity);
+	}
+	if let Some(note) = &payment.payer_note {
+		println!("\t\tpayer_note: {},", note);
+	}
+	if let Some(user) = &payment.lnurl_user {
+		println!("\t\tlnurl_user: {},", user);
+	}
+	if let Some(comment) = &payment.lnurl_comment {
+		println!("\t\tlnurl_comment: {},", comment);
+	}
+	if !payment.custom_tlvs.is_empty() {
+		println!("\t\tcustom_tlvs: [");
+		for (typ, value) in payment.custom_tlvs.iter() {
+			println!("\t\t\t{}: {},", typ, hex_utils::hex_str(value));
+		}
+		println!("\t\t],");
+	}
+	println!("\t}},");
+}
+
+fn list_messages(
+	conversation_log: &ConversationLog, outbound_payments: &OutboundPaymentInfoStorage,
+	peer: Option<PublicKey>,
+) {
+	print!("[");
+	for message in conversation_log.messages.iter() {
+		if peer.is_some() && message.peer != peer {
+			continue;
+		}
+		println!();
+		println!("\t{{");
+		let peer = message.peer.map_or("unknown".to_string(), |peer| peer.to_string());
+		if message.outgoing {
+			println!("\t\tto: {},", peer);
+		} else {
+			println!("\t\tfrom: {},", peer);
+		}
+		let time = chrono::NaiveDateTime::from_timestamp_opt(message.timestamp as i64, 0);
+		if let Some(time) = time {
+			println!("\t\ttime: {},", time.format("%Y-%m-%d %H:%M:%S UTC"));
+		}
+		println!("\t\tmessage: {},", message.text);
+		println!("\t\tamount_millisatoshis: {},", message.amt_msat);
+		let payment = message.payment_id.and_then(|id| outbound_payments.payments.get(&id));
+		if let Some(payment) = payment {
+			println!(
+				"\t\tdelivery: {},",
+				match payment.status {
+					HTLCStatus::Pending => "pending",
+					HTLCStatus::Succeeded => "delivered",
+					HTLCStatus::Failed => "failed",
+					HTLCStatus::Expired => "expired",
+				}
+			);
+		}
+		println!("\t}},");
+	}
+	println!("]");
+}
+
 fn list_payments(
 	inbound_payments: &InboundPaymentInfoStorage, outbound_payments: &OutboundPaymentInfoStorage,
 ) {
@@ -636,6 +2237,7 @@ fn list_payments(
 				HTLCStatus::Pending => "pending",
 				HTLCStatus::Succeeded => "succeeded",
 				HTLCStatus::Failed => "failed",
+				HTLCStatus::Expired => "expired",
 			}
 		);
 
@@ -654,6 +2256,7 @@ fn list_payments(
 				HTLCStatus::Pending => "pending",
 				HTLCStatus::Succeeded => "succeeded",
 				HTLCStatus::Failed => "failed",
+				HTLCStatus::Expired => "expired",
 			}
 		);
 
@@ -752,7 +2355,8 @@ fn open_channel(
 
 fn send_payment(
 	channel_manager: &ChannelManager, invoice: &Bolt11Invoice, required_amount_msat: Option<u64>,
-	outbound_payments: &mut OutboundPaymentInfoStorage, fs_store: Arc<FilesystemStore>,
+	options: &PaymentOptions, outbound_payments: &mut OutboundPaymentInfoStorage,
+	fs_store: Arc<FilesystemStore>,
 ) {
 	let payment_id = PaymentId((*invoice.payment_hash()).to_byte_array());
 	let payment_secret = Some(*invoice.payment_secret());
@@ -778,7 +2382,7 @@ fn send_payment(
 		}
 		payment_parameters_from_invoice(invoice)
 	};
-	let (payment_hash, recipient_onion, route_params) = match pay_params_opt {
+	let (payment_hash, recipient_onion, mut route_params) = match pay_params_opt {
 		Ok(res) => res,
 		Err(e) => {
 			println!("Failed to parse invoice: {:?}", e);
@@ -786,6 +2390,15 @@ fn send_payment(
 			return;
 		},
 	};
+	let recipient_onion = match recipient_onion.with_custom_tlvs(options.custom_tlvs.clone()) {
+		Ok(recipient_onion) => recipient_onion,
+		Err(()) => {
+			println!("ERROR: invalid custom TLVs");
+			print!("> ");
+			return;
+		},
+	};
+	options.apply(&mut route_params);
 	outbound_payments.payments.insert(
 		payment_id,
 		PaymentInfo {
@@ -793,6 +2406,13 @@ fn send_payment(
 			secret: payment_secret,
 			status: HTLCStatus::Pending,
 			amt_msat: MillisatAmount(invoice.amount_milli_satoshis()),
+			custom_tlvs: options.custom_tlvs.clone(),
+			bolt11_invoice: None,
+			settle_index: None,
+			payer_note: None,
+			quantity: None,
+			lnurl_user: None,
+			lnurl_comment: None,
 		},
 	);
 	fs_store.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.encode()).unwrap();
@@ -802,7 +2422,7 @@ fn send_payment(
 		recipient_onion,
 		payment_id,
 		route_params,
-		Retry::Timeout(Duration::from_secs(10)),
+		options.retry(),
 	) {
 		Ok(_) => {
 			let payee_pubkey = invoice.recover_payee_pub_key();
@@ -820,16 +2440,27 @@ fn send_payment(
 }
 
 fn keysend<E: EntropySource>(
-	channel_manager: &ChannelManager, payee_pubkey: PublicKey, amt_msat: u64, entropy_source: &E,
-	outbound_payments: &mut OutboundPaymentInfoStorage, fs_store: Arc<FilesystemStore>,
-) {
+	channel_manager: &ChannelManager, payee_pubkey: PublicKey, amt_msat: u64,
+	options: &PaymentOptions, entropy_source: &E, outbound_payments: &mut OutboundPaymentInfoStorage,
+	fs_store: Arc<FilesystemStore>,
+) -> Option<PaymentId> {
 	let payment_preimage = PaymentPreimage(entropy_source.get_secure_random_bytes());
 	let payment_id = PaymentId(Sha256::hash(&payment_preimage.0[..]).to_byte_array());
 
-	let route_params = RouteParameters::from_payment_params_and_value(
+	let mut route_params = RouteParameters::from_payment_params_and_value(
 		PaymentParameters::for_keysend(payee_pubkey, 40, false),
 		amt_msat,
 	);
+	options.apply(&mut route_params);
+	let onion_fields = RecipientOnionFields::spontaneous_empty();
+	let recipient_onion = match onion_fields.with_custom_tlvs(options.custom_tlvs.clone()) {
+		Ok(recipient_onion) => recipient_onion,
+		Err(()) => {
+			println!("ERROR: invalid custom TLVs");
+			print!("> ");
+			return None;
+		},
+	};
 	outbound_payments.payments.insert(
 		payment_id,
 		PaymentInfo {
@@ -837,72 +2468,305 @@ fn keysend<E: EntropySource>(
 			secret: None,
 			status: HTLCStatus::Pending,
 			amt_msat: MillisatAmount(Some(amt_msat)),
+			custom_tlvs: options.custom_tlvs.clone(),
+			bolt11_invoice: None,
+			settle_index: None,
+			payer_note: None,
+			quantity: None,
+			lnurl_user: None,
+			lnurl_comment: None,
 		},
 	);
 	fs_store.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.encode()).unwrap();
 	match channel_manager.send_spontaneous_payment_with_retry(
 		Some(payment_preimage),
-		RecipientOnionFields::spontaneous_empty(),
+		recipient_onion,
 		payment_id,
 		route_params,
-		Retry::Timeout(Duration::from_secs(10)),
+		options.retry(),
 	) {
 		Ok(_payment_hash) => {
 			println!("EVENT: initiated sending {} msats to {}", amt_msat, payee_pubkey);
 			print!("> ");
+			Some(payment_id)
 		},
 		Err(e) => {
 			println!("ERROR: failed to send payment: {:?}", e);
 			print!("> ");
 			outbound_payments.payments.get_mut(&payment_id).unwrap().status = HTLCStatus::Failed;
 			fs_store.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.encode()).unwrap();
+			None
+		},
+	}
+}
+
+/// Creates a refund that whoever we hand it to can claim by sending us an invoice for it, which
+/// LDK then pays under `payment_id`.
+fn create_refund(
+	channel_manager: &ChannelManager, options: &RefundOptions, payment_id: PaymentId,
+	outbound_payments: &mut OutboundPaymentInfoStorage, fs_store: &FilesystemStore,
+) {
+	let absolute_expiry = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap()
+		+ Duration::from_secs(options.expiry_secs);
+	let builder = channel_manager.create_refund_builder(
+		options.amt_msat,
+		absolute_expiry,
+		payment_id,
+		routing::DEFAULT_PAYMENT_RETRY,
+		None,
+	);
+	let builder = match builder {
+		Ok(builder) => builder,
+		Err(e) => {
+			println!("ERROR: failed to initiate refund building: {:?}", e);
+			return;
+		},
+	};
+	let builder = match &options.description {
+		Some(description) => builder.description(description.clone()),
+		None => builder,
+	};
+	let refund = match builder.build() {
+		Ok(refund) => refund,
+		Err(e) => {
+			println!("ERROR: failed to build refund: {:?}", e);
+			// The payment was registered when the builder was created; don't leave it waiting
+			// for an invoice that can never come.
+			channel_manager.abandon_payment(payment_id);
+			return;
+		},
+	};
+	outbound_payments.payments.insert(
+		payment_id,
+		PaymentInfo {
+			preimage: None,
+			secret: None,
+			status: HTLCStatus::Pending,
+			amt_msat: MillisatAmount(Some(options.amt_msat)),
+			custom_tlvs: Vec::new(),
+			bolt11_invoice: None,
+			settle_index: None,
+			payer_note: None,
+			quantity: None,
+			lnurl_user: None,
+			lnurl_comment: None,
+		},
+	);
+	fs_store.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.encode()).unwrap();
+	println!("SUCCESS: created refund with payment ID {}: {}", payment_id, refund);
+}
+
+/// Claims a refund someone else created by sending them an invoice for it.
+fn pay_refund(
+	channel_manager: &ChannelManager, refund: &Refund,
+	inbound_payments: &mut InboundPaymentInfoStorage, fs_store: &FilesystemStore,
+) {
+	let invoice = match channel_manager.request_refund_payment(refund) {
+		Ok(invoice) => invoice,
+		Err(e) => {
+			println!("ERROR: failed to request refund payment: {:?}", e);
+			return;
 		},
 	};
+	inbound_payments.payments.insert(
+		invoice.payment_hash(),
+		PaymentInfo {
+			preimage: None,
+			secret: None,
+			status: HTLCStatus::Pending,
+			amt_msat: MillisatAmount(Some(invoice.amount_msats())),
+			custom_tlvs: Vec::new(),
+			bolt11_invoice: None,
+			settle_index: None,
+			payer_note: None,
+			quantity: None,
+			lnurl_user: None,
+			lnurl_comment: None,
+		},
+	);
+	fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound_payments.encode()).unwrap();
+	println!(
+		"SUCCESS: sent an invoice for {} msat with payment hash {} to the refund's creator",
+		invoice.amount_msats(),
+		invoice.payment_hash()
+	);
 }
 
 fn get_invoice(
-	amt_msat: u64, inbound_payments: &mut InboundPaymentInfoStorage,
-	channel_manager: &ChannelManager, keys_manager: Arc<KeysManager>, network: Network,
-	expiry_secs: u32, logger: Arc<FilesystemLogger>,
+	options: &InvoiceOptions, inbound_payments: &mut InboundPaymentInfoStorage,
+	channel_manager: &ChannelManager, keys_manager: &KeysManager, network: Network,
 ) {
-	let currency = match network {
-		Network::Bitcoin => Currency::Bitcoin,
-		Network::Regtest => Currency::Regtest,
-		Network::Signet => Currency::Signet,
-		Network::Testnet | _ => Currency::BitcoinTestnet,
-	};
-	let invoice = match utils::create_invoice_from_channelmanager(
-		channel_manager,
-		keys_manager,
-		logger,
-		currency,
-		Some(amt_msat),
-		"ldk-tutorial-node".to_string(),
-		expiry_secs,
-		None,
-	) {
+	let invoice = match invoice::create_invoice(channel_manager, keys_manager, network, options) {
 		Ok(inv) => {
 			println!("SUCCESS: generated invoice: {}", inv);
+			qr::show(&inv.to_string(), &options.qr);
 			inv
 		},
 		Err(e) => {
-			println!("ERROR: failed to create invoice: {:?}", e);
+			println!("ERROR: failed to create invoice: {}", e);
 			return;
 		},
 	};
 
+	record_invoice(&invoice, options, inbound_payments);
+}
+
+/// Starts tracking a BOLT11 invoice we issued as a pending inbound payment.
+fn record_invoice(
+	invoice: &Bolt11Invoice, options: &InvoiceOptions,
+	inbound_payments: &mut InboundPaymentInfoStorage,
+) {
 	let payment_hash = PaymentHash(invoice.payment_hash().to_byte_array());
 	inbound_payments.payments.insert(
 		payment_hash,
 		PaymentInfo {
-			preimage: None,
+			preimage: options.preimage,
 			secret: Some(invoice.payment_secret().clone()),
 			status: HTLCStatus::Pending,
-			amt_msat: MillisatAmount(Some(amt_msat)),
+			amt_msat: MillisatAmount(options.amt_msat),
+			custom_tlvs: Vec::new(),
+			bolt11_invoice: Some(invoice.to_string()),
+			settle_index: None,
+			payer_note: None,
+			quantity: None,
+			lnurl_user: None,
+			lnurl_comment: None,
 		},
 	);
 }
 
+/// Where a unified request and the invoice and offer it's made of are recorded.
+struct UnifiedRequestStores<'a> {
+	unified_requests: &'a mut UnifiedRequestStorage,
+	inbound_payments: &'a mut InboundPaymentInfoStorage,
+	offers: &'a mut OfferStorage,
+	fs_store: &'a FilesystemStore,
+}
+
+/// Creates a BIP 21 URI that can be paid on-chain, with a BOLT11 invoice or with a single-use
+/// offer, and starts tracking it so it's only counted as paid once.
+fn get_unified_request(
+	amt_msat: u64, description: &str, on_chain_wallet: &LdkOnChainWallet,
+	channel_manager: &ChannelManager, keys_manager: &KeysManager, network: Network,
+	stores: UnifiedRequestStores,
+) -> Result<String, String> {
+	let UnifiedRequestStores { unified_requests, inbound_payments, offers, fs_store } = stores;
+	let invoice_options = InvoiceOptions {
+		amt_msat: Some(amt_msat),
+		description: InvoiceDescription::Direct(description.to_string()),
+		..InvoiceOptions::default()
+	};
+	let invoice = invoice::create_invoice(channel_manager, keys_manager, network, &invoice_options)
+		.map_err(|e| format!("failed to create invoice: {}", e))?;
+	let offer_options = OfferOptions {
+		amt_msat: Some(amt_msat),
+		description: Some(description.to_string()),
+		single_use: true,
+		..OfferOptions::default()
+	};
+	let offer = offers::create_offer(channel_manager, &offer_options, offers, fs_store)?;
+	record_invoice(&invoice, &invoice_options, inbound_payments);
+	fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound_payments.encode()).unwrap();
+
+	let address = on_chain_wallet.get_address();
+	let uri = UnifiedUri {
+		address: Some(address.as_unchecked().clone()),
+		// On-chain payments can only be made in whole satoshis, so round up.
+		amount: Some(Amount::from_sat(amt_msat.div_ceil(1000))),
+		label: None,
+		message: Some(description.to_string()),
+		invoice: Some(invoice.clone()),
+		offer: Some(offer.clone()),
+	}
+	.to_uri();
+	unified_requests.requests.push(UnifiedRequest {
+		uri: uri.clone(),
+		script_pubkey: address.script_pubkey(),
+		payment_hash: PaymentHash(invoice.payment_hash().to_byte_array()),
+		offer_id: offer.id(),
+		amt_msat,
+		paid_via: None,
+	});
+	unified_requests.persist(fs_store);
+	Ok(uri)
+}
+
+/// Pays `amount` to `address` from the on-chain wallet.
+/// Builds and signs, but doesn't broadcast, a transaction paying `amount` to `address`, returning
+/// the checked address, the transaction and its fee.
+fn create_onchain_send(
+	address: Address<NetworkUnchecked>, amount: Amount, network: Network,
+	on_chain_wallet: &LdkOnChainWallet,
+) -> Result<(Address, Transaction, Amount), String> {
+	let address = address
+		.require_network(network)
+		.map_err(|_| "this address is not valid on our network".to_string())?;
+	let (tx, fee) = on_chain_wallet.create_send_transaction(
+		address.script_pubkey(),
+		amount,
+		ConfirmationTarget::NonAnchorChannelFee,
+	)?;
+	Ok((address, tx, fee))
+}
+
+/// Blocks until the invoice for `payment_hash` is paid or expires, then prints it.
+fn wait_invoice(
+	inbound_payments: &Mutex<InboundPaymentInfoStorage>, payment_hash: PaymentHash,
+	timeout: Option<Duration>, fs_store: &FilesystemStore,
+) {
+	let deadline = timeout.map(|timeout| Instant::now() + timeout);
+	loop {
+		{
+			let mut inbound = inbound_payments.lock().unwrap();
+			// Don't wait for the background task to notice the invoice has expired.
+			if invoice::expire_invoices(&mut inbound) {
+				fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound.encode()).unwrap();
+			}
+			match inbound.payments.get(&payment_hash) {
+				Some(payment) if matches!(payment.status, HTLCStatus::Pending) => {},
+				Some(payment) => {
+					print_payment(&payment_hash.to_string(), "inbound", payment);
+					return;
+				},
+				None => {
+					println!("ERROR: no invoice found with payment hash {}", payment_hash);
+					return;
+				},
+			}
+		}
+		if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+			println!("ERROR: timed out waiting for invoice {}", payment_hash);
+			return;
+		}
+		std::thread::sleep(invoice::INVOICE_WAIT_POLL_INTERVAL);
+	}
+}
+
+/// Blocks until a payment is settled with a settle index above `since_index` (by default, the
+/// most recent one) or `timeout` passes, then prints the first such payment.
+fn wait_any_invoice(
+	inbound_payments: &Mutex<InboundPaymentInfoStorage>, since_index: Option<u64>,
+	timeout: Duration,
+) {
+	let since_index =
+		since_index.unwrap_or_else(|| inbound_payments.lock().unwrap().last_settle_index);
+	let deadline = Instant::now() + timeout;
+	loop {
+		{
+			let inbound = inbound_payments.lock().unwrap();
+			if let Some((payment_hash, payment)) = invoice::next_settled(&inbound, since_index) {
+				print_payment(&payment_hash.to_string(), "inbound", payment);
+				return;
+			}
+		}
+		if Instant::now() >= deadline {
+			println!("ERROR: timed out waiting for a payment after settle index {}", since_index);
+			return;
+		}
+		std::thread::sleep(invoice::INVOICE_WAIT_POLL_INTERVAL);
+	}
+}
+
 fn close_channel(
 	channel_id: [u8; 32], counterparty_node_id: PublicKey, channel_manager: Arc<ChannelManager>,
 ) {
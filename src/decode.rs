@@ -0,0 +1,335 @@
+use crate::graph;
+use crate::hex_utils;
+use crate::invoice;
+use bitcoin::constants::ChainHash;
+use bitcoin::network::Network;
+use lightning::blinded_path::message::BlindedMessagePath;
+use lightning::blinded_path::payment::BlindedPaymentPath;
+use lightning::blinded_path::IntroductionNode;
+use lightning::offers::invoice::Bolt12Invoice;
+use lightning::offers::offer::{Amount, Offer, Quantity};
+use lightning::offers::refund::Refund;
+use lightning::routing::router::RouteHint;
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription};
+use std::convert::TryFrom;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
+
+/// Anything `decode` knows how to parse.
+pub(crate) enum Decoded {
+	Bolt11Invoice(Bolt11Invoice),
+	Offer(Offer),
+	Refund(Refund),
+	/// BOLT12 invoices have no string encoding of their own, as they're normally only exchanged
+	/// over onion messages, so we take them hex-encoded.
+	Bolt12Invoice(Box<Bolt12Invoice>),
+}
+
+impl Decoded {
+	pub(crate) fn parse(encoded: &str) -> Result<Self, String> {
+		let encoded = encoded.trim();
+		let encoded = match encoded.get(..10) {
+			Some(scheme) if scheme.eq_ignore_ascii_case("lightning:") => &encoded[10..],
+			_ => encoded,
+		};
+		let lower = encoded.to_lowercase();
+		if lower.starts_with("lno") {
+			Offer::from_str(encoded)
+				.map(Decoded::Offer)
+				.map_err(|e| format!("couldn't decode offer: {:?}", e))
+		} else if lower.starts_with("lnr") {
+			Refund::from_str(encoded)
+				.map(Decoded::Refund)
+				.map_err(|e| format!("couldn't decode refund: {:?}", e))
+		} else if lower.starts_with("ln") {
+			Bolt11Invoice::from_str(encoded)
+				.map(Decoded::Bolt11Invoice)
+				.map_err(|e| format!("couldn't decode invoice: {}", e))
+		} else if let Some(bytes) = hex_utils::to_vec(encoded) {
+			Bolt12Invoice::try_from(bytes)
+				.map(|invoice| Decoded::Bolt12Invoice(Box::new(invoice)))
+				.map_err(|e| format!("couldn't decode BOLT12 invoice: {:?}", e))
+		} else {
+			Err("expected a BOLT11 invoice, an offer, a refund or a hex-encoded BOLT12 invoice"
+				.to_string())
+		}
+	}
+
+	/// The networks this is valid on. Offers may list several chains.
+	pub(crate) fn networks(&self) -> Vec<Option<Network>> {
+		match self {
+			Decoded::Bolt11Invoice(invoice) => vec![Some(invoice.network())],
+			Decoded::Offer(offer) => {
+				offer.chains().into_iter().map(Network::from_chain_hash).collect()
+			},
+			Decoded::Refund(refund) => vec![Network::from_chain_hash(refund.chain())],
+			Decoded::Bolt12Invoice(invoice) => vec![Network::from_chain_hash(invoice.chain())],
+		}
+	}
+
+	pub(crate) fn is_for_network(&self, network: Network) -> bool {
+		match self {
+			Decoded::Bolt11Invoice(invoice) => {
+				invoice.currency() == invoice::currency_for_network(network)
+			},
+			Decoded::Offer(offer) => offer.supports_chain(ChainHash::using_genesis_block(network)),
+			Decoded::Refund(refund) => refund.chain() == ChainHash::using_genesis_block(network),
+			Decoded::Bolt12Invoice(invoice) => {
+				invoice.chain() == ChainHash::using_genesis_block(network)
+			},
+		}
+	}
+
+	pub(crate) fn print(&self) {
+		println!("\t{{");
+		match self {
+			Decoded::Bolt11Invoice(invoice) => print_bolt11_invoice(invoice),
+			Decoded::Offer(offer) => print_offer(offer),
+			Decoded::Refund(refund) => print_refund(refund),
+			Decoded::Bolt12Invoice(invoice) => print_bolt12_invoice(invoice),
+		}
+		let networks = self
+			.networks()
+			.iter()
+			.map(|network| network.map_or("unknown".to_string(), |network| network.to_string()))
+			.collect::<Vec<_>>();
+		println!("\t\tnetworks: [{}],", networks.join(", "));
+		println!("\t}},");
+	}
+}
+
+fn format_time(since_epoch: Duration) -> String {
+	match chrono::NaiveDateTime::from_timestamp_opt(since_epoch.as_secs() as i64, 0) {
+		Some(time) => time.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+		None => since_epoch.as_secs().to_string(),
+	}
+}
+
+fn print_expiry(expires_at: Option<Duration>) {
+	let expires_at = match expires_at {
+		Some(expires_at) => expires_at,
+		None => {
+			println!("\t\texpires_at: never,");
+			return;
+		},
+	};
+	println!("\t\texpires_at: {},", format_time(expires_at));
+	let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+	match expires_at.checked_sub(now) {
+		Some(remaining) if remaining > Duration::ZERO => {
+			println!("\t\tseconds_remaining: {},", remaining.as_secs())
+		},
+		_ => println!("\t\texpired: true,"),
+	}
+}
+
+fn print_route_hints(route_hints: &[RouteHint]) {
+	print!("\t\troute_hints: [");
+	for hint in route_hints {
+		println!();
+		println!("\t\t\t[");
+		for hop in hint.0.iter() {
+			println!("\t\t\t\t{{");
+			println!("\t\t\t\t\tnode_id: {},", hop.src_node_id);
+			println!("\t\t\t\t\tshort_channel_id: {},", graph::scid_str(hop.short_channel_id));
+			println!("\t\t\t\t\tfee_base_msat: {},", hop.fees.base_msat);
+			println!(
+				"\t\t\t\t\tfee_proportional_millionths: {},",
+				hop.fees.proportional_millionths
+			);
+			println!("\t\t\t\t\tcltv_expiry_delta: {},", hop.cltv_expiry_delta);
+			println!("\t\t\t\t}},");
+		}
+		println!("\t\t\t],");
+	}
+	println!("\t\t],");
+}
+
+fn introduction_node_str(introduction_node: &IntroductionNode) -> String {
+	match introduction_node {
+		IntroductionNode::NodeId(pubkey) => pubkey.to_string(),
+		IntroductionNode::DirectedShortChannelId(direction, scid) => {
+			format!("{} ({:?})", graph::scid_str(*scid), direction)
+		},
+	}
+}
+
+fn print_message_paths(paths: &[BlindedMessagePath]) {
+	print!("\t\tblinded_paths: [");
+	for path in paths {
+		println!();
+		println!("\t\t\t{{");
+		println!("\t\t\t\tintroduction_node: {},", introduction_node_str(path.introduction_node()));
+		println!("\t\t\t\tnum_hops: {},", path.blinded_hops().len());
+		println!("\t\t\t}},");
+	}
+	println!("\t\t],");
+}
+
+fn print_payment_paths(paths: &[BlindedPaymentPath]) {
+	print!("\t\tpayment_paths: [");
+	for path in paths {
+		println!();
+		println!("\t\t\t{{");
+		println!("\t\t\t\tintroduction_node: {},", introduction_node_str(path.introduction_node()));
+		println!("\t\t\t\tnum_hops: {},", path.blinded_hops().len());
+		println!("\t\t\t\tfee_base_msat: {},", path.payinfo.fee_base_msat);
+		println!(
+			"\t\t\t\tfee_proportional_millionths: {},",
+			path.payinfo.fee_proportional_millionths
+		);
+		println!("\t\t\t\tcltv_expiry_delta: {},", path.payinfo.cltv_expiry_delta);
+		println!("\t\t\t}},");
+	}
+	println!("\t\t],");
+}
+
+fn print_bolt11_invoice(invoice: &Bolt11Invoice) {
+	println!("\t\ttype: bolt11_invoice,");
+	println!("\t\tpayee: {},", invoice.get_payee_pub_key());
+	println!("\t\tpayment_hash: {},", invoice.payment_hash());
+	match invoice.amount_milli_satoshis() {
+		Some(amt_msat) => println!("\t\tamount_millisatoshis: {},", amt_msat),
+		None => println!("\t\tamount_millisatoshis: any,"),
+	}
+	match invoice.description() {
+		Bolt11InvoiceDescription::Direct(description) => {
+			println!("\t\tdescription: {},", description)
+		},
+		Bolt11InvoiceDescription::Hash(hash) => println!("\t\tdescription_hash: {},", hash.0),
+	}
+	println!("\t\tcreated_at: {},", format_time(invoice.duration_since_epoch()));
+	println!("\t\texpiry_secs: {},", invoice.expiry_time().as_secs());
+	print_expiry(invoice.expires_at());
+	println!("\t\tmin_final_cltv_expiry_delta: {},", invoice.min_final_cltv_expiry_delta());
+	if let Some(features) = invoice.features() {
+		println!("\t\tfeatures: {},", features);
+	}
+	print_route_hints(&invoice.route_hints());
+	let fallbacks = invoice.fallback_addresses();
+	if !fallbacks.is_empty() {
+		let fallbacks = fallbacks.iter().map(|address| address.to_string()).collect::<Vec<_>>();
+		println!("\t\tfallback_addresses: [{}],", fallbacks.join(", "));
+	}
+	if let Some(metadata) = invoice.payment_metadata() {
+		println!("\t\tpayment_metadata: {},", hex_utils::hex_str(metadata));
+	}
+}
+
+fn print_offer(offer: &Offer) {
+	println!("\t\ttype: offer,");
+	println!("\t\toffer_id: {},", hex_utils::hex_str(&offer.id().0));
+	if let Some(signing_pubkey) = offer.signing_pubkey() {
+		println!("\t\tsigning_pubkey: {},", signing_pubkey);
+	}
+	match offer.amount() {
+		Some(Amount::Bitcoin { amount_msats }) => {
+			println!("\t\tamount_millisatoshis: {},", amount_msats)
+		},
+		Some(Amount::Currency { iso4217_code, amount }) => {
+			println!("\t\tamount: {} {},", amount, String::from_utf8_lossy(&iso4217_code))
+		},
+		None => println!("\t\tamount_millisatoshis: any,"),
+	}
+	if let Some(description) = offer.description() {
+		println!("\t\tdescription: {},", description);
+	}
+	if let Some(issuer) = offer.issuer() {
+		println!("\t\tissuer: {},", issuer);
+	}
+	match offer.supported_quantity() {
+		Quantity::One => println!("\t\tquantity: 1,"),
+		Quantity::Bounded(max) => println!("\t\tquantity: 1 to {},", max),
+		Quantity::Unbounded => println!("\t\tquantity: unbounded,"),
+	}
+	print_expiry(offer.absolute_expiry());
+	println!("\t\tfeatures: {},", offer.offer_features());
+	print_message_paths(offer.paths());
+}
+
+fn print_refund(refund: &Refund) {
+	println!("\t\ttype: refund,");
+	println!("\t\tpayer_id: {},", refund.payer_id());
+	println!("\t\tamount_millisatoshis: {},", refund.amount_msats());
+	println!("\t\tdescription: {},", refund.description());
+	if let Some(issuer) = refund.issuer() {
+		println!("\t\tissuer: {},", issuer);
+	}
+	if let Some(quantity) = refund.quantity() {
+		println!("\t\tquantity: {},", quantity);
+	}
+	if let Some(payer_note) = refund.payer_note() {
+		println!("\t\tpayer_note: {},", payer_note);
+	}
+	print_expiry(refund.absolute_expiry());
+	println!("\t\tfeatures: {},", refund.features());
+	print_message_paths(refund.paths());
+}
+
+fn print_bolt12_invoice(invoice: &Bolt12Invoice) {
+	println!("\t\ttype: bolt12_invoice,");
+	println!("\t\tsigning_pubkey: {},", invoice.signing_pubkey());
+	println!("\t\tpayment_hash: {},", invoice.payment_hash());
+	println!("\t\tamount_millisatoshis: {},", invoice.amount_msats());
+	if let Some(description) = invoice.description() {
+		println!("\t\tdescription: {},", description);
+	}
+	if let Some(issuer) = invoice.issuer() {
+		println!("\t\tissuer: {},", issuer);
+	}
+	if let Some(quantity) = invoice.quantity() {
+		println!("\t\tquantity: {},", quantity);
+	}
+	if let Some(payer_note) = invoice.payer_note() {
+		println!("\t\tpayer_note: {},", payer_note);
+	}
+	println!("\t\tcreated_at: {},", format_time(invoice.created_at()));
+	println!("\t\texpiry_secs: {},", invoice.relative_expiry().as_secs());
+	print_expiry(Some(invoice.created_at() + invoice.relative_expiry()));
+	println!("\t\tfeatures: {},", invoice.invoice_features());
+	print_payment_paths(invoice.payment_paths());
+	let fallbacks = invoice.fallbacks();
+	if !fallbacks.is_empty() {
+		let fallbacks = fallbacks.iter().map(|address| address.to_string()).collect::<Vec<_>>();
+		println!("\t\tfallback_addresses: [{}],", fallbacks.join(", "));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+	use lightning::offers::offer::OfferBuilder;
+
+	#[test]
+	fn test_decode_offer() {
+		let secp_ctx = Secp256k1::new();
+		let signing_pubkey =
+			PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[42; 32]).unwrap());
+		let offer = OfferBuilder::new(signing_pubkey)
+			.chain(Network::Testnet)
+			.amount_msats(1000)
+			.description("coffee".to_string())
+			.issuer("cafe".to_string())
+			.build()
+			.unwrap();
+
+		let decoded = match Decoded::parse(&format!("lightning:{}", offer)) {
+			Ok(Decoded::Offer(decoded)) => decoded,
+			_ => panic!("expected an offer"),
+		};
+		assert_eq!(decoded, offer);
+		let decoded = Decoded::Offer(decoded);
+		assert_eq!(decoded.networks(), vec![Some(Network::Testnet)]);
+		assert!(decoded.is_for_network(Network::Testnet));
+		assert!(!decoded.is_for_network(Network::Bitcoin));
+	}
+
+	#[test]
+	fn test_decode_garbage() {
+		assert!(Decoded::parse("lno1qqqq").is_err());
+		assert!(Decoded::parse("lnbc1qqqq").is_err());
+		assert!(Decoded::parse("abcd").is_err());
+		assert!(Decoded::parse("hello").is_err());
+	}
+}
@@ -61,7 +61,7 @@ pub(crate) fn read_inbound_payment_info(path: &Path) -> InboundPaymentInfoStorag
 			return info;
 		}
 	}
-	InboundPaymentInfoStorage { payments: HashMap::new() }
+	InboundPaymentInfoStorage { payments: HashMap::new(), last_settle_index: 0 }
 }
 
 pub(crate) fn read_outbound_payment_info(path: &Path) -> OutboundPaymentInfoStorage {
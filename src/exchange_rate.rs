@@ -0,0 +1,185 @@
+use crate::http;
+use std::collections::HashMap;
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+const MSAT_PER_BTC: f64 = 100_000_000_000.0;
+
+/// The price of one bitcoin in each currency we know about, keyed by ISO 4217 code and given in
+/// the currency's major unit (e.g. dollars rather than cents).
+#[derive(Debug, PartialEq)]
+pub(crate) struct ExchangeRates {
+	pub(crate) btc_prices: HashMap<String, f64>,
+}
+
+impl ExchangeRates {
+	/// Parses a JSON object mapping currency codes to the price of one bitcoin, e.g.
+	/// `{"USD": 65000.0, "EUR": 60000.5}`.
+	pub(crate) fn from_json(json: &[u8]) -> Result<Self, String> {
+		let value: serde_json::Value = serde_json::from_slice(json)
+			.map_err(|e| format!("couldn't parse exchange rates: {}", e))?;
+		let object = value.as_object().ok_or("exchange rates must be a JSON object")?;
+		let mut btc_prices = HashMap::new();
+		for (currency, price) in object {
+			match price.as_f64() {
+				Some(price) if price > 0.0 => {
+					btc_prices.insert(currency.to_ascii_uppercase(), price);
+				},
+				_ => return Err(format!("invalid exchange rate for {}: {}", currency, price)),
+			}
+		}
+		Ok(ExchangeRates { btc_prices })
+	}
+
+	/// Converts `amount` of `currency`, given in the currency's smallest unit as in BOLT12 offers,
+	/// to millisatoshis.
+	pub(crate) fn to_msat(&self, currency: &str, amount: u64) -> Result<u64, String> {
+		let price = self
+			.btc_prices
+			.get(currency)
+			.ok_or(format!("no exchange rate is known for {}", currency))?;
+		let major_units = amount as f64 / 10f64.powi(currency_exponent(currency) as i32);
+		Ok((major_units / price * MSAT_PER_BTC).round() as u64)
+	}
+}
+
+pub(crate) type RatesFuture<'a> =
+	Pin<Box<dyn Future<Output = Result<ExchangeRates, String>> + Send + 'a>>;
+
+/// Somewhere we can get current exchange rates from, used to price and pay offers denominated in
+/// fiat currencies.
+pub(crate) trait ExchangeRateSource: Send + Sync {
+	fn fetch_rates(&self) -> RatesFuture<'_>;
+}
+
+/// Reads rates from a JSON file on local disk, re-reading it on every fetch so it can be updated
+/// while the node is running.
+pub(crate) struct FileExchangeRateSource {
+	pub(crate) path: PathBuf,
+}
+
+impl ExchangeRateSource for FileExchangeRateSource {
+	fn fetch_rates(&self) -> RatesFuture<'_> {
+		Box::pin(async move {
+			let json = std::fs::read(&self.path).map_err(|e| {
+				format!("failed to read exchange rates {}: {}", self.path.display(), e)
+			})?;
+			ExchangeRates::from_json(&json)
+		})
+	}
+}
+
+/// Fetches rates from an HTTP endpoint returning the same JSON as the file source.
+pub(crate) struct HttpExchangeRateSource {
+	pub(crate) url: String,
+}
+
+impl ExchangeRateSource for HttpExchangeRateSource {
+	fn fetch_rates(&self) -> RatesFuture<'_> {
+		Box::pin(async move { ExchangeRates::from_json(&http::get(&self.url).await?) })
+	}
+}
+
+/// Builds a rate source from a `--exchange-rate-source` value, which is either an HTTP(S) URL or a
+/// file path.
+pub(crate) fn parse_source(source: &str) -> Box<dyn ExchangeRateSource> {
+	if source.starts_with("http://") || source.starts_with("https://") {
+		Box::new(HttpExchangeRateSource { url: source.to_string() })
+	} else {
+		Box::new(FileExchangeRateSource { path: PathBuf::from(source) })
+	}
+}
+
+/// The number of decimal places in a currency's smallest unit, per ISO 4217. Most currencies have
+/// two, so only the exceptions are listed.
+pub(crate) fn currency_exponent(currency: &str) -> u32 {
+	match currency {
+		"BIF" | "CLP" | "DJF" | "GNF" | "ISK" | "JPY" | "KMF" | "KRW" | "PYG" | "RWF" | "UGX"
+		| "UYI" | "VND" | "VUV" | "XAF" | "XOF" | "XPF" => 0,
+		"BHD" | "IQD" | "JOD" | "KWD" | "LYD" | "OMR" | "TND" => 3,
+		_ => 2,
+	}
+}
+
+/// Parses a decimal amount such as `5.00` of `currency` into the currency's smallest unit.
+pub(crate) fn parse_fiat_amount(currency: &str, amount: &str) -> Result<u64, String> {
+	let exponent = currency_exponent(currency) as usize;
+	let mut parts = amount.splitn(2, '.');
+	let whole = parts.next().unwrap();
+	let fraction = parts.next().unwrap_or("");
+	let invalid = || format!("couldn't parse {} amount {}", currency, amount);
+	if whole.is_empty() || fraction.len() > exponent {
+		return Err(invalid());
+	}
+	if !whole.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+		return Err(invalid());
+	}
+	let digits = format!("{}{:0<width$}", whole, fraction, width = exponent);
+	digits.parse().map_err(|_| invalid())
+}
+
+/// Formats an amount in `currency`'s smallest unit as a decimal, e.g. 500 USD cents as `5.00`.
+pub(crate) fn format_fiat_amount(currency: &str, amount: u64) -> String {
+	let exponent = currency_exponent(currency);
+	if exponent == 0 {
+		return amount.to_string();
+	}
+	let scale = 10u64.pow(exponent);
+	format!("{}.{:0width$}", amount / scale, amount % scale, width = exponent as usize)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::stand_in_http_server;
+
+	#[test]
+	fn test_parse_and_format_fiat_amounts() {
+		assert_eq!(parse_fiat_amount("USD", "5.00"), Ok(500));
+		assert_eq!(parse_fiat_amount("USD", "5.5"), Ok(550));
+		assert_eq!(parse_fiat_amount("EUR", "12"), Ok(1200));
+		assert_eq!(parse_fiat_amount("JPY", "800"), Ok(800));
+		assert_eq!(parse_fiat_amount("KWD", "1.250"), Ok(1250));
+		assert!(parse_fiat_amount("USD", "5.001").is_err());
+		assert!(parse_fiat_amount("JPY", "800.5").is_err());
+		assert!(parse_fiat_amount("USD", "-5").is_err());
+		assert!(parse_fiat_amount("USD", ".50").is_err());
+
+		assert_eq!(format_fiat_amount("USD", 500), "5.00");
+		assert_eq!(format_fiat_amount("USD", 7), "0.07");
+		assert_eq!(format_fiat_amount("JPY", 800), "800");
+	}
+
+	#[test]
+	fn test_convert_to_msat() {
+		let rates = ExchangeRates::from_json(br#"{"USD": 50000, "jpy": 8000000}"#).unwrap();
+		// $5 at $50,000/BTC is 10,000 sats.
+		assert_eq!(rates.to_msat("USD", 500), Ok(10_000_000));
+		assert_eq!(rates.to_msat("JPY", 800), Ok(10_000_000));
+		assert!(rates.to_msat("EUR", 500).is_err());
+
+		assert!(ExchangeRates::from_json(br#"{"USD": -1}"#).is_err());
+		assert!(ExchangeRates::from_json(br#"["USD", 50000]"#).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_file_source() {
+		let path = std::env::temp_dir().join("ldk_exchange_rates_test.json");
+		std::fs::write(&path, r#"{"EUR": 40000.0}"#).unwrap();
+		let source = parse_source(path.to_str().unwrap());
+		let rates = source.fetch_rates().await.unwrap();
+		assert_eq!(rates.to_msat("EUR", 400), Ok(10_000_000));
+
+		std::fs::remove_file(&path).unwrap();
+		assert!(source.fetch_rates().await.is_err());
+	}
+
+	#[tokio::test]
+	async fn test_http_source() {
+		let (host, _) = stand_in_http_server(|_, _| r#"{"USD": 62500.0, "EUR": 58000.0}"#).await;
+		let rates = parse_source(&format!("http://{}/rates", host)).fetch_rates().await.unwrap();
+		assert_eq!(rates.btc_prices.len(), 2);
+		assert_eq!(rates.to_msat("USD", 625), Ok(10_000_000));
+	}
+}
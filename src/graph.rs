@@ -0,0 +1,511 @@
+use crate::hex_utils;
+use crate::NetworkGraph;
+use lightning::routing::gossip::{
+	ChannelInfo, ChannelUpdateInfo, NodeId, NodeInfo, ReadOnlyNetworkGraph,
+};
+use serde_json::json;
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, Write};
+
+/// Parses a short channel id given either as a plain integer or in `<block>x<tx>x<output>` form.
+pub(crate) fn parse_scid(scid_str: &str) -> Option<u64> {
+	if let Ok(scid) = scid_str.parse::<u64>() {
+		return Some(scid);
+	}
+	let parts = scid_str.split('x').map(|p| p.parse::<u64>().ok()).collect::<Option<Vec<_>>>()?;
+	match parts[..] {
+		[block, tx, output] if block < 1 << 24 && tx < 1 << 24 && output < 1 << 16 => {
+			Some(block << 40 | tx << 16 | output)
+		},
+		_ => None,
+	}
+}
+
+pub(crate) fn scid_str(scid: u64) -> String {
+	format!("{}x{}x{}", scid >> 40, (scid >> 16) & 0xff_ffff, scid & 0xffff)
+}
+
+pub(crate) fn node_alias(node: &NodeInfo) -> Option<String> {
+	node.announcement_info.as_ref().map(|announcement| announcement.alias().to_string())
+}
+
+/// Resolves a node given either as a hex pubkey or as its exact (case-insensitive) alias.
+pub(crate) fn find_node(
+	graph: &ReadOnlyNetworkGraph, pubkey_or_alias: &str,
+) -> Result<NodeId, String> {
+	if let Some(pubkey) = hex_utils::to_compressed_pubkey(pubkey_or_alias) {
+		let node_id = NodeId::from_pubkey(&pubkey);
+		return match graph.node(&node_id) {
+			Some(_) => Ok(node_id),
+			None => Err(format!("node {} is not in the network graph", pubkey)),
+		};
+	}
+	let matches = graph
+		.nodes()
+		.unordered_iter()
+		.filter(|(_, node)| {
+			node_alias(node).is_some_and(|alias| alias.eq_ignore_ascii_case(pubkey_or_alias))
+		})
+		.map(|(node_id, _)| *node_id)
+		.collect::<Vec<_>>();
+	match matches[..] {
+		[node_id] => Ok(node_id),
+		[] => Err(format!("no node with alias \"{}\"", pubkey_or_alias)),
+		_ => Err(format!(
+			"alias \"{}\" is ambiguous, use one of: {}",
+			pubkey_or_alias,
+			matches.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ")
+		)),
+	}
+}
+
+pub(crate) fn print_node_info(graph: &ReadOnlyNetworkGraph, node_id: &NodeId) {
+	let node = match graph.node(node_id) {
+		Some(node) => node,
+		None => return,
+	};
+	println!("\t{{");
+	println!("\t\tnode_id: {},", node_id);
+	match &node.announcement_info {
+		Some(announcement) => {
+			println!("\t\talias: {},", announcement.alias());
+			println!("\t\tcolor: #{},", hex_utils::hex_str(&announcement.rgb()));
+			println!("\t\tlast_update: {},", announcement.last_update());
+			println!("\t\tfeatures: {},", announcement.features());
+			print!("\t\taddresses: [");
+			for address in announcement.addresses() {
+				print!("{}, ", address);
+			}
+			println!("],");
+		},
+		None => println!("\t\tannounced: false,"),
+	}
+	println!("\t\tnum_channels: {},", node.channels.len());
+	let capacity_sats = node
+		.channels
+		.iter()
+		.filter_map(|scid| graph.channel(*scid).and_then(|chan| chan.capacity_sats))
+		.sum::<u64>();
+	println!("\t\ttotal_capacity_sats: {},", capacity_sats);
+	print!("\t\tchannels: [");
+	for scid in node.channels.iter() {
+		let channel = match graph.channel(*scid) {
+			Some(channel) => channel,
+			None => continue,
+		};
+		let (peer, outbound_update) = if channel.node_one == *node_id {
+			(channel.node_two, &channel.one_to_two)
+		} else {
+			(channel.node_one, &channel.two_to_one)
+		};
+		println!();
+		println!("\t\t\t{{");
+		println!("\t\t\t\tshort_channel_id: {},", scid_str(*scid));
+		println!("\t\t\t\tpeer: {},", peer);
+		if let Some(alias) = graph.node(&peer).and_then(node_alias) {
+			println!("\t\t\t\tpeer_alias: {},", alias);
+		}
+		if let Some(capacity_sats) = channel.capacity_sats {
+			println!("\t\t\t\tcapacity_sats: {},", capacity_sats);
+		}
+		if let Some(update) = outbound_update {
+			println!("\t\t\t\tfee_base_msat: {},", update.fees.base_msat);
+			println!(
+				"\t\t\t\tfee_proportional_millionths: {},",
+				update.fees.proportional_millionths
+			);
+		}
+		println!("\t\t\t}},");
+	}
+	println!("\t\t],");
+	println!("\t}},");
+}
+
+fn print_channel_update(direction: &str, update: &Option<ChannelUpdateInfo>) {
+	match update {
+		Some(update) => {
+			println!("\t\t{}: {{", direction);
+			println!("\t\t\tfee_base_msat: {},", update.fees.base_msat);
+			println!("\t\t\tfee_proportional_millionths: {},", update.fees.proportional_millionths);
+			println!("\t\t\tcltv_expiry_delta: {},", update.cltv_expiry_delta);
+			println!("\t\t\thtlc_minimum_msat: {},", update.htlc_minimum_msat);
+			println!("\t\t\thtlc_maximum_msat: {},", update.htlc_maximum_msat);
+			println!("\t\t\tdisabled: {},", !update.enabled);
+			println!("\t\t\tlast_update: {},", update.last_update);
+			println!("\t\t}},");
+		},
+		None => println!("\t\t{}: none,", direction),
+	}
+}
+
+pub(crate) fn print_channel_info(graph: &ReadOnlyNetworkGraph, scid: u64, channel: &ChannelInfo) {
+	println!("\t{{");
+	println!("\t\tshort_channel_id: {},", scid_str(scid));
+	if let Some(capacity_sats) = channel.capacity_sats {
+		println!("\t\tcapacity_sats: {},", capacity_sats);
+	}
+	println!("\t\tfeatures: {},", channel.features);
+	for node_id in [&channel.node_one, &channel.node_two] {
+		match graph.node(node_id).and_then(node_alias) {
+			Some(alias) => println!("\t\tnode: {} ({}),", node_id, alias),
+			None => println!("\t\tnode: {},", node_id),
+		}
+	}
+	print_channel_update("node_one_to_node_two", &channel.one_to_two);
+	print_channel_update("node_two_to_node_one", &channel.two_to_one);
+	println!("\t}},");
+}
+
+/// Filters applied by `describegraph`.
+#[derive(Default)]
+pub(crate) struct GraphFilter {
+	pub(crate) min_capacity_sats: Option<u64>,
+	pub(crate) has_address: bool,
+}
+
+pub(crate) fn describe_graph(graph: &ReadOnlyNetworkGraph, filter: &GraphFilter) {
+	let channel_matches = |channel: &ChannelInfo| match filter.min_capacity_sats {
+		Some(min) => channel.capacity_sats.is_some_and(|capacity| capacity >= min),
+		None => true,
+	};
+	let node_matches = |node: &NodeInfo| {
+		!filter.has_address
+			|| node.announcement_info.as_ref().is_some_and(|a| !a.addresses().is_empty())
+	};
+
+	let mut num_nodes = 0;
+	print!("nodes: [");
+	for (node_id, node) in graph.nodes().unordered_iter() {
+		if !node_matches(node) {
+			continue;
+		}
+		// With a capacity filter, only list nodes that still have a qualifying channel.
+		if filter.min_capacity_sats.is_some()
+			&& !node.channels.iter().any(|scid| graph.channel(*scid).is_some_and(channel_matches))
+		{
+			continue;
+		}
+		num_nodes += 1;
+		println!();
+		print!("\t{{ node_id: {}", node_id);
+		if let Some(alias) = node_alias(node) {
+			print!(", alias: {}", alias);
+		}
+		print!(", num_channels: {} }},", node.channels.len());
+	}
+	println!("\n]");
+
+	let mut num_channels = 0;
+	print!("channels: [");
+	for (scid, channel) in graph.channels().unordered_iter() {
+		if !channel_matches(channel) {
+			continue;
+		}
+		let endpoints_match = [&channel.node_one, &channel.node_two]
+			.iter()
+			.all(|node_id| graph.node(node_id).is_some_and(node_matches));
+		if !endpoints_match {
+			continue;
+		}
+		num_channels += 1;
+		println!();
+		print!(
+			"\t{{ short_channel_id: {}, node_one: {}, node_two: {}",
+			scid_str(*scid),
+			channel.node_one,
+			channel.node_two
+		);
+		if let Some(capacity_sats) = channel.capacity_sats {
+			print!(", capacity_sats: {}", capacity_sats);
+		}
+		print!(" }},");
+	}
+	println!("\n]");
+	println!("num_nodes: {}, num_channels: {}", num_nodes, num_channels);
+}
+
+pub(crate) fn search_nodes(graph: &ReadOnlyNetworkGraph, alias_substring: &str) {
+	let needle = alias_substring.to_lowercase();
+	print!("[");
+	for (node_id, node) in graph.nodes().unordered_iter() {
+		if let Some(alias) = node_alias(node) {
+			if alias.to_lowercase().contains(&needle) {
+				println!();
+				print!(
+					"\t{{ node_id: {}, alias: {}, num_channels: {} }},",
+					node_id,
+					alias,
+					node.channels.len()
+				);
+			}
+		}
+	}
+	println!("\n]");
+}
+
+pub(crate) enum ExportFormat {
+	Json,
+	Dot,
+}
+
+/// Returns every node within `hops` channels of `origin`, including `origin` itself.
+fn neighborhood(graph: &ReadOnlyNetworkGraph, origin: NodeId, hops: u32) -> HashSet<NodeId> {
+	let mut seen = HashSet::new();
+	seen.insert(origin);
+	let mut queue = VecDeque::new();
+	queue.push_back((origin, 0));
+	while let Some((node_id, depth)) = queue.pop_front() {
+		if depth == hops {
+			continue;
+		}
+		let node = match graph.node(&node_id) {
+			Some(node) => node,
+			None => continue,
+		};
+		for channel in node.channels.iter().filter_map(|scid| graph.channel(*scid)) {
+			let peer =
+				if channel.node_one == node_id { channel.node_two } else { channel.node_one };
+			if seen.insert(peer) {
+				queue.push_back((peer, depth + 1));
+			}
+		}
+	}
+	seen
+}
+
+fn channel_update_json(update: &Option<ChannelUpdateInfo>) -> serde_json::Value {
+	match update {
+		Some(update) => json!({
+			"enabled": update.enabled,
+			"last_update": update.last_update,
+			"cltv_expiry_delta": update.cltv_expiry_delta,
+			"htlc_minimum_msat": update.htlc_minimum_msat,
+			"htlc_maximum_msat": update.htlc_maximum_msat,
+			"fee_base_msat": update.fees.base_msat,
+			"fee_proportional_millionths": update.fees.proportional_millionths,
+		}),
+		None => serde_json::Value::Null,
+	}
+}
+
+fn dot_escape(label: &str) -> String {
+	label.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Writes the network graph to `writer`. If `neighborhood_of` is set, only nodes within that many
+/// hops of the given node (and channels between them) are written. Returns the number of nodes and
+/// channels written.
+///
+/// The caller holds the graph's read lock for as long as this runs, which blocks gossip updates,
+/// so `writer` should be an in-memory buffer rather than a file.
+pub(crate) fn export_graph<W: Write>(
+	graph: &ReadOnlyNetworkGraph, format: ExportFormat, writer: &mut W,
+	neighborhood_of: Option<(NodeId, u32)>,
+) -> io::Result<(usize, usize)> {
+	let included = neighborhood_of.map(|(origin, hops)| neighborhood(graph, origin, hops));
+	let node_included = |node_id: &NodeId| included.as_ref().is_none_or(|n| n.contains(node_id));
+	let channel_included = |channel: &ChannelInfo| {
+		node_included(&channel.node_one) && node_included(&channel.node_two)
+	};
+	let (mut num_nodes, mut num_channels) = (0, 0);
+
+	match format {
+		ExportFormat::Json => writer.write_all(b"{\"nodes\":[")?,
+		ExportFormat::Dot => writer.write_all(b"digraph network_graph {\n")?,
+	}
+	for (node_id, node) in graph.nodes().unordered_iter() {
+		if !node_included(node_id) {
+			continue;
+		}
+		match format {
+			ExportFormat::Json => {
+				if num_nodes > 0 {
+					writer.write_all(b",")?;
+				}
+				let announcement = match &node.announcement_info {
+					Some(announcement) => json!({
+						"alias": announcement.alias().to_string(),
+						"rgb": hex_utils::hex_str(&announcement.rgb()),
+						"last_update": announcement.last_update(),
+						"features": announcement.features().to_string(),
+						"addresses": announcement
+							.addresses()
+							.iter()
+							.map(|address| address.to_string())
+							.collect::<Vec<_>>(),
+					}),
+					None => serde_json::Value::Null,
+				};
+				let channels = node
+					.channels
+					.iter()
+					.filter(|scid| graph.channel(**scid).is_some_and(channel_included))
+					.map(|scid| scid_str(*scid))
+					.collect::<Vec<_>>();
+				let node_json = json!({
+					"node_id": node_id.to_string(),
+					"announcement": announcement,
+					"channels": channels,
+				});
+				serde_json::to_writer(&mut *writer, &node_json)?;
+			},
+			ExportFormat::Dot => {
+				let label =
+					node_alias(node).unwrap_or_else(|| node_id.to_string()[..16].to_string());
+				writeln!(writer, "\t\"{}\" [label=\"{}\"];", node_id, dot_escape(&label))?;
+			},
+		}
+		num_nodes += 1;
+	}
+
+	if let ExportFormat::Json = format {
+		writer.write_all(b"],\"channels\":[")?;
+	}
+	for (scid, channel) in graph.channels().unordered_iter() {
+		if !channel_included(channel) {
+			continue;
+		}
+		match format {
+			ExportFormat::Json => {
+				if num_channels > 0 {
+					writer.write_all(b",")?;
+				}
+				let channel_json = json!({
+					"short_channel_id": scid_str(*scid),
+					"node_one": channel.node_one.to_string(),
+					"node_two": channel.node_two.to_string(),
+					"capacity_sats": channel.capacity_sats,
+					"features": channel.features.to_string(),
+					"one_to_two": channel_update_json(&channel.one_to_two),
+					"two_to_one": channel_update_json(&channel.two_to_one),
+				});
+				serde_json::to_writer(&mut *writer, &channel_json)?;
+			},
+			ExportFormat::Dot => {
+				let capacity = match channel.capacity_sats {
+					Some(capacity_sats) => format!("{} sats", capacity_sats),
+					None => "unknown capacity".to_string(),
+				};
+				let directions = [
+					(&channel.node_one, &channel.node_two, &channel.one_to_two),
+					(&channel.node_two, &channel.node_one, &channel.two_to_one),
+				];
+				for (from, to, update) in directions.iter() {
+					if let Some(update) = update {
+						writeln!(
+							writer,
+							"\t\"{}\" -> \"{}\" [label=\"{}\\n{} msat + {} ppm\"];",
+							from,
+							to,
+							capacity,
+							update.fees.base_msat,
+							update.fees.proportional_millionths
+						)?;
+					}
+				}
+			},
+		}
+		num_channels += 1;
+	}
+	match format {
+		ExportFormat::Json => writer.write_all(b"]}\n")?,
+		ExportFormat::Dot => writer.write_all(b"}\n")?,
+	}
+	writer.flush()?;
+	Ok((num_nodes, num_channels))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{add_channel, node_key, test_network_graph};
+
+	#[test]
+	fn test_scid_round_trip() {
+		let scid = parse_scid("700123x1456x1").unwrap();
+		assert_eq!(scid, 700123 << 40 | 1456 << 16 | 1);
+		assert_eq!(scid_str(scid), "700123x1456x1");
+		assert_eq!(parse_scid(&scid.to_string()), Some(scid));
+		assert_eq!(parse_scid("1x2"), None);
+		assert_eq!(parse_scid("1x2x65536"), None);
+		assert_eq!(parse_scid("not_an_scid"), None);
+	}
+
+	fn export_json(
+		graph: &NetworkGraph, neighborhood_of: Option<(NodeId, u32)>,
+	) -> (usize, usize, serde_json::Value) {
+		let mut buffer = Vec::new();
+		let (num_nodes, num_channels) =
+			export_graph(&graph.read_only(), ExportFormat::Json, &mut buffer, neighborhood_of)
+				.unwrap();
+		(num_nodes, num_channels, serde_json::from_slice(&buffer).unwrap())
+	}
+
+	#[test]
+	fn test_export_graph_neighborhood() {
+		// A line of four nodes: 1 - 2 - 3 - 4.
+		let graph = test_network_graph();
+		let nodes = (1..=4).map(node_key).collect::<Vec<_>>();
+		for (i, pair) in nodes.windows(2).enumerate() {
+			add_channel(&graph, i as u64 + 1, &pair[0], &pair[1], 1000, 100, 40);
+		}
+		let origin = NodeId::from_pubkey(&nodes[0]);
+
+		let (num_nodes, num_channels, json) = export_json(&graph, None);
+		assert_eq!((num_nodes, num_channels), (4, 3));
+		assert_eq!(json["nodes"].as_array().unwrap().len(), 4);
+		assert_eq!(json["channels"].as_array().unwrap().len(), 3);
+
+		let (num_nodes, num_channels, json) = export_json(&graph, Some((origin, 0)));
+		assert_eq!((num_nodes, num_channels), (1, 0));
+		assert_eq!(json["nodes"][0]["channels"], json!([]));
+
+		let (num_nodes, num_channels, json) = export_json(&graph, Some((origin, 2)));
+		assert_eq!((num_nodes, num_channels), (3, 2));
+		let node_ids = json["nodes"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.map(|node| node["node_id"].as_str().unwrap().to_string())
+			.collect::<HashSet<_>>();
+		assert_eq!(node_ids, nodes[..3].iter().map(|node| node.to_string()).collect());
+		// Node 3's channel to node 4 lies outside the neighborhood, so it isn't listed.
+		let node_three = json["nodes"]
+			.as_array()
+			.unwrap()
+			.iter()
+			.find(|node| node["node_id"] == json!(nodes[2].to_string()))
+			.unwrap();
+		assert_eq!(node_three["channels"], json!([scid_str(2)]));
+	}
+
+	#[test]
+	fn test_export_graph_json() {
+		let graph = test_network_graph();
+		add_channel(&graph, 42, &node_key(1), &node_key(2), 1000, 250, 144);
+
+		let (_, _, json) = export_json(&graph, None);
+		let channel = &json["channels"][0];
+		assert_eq!(channel["short_channel_id"], json!(scid_str(42)));
+		assert_eq!(channel["capacity_sats"], serde_json::Value::Null);
+		for direction in ["one_to_two", "two_to_one"] {
+			let update = &channel[direction];
+			assert_eq!(update["enabled"], json!(true));
+			assert_eq!(update["fee_base_msat"], json!(1000));
+			assert_eq!(update["fee_proportional_millionths"], json!(250));
+			assert_eq!(update["cltv_expiry_delta"], json!(144));
+			assert_eq!(update["htlc_maximum_msat"], json!(100_000_000));
+		}
+		// Partial announcements leave the nodes without announcement info.
+		for node in json["nodes"].as_array().unwrap() {
+			assert_eq!(node["announcement"], serde_json::Value::Null);
+			assert_eq!(node["channels"], json!([scid_str(42)]));
+		}
+	}
+
+	#[test]
+	fn test_dot_escape() {
+		assert_eq!(dot_escape("say \"hi\""), "say \\\"hi\\\"");
+		assert_eq!(dot_escape("back\\slash"), "back\\\\slash");
+	}
+}
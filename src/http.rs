@@ -0,0 +1,43 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+/// How long we wait on any single HTTP request before giving up.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches `url` and returns the response body, failing on any non-2xx status.
+pub(crate) async fn get(url: &str) -> Result<Vec<u8>, String> {
+	get_accepting(url, "*/*").await
+}
+
+/// Fetches `url` as `get` does, asking for a response of the `accept` media type.
+pub(crate) async fn get_accepting(url: &str, accept: &str) -> Result<Vec<u8>, String> {
+	let client = reqwest::Client::builder()
+		.timeout(HTTP_TIMEOUT)
+		.build()
+		.map_err(|e| format!("failed to build HTTP client: {}", e))?;
+	let request = client.get(url).header(reqwest::header::ACCEPT, accept);
+	let response = request.send().await.map_err(|e| format!("request to {} failed: {}", url, e))?;
+	if !response.status().is_success() {
+		return Err(format!("request to {} returned {}", url, response.status()));
+	}
+	let body = response.bytes().await.map_err(|e| format!("failed to read response: {}", e))?;
+	Ok(body.to_vec())
+}
+
+pub(crate) type HttpFuture<'a> = Pin<Box<dyn Future<Output = Result<Vec<u8>, String>> + Send + 'a>>;
+
+/// Makes the HTTP GET requests of protocols built on top of HTTP, such as LNURL, so they can be
+/// pointed at a local stand-in server in tests.
+pub(crate) trait HttpClient: Send + Sync {
+	fn get<'a>(&'a self, url: &'a str) -> HttpFuture<'a>;
+}
+
+/// The `HttpClient` used outside of tests, making requests with `get`.
+pub(crate) struct ReqwestClient;
+
+impl HttpClient for ReqwestClient {
+	fn get<'a>(&'a self, url: &'a str) -> HttpFuture<'a> {
+		Box::pin(get(url))
+	}
+}
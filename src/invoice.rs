@@ -0,0 +1,477 @@
+use crate::disk::INBOUND_PAYMENTS_FNAME;
+use crate::filesystem_store::FilesystemStore;
+use crate::hex_utils;
+use crate::qr::QrOptions;
+use crate::routing;
+use crate::{ChannelManager, HTLCStatus, InboundPaymentInfoStorage, PaymentInfo};
+use bitcoin::address::AddressData;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::network::Network;
+use bitcoin::Address;
+use lightning::ln::channel_state::ChannelDetails;
+use lightning::ln::channelmanager::MIN_FINAL_CLTV_EXPIRY_DELTA;
+use lightning::routing::gossip::RoutingFees;
+use lightning::routing::router::{RouteHint, RouteHintHop};
+use lightning::sign::{KeysManager, NodeSigner, Recipient};
+use lightning::types::payment::{PaymentHash, PaymentPreimage};
+use lightning::util::persist::KVStore;
+use lightning::util::ser::Writeable;
+use lightning_invoice::{Bolt11Invoice, Currency, Fallback, InvoiceBuilder};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The description used when `getinvoice` isn't given one.
+pub(crate) const DEFAULT_INVOICE_DESCRIPTION: &str = "ldk-tutorial-node";
+
+/// BOLT 11's default expiry, used when `getinvoice` isn't given one.
+pub(crate) const DEFAULT_INVOICE_EXPIRY_SECS: u32 = 3600;
+
+/// How often the background task looks for unpaid invoices that have expired.
+const INVOICE_EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often `waitinvoice` and `waitanyinvoice` check whether what they're waiting for happened.
+pub(crate) const INVOICE_WAIT_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long `waitanyinvoice` waits for a payment when no `--timeout` is given. The CLI can't be
+/// interrupted while it waits, so it must not wait forever.
+pub(crate) const DEFAULT_WAIT_ANY_INVOICE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum InvoiceDescription {
+	Direct(String),
+	/// The SHA-256 hash of a description passed out of band, as LNURL-pay does with its metadata.
+	Hash(Sha256),
+}
+
+/// The options `getinvoice` takes.
+#[derive(Debug, PartialEq)]
+pub(crate) struct InvoiceOptions {
+	/// `None` for an invoice the payer may pay any amount to.
+	pub(crate) amt_msat: Option<u64>,
+	pub(crate) description: InvoiceDescription,
+	pub(crate) expiry_secs: u32,
+	pub(crate) min_final_cltv_expiry_delta: Option<u16>,
+	pub(crate) fallback_address: Option<String>,
+	/// Channels (by channel id or short channel id) to include route hints for. If empty, hints
+	/// are only included when we have no public channels.
+	pub(crate) private_hints: Vec<String>,
+	/// A preimage of our own choosing, rather than one derived by the `ChannelManager`.
+	pub(crate) preimage: Option<PaymentPreimage>,
+	/// Where to show a QR code of the result, from `--qr` and `--qr-file <path>`.
+	pub(crate) qr: QrOptions,
+}
+
+impl Default for InvoiceOptions {
+	fn default() -> Self {
+		Self {
+			amt_msat: None,
+			description: InvoiceDescription::Direct(DEFAULT_INVOICE_DESCRIPTION.to_string()),
+			expiry_secs: DEFAULT_INVOICE_EXPIRY_SECS,
+			min_final_cltv_expiry_delta: None,
+			fallback_address: None,
+			private_hints: Vec::new(),
+			preimage: None,
+			qr: QrOptions::default(),
+		}
+	}
+}
+
+impl InvoiceOptions {
+	/// Parses the arguments of `getinvoice`. The amount and expiry may also be given positionally,
+	/// as in `getinvoice <amt_msat> <expiry_secs>`. Descriptions containing spaces must be quoted.
+	pub(crate) fn parse(args: &str) -> Result<Self, String> {
+		let mut args = split_quoted(args)?;
+		let qr = QrOptions::extract(&mut args)?;
+		let mut args = args.iter().map(|arg| arg.as_str()).peekable();
+		let mut options = InvoiceOptions { qr, ..InvoiceOptions::default() };
+		match args.next_if(|arg| !arg.starts_with("--")) {
+			Some("any") | None => {},
+			Some(amt) => options.amt_msat = Some(parse_option("amount", amt)?),
+		}
+		if let Some(expiry) = args.next_if(|arg| !arg.starts_with("--")) {
+			options.expiry_secs = parse_option("expiry", expiry)?;
+		}
+		let mut description_given = false;
+		while let Some(flag) = args.next() {
+			if flag == "--private-hints" {
+				while let Some(channel) = args.next_if(|arg| !arg.starts_with("--")) {
+					options.private_hints.push(channel.to_string());
+				}
+				if options.private_hints.is_empty() {
+					return Err("--private-hints requires at least one channel".to_string());
+				}
+				continue;
+			}
+			let value = args.next().ok_or(format!("{} requires a value", flag))?;
+			match flag {
+				"--description" | "--description-hash" if description_given => {
+					return Err(
+						"only one of --description and --description-hash may be given".to_string()
+					)
+				},
+				"--description" => {
+					options.description = InvoiceDescription::Direct(value.to_string());
+					description_given = true;
+				},
+				"--description-hash" => match hex_utils::to_vec(value) {
+					Some(hash) if hash.len() == 32 => {
+						let hash = Sha256::from_slice(&hash).unwrap();
+						options.description = InvoiceDescription::Hash(hash);
+						description_given = true;
+					},
+					_ => return Err(format!("couldn't parse --description-hash {}", value)),
+				},
+				"--expiry" => options.expiry_secs = parse_option(flag, value)?,
+				"--min-final-cltv" => {
+					let delta = parse_option(flag, value)?;
+					if delta < MIN_FINAL_CLTV_EXPIRY_DELTA {
+						return Err(format!(
+							"--min-final-cltv must be at least {}",
+							MIN_FINAL_CLTV_EXPIRY_DELTA
+						));
+					}
+					options.min_final_cltv_expiry_delta = Some(delta);
+				},
+				"--fallback-address" => options.fallback_address = Some(value.to_string()),
+				"--preimage" => match hex_utils::to_vec(value) {
+					Some(preimage) if preimage.len() == 32 => {
+						let mut bytes = [0; 32];
+						bytes.copy_from_slice(&preimage);
+						options.preimage = Some(PaymentPreimage(bytes));
+					},
+					_ => return Err(format!("couldn't parse --preimage {}", value)),
+				},
+				_ => return Err(format!("unknown option {}", flag)),
+			}
+		}
+		Ok(options)
+	}
+}
+
+fn parse_option<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+	T::Err: std::fmt::Display,
+{
+	value.parse().map_err(|e| format!("couldn't parse {} {}: {}", name, value, e))
+}
+
+/// Splits `args` on whitespace, keeping double-quoted strings together.
+pub(crate) fn split_quoted(args: &str) -> Result<Vec<String>, String> {
+	let mut words = Vec::new();
+	let mut rest = args.trim_start();
+	while !rest.is_empty() {
+		if let Some(quoted) = rest.strip_prefix('"') {
+			let end = quoted.find('"').ok_or("unterminated quote".to_string())?;
+			words.push(quoted[..end].to_string());
+			rest = quoted[end + 1..].trim_start();
+		} else {
+			let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+			words.push(rest[..end].to_string());
+			rest = rest[end..].trim_start();
+		}
+	}
+	Ok(words)
+}
+
+pub(crate) fn currency_for_network(network: Network) -> Currency {
+	match network {
+		Network::Bitcoin => Currency::Bitcoin,
+		Network::Regtest => Currency::Regtest,
+		Network::Signet => Currency::Signet,
+		_ => Currency::BitcoinTestnet,
+	}
+}
+
+fn fallback_for_address(address: &str, network: Network) -> Result<Fallback, String> {
+	let address = Address::from_str(address)
+		.and_then(|address| address.require_network(network))
+		.map_err(|e| format!("invalid fallback address {}: {}", address, e))?;
+	match address.to_address_data() {
+		AddressData::P2pkh { pubkey_hash } => Ok(Fallback::PubKeyHash(pubkey_hash)),
+		AddressData::P2sh { script_hash } => Ok(Fallback::ScriptHash(script_hash)),
+		AddressData::Segwit { witness_program } => Ok(Fallback::SegWitProgram {
+			version: witness_program.version(),
+			program: witness_program.program().as_bytes().to_vec(),
+		}),
+		_ => Err(format!("fallback address {} has an unsupported type", address)),
+	}
+}
+
+/// A single-hop route hint from the counterparty of `channel` to us, if we know enough about the
+/// channel to build one.
+fn route_hint(channel: &ChannelDetails) -> Option<RouteHint> {
+	let forwarding_info = channel.counterparty.forwarding_info.as_ref()?;
+	Some(RouteHint(vec![RouteHintHop {
+		src_node_id: channel.counterparty.node_id,
+		short_channel_id: channel.get_inbound_payment_scid()?,
+		fees: RoutingFees {
+			base_msat: forwarding_info.fee_base_msat,
+			proportional_millionths: forwarding_info.fee_proportional_millionths,
+		},
+		cltv_expiry_delta: forwarding_info.cltv_expiry_delta,
+		htlc_minimum_msat: channel.inbound_htlc_minimum_msat,
+		htlc_maximum_msat: channel.inbound_htlc_maximum_msat,
+	}]))
+}
+
+/// The route hints to include in an invoice. Without explicit channels we follow what LDK's
+/// invoice utilities do in spirit: a node with public channels can be found through the graph, so
+/// hints are only added for a node whose channels are all private.
+fn route_hints(
+	channel_manager: &ChannelManager, private_hints: &[String],
+) -> Result<Vec<RouteHint>, String> {
+	let channels = channel_manager.list_usable_channels();
+	if private_hints.is_empty() {
+		if channels.iter().any(|chan| chan.is_announced) {
+			return Ok(Vec::new());
+		}
+		return Ok(channels.iter().filter_map(route_hint).collect());
+	}
+	private_hints
+		.iter()
+		.map(|hint| {
+			let channel = routing::find_first_hop(&channels, hint)
+				.ok_or(format!("{} is not one of our usable channels", hint))?;
+			route_hint(channel)
+				.ok_or(format!("we don't know the forwarding policy of channel {} yet", hint))
+		})
+		.collect()
+}
+
+/// Registers an inbound payment with the `ChannelManager` and builds a signed invoice for it.
+pub(crate) fn create_invoice(
+	channel_manager: &ChannelManager, keys_manager: &KeysManager, network: Network,
+	options: &InvoiceOptions,
+) -> Result<Bolt11Invoice, String> {
+	let (payment_hash, payment_secret) = match options.preimage {
+		Some(preimage) => {
+			let payment_hash = PaymentHash(Sha256::hash(&preimage.0).to_byte_array());
+			let payment_secret = channel_manager
+				.create_inbound_payment_for_hash(
+					payment_hash,
+					options.amt_msat,
+					options.expiry_secs,
+					options.min_final_cltv_expiry_delta,
+				)
+				.map_err(|()| "failed to register the payment hash".to_string())?;
+			(payment_hash, payment_secret)
+		},
+		None => channel_manager
+			.create_inbound_payment(
+				options.amt_msat,
+				options.expiry_secs,
+				options.min_final_cltv_expiry_delta,
+			)
+			.map_err(|()| "failed to create an inbound payment".to_string())?,
+	};
+
+	let builder = InvoiceBuilder::new(currency_for_network(network));
+	let builder = match &options.description {
+		InvoiceDescription::Direct(description) => builder.description(description.clone()),
+		InvoiceDescription::Hash(hash) => builder.description_hash(*hash),
+	};
+	// As in LDK's invoice utilities, we advertise a few more blocks than we require so that
+	// blocks found while the payment is in flight don't cause it to fail.
+	let min_final_cltv_expiry_delta = options
+		.min_final_cltv_expiry_delta
+		.unwrap_or(MIN_FINAL_CLTV_EXPIRY_DELTA)
+		.saturating_add(3);
+	let mut builder = builder
+		.payment_hash(Sha256::from_byte_array(payment_hash.0))
+		.payment_secret(payment_secret)
+		.basic_mpp()
+		.current_timestamp()
+		.min_final_cltv_expiry_delta(min_final_cltv_expiry_delta as u64)
+		.expiry_time(Duration::from_secs(options.expiry_secs as u64));
+	if let Some(amt_msat) = options.amt_msat {
+		builder = builder.amount_milli_satoshis(amt_msat);
+	}
+	if let Some(address) = &options.fallback_address {
+		builder = builder.fallback(fallback_for_address(address, network)?);
+	}
+	for hint in route_hints(channel_manager, &options.private_hints)? {
+		builder = builder.private_route(hint);
+	}
+
+	let raw_invoice = builder.build_raw().map_err(|e| format!("failed to build invoice: {}", e))?;
+	let signature = keys_manager.sign_invoice(&raw_invoice, Recipient::Node);
+	let signed_raw_invoice =
+		raw_invoice.sign(|_| signature).map_err(|()| "failed to sign invoice".to_string())?;
+	Bolt11Invoice::from_signed(signed_raw_invoice)
+		.map_err(|e| format!("failed to build invoice: {}", e))
+}
+
+/// Whether `payment` is still waiting on an invoice of ours that has since expired. Payments
+/// recorded without an invoice never expire.
+fn invoice_expired(payment: &PaymentInfo) -> bool {
+	if !matches!(payment.status, HTLCStatus::Pending) {
+		return false;
+	}
+	payment
+		.bolt11_invoice
+		.as_ref()
+		.and_then(|invoice| Bolt11Invoice::from_str(invoice).ok())
+		.is_some_and(|invoice| invoice.is_expired())
+}
+
+/// Marks unpaid invoices that have expired as `HTLCStatus::Expired`, returning whether there were
+/// any.
+pub(crate) fn expire_invoices(inbound_payments: &mut InboundPaymentInfoStorage) -> bool {
+	let mut expired_any = false;
+	for payment in inbound_payments.payments.values_mut().filter(|p| invoice_expired(p)) {
+		payment.status = HTLCStatus::Expired;
+		expired_any = true;
+	}
+	expired_any
+}
+
+pub(crate) async fn run_invoice_expiry(
+	inbound_payments: Arc<Mutex<InboundPaymentInfoStorage>>, fs_store: Arc<FilesystemStore>,
+) {
+	let mut interval = tokio::time::interval(INVOICE_EXPIRY_CHECK_INTERVAL);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+	loop {
+		interval.tick().await;
+		let mut inbound = inbound_payments.lock().unwrap();
+		if expire_invoices(&mut inbound) {
+			fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound.encode()).unwrap();
+		}
+	}
+}
+
+/// The first payment settled after `since_index`, if there has been one yet.
+pub(crate) fn next_settled(
+	inbound_payments: &InboundPaymentInfoStorage, since_index: u64,
+) -> Option<(&PaymentHash, &PaymentInfo)> {
+	inbound_payments
+		.payments
+		.iter()
+		.filter(|(_, payment)| payment.settle_index.is_some_and(|index| index > since_index))
+		.min_by_key(|(_, payment)| payment.settle_index)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::MillisatAmount;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use lightning::types::payment::PaymentSecret;
+	use std::collections::HashMap;
+	use std::time::{SystemTime, UNIX_EPOCH};
+
+	#[test]
+	fn test_parse_invoice_options() {
+		assert_eq!(InvoiceOptions::parse("").unwrap(), InvoiceOptions::default());
+		assert_eq!(InvoiceOptions::parse("any").unwrap(), InvoiceOptions::default());
+
+		let options = InvoiceOptions::parse("500000 600").unwrap();
+		assert_eq!(options.amt_msat, Some(500000));
+		assert_eq!(options.expiry_secs, 600);
+
+		let options = InvoiceOptions::parse("500000 --qr --expiry 600").unwrap();
+		assert!(options.qr.terminal);
+		assert_eq!(options.expiry_secs, 600);
+
+		let args = "any --description \"coffee and cake\" --expiry 60 --min-final-cltv 144 \
+			--private-hints 800000x1x0 42 --preimage "
+			.to_string()
+			+ &"11".repeat(32);
+		let options = InvoiceOptions::parse(&args).unwrap();
+		assert_eq!(options.amt_msat, None);
+		assert_eq!(options.description, InvoiceDescription::Direct("coffee and cake".to_string()));
+		assert_eq!(options.expiry_secs, 60);
+		assert_eq!(options.min_final_cltv_expiry_delta, Some(144));
+		assert_eq!(options.private_hints, vec!["800000x1x0".to_string(), "42".to_string()]);
+		assert_eq!(options.preimage, Some(PaymentPreimage([0x11; 32])));
+
+		let args = "1000 --description-hash ".to_string() + &"ab".repeat(32);
+		assert_eq!(
+			InvoiceOptions::parse(&args).unwrap().description,
+			InvoiceDescription::Hash(Sha256::from_byte_array([0xab; 32]))
+		);
+
+		assert!(InvoiceOptions::parse("lots").is_err());
+		assert!(InvoiceOptions::parse("1000 --description \"unterminated").is_err());
+		assert!(InvoiceOptions::parse("1000 --description a --description-hash 00").is_err());
+		assert!(InvoiceOptions::parse("1000 --description-hash abcd").is_err());
+		assert!(InvoiceOptions::parse("1000 --min-final-cltv 6").is_err());
+		assert!(InvoiceOptions::parse("1000 --private-hints --expiry 60").is_err());
+		assert!(InvoiceOptions::parse("1000 --preimage 00").is_err());
+		assert!(InvoiceOptions::parse("1000 --bogus 1").is_err());
+	}
+
+	#[test]
+	fn test_next_settled() {
+		let payment = |status, settle_index| PaymentInfo {
+			preimage: None,
+			secret: None,
+			status,
+			amt_msat: MillisatAmount(Some(1000)),
+			custom_tlvs: Vec::new(),
+			bolt11_invoice: None,
+			settle_index,
+			payer_note: None,
+			quantity: None,
+			lnurl_user: None,
+			lnurl_comment: None,
+		};
+		let mut inbound =
+			InboundPaymentInfoStorage { payments: HashMap::new(), last_settle_index: 3 };
+		inbound.payments.insert(PaymentHash([1; 32]), payment(HTLCStatus::Succeeded, Some(2)));
+		inbound.payments.insert(PaymentHash([2; 32]), payment(HTLCStatus::Pending, None));
+		inbound.payments.insert(PaymentHash([3; 32]), payment(HTLCStatus::Succeeded, Some(3)));
+		inbound.payments.insert(PaymentHash([4; 32]), payment(HTLCStatus::Succeeded, Some(1)));
+
+		assert_eq!(next_settled(&inbound, 0).map(|(hash, _)| *hash), Some(PaymentHash([4; 32])));
+		assert_eq!(next_settled(&inbound, 1).map(|(hash, _)| *hash), Some(PaymentHash([1; 32])));
+		assert_eq!(next_settled(&inbound, 2).map(|(hash, _)| *hash), Some(PaymentHash([3; 32])));
+		assert!(next_settled(&inbound, 3).is_none());
+		// Payments recorded without an invoice can't expire.
+		assert!(!expire_invoices(&mut inbound));
+	}
+
+	#[test]
+	fn test_expire_invoices() {
+		let secp_ctx = Secp256k1::new();
+		let key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+		let payment = |status, created_secs_ago: u64| {
+			let invoice = InvoiceBuilder::new(Currency::Regtest)
+				.description("test".to_string())
+				.payment_hash(Sha256::from_byte_array([1; 32]))
+				.payment_secret(PaymentSecret([2; 32]))
+				.duration_since_epoch(now - Duration::from_secs(created_secs_ago))
+				.min_final_cltv_expiry_delta(144)
+				.expiry_time(Duration::from_secs(3600))
+				.build_signed(|hash| secp_ctx.sign_ecdsa_recoverable(hash, &key))
+				.unwrap();
+			PaymentInfo {
+				preimage: None,
+				secret: None,
+				status,
+				amt_msat: MillisatAmount(None),
+				custom_tlvs: Vec::new(),
+				bolt11_invoice: Some(invoice.to_string()),
+				settle_index: None,
+				payer_note: None,
+				quantity: None,
+				lnurl_user: None,
+				lnurl_comment: None,
+			}
+		};
+		let mut inbound =
+			InboundPaymentInfoStorage { payments: HashMap::new(), last_settle_index: 0 };
+		inbound.payments.insert(PaymentHash([1; 32]), payment(HTLCStatus::Pending, 60));
+		inbound.payments.insert(PaymentHash([2; 32]), payment(HTLCStatus::Pending, 7200));
+		inbound.payments.insert(PaymentHash([3; 32]), payment(HTLCStatus::Succeeded, 7200));
+
+		assert!(expire_invoices(&mut inbound));
+		let status = |hash| &inbound.payments[&PaymentHash([hash; 32])].status;
+		assert!(matches!(status(1), HTLCStatus::Pending));
+		assert!(matches!(status(2), HTLCStatus::Expired));
+		assert!(matches!(status(3), HTLCStatus::Succeeded));
+		assert!(!expire_invoices(&mut inbound));
+	}
+}
@@ -0,0 +1,900 @@
+use crate::bip21;
+use crate::filesystem_store::FilesystemStore;
+use crate::hex_utils;
+use crate::http::HttpClient;
+use crate::invoice;
+use crate::logger::FilesystemLogger;
+use crate::{ChannelManager, HTLCStatus, MillisatAmount, PaymentInfo};
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::hashes::Hash;
+use bitcoin::network::Network;
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::invoice_utils;
+use lightning::sign::KeysManager;
+use lightning::types::payment::PaymentHash;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable};
+use lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use std::{fmt, io};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Where the LNURL-pay server listens when `--lnurl-listen` isn't given. It's expected to sit
+/// behind a reverse proxy terminating TLS, as LNURL requires HTTPS.
+pub(crate) const DEFAULT_LNURL_LISTEN_ADDR: &str = "127.0.0.1:8080";
+
+/// The smallest amount our Lightning Addresses accept when a user isn't given limits.
+pub(crate) const DEFAULT_MIN_SENDABLE_MSAT: u64 = 1_000;
+
+/// The largest amount our Lightning Addresses accept when a user isn't given limits.
+pub(crate) const DEFAULT_MAX_SENDABLE_MSAT: u64 = 1_000_000_000;
+
+/// The most we read of an HTTP request before giving up on it.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// How long we wait for a client to send its whole HTTP request.
+const REQUEST_READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most unpaid invoices we hold for our Lightning Addresses at once. Anyone can ask for
+/// invoices, so this bounds how much memory they can make us use.
+const MAX_UNPAID_LNURL_INVOICES: usize = 1000;
+
+/// A Lightning Address (LUD-16), `user@domain`, which resolves to an LNURL-pay endpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LightningAddress {
+	pub(crate) user: String,
+	pub(crate) domain: String,
+}
+
+impl LightningAddress {
+	/// Parses `user@domain`, returning `None` for anything that isn't shaped like an address so
+	/// callers can try other formats.
+	pub(crate) fn parse(address: &str) -> Option<Self> {
+		let mut parts = address.splitn(2, '@');
+		let user = parts.next().unwrap();
+		let domain = parts.next()?;
+		let valid_user = user
+			.chars()
+			.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "-_.+".contains(c));
+		let valid_domain = domain.contains('.') || domain.contains(':');
+		let valid_domain =
+			valid_domain && domain.chars().all(|c| c.is_ascii_alphanumeric() || "-.:".contains(c));
+		if user.is_empty() || !valid_user || !valid_domain {
+			return None;
+		}
+		Some(LightningAddress { user: user.to_string(), domain: domain.to_ascii_lowercase() })
+	}
+
+	/// The URL of the address's LNURL-pay endpoint. Onion services are reached over plain HTTP,
+	/// as Tor already authenticates and encrypts the connection.
+	pub(crate) fn lnurlp_url(&self) -> String {
+		let scheme = if self.domain.ends_with(".onion") { "http" } else { "https" };
+		format!("{}://{}/.well-known/lnurlp/{}", scheme, self.domain, self.user)
+	}
+}
+
+impl fmt::Display for LightningAddress {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}@{}", self.user, self.domain)
+	}
+}
+
+/// The parameters of an LNURL-pay endpoint (LUD-06), telling us what we may pay and where to
+/// fetch the invoice from.
+#[derive(Debug, PartialEq)]
+pub(crate) struct PayRequest {
+	pub(crate) callback: String,
+	pub(crate) min_sendable_msat: u64,
+	pub(crate) max_sendable_msat: u64,
+	/// The JSON-encoded metadata, which the invoice's description hash must commit to.
+	pub(crate) metadata: String,
+	/// The longest comment the payee accepts (LUD-12), zero if comments aren't accepted.
+	pub(crate) comment_allowed: usize,
+}
+
+/// Parses an LNURL response, turning `{"status": "ERROR", "reason": ...}` into an error.
+fn parse_response(json: &[u8]) -> Result<serde_json::Value, String> {
+	let value: serde_json::Value = serde_json::from_slice(json)
+		.map_err(|e| format!("couldn't parse LNURL response: {}", e))?;
+	if value["status"].as_str().is_some_and(|status| status.eq_ignore_ascii_case("ERROR")) {
+		let reason = value["reason"].as_str().unwrap_or("no reason given");
+		return Err(format!("LNURL service returned an error: {}", reason));
+	}
+	Ok(value)
+}
+
+impl PayRequest {
+	pub(crate) fn to_json(&self) -> String {
+		serde_json::json!({
+			"tag": "payRequest",
+			"callback": self.callback,
+			"minSendable": self.min_sendable_msat,
+			"maxSendable": self.max_sendable_msat,
+			"metadata": self.metadata,
+			"commentAllowed": self.comment_allowed,
+		})
+		.to_string()
+	}
+
+	pub(crate) fn from_json(json: &[u8]) -> Result<Self, String> {
+		let value = parse_response(json)?;
+		if value["tag"].as_str() != Some("payRequest") {
+			return Err("LNURL response is not a pay request".to_string());
+		}
+		let field = |name: &str| format!("LNURL pay request is missing {}", name);
+		let pay_request = PayRequest {
+			callback: value["callback"].as_str().ok_or(field("callback"))?.to_string(),
+			min_sendable_msat: value["minSendable"].as_u64().ok_or(field("minSendable"))?,
+			max_sendable_msat: value["maxSendable"].as_u64().ok_or(field("maxSendable"))?,
+			metadata: value["metadata"].as_str().ok_or(field("metadata"))?.to_string(),
+			comment_allowed: value["commentAllowed"].as_u64().unwrap_or(0) as usize,
+		};
+		if pay_request.min_sendable_msat > pay_request.max_sendable_msat {
+			return Err(format!(
+				"LNURL pay request has minSendable {} above maxSendable {}",
+				pay_request.min_sendable_msat, pay_request.max_sendable_msat
+			));
+		}
+		Ok(pay_request)
+	}
+
+	pub(crate) fn check_amount(&self, amt_msat: u64) -> Result<(), String> {
+		if amt_msat < self.min_sendable_msat || amt_msat > self.max_sendable_msat {
+			return Err(format!(
+				"amount {} msat is outside of the {} to {} msat the payee accepts",
+				amt_msat, self.min_sendable_msat, self.max_sendable_msat
+			));
+		}
+		Ok(())
+	}
+
+	pub(crate) fn check_comment(&self, comment: &str) -> Result<(), String> {
+		match self.comment_allowed {
+			0 => Err("the payee doesn't accept comments".to_string()),
+			max if comment.chars().count() > max => {
+				Err(format!("the payee accepts comments of at most {} characters", max))
+			},
+			_ => Ok(()),
+		}
+	}
+
+	/// The URL to fetch an invoice for `amt_msat` from.
+	pub(crate) fn callback_url(&self, amt_msat: u64, comment: Option<&str>) -> String {
+		let separator = if self.callback.contains('?') { '&' } else { '?' };
+		let mut url = format!("{}{}amount={}", self.callback, separator, amt_msat);
+		if let Some(comment) = comment {
+			url.push_str(&format!("&comment={}", bip21::percent_encode(comment)));
+		}
+		url
+	}
+
+	/// Checks the invoice the callback returned is for what we asked for. Without the description
+	/// hash check, the service could hand us an invoice for anything at all.
+	pub(crate) fn check_invoice(
+		&self, invoice: &Bolt11Invoice, amt_msat: u64,
+	) -> Result<(), String> {
+		let metadata_hash = Sha256::hash(self.metadata.as_bytes());
+		match invoice.description() {
+			Bolt11InvoiceDescription::Hash(hash) if hash.0 == metadata_hash => {},
+			_ => {
+				return Err("invoice description hash doesn't match the LNURL metadata".to_string())
+			},
+		}
+		if invoice.amount_milli_satoshis() != Some(amt_msat) {
+			return Err(format!(
+				"invoice is for {} msat rather than the {} msat requested",
+				invoice.amount_milli_satoshis().unwrap_or(0),
+				amt_msat
+			));
+		}
+		Ok(())
+	}
+}
+
+pub(crate) async fn fetch_pay_request(
+	client: &dyn HttpClient, address: &LightningAddress,
+) -> Result<PayRequest, String> {
+	PayRequest::from_json(&client.get(&address.lnurlp_url()).await?)
+}
+
+/// Fetches and checks an invoice for `amt_msat` from a pay request's callback.
+pub(crate) async fn fetch_invoice(
+	client: &dyn HttpClient, pay_request: &PayRequest, amt_msat: u64, comment: Option<&str>,
+) -> Result<Bolt11Invoice, String> {
+	pay_request.check_amount(amt_msat)?;
+	if let Some(comment) = comment {
+		pay_request.check_comment(comment)?;
+	}
+	let response =
+		parse_response(&client.get(&pay_request.callback_url(amt_msat, comment)).await?)?;
+	let invoice = response["pr"].as_str().ok_or("LNURL callback returned no invoice")?;
+	let invoice = Bolt11Invoice::from_str(invoice)
+		.map_err(|e| format!("LNURL callback returned an invalid invoice: {}", e))?;
+	pay_request.check_invoice(&invoice, amt_msat)?;
+	Ok(invoice)
+}
+
+/// Resolves a Lightning Address to an invoice for `amt_msat`, ready to be paid.
+pub(crate) async fn resolve_invoice(
+	client: &dyn HttpClient, address: &LightningAddress, amt_msat: u64, comment: Option<&str>,
+) -> Result<Bolt11Invoice, String> {
+	let pay_request = fetch_pay_request(client, address).await?;
+	fetch_invoice(client, &pay_request, amt_msat, comment).await
+}
+
+/// Someone we receive payments for at `name@domain`, and the limits on what they're paid.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LnurlUser {
+	pub(crate) name: String,
+	pub(crate) min_sendable_msat: u64,
+	pub(crate) max_sendable_msat: u64,
+	/// The longest comment we accept from payers, zero to accept none.
+	pub(crate) comment_allowed: usize,
+}
+
+impl LnurlUser {
+	/// Parses a `--lnurl-users` entry, `name[:min_msat:max_msat[:comment_len]]`.
+	pub(crate) fn parse(user: &str) -> Result<Self, String> {
+		let parts: Vec<&str> = user.split(':').collect();
+		let name = parts[0];
+		if LightningAddress::parse(&format!("{}@example.com", name)).is_none() {
+			return Err(format!("{} is not a valid Lightning Address username", name));
+		}
+		let number = |value: &str| {
+			value.parse::<u64>().map_err(|e| format!("couldn't parse {} in {}: {}", value, user, e))
+		};
+		let (min_sendable_msat, max_sendable_msat, comment_allowed) = match parts[1..] {
+			[] => (DEFAULT_MIN_SENDABLE_MSAT, DEFAULT_MAX_SENDABLE_MSAT, 0),
+			[min, max] => (number(min)?, number(max)?, 0),
+			[min, max, comment] => (number(min)?, number(max)?, number(comment)? as usize),
+			_ => {
+				return Err(format!(
+					"{} must be given as name[:min_msat:max_msat[:comment_len]]",
+					user
+				))
+			},
+		};
+		if min_sendable_msat == 0 || min_sendable_msat > max_sendable_msat {
+			return Err(format!(
+				"{} must have a non-zero minimum no larger than its maximum",
+				user
+			));
+		}
+		Ok(LnurlUser {
+			name: name.to_string(),
+			min_sendable_msat,
+			max_sendable_msat,
+			comment_allowed,
+		})
+	}
+}
+
+/// Settings for the embedded LNURL-pay server, which lets us be paid at Lightning Addresses.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LnurlServerConfig {
+	pub(crate) listen_addr: String,
+	/// The public HTTPS URL the server is reached at, e.g. `https://example.com`. Its host is the
+	/// domain of our Lightning Addresses.
+	pub(crate) base_url: String,
+	pub(crate) users: Vec<LnurlUser>,
+}
+
+impl Default for LnurlServerConfig {
+	fn default() -> Self {
+		LnurlServerConfig {
+			listen_addr: DEFAULT_LNURL_LISTEN_ADDR.to_string(),
+			base_url: String::new(),
+			users: Vec::new(),
+		}
+	}
+}
+
+impl LnurlServerConfig {
+	pub(crate) fn domain(&self) -> &str {
+		let without_scheme = self.base_url.splitn(2, "://").last().unwrap();
+		without_scheme.split('/').next().unwrap()
+	}
+
+	fn user(&self, name: &str) -> Option<&LnurlUser> {
+		self.users.iter().find(|user| user.name == name)
+	}
+
+	/// The pay request we serve for `user`. Its metadata is what the description hash of the
+	/// invoices we mint for them commits to.
+	fn pay_request(&self, user: &LnurlUser) -> PayRequest {
+		let address = format!("{}@{}", user.name, self.domain());
+		let metadata = serde_json::json!([
+			["text/plain", format!("Payment to {}", address)],
+			["text/identifier", address],
+		]);
+		PayRequest {
+			callback: format!(
+				"{}/lnurlp/{}/callback",
+				self.base_url.trim_end_matches('/'),
+				user.name
+			),
+			min_sendable_msat: user.min_sendable_msat,
+			max_sendable_msat: user.max_sendable_msat,
+			metadata: metadata.to_string(),
+			comment_allowed: user.comment_allowed,
+		}
+	}
+}
+
+fn error_json(reason: &str) -> String {
+	serde_json::json!({ "status": "ERROR", "reason": reason }).to_string()
+}
+
+/// Answers a GET of `target`, returning the HTTP status and JSON body. Invoices are minted with
+/// `mint_invoice`, given the user, amount, description hash and payer's comment.
+pub(crate) fn handle_request<F>(
+	config: &LnurlServerConfig, target: &str, mint_invoice: F,
+) -> (u16, String)
+where
+	F: FnOnce(&LnurlUser, u64, Sha256, Option<String>) -> Result<Bolt11Invoice, String>,
+{
+	let mut target = target.splitn(2, '?');
+	let path: Vec<&str> = target.next().unwrap().trim_matches('/').split('/').collect();
+	let query = target.next().unwrap_or("");
+	let (name, is_callback) = match path[..] {
+		[".well-known", "lnurlp", name] => (name, false),
+		["lnurlp", name, "callback"] => (name, true),
+		_ => return (404, error_json("not found")),
+	};
+	let user = match config.user(name) {
+		Some(user) => user,
+		None => return (404, error_json(&format!("no such user {}", name))),
+	};
+	let pay_request = config.pay_request(user);
+	if !is_callback {
+		return (200, pay_request.to_json());
+	}
+
+	// LNURL errors are reported in the body, which is what wallets show to the payer.
+	let mut amount = None;
+	let mut comment = None;
+	for param in query.split('&').filter(|param| !param.is_empty()) {
+		let mut kv = param.splitn(2, '=');
+		let key = kv.next().unwrap();
+		let value = match bip21::percent_decode(kv.next().unwrap_or("")) {
+			Ok(value) => value,
+			Err(e) => return (200, error_json(&e)),
+		};
+		match key {
+			"amount" => amount = Some(value),
+			"comment" => comment = Some(value),
+			_ => {},
+		}
+	}
+	let amt_msat = match amount.map(|amt| amt.parse::<u64>()) {
+		Some(Ok(amt)) => amt,
+		Some(Err(e)) => return (200, error_json(&format!("couldn't parse amount: {}", e))),
+		None => return (200, error_json("an amount is required")),
+	};
+	if let Err(e) = pay_request.check_amount(amt_msat) {
+		return (200, error_json(&e));
+	}
+	if let Some(comment) = &comment {
+		if let Err(e) = pay_request.check_comment(comment) {
+			return (200, error_json(&e));
+		}
+	}
+	let description_hash = Sha256::hash(pay_request.metadata.as_bytes());
+	match mint_invoice(user, amt_msat, description_hash, comment) {
+		Ok(invoice) => {
+			(200, serde_json::json!({ "pr": invoice.to_string(), "routes": [] }).to_string())
+		},
+		Err(e) => (500, error_json(&e)),
+	}
+}
+
+/// Reads a single GET request from `stream` and writes back the response `handler` gives for
+/// its target.
+async fn serve_connection<F>(mut stream: TcpStream, handler: F)
+where
+	F: FnOnce(&str) -> (u16, String),
+{
+	let read_request = async {
+		let mut request = Vec::new();
+		let mut buf = [0; 1024];
+		while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+			match stream.read(&mut buf).await {
+				Ok(0) | Err(_) => return None,
+				Ok(len) => request.extend_from_slice(&buf[..len]),
+			}
+			if request.len() > MAX_REQUEST_LEN {
+				return None;
+			}
+		}
+		Some(request)
+	};
+	// Don't let a client that never finishes its request hold the connection open.
+	let request = match tokio::time::timeout(REQUEST_READ_TIMEOUT, read_request).await {
+		Ok(Some(request)) => request,
+		Ok(None) | Err(_) => return,
+	};
+	let request = String::from_utf8_lossy(&request).to_string();
+	let mut request_line = request.lines().next().unwrap_or("").split(' ');
+	let (status, body) = match (request_line.next(), request_line.next()) {
+		(Some("GET"), Some(target)) => handler(target),
+		_ => (405, error_json("only GET requests are supported")),
+	};
+	let reason = match status {
+		200 => "OK",
+		404 => "Not Found",
+		405 => "Method Not Allowed",
+		_ => "Internal Server Error",
+	};
+	// Web wallets fetch LNURL endpoints from the browser, so cross-origin requests are allowed.
+	let response = format!(
+		"HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nAccess-Control-Allow-Origin: *\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		status,
+		reason,
+		body.len(),
+		body
+	);
+	let _ = stream.write_all(response.as_bytes()).await;
+}
+
+/// Serves LNURL-pay requests arriving on `listener`, one task per connection.
+async fn serve<F>(listener: TcpListener, config: Arc<LnurlServerConfig>, mint_invoice: Arc<F>)
+where
+	F: Fn(&LnurlUser, u64, Sha256, Option<String>) -> Result<Bolt11Invoice, String>
+		+ Send
+		+ Sync
+		+ 'static,
+{
+	loop {
+		let stream = match listener.accept().await {
+			Ok((stream, _)) => stream,
+			Err(_) => continue,
+		};
+		let config = Arc::clone(&config);
+		let mint_invoice = Arc::clone(&mint_invoice);
+		tokio::spawn(async move {
+			serve_connection(stream, |target| {
+				handle_request(&config, target, |user, amt_msat, description_hash, comment| {
+					mint_invoice(user, amt_msat, description_hash, comment)
+				})
+			})
+			.await;
+		});
+	}
+}
+
+/// Each unpaid invoice is stored under its payment hash in this namespace, so issuing one only
+/// writes a small file.
+pub(crate) const UNPAID_LNURL_INVOICES_NAMESPACE: &str = "unpaid_lnurl_invoices";
+
+struct UnpaidLnurlInvoice {
+	/// When the invoice expires, in seconds since the UNIX epoch.
+	expires_at: u64,
+	payment: PaymentInfo,
+}
+
+impl_writeable_tlv_based!(UnpaidLnurlInvoice, {
+	(0, expires_at, required),
+	(2, payment, required),
+});
+
+/// Invoices we've issued for our Lightning Addresses that haven't been paid yet. As anyone can
+/// request these, they're kept apart from our inbound payments, bounded in number and dropped
+/// once expired, and only moved to our inbound payments once paid.
+pub(crate) struct UnpaidLnurlInvoices {
+	invoices: Mutex<HashMap<PaymentHash, UnpaidLnurlInvoice>>,
+	fs_store: Arc<FilesystemStore>,
+}
+
+pub(crate) fn read_unpaid_lnurl_invoices(fs_store: Arc<FilesystemStore>) -> UnpaidLnurlInvoices {
+	let mut invoices = HashMap::new();
+	for key in fs_store.list(UNPAID_LNURL_INVOICES_NAMESPACE, "").unwrap_or_default() {
+		let payment_hash = match hex_utils::to_vec(&key).and_then(|hash| hash.try_into().ok()) {
+			Some(hash) => PaymentHash(hash),
+			None => continue,
+		};
+		let invoice = fs_store
+			.read(UNPAID_LNURL_INVOICES_NAMESPACE, "", &key)
+			.ok()
+			.and_then(|bytes| Readable::read(&mut io::Cursor::new(bytes)).ok());
+		if let Some(invoice) = invoice {
+			invoices.insert(payment_hash, invoice);
+		}
+	}
+	let unpaid_invoices = UnpaidLnurlInvoices { invoices: Mutex::new(invoices), fs_store };
+	unpaid_invoices.prune_expired(&mut unpaid_invoices.invoices.lock().unwrap());
+	unpaid_invoices
+}
+
+impl UnpaidLnurlInvoices {
+	fn forget(
+		&self, invoices: &mut HashMap<PaymentHash, UnpaidLnurlInvoice>, hash: &PaymentHash,
+	) -> Option<UnpaidLnurlInvoice> {
+		let invoice = invoices.remove(hash)?;
+		let key = hex_utils::hex_str(&hash.0);
+		let _ = self.fs_store.remove(UNPAID_LNURL_INVOICES_NAMESPACE, "", &key, false);
+		Some(invoice)
+	}
+
+	fn prune_expired(&self, invoices: &mut HashMap<PaymentHash, UnpaidLnurlInvoice>) {
+		let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+		let expired = invoices
+			.iter()
+			.filter(|(_, invoice)| invoice.expires_at <= now)
+			.map(|(hash, _)| *hash)
+			.collect::<Vec<_>>();
+		for hash in expired {
+			self.forget(invoices, &hash);
+		}
+	}
+
+	/// Starts tracking `invoice`, first dropping any that have expired. Fails if we're already
+	/// holding as many as we allow.
+	fn insert(&self, invoice: Bolt11Invoice, payment: PaymentInfo) -> Result<(), String> {
+		let mut invoices = self.invoices.lock().unwrap();
+		self.prune_expired(&mut invoices);
+		if invoices.len() >= MAX_UNPAID_LNURL_INVOICES {
+			return Err("too many unpaid invoices, try again later".to_string());
+		}
+		let payment_hash = PaymentHash(invoice.payment_hash().to_byte_array());
+		let expires_at = invoice.expires_at().map_or(u64::MAX, |expiry| expiry.as_secs());
+		let unpaid = UnpaidLnurlInvoice { expires_at, payment };
+		let key = hex_utils::hex_str(&payment_hash.0);
+		self.fs_store
+			.write(UNPAID_LNURL_INVOICES_NAMESPACE, "", &key, &unpaid.encode())
+			.map_err(|e| format!("failed to persist invoice: {}", e))?;
+		invoices.insert(payment_hash, unpaid);
+		Ok(())
+	}
+
+	/// Stops tracking the invoice for `payment_hash`, returning its payment to be recorded with
+	/// our inbound payments.
+	pub(crate) fn take(&self, payment_hash: &PaymentHash) -> Option<PaymentInfo> {
+		let mut invoices = self.invoices.lock().unwrap();
+		self.forget(&mut invoices, payment_hash).map(|invoice| invoice.payment)
+	}
+}
+
+/// Creates invoices for payments to our Lightning Addresses.
+struct InvoiceMinter {
+	channel_manager: Arc<ChannelManager>,
+	keys_manager: Arc<KeysManager>,
+	logger: Arc<FilesystemLogger>,
+	unpaid_invoices: Arc<UnpaidLnurlInvoices>,
+	network: Network,
+}
+
+impl InvoiceMinter {
+	/// Creates an invoice for a payment to one of our Lightning Addresses and holds it until it's
+	/// paid, tagged with the username it was issued for.
+	fn mint(
+		&self, user: &LnurlUser, amt_msat: u64, description_hash: Sha256, comment: Option<String>,
+	) -> Result<Bolt11Invoice, String> {
+		let invoice = invoice_utils::create_invoice_from_channelmanager_with_description_hash(
+			&*self.channel_manager,
+			Arc::clone(&self.keys_manager),
+			Arc::clone(&self.logger),
+			invoice::currency_for_network(self.network),
+			Some(amt_msat),
+			lightning_invoice::Sha256(description_hash),
+			invoice::DEFAULT_INVOICE_EXPIRY_SECS,
+			None,
+		)
+		.map_err(|e| format!("failed to create invoice: {:?}", e))?;
+
+		let payment = PaymentInfo {
+			preimage: None,
+			secret: Some(*invoice.payment_secret()),
+			status: HTLCStatus::Pending,
+			amt_msat: MillisatAmount(Some(amt_msat)),
+			custom_tlvs: Vec::new(),
+			bolt11_invoice: Some(invoice.to_string()),
+			settle_index: None,
+			payer_note: None,
+			quantity: None,
+			lnurl_user: Some(user.name.clone()),
+			lnurl_comment: comment,
+		};
+		self.unpaid_invoices.insert(invoice.clone(), payment)?;
+		Ok(invoice)
+	}
+}
+
+/// Runs the embedded LNURL-pay server, so we can be paid at `<user>@<domain>` for each of the
+/// configured users.
+pub(crate) async fn run_server(
+	config: LnurlServerConfig, channel_manager: Arc<ChannelManager>,
+	keys_manager: Arc<KeysManager>, logger: Arc<FilesystemLogger>,
+	unpaid_invoices: Arc<UnpaidLnurlInvoices>, network: Network,
+) {
+	let listener = match TcpListener::bind(&config.listen_addr).await {
+		Ok(listener) => listener,
+		Err(e) => {
+			println!("ERROR: failed to listen for LNURL requests on {}: {}", config.listen_addr, e);
+			return;
+		},
+	};
+	let minter = InvoiceMinter { channel_manager, keys_manager, logger, unpaid_invoices, network };
+	let mint = move |user: &LnurlUser, amt_msat, description_hash, comment| {
+		minter.mint(user, amt_msat, description_hash, comment)
+	};
+	serve(listener, Arc::new(config), Arc::new(mint)).await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::http::{self, HttpFuture};
+	use crate::test_utils::stand_in_http_server;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use lightning::types::payment::PaymentSecret;
+	use lightning_invoice::{Currency, InvoiceBuilder};
+
+	const METADATA: &str = r#"[["text/plain","Pay alice"],["text/identifier","alice@127.0.0.1"]]"#;
+
+	/// Talks plain HTTP to the local stand-in server in place of HTTPS.
+	struct LocalClient;
+
+	impl HttpClient for LocalClient {
+		fn get<'a>(&'a self, url: &'a str) -> HttpFuture<'a> {
+			Box::pin(async move { http::get(&url.replacen("https://", "http://", 1)).await })
+		}
+	}
+
+	fn invoice(amt_msat: u64, description_hash: Sha256) -> Bolt11Invoice {
+		let secp_ctx = Secp256k1::new();
+		let key = SecretKey::from_slice(&[42; 32]).unwrap();
+		InvoiceBuilder::new(Currency::Regtest)
+			.description_hash(description_hash)
+			.payment_hash(Sha256::from_byte_array([1; 32]))
+			.payment_secret(PaymentSecret([2; 32]))
+			.current_timestamp()
+			.min_final_cltv_expiry_delta(144)
+			.amount_milli_satoshis(amt_msat)
+			.build_signed(|hash| secp_ctx.sign_ecdsa_recoverable(hash, &key))
+			.unwrap()
+	}
+
+	fn pay_request_json(host: &str) -> String {
+		serde_json::json!({
+			"tag": "payRequest",
+			"callback": format!("https://{}/lnurlp/alice/callback", host),
+			"minSendable": 1_000,
+			"maxSendable": 1_000_000,
+			"metadata": METADATA,
+			"commentAllowed": 20,
+		})
+		.to_string()
+	}
+
+	#[test]
+	fn test_parse_lightning_address() {
+		let address = LightningAddress::parse("alice@Example.com").unwrap();
+		assert_eq!(address.to_string(), "alice@example.com");
+		assert_eq!(address.lnurlp_url(), "https://example.com/.well-known/lnurlp/alice");
+		let onion = LightningAddress::parse("bob@abcdef.onion").unwrap();
+		assert_eq!(onion.lnurlp_url(), "http://abcdef.onion/.well-known/lnurlp/bob");
+
+		assert!(LightningAddress::parse("lnbcrt1pabc").is_none());
+		assert!(LightningAddress::parse("@example.com").is_none());
+		assert!(LightningAddress::parse("alice@localhost").is_none());
+		assert!(LightningAddress::parse("alice@example.com/path").is_none());
+		assert!(LightningAddress::parse("Alice@example.com").is_none());
+	}
+
+	#[test]
+	fn test_pay_request() {
+		let pay_request =
+			PayRequest::from_json(pay_request_json("example.com").as_bytes()).unwrap();
+		assert_eq!(pay_request.metadata, METADATA);
+		assert!(pay_request.check_amount(1_000).is_ok());
+		assert!(pay_request.check_amount(999).is_err());
+		assert!(pay_request.check_amount(1_000_001).is_err());
+		assert!(pay_request.check_comment("thanks").is_ok());
+		assert!(pay_request.check_comment("much too long a comment to accept").is_err());
+		assert_eq!(
+			pay_request.callback_url(5_000, Some("for lunch")),
+			"https://example.com/lnurlp/alice/callback?amount=5000&comment=for%20lunch"
+		);
+
+		let error = br#"{"status": "ERROR", "reason": "no such user"}"#;
+		assert_eq!(
+			PayRequest::from_json(error),
+			Err("LNURL service returned an error: no such user".to_string())
+		);
+		assert!(PayRequest::from_json(br#"{"tag": "withdrawRequest"}"#).is_err());
+
+		let invoice_for_metadata = invoice(5_000, Sha256::hash(METADATA.as_bytes()));
+		assert!(pay_request.check_invoice(&invoice_for_metadata, 5_000).is_ok());
+		assert!(pay_request.check_invoice(&invoice_for_metadata, 6_000).is_err());
+		let other_invoice = invoice(5_000, Sha256::hash(b"something else"));
+		assert!(pay_request.check_invoice(&other_invoice, 5_000).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_resolve_invoice() {
+		let (host, requests) = stand_in_http_server(|host, path| {
+			if path.starts_with("/.well-known/lnurlp/alice") {
+				pay_request_json(host)
+			} else if path.starts_with("/lnurlp/alice/callback") {
+				let amount = path.split("amount=").nth(1).unwrap().split('&').next().unwrap();
+				let invoice = invoice(amount.parse().unwrap(), Sha256::hash(METADATA.as_bytes()));
+				serde_json::json!({ "pr": invoice.to_string(), "routes": [] }).to_string()
+			} else {
+				r#"{"status": "ERROR", "reason": "not found"}"#.to_string()
+			}
+		})
+		.await;
+		let address = LightningAddress::parse(&format!("alice@{}", host)).unwrap();
+
+		let invoice = resolve_invoice(&LocalClient, &address, 5_000, Some("for lunch")).await;
+		assert_eq!(invoice.unwrap().amount_milli_satoshis(), Some(5_000));
+		assert_eq!(
+			*requests.lock().unwrap(),
+			vec![
+				"/.well-known/lnurlp/alice".to_string(),
+				"/lnurlp/alice/callback?amount=5000&comment=for%20lunch".to_string(),
+			]
+		);
+
+		// Out-of-range amounts are refused before the callback is used.
+		assert!(resolve_invoice(&LocalClient, &address, 10, None).await.is_err());
+		assert_eq!(requests.lock().unwrap().len(), 3);
+
+		let unknown = LightningAddress::parse(&format!("bob@{}", host)).unwrap();
+		let error = resolve_invoice(&LocalClient, &unknown, 5_000, None).await.unwrap_err();
+		assert_eq!(error, "LNURL service returned an error: not found");
+	}
+
+	fn server_config(base_url: &str) -> LnurlServerConfig {
+		LnurlServerConfig {
+			listen_addr: DEFAULT_LNURL_LISTEN_ADDR.to_string(),
+			base_url: base_url.to_string(),
+			users: vec![LnurlUser::parse("alice:1000:50000:10").unwrap()],
+		}
+	}
+
+	fn mint_test_invoice(
+		user: &LnurlUser, amt_msat: u64, description_hash: Sha256, comment: Option<String>,
+	) -> Result<Bolt11Invoice, String> {
+		Ok(invoice(amt_msat, description_hash))
+	}
+
+	#[test]
+	fn test_parse_lnurl_user() {
+		let user = LnurlUser::parse("alice").unwrap();
+		assert_eq!(user.min_sendable_msat, DEFAULT_MIN_SENDABLE_MSAT);
+		assert_eq!(user.max_sendable_msat, DEFAULT_MAX_SENDABLE_MSAT);
+		assert_eq!(user.comment_allowed, 0);
+		let user = LnurlUser::parse("bob:1000:50000:140").unwrap();
+		assert_eq!((user.min_sendable_msat, user.max_sendable_msat), (1000, 50000));
+		assert_eq!(user.comment_allowed, 140);
+
+		assert!(LnurlUser::parse("Alice").is_err());
+		assert!(LnurlUser::parse("alice:1000").is_err());
+		assert!(LnurlUser::parse("alice:5000:1000").is_err());
+		assert!(LnurlUser::parse("alice:0:1000").is_err());
+		assert!(LnurlUser::parse("alice:1000:lots").is_err());
+	}
+
+	#[test]
+	fn test_handle_request() {
+		let config = server_config("https://example.com/");
+		assert_eq!(config.domain(), "example.com");
+
+		let (status, body) =
+			handle_request(&config, "/.well-known/lnurlp/alice", mint_test_invoice);
+		assert_eq!(status, 200);
+		let pay_request = PayRequest::from_json(body.as_bytes()).unwrap();
+		assert_eq!(pay_request.callback, "https://example.com/lnurlp/alice/callback");
+		assert_eq!((pay_request.min_sendable_msat, pay_request.max_sendable_msat), (1000, 50000));
+		assert!(pay_request.metadata.contains("alice@example.com"));
+
+		let minted = Mutex::new(None);
+		let target = "/lnurlp/alice/callback?amount=2000&comment=hi%20there";
+		let (status, body) = handle_request(&config, target, |user, amt_msat, hash, comment| {
+			*minted.lock().unwrap() = Some((user.name.clone(), amt_msat, comment));
+			mint_test_invoice(user, amt_msat, hash, None)
+		});
+		assert_eq!(status, 200);
+		assert_eq!(
+			minted.into_inner().unwrap(),
+			Some(("alice".to_string(), 2000, Some("hi there".to_string())))
+		);
+		let pr = serde_json::from_str::<serde_json::Value>(&body).unwrap()["pr"].clone();
+		let invoice = Bolt11Invoice::from_str(pr.as_str().unwrap()).unwrap();
+		assert!(pay_request.check_invoice(&invoice, 2000).is_ok());
+
+		let rejected = |target: &str| {
+			let (_, body) = handle_request(&config, target, |_, _, _, _| -> Result<_, String> {
+				panic!("no invoice should be minted")
+			});
+			PayRequest::from_json(body.as_bytes()).unwrap_err()
+		};
+		assert!(rejected("/lnurlp/alice/callback?amount=999").contains("outside"));
+		assert!(rejected("/lnurlp/alice/callback").contains("amount is required"));
+		assert!(rejected("/lnurlp/alice/callback?amount=2000&comment=far%20too%20long")
+			.contains("at most"));
+		assert!(rejected("/lnurlp/bob/callback?amount=2000").contains("no such user"));
+		assert_eq!(handle_request(&config, "/favicon.ico", mint_test_invoice).0, 404);
+	}
+
+	#[test]
+	fn test_unpaid_invoices() {
+		let secp_ctx = Secp256k1::new();
+		let key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let unpaid_invoice = |id: usize, expiry_secs| {
+			let invoice = InvoiceBuilder::new(Currency::Regtest)
+				.description("tip".to_string())
+				.payment_hash(Sha256::hash(&id.to_be_bytes()))
+				.payment_secret(PaymentSecret([2; 32]))
+				.current_timestamp()
+				.expiry_time(std::time::Duration::from_secs(expiry_secs))
+				.min_final_cltv_expiry_delta(144)
+				.build_signed(|hash| secp_ctx.sign_ecdsa_recoverable(hash, &key))
+				.unwrap();
+			let payment = PaymentInfo {
+				preimage: None,
+				secret: Some(*invoice.payment_secret()),
+				status: HTLCStatus::Pending,
+				amt_msat: MillisatAmount(None),
+				custom_tlvs: Vec::new(),
+				bolt11_invoice: None,
+				settle_index: None,
+				payer_note: None,
+				quantity: None,
+				lnurl_user: Some("alice".to_string()),
+				lnurl_comment: Some(format!("tip {}", id)),
+			};
+			(invoice, payment)
+		};
+		let hash = |id: usize| PaymentHash(Sha256::hash(&id.to_be_bytes()).to_byte_array());
+
+		let data_dir = std::env::temp_dir().join("ldk_unpaid_lnurl_invoices_test");
+		let _ = std::fs::remove_dir_all(&data_dir);
+		let fs_store = Arc::new(FilesystemStore::new(data_dir));
+
+		// One of the invoices has already expired, so it makes room for another.
+		let unpaid = read_unpaid_lnurl_invoices(Arc::clone(&fs_store));
+		let (invoice, payment) = unpaid_invoice(0, 0);
+		unpaid.insert(invoice, payment).unwrap();
+		for id in 1..=MAX_UNPAID_LNURL_INVOICES {
+			let (invoice, payment) = unpaid_invoice(id, 3600);
+			unpaid.insert(invoice, payment).unwrap();
+		}
+		assert!(unpaid.take(&hash(0)).is_none());
+		let (invoice, payment) = unpaid_invoice(MAX_UNPAID_LNURL_INVOICES + 1, 3600);
+		assert!(unpaid.insert(invoice, payment).is_err());
+
+		let payment = unpaid.take(&hash(1)).unwrap();
+		assert_eq!(payment.lnurl_comment.as_deref(), Some("tip 1"));
+		assert!(unpaid.take(&hash(1)).is_none());
+		let (invoice, payment) = unpaid_invoice(MAX_UNPAID_LNURL_INVOICES + 1, 3600);
+		assert!(unpaid.insert(invoice, payment).is_ok());
+
+		// Who an invoice was for and the payer's comment survive a restart.
+		let unpaid = read_unpaid_lnurl_invoices(fs_store);
+		assert!(unpaid.take(&hash(1)).is_none());
+		let payment = unpaid.take(&hash(2)).unwrap();
+		assert_eq!(payment.lnurl_user.as_deref(), Some("alice"));
+		assert_eq!(payment.lnurl_comment.as_deref(), Some("tip 2"));
+	}
+
+	#[tokio::test]
+	async fn test_server_pays_to_client() {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let host = listener.local_addr().unwrap().to_string();
+		let config = server_config(&format!("http://{}", host));
+		tokio::spawn(serve(listener, Arc::new(config), Arc::new(mint_test_invoice)));
+
+		let address = LightningAddress::parse(&format!("alice@{}", host)).unwrap();
+		let invoice = resolve_invoice(&LocalClient, &address, 20_000, Some("thanks")).await;
+		assert_eq!(invoice.unwrap().amount_milli_satoshis(), Some(20_000));
+		let error = resolve_invoice(&LocalClient, &address, 60_000, None).await.unwrap_err();
+		assert!(error.contains("outside"));
+	}
+}
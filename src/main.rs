@@ -1,26 +1,55 @@
 #![allow(dead_code, unused_imports, unused_variables, unused_must_use, unexpected_cfgs)]
+// LDK's serialization macros drop the reader they wrap, which doesn't implement `Drop`.
+#![allow(clippy::drop_non_drop)]
 mod args;
+mod bip21;
+mod bip353;
 pub mod bitcoind_client;
 mod cli;
 mod commands;
 mod convert;
+mod decode;
 mod disk;
 mod events;
+mod exchange_rate;
 mod filesystem_store;
+mod graph;
 mod hex_utils;
+mod http;
 mod internal;
 mod intro;
+mod invoice;
 mod keys_manager;
+mod lnurl;
 mod logger;
+mod messaging;
 mod networking;
+mod offers;
 mod onchain_wallet;
+mod probing;
+mod qr;
+mod rebalance;
+mod rgs;
+mod routing;
 mod sweep;
 mod tests;
+#[cfg(test)]
+mod test_utils;
 
+use crate::bip21::{PaymentRail, UnifiedRequestStorage};
 use crate::bitcoind_client::BitcoindClient;
+use crate::decode::Decoded;
 use crate::filesystem_store::FilesystemStore;
+use crate::lnurl::UnpaidLnurlInvoices;
 use crate::logger::FilesystemLogger;
+use crate::messaging::ConversationLog;
+use crate::offers::{Bolt12InvoicePolicy, OfferStorage, PendingBolt12Invoices};
 use crate::onchain_wallet::OnChainWallet;
+use crate::probing::Prober;
+use crate::rebalance::RebalanceStorage;
+use crate::rgs::{RapidGossipSync, RgsSource};
+use crate::routing::AvoidListRouter;
+use crate::sweep::SweepDestination;
 use ::bdk_wallet::template::Bip84;
 use ::bdk_wallet::KeychainKind;
 use ::bdk_wallet::Wallet as BdkWallet;
@@ -37,19 +66,16 @@ use lightning::chain::{BestBlock, Filter, Watch};
 use lightning::events::bump_transaction::{BumpTransactionEventHandler, Wallet};
 use lightning::events::{Event, PaymentFailureReason, PaymentPurpose};
 use lightning::ln::channelmanager::{self, RecentPaymentDetails};
-use lightning::ln::channelmanager::{
-	ChainParameters, ChannelManagerReadArgs, PaymentId, SimpleArcChannelManager,
-};
+use lightning::ln::channelmanager::{ChainParameters, ChannelManagerReadArgs, PaymentId};
 use lightning::ln::msgs::DecodeError;
-use lightning::ln::peer_handler::{IgnoringMessageHandler, MessageHandler, SimpleArcPeerManager};
+use lightning::ln::peer_handler::{self, IgnoringMessageHandler, MessageHandler};
 use lightning::ln::types::ChannelId;
 use lightning::log_error;
 use lightning::log_info;
-use lightning::onion_message::messenger::{DefaultMessageRouter, SimpleArcOnionMessenger};
+use lightning::onion_message::messenger::{self, DefaultMessageRouter};
 use lightning::routing::gossip;
 use lightning::routing::gossip::{NodeId, P2PGossipSync};
-use lightning::routing::router::DefaultRouter;
-use lightning::routing::scoring::ProbabilisticScoringFeeParameters;
+use lightning::routing::scoring::ProbabilisticScorer;
 use lightning::sign::{EntropySource, InMemorySigner, KeysManager};
 use lightning::types::payment::{PaymentHash, PaymentPreimage, PaymentSecret};
 use lightning::util::config::UserConfig;
@@ -91,12 +117,15 @@ pub(crate) enum HTLCStatus {
 	Pending,
 	Succeeded,
 	Failed,
+	/// An inbound invoice that expired before it was paid.
+	Expired,
 }
 
 impl_writeable_tlv_based_enum!(HTLCStatus,
 	(0, Pending) => {},
 	(1, Succeeded) => {},
 	(2, Failed) => {},
+	(3, Expired) => {},
 );
 
 pub(crate) struct MillisatAmount(Option<u64>);
@@ -128,6 +157,22 @@ pub(crate) struct PaymentInfo {
 	secret: Option<PaymentSecret>,
 	status: HTLCStatus,
 	amt_msat: MillisatAmount,
+	/// Custom TLV records sent with, or accepted on, this payment.
+	custom_tlvs: Vec<(u64, Vec<u8>)>,
+	/// For inbound payments, the BOLT11 invoice we issued, which records the description, expiry
+	/// and other options it was created with.
+	bolt11_invoice: Option<String>,
+	/// For inbound payments, the order in which this payment was settled relative to the others,
+	/// starting at 1.
+	settle_index: Option<u64>,
+	/// For payments to offers, the note we included for the recipient.
+	payer_note: Option<String>,
+	/// For payments to offers, the number of items paid for.
+	quantity: Option<u64>,
+	/// For inbound payments, the Lightning Address username the invoice was issued for.
+	lnurl_user: Option<String>,
+	/// For payments to our Lightning Addresses, the comment the payer sent.
+	lnurl_comment: Option<String>,
 }
 
 impl_writeable_tlv_based!(PaymentInfo, {
@@ -135,14 +180,24 @@ impl_writeable_tlv_based!(PaymentInfo, {
 	(2, secret, required),
 	(4, status, required),
 	(6, amt_msat, required),
+	(7, custom_tlvs, optional_vec),
+	(9, bolt11_invoice, option),
+	(11, settle_index, option),
+	(13, payer_note, option),
+	(15, quantity, option),
+	(17, lnurl_user, option),
+	(19, lnurl_comment, option),
 });
 
 pub(crate) struct InboundPaymentInfoStorage {
 	payments: HashMap<PaymentHash, PaymentInfo>,
+	/// The settle index given to the most recently settled payment.
+	last_settle_index: u64,
 }
 
 impl_writeable_tlv_based!(InboundPaymentInfoStorage, {
 	(0, payments, required),
+	(2, last_settle_index, (default_value, 0)),
 });
 
 pub(crate) struct OutboundPaymentInfoStorage {
@@ -180,22 +235,41 @@ pub(crate) type GossipVerifier = lightning_block_sync::gossip::GossipVerifier<
 	Arc<FilesystemLogger>,
 >;
 
-pub(crate) type PeerManager = SimpleArcPeerManager<
+pub(crate) type PeerManager = peer_handler::PeerManager<
 	SocketDescriptor,
-	ChainMonitor,
-	BitcoindClient,
-	BitcoindClient,
-	GossipVerifier,
-	FilesystemLogger,
+	Arc<ChannelManager>,
+	Arc<P2PGossipSync<Arc<NetworkGraph>, GossipVerifier, Arc<FilesystemLogger>>>,
+	Arc<OnionMessenger>,
+	Arc<FilesystemLogger>,
+	IgnoringMessageHandler,
+	Arc<KeysManager>,
 >;
 
-pub(crate) type ChannelManager =
-	SimpleArcChannelManager<ChainMonitor, BitcoindClient, BitcoindClient, FilesystemLogger>;
+pub(crate) type ChannelManager = channelmanager::ChannelManager<
+	Arc<ChainMonitor>,
+	Arc<BitcoindClient>,
+	Arc<KeysManager>,
+	Arc<KeysManager>,
+	Arc<KeysManager>,
+	Arc<BitcoindClient>,
+	Arc<AvoidListRouter>,
+	Arc<FilesystemLogger>,
+>;
 
 pub(crate) type NetworkGraph = gossip::NetworkGraph<Arc<FilesystemLogger>>;
 
-type OnionMessenger =
-	SimpleArcOnionMessenger<ChainMonitor, BitcoindClient, BitcoindClient, FilesystemLogger>;
+pub(crate) type Scorer = ProbabilisticScorer<Arc<NetworkGraph>, Arc<FilesystemLogger>>;
+
+type OnionMessenger = messenger::OnionMessenger<
+	Arc<KeysManager>,
+	Arc<KeysManager>,
+	Arc<FilesystemLogger>,
+	Arc<ChannelManager>,
+	Arc<DefaultMessageRouter<Arc<NetworkGraph>, Arc<FilesystemLogger>, Arc<KeysManager>>>,
+	Arc<ChannelManager>,
+	Arc<ChannelManager>,
+	IgnoringMessageHandler,
+>;
 
 pub(crate) type BumpTxEventHandler = BumpTransactionEventHandler<
 	Arc<BitcoindClient>,
@@ -206,7 +280,7 @@ pub(crate) type BumpTxEventHandler = BumpTransactionEventHandler<
 
 pub(crate) type OutputSweeper = ldk_sweep::OutputSweeper<
 	Arc<BitcoindClient>,
-	Arc<LdkOnChainWallet>,
+	Arc<SweepDestination>,
 	Arc<BitcoindClient>,
 	Arc<dyn Filter + Send + Sync>,
 	Arc<FilesystemStore>,
@@ -214,17 +288,63 @@ pub(crate) type OutputSweeper = ldk_sweep::OutputSweeper<
 	Arc<KeysManager>,
 >;
 
-// Needed due to rust-lang/rust#63033.
-struct OutputSweeperWrapper(Arc<OutputSweeper>);
+type BackgroundGossipSync = GossipSync<
+	Arc<P2PGossipSync<Arc<NetworkGraph>, GossipVerifier, Arc<FilesystemLogger>>>,
+	Arc<RapidGossipSync>,
+	Arc<NetworkGraph>,
+	GossipVerifier,
+	Arc<FilesystemLogger>,
+>;
 
-async fn handle_ldk_events(
-	channel_manager: Arc<ChannelManager>, bitcoind_client: &BitcoindClient,
-	network_graph: &NetworkGraph, keys_manager: &KeysManager, on_chain_wallet: &LdkOnChainWallet,
-	bump_tx_event_handler: &BumpTxEventHandler, peer_manager: Arc<PeerManager>,
+/// Everything `handle_ldk_events` needs, shared by every event it handles.
+struct EventHandlerContext {
+	channel_manager: Arc<ChannelManager>,
+	bitcoind_client: Arc<BitcoindClient>,
+	network_graph: Arc<NetworkGraph>,
+	keys_manager: Arc<KeysManager>,
+	on_chain_wallet: Arc<LdkOnChainWallet>,
+	bump_tx_event_handler: Arc<BumpTxEventHandler>,
+	peer_manager: Arc<PeerManager>,
 	inbound_payments: Arc<Mutex<InboundPaymentInfoStorage>>,
-	outbound_payments: Arc<Mutex<OutboundPaymentInfoStorage>>, fs_store: Arc<FilesystemStore>,
-	output_sweeper: OutputSweeperWrapper, network: Network, event: Event,
-) {
+	outbound_payments: Arc<Mutex<OutboundPaymentInfoStorage>>,
+	fs_store: Arc<FilesystemStore>,
+	output_sweeper: Arc<OutputSweeper>,
+	prober: Arc<Prober>,
+	rebalances: Arc<Mutex<RebalanceStorage>>,
+	conversation_log: Arc<Mutex<ConversationLog>>,
+	offers: Arc<Mutex<OfferStorage>>,
+	unified_requests: Arc<Mutex<UnifiedRequestStorage>>,
+	bolt12_invoice_policy: Bolt12InvoicePolicy,
+	pending_bolt12_invoices: Arc<PendingBolt12Invoices>,
+	unpaid_lnurl_invoices: Arc<UnpaidLnurlInvoices>,
+	accepted_tlv_types: Vec<u64>,
+	network: Network,
+}
+
+async fn handle_ldk_events(context: &EventHandlerContext, event: Event) {
+	let EventHandlerContext {
+		channel_manager,
+		bitcoind_client,
+		network_graph,
+		keys_manager,
+		on_chain_wallet,
+		bump_tx_event_handler,
+		peer_manager,
+		inbound_payments,
+		outbound_payments,
+		fs_store,
+		output_sweeper,
+		prober,
+		rebalances,
+		conversation_log,
+		offers,
+		unified_requests,
+		bolt12_invoice_policy,
+		pending_bolt12_invoices,
+		unpaid_lnurl_invoices,
+		accepted_tlv_types,
+		network,
+	} = context;
 	match event {
 		Event::FundingGenerationReady {
 			temporary_channel_id,
@@ -273,7 +393,7 @@ async fn handle_ldk_events(
 			via_channel_id: _,
 			via_user_channel_id: _,
 			claim_deadline: _,
-			onion_fields: _,
+			onion_fields,
 			counterparty_skimmed_fee_msat: _,
 		} => {
 			println!(
@@ -282,13 +402,137 @@ async fn handle_ldk_events(
 			);
 			print!("> ");
 			std::io::stdout().flush().unwrap();
+			let custom_tlvs =
+				onion_fields.as_ref().map_or(Vec::new(), |fields| fields.custom_tlvs().clone());
+			// Following "it's OK to be odd", we must reject payments carrying even TLV types we
+			// don't understand, but can simply drop unknown odd ones.
+			if custom_tlvs.iter().any(|(typ, _)| typ % 2 == 0 && !accepted_tlv_types.contains(typ)) {
+				println!(
+					"\nEVENT: rejected payment from payment hash {} with unknown required custom TLVs",
+					payment_hash,
+				);
+				print!("> ");
+				std::io::stdout().flush().unwrap();
+				channel_manager.fail_htlc_backwards(&payment_hash);
+				return;
+			}
+			let custom_tlvs = custom_tlvs
+				.into_iter()
+				.filter(|(typ, _)| accepted_tlv_types.contains(typ))
+				.collect::<Vec<_>>();
+			// Invoices for our Lightning Addresses are only recorded once they're paid.
+			if let Some(payment) = unpaid_lnurl_invoices.take(&payment_hash) {
+				let mut inbound = inbound_payments.lock().unwrap();
+				inbound.payments.entry(payment_hash).or_insert(payment);
+				fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound.encode()).unwrap();
+			}
+			// Expired invoices include those withdrawn because their unified payment request was
+			// paid some other way.
+			let expired = inbound_payments
+				.lock()
+				.unwrap()
+				.payments
+				.get(&payment_hash)
+				.is_some_and(|payment| matches!(payment.status, HTLCStatus::Expired));
+			if expired {
+				println!(
+					"\nEVENT: rejected payment from payment hash {} to an expired invoice",
+					payment_hash,
+				);
+				print!("> ");
+				std::io::stdout().flush().unwrap();
+				channel_manager.fail_htlc_backwards(&payment_hash);
+				return;
+			}
+			if let PaymentPurpose::Bolt12OfferPayment { payment_context, .. } = &purpose {
+				if !offers.lock().unwrap().accepts_payment(&payment_context.offer_id) {
+					println!(
+						"\nEVENT: rejected payment from payment hash {} to a disabled offer",
+						payment_hash,
+					);
+					print!("> ");
+					std::io::stdout().flush().unwrap();
+					channel_manager.fail_htlc_backwards(&payment_hash);
+					return;
+				}
+			}
+			// The background reconciliation only runs periodically, so check right before claiming
+			// that the unified request this pays, if any, wasn't already paid some other way.
+			let (rail, offer_id) = match &purpose {
+				PaymentPurpose::Bolt11InvoicePayment { .. } => (Some(PaymentRail::Bolt11), None),
+				PaymentPurpose::Bolt12OfferPayment { payment_context, .. } => {
+					(Some(PaymentRail::Bolt12), Some(payment_context.offer_id))
+				},
+				_ => (None, None),
+			};
+			if let Some(rail) = rail {
+				let mut requests = unified_requests.lock().unwrap();
+				let mut inbound = inbound_payments.lock().unwrap();
+				let mut offers = offers.lock().unwrap();
+				let paid_via = bip21::paid_on_other_rail(
+					&mut requests,
+					&mut inbound,
+					&mut offers,
+					rail,
+					&payment_hash,
+					offer_id.as_ref(),
+					|script| on_chain_wallet.received_to(script).to_sat(),
+				);
+				if let Some(paid_via) = paid_via {
+					requests.persist(fs_store);
+					offers.persist(fs_store);
+					fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound.encode()).unwrap();
+					println!(
+						"\nEVENT: rejected payment from payment hash {} as its unified payment request was already paid {}",
+						payment_hash,
+						paid_via.description(),
+					);
+					print!("> ");
+					std::io::stdout().flush().unwrap();
+					channel_manager.fail_htlc_backwards(&payment_hash);
+					return;
+				}
+			}
 			let payment_preimage = match purpose {
 				PaymentPurpose::Bolt11InvoicePayment { payment_preimage, .. } => payment_preimage,
 				PaymentPurpose::Bolt12OfferPayment { payment_preimage, .. } => payment_preimage,
 				PaymentPurpose::Bolt12RefundPayment { payment_preimage, .. } => payment_preimage,
 				PaymentPurpose::SpontaneousPayment(preimage) => Some(preimage),
 			};
-			channel_manager.claim_funds(payment_preimage.unwrap());
+			// Any chat message is kept with the payment and logged once it's claimed, as this
+			// event may be replayed.
+			if !custom_tlvs.is_empty() {
+				let mut inbound = inbound_payments.lock().unwrap();
+				match inbound.payments.entry(payment_hash) {
+					Entry::Occupied(mut e) => e.get_mut().custom_tlvs = custom_tlvs,
+					Entry::Vacant(e) => {
+						e.insert(PaymentInfo {
+							preimage: payment_preimage,
+							secret: None,
+							status: HTLCStatus::Pending,
+							amt_msat: MillisatAmount(Some(amount_msat)),
+							custom_tlvs,
+							bolt11_invoice: None,
+							settle_index: None,
+							payer_note: None,
+							quantity: None,
+							lnurl_user: None,
+							lnurl_comment: None,
+						});
+					},
+				}
+				fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound.encode()).unwrap();
+			}
+			// Invoices created with a preimage of our choosing don't get it back in the purpose.
+			let payment_preimage = payment_preimage.or_else(|| {
+				let inbound = inbound_payments.lock().unwrap();
+				inbound.payments.get(&payment_hash).and_then(|payment| payment.preimage)
+			});
+			match payment_preimage {
+				// Any even TLVs left have been checked against our accepted types above.
+				Some(preimage) => channel_manager.claim_funds_with_known_custom_tlvs(preimage),
+				None => channel_manager.fail_htlc_backwards(&payment_hash),
+			}
 		},
 		Event::PaymentClaimed { payment_hash, purpose, amount_msat, .. } => {
 			println!(
@@ -297,6 +541,16 @@ async fn handle_ldk_events(
 			);
 			print!("> ");
 			std::io::stdout().flush().unwrap();
+			// The receiving end of a rebalance is tracked with the rebalance itself.
+			if rebalances.lock().unwrap().is_rebalance(&payment_hash) {
+				return;
+			}
+			if let PaymentPurpose::Bolt12OfferPayment { payment_context, .. } = &purpose {
+				let mut offers = offers.lock().unwrap();
+				if offers.record_payment(&payment_context.offer_id, amount_msat) {
+					offers.persist(fs_store);
+				}
+			}
 			let (payment_preimage, payment_secret) = match purpose {
 				PaymentPurpose::Bolt11InvoicePayment {
 					payment_preimage, payment_secret, ..
@@ -309,28 +563,79 @@ async fn handle_ldk_events(
 				} => (payment_preimage, Some(payment_secret)),
 				PaymentPurpose::SpontaneousPayment(preimage) => (Some(preimage), None),
 			};
+			let mut message = None;
 			let mut inbound = inbound_payments.lock().unwrap();
-			match inbound.payments.entry(payment_hash) {
+			let InboundPaymentInfoStorage { payments, last_settle_index } = &mut *inbound;
+			match payments.entry(payment_hash) {
 				Entry::Occupied(mut e) => {
 					let payment = e.get_mut();
 					payment.status = HTLCStatus::Succeeded;
-					payment.preimage = payment_preimage;
+					payment.preimage = payment_preimage.or(payment.preimage);
 					payment.secret = payment_secret;
+					if payment.amt_msat.0.is_none() {
+						payment.amt_msat = MillisatAmount(Some(amount_msat));
+					}
+					// The event may be replayed after a restart; keep the original index and
+					// only log a message the first time.
+					if payment.settle_index.is_none() {
+						*last_settle_index += 1;
+						payment.settle_index = Some(*last_settle_index);
+						message = messaging::message_from_tlvs(&payment.custom_tlvs, amount_msat);
+					}
 				},
 				Entry::Vacant(e) => {
+					*last_settle_index += 1;
 					e.insert(PaymentInfo {
 						preimage: payment_preimage,
 						secret: payment_secret,
 						status: HTLCStatus::Succeeded,
 						amt_msat: MillisatAmount(Some(amount_msat)),
+						custom_tlvs: Vec::new(),
+						bolt11_invoice: None,
+						settle_index: Some(*last_settle_index),
+						payer_note: None,
+						quantity: None,
+						lnurl_user: None,
+						lnurl_comment: None,
 					});
 				},
 			}
 			fs_store.write("", "", INBOUND_PAYMENTS_FNAME, &inbound.encode()).unwrap();
+			drop(inbound);
+			if let Some(message) = message {
+				println!(
+					"\nEVENT: message from {}: {}",
+					message.peer.map_or("unknown sender".to_string(), |peer| peer.to_string()),
+					message.text,
+				);
+				print!("> ");
+				std::io::stdout().flush().unwrap();
+				let mut conversation_log = conversation_log.lock().unwrap();
+				conversation_log.messages.push(message);
+				conversation_log.persist(fs_store);
+			}
 		},
 		Event::PaymentSent {
 			payment_preimage, payment_hash, fee_paid_msat, payment_id, ..
 		} => {
+			let mut rebalances = rebalances.lock().unwrap();
+			if let Some(rebalance) = rebalances.rebalances.get_mut(&payment_id.unwrap()) {
+				rebalance.status = HTLCStatus::Succeeded;
+				rebalance.fee_paid_msat = fee_paid_msat;
+				println!(
+					"\nEVENT: rebalanced {} millisatoshis from channel {} to channel {} (fee {} msat)",
+					rebalance.amt_msat,
+					rebalance.from_channel,
+					rebalance.to_channel,
+					fee_paid_msat.unwrap_or(0),
+				);
+				print!("> ");
+				std::io::stdout().flush().unwrap();
+				rebalances.persist(fs_store);
+				return;
+			}
+			drop(rebalances);
+
 			let mut outbound = outbound_payments.lock().unwrap();
 			for (id, payment) in outbound.payments.iter_mut() {
 				if *id == payment_id.unwrap() {
@@ -385,8 +690,35 @@ async fn handle_ldk_events(
 		},
 		Event::PaymentPathSuccessful { .. } => {},
 		Event::PaymentPathFailed { .. } => {},
-		Event::ProbeSuccessful { .. } => {},
-		Event::ProbeFailed { .. } => {},
+		Event::ProbeSuccessful { payment_id, path, .. } => {
+			if prober.probe_finished(&payment_id, &path) {
+				print!(
+					"\nEVENT: Probe {} succeeded over {} hop(s) to {}, able to carry {} msat",
+					payment_id,
+					path.hops.len(),
+					path.hops.last().map_or("unknown".to_string(), |hop| hop.pubkey.to_string()),
+					path.final_value_msat(),
+				);
+				print!("> ");
+				std::io::stdout().flush().unwrap();
+			}
+		},
+		Event::ProbeFailed { payment_id, path, short_channel_id, .. } => {
+			if prober.probe_finished(&payment_id, &path) {
+				// `short_channel_id` is the channel the probe couldn't be forwarded over.
+				match path.hops.iter().find(|hop| Some(hop.short_channel_id) == short_channel_id) {
+					Some(hop) => print!(
+						"\nEVENT: Probe {} failed at channel {} towards {}",
+						payment_id,
+						graph::scid_str(hop.short_channel_id),
+						hop.pubkey,
+					),
+					None => print!("\nEVENT: Probe {} failed at an unknown hop", payment_id),
+				}
+				print!("> ");
+				std::io::stdout().flush().unwrap();
+			}
+		},
 		Event::PaymentFailed { payment_hash, reason, payment_id, .. } => {
 			if let Some(hash) = payment_hash {
 				print!(
@@ -405,6 +737,15 @@ async fn handle_ldk_events(
 			print!("> ");
 			std::io::stdout().flush().unwrap();
 
+			let mut rebalances = rebalances.lock().unwrap();
+			if let Some(rebalance) = rebalances.rebalances.get_mut(&payment_id) {
+				rebalance.status = HTLCStatus::Failed;
+				rebalances.persist(fs_store);
+				return;
+			}
+			drop(rebalances);
+			pending_bolt12_invoices.forget(&payment_id);
+
 			let mut outbound = outbound_payments.lock().unwrap();
 			if outbound.payments.contains_key(&payment_id) {
 				let payment = outbound.payments.get_mut(&payment_id).unwrap();
@@ -412,8 +753,28 @@ async fn handle_ldk_events(
 			}
 			fs_store.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound.encode()).unwrap();
 		},
-		Event::InvoiceReceived { .. } => {
-			// We don't use the manual invoice payment logic, so this event should never be seen.
+		Event::InvoiceReceived { payment_id, invoice, context, .. } => {
+			// Only seen with manual BOLT12 invoice handling enabled.
+			println!("\nEVENT: received invoice for payment {}:", payment_id);
+			Decoded::Bolt12Invoice(Box::new(invoice.clone())).print();
+			match bolt12_invoice_policy.check(&invoice) {
+				Ok(()) => {
+					match channel_manager.send_payment_for_bolt12_invoice(&invoice, context.as_ref())
+					{
+						Ok(()) => println!("EVENT: invoice is within policy, paying it"),
+						Err(e) => println!("ERROR: failed to pay invoice: {:?}", e),
+					}
+				},
+				Err(reason) => {
+					println!(
+						"EVENT: not paying invoice as {}; use `approveinvoice {}` or `rejectinvoice {}`",
+						reason, payment_id, payment_id,
+					);
+					pending_bolt12_invoices.hold(payment_id, invoice, context);
+				},
+			}
+			print!("> ");
+			std::io::stdout().flush().unwrap();
 		},
 		Event::PaymentForwarded {
 			prev_channel_id,
@@ -491,7 +852,7 @@ async fn handle_ldk_events(
 			});
 		},
 		Event::SpendableOutputs { outputs, channel_id } => {
-			output_sweeper.0.track_spendable_outputs(outputs, channel_id, false, None).unwrap();
+			output_sweeper.track_spendable_outputs(outputs, channel_id, false, None).unwrap();
 		},
 		Event::ChannelPending { channel_id, counterparty_node_id, .. } => {
 			println!(
@@ -548,6 +909,7 @@ async fn handle_ldk_events(
 		},
 		Event::BumpTransaction(event) => bump_tx_event_handler.handle_event(&event),
 		Event::ConnectionNeeded { node_id, addresses } => {
+			let peer_manager = Arc::clone(peer_manager);
 			tokio::spawn(async move {
 				for address in addresses {
 					if let Ok(sockaddrs) = address.to_socket_addrs() {
@@ -740,14 +1102,26 @@ async fn start_ldk() {
 		Arc::clone(&logger),
 	)));
 
+	// Optionally bootstrap the network graph from a Rapid Gossip Sync snapshot so a fresh node can
+	// route without waiting for P2P gossip.
+	let rapid_gossip_sync =
+		Arc::new(RapidGossipSync::new(Arc::clone(&network_graph), Arc::clone(&logger)));
+	let rgs_source = args.rgs_source.as_ref().map(|source| RgsSource::parse(source));
+	if let Some(source) = &rgs_source {
+		match rgs::sync(&rapid_gossip_sync, source).await {
+			Ok(timestamp) => println!("Applied RGS snapshot with timestamp {}", timestamp),
+			Err(e) => println!("ERROR: failed to sync network graph via RGS: {}", e),
+		}
+	}
+
 	// Step 10: Create Router
-	let scoring_fee_params = ProbabilisticScoringFeeParameters::default();
-	let router = Arc::new(DefaultRouter::new(
+	let avoid_list = Arc::new(Mutex::new(routing::read_avoid_list(&fs_store)));
+	let router = Arc::new(AvoidListRouter::new(
 		network_graph.clone(),
 		logger.clone(),
 		keys_manager.clone(),
 		scorer.clone(),
-		scoring_fee_params,
+		Arc::clone(&avoid_list),
 	));
 
 	// Step 11: Initialize the ChannelManager
@@ -755,6 +1129,7 @@ async fn start_ldk() {
 	user_config.channel_handshake_limits.force_announced_channel_preference = false;
 	user_config.channel_handshake_config.negotiate_anchors_zero_fee_htlc_tx = true;
 	user_config.manually_accept_inbound_channels = true;
+	user_config.manually_handle_bolt12_invoices = args.bolt12_invoice_policy.is_some();
 	let mut restarting_node = true;
 	let (channel_manager_blockhash, channel_manager) = {
 		if let Ok(f) = fs::File::open(format!("{}/manager", ldk_data_dir.clone())) {
@@ -801,6 +1176,18 @@ async fn start_ldk() {
 	};
 
 	// Step 12: Initialize the OutputSweeper.
+	let sweep_destination = match &args.sweep_descriptor {
+		Some(descriptor) => {
+			match SweepDestination::from_descriptor_str(descriptor, Arc::clone(&fs_store)) {
+				Ok(destination) => Arc::new(destination),
+				Err(e) => {
+					println!("ERROR: {}", e);
+					return;
+				},
+			}
+		},
+		None => Arc::new(SweepDestination::Wallet(Arc::clone(&on_chain_wallet))),
+	};
 	let (sweeper_best_block, output_sweeper) = match fs_store.read(
 		OUTPUT_SWEEPER_PERSISTENCE_PRIMARY_NAMESPACE,
 		OUTPUT_SWEEPER_PERSISTENCE_SECONDARY_NAMESPACE,
@@ -813,7 +1200,7 @@ async fn start_ldk() {
 				fee_estimator.clone(),
 				None,
 				keys_manager.clone(),
-				on_chain_wallet.clone(),
+				sweep_destination.clone(),
 				fs_store.clone(),
 				logger.clone(),
 			);
@@ -825,7 +1212,7 @@ async fn start_ldk() {
 				fee_estimator.clone(),
 				None,
 				keys_manager.clone(),
-				on_chain_wallet.clone(),
+				sweep_destination.clone(),
 				fs_store.clone(),
 				logger.clone(),
 			);
@@ -977,6 +1364,10 @@ async fn start_ldk() {
 	let outbound_payments = Arc::new(Mutex::new(disk::read_outbound_payment_info(Path::new(
 		&format!("{}/{}", ldk_data_dir, OUTBOUND_PAYMENTS_FNAME),
 	))));
+	let rebalances = Arc::new(Mutex::new(rebalance::read_rebalances(&fs_store)));
+	let conversation_log = Arc::new(Mutex::new(messaging::read_conversation_log(&fs_store)));
+	let offers = Arc::new(Mutex::new(offers::read_offers(&fs_store)));
+	let unified_requests = Arc::new(Mutex::new(bip21::read_unified_requests(&fs_store)));
 	let recent_payments_payment_ids = channel_manager
 		.list_recent_payments()
 		.into_iter()
@@ -1001,48 +1392,62 @@ async fn start_ldk() {
 	fs_store
 		.write("", "", OUTBOUND_PAYMENTS_FNAME, &outbound_payments.lock().unwrap().encode())
 		.unwrap();
+	{
+		let mut rebalances = rebalances.lock().unwrap();
+		for (payment_id, rebalance) in rebalances
+			.rebalances
+			.iter_mut()
+			.filter(|(_, r)| matches!(r.status, HTLCStatus::Pending))
+		{
+			if !recent_payments_payment_ids.contains(payment_id) {
+				rebalance.status = HTLCStatus::Failed;
+			}
+		}
+		rebalances.persist(&fs_store);
+	}
 
 	// Step 19: Handle LDK Events
-	let channel_manager_event_listener = Arc::clone(&channel_manager);
-	let bitcoind_client_event_listener = Arc::clone(&bitcoind_client);
-	let network_graph_event_listener = Arc::clone(&network_graph);
-	let keys_manager_event_listener = Arc::clone(&keys_manager);
-	let on_chain_wallet_event_listener = Arc::clone(&on_chain_wallet);
-	let inbound_payments_event_listener = Arc::clone(&inbound_payments);
-	let outbound_payments_event_listener = Arc::clone(&outbound_payments);
-	let fs_store_event_listener = Arc::clone(&fs_store);
-	let peer_manager_event_listener = Arc::clone(&peer_manager);
-	let output_sweeper_event_listener = Arc::clone(&output_sweeper);
+	// Probes are sent both from the CLI and in the background; the prober tracks which is which so
+	// we only report on the ones the user asked for.
+	let prober = Arc::new(Prober::new(
+		Arc::clone(&channel_manager),
+		Arc::clone(&network_graph),
+		Arc::clone(&logger),
+		Arc::clone(&fs_store),
+	));
+	let pending_bolt12_invoices =
+		Arc::new(offers::read_pending_bolt12_invoices(Arc::clone(&fs_store)));
+	let unpaid_lnurl_invoices = Arc::new(lnurl::read_unpaid_lnurl_invoices(Arc::clone(&fs_store)));
+	let mut accepted_tlv_types = args.accepted_tlv_types.clone();
+	accepted_tlv_types.extend(messaging::MESSAGE_TLV_TYPES.iter());
 	let network = args.network;
+	let event_handler_context = Arc::new(EventHandlerContext {
+		channel_manager: Arc::clone(&channel_manager),
+		bitcoind_client: Arc::clone(&bitcoind_client),
+		network_graph: Arc::clone(&network_graph),
+		keys_manager: Arc::clone(&keys_manager),
+		on_chain_wallet: Arc::clone(&on_chain_wallet),
+		bump_tx_event_handler: Arc::clone(&bump_tx_event_handler),
+		peer_manager: Arc::clone(&peer_manager),
+		inbound_payments: Arc::clone(&inbound_payments),
+		outbound_payments: Arc::clone(&outbound_payments),
+		fs_store: Arc::clone(&fs_store),
+		output_sweeper: Arc::clone(&output_sweeper),
+		prober: Arc::clone(&prober),
+		rebalances: Arc::clone(&rebalances),
+		conversation_log: Arc::clone(&conversation_log),
+		offers: Arc::clone(&offers),
+		unified_requests: Arc::clone(&unified_requests),
+		bolt12_invoice_policy: args.bolt12_invoice_policy.clone().unwrap_or_default(),
+		pending_bolt12_invoices: Arc::clone(&pending_bolt12_invoices),
+		unpaid_lnurl_invoices: Arc::clone(&unpaid_lnurl_invoices),
+		accepted_tlv_types,
+		network,
+	});
 	let event_handler = move |event: Event| {
-		let channel_manager_event_listener = Arc::clone(&channel_manager_event_listener);
-		let bitcoind_client_event_listener = Arc::clone(&bitcoind_client_event_listener);
-		let network_graph_event_listener = Arc::clone(&network_graph_event_listener);
-		let keys_manager_event_listener = Arc::clone(&keys_manager_event_listener);
-		let on_chain_wallet_event_listener = Arc::clone(&on_chain_wallet_event_listener);
-		let bump_tx_event_handler = Arc::clone(&bump_tx_event_handler);
-		let inbound_payments_event_listener = Arc::clone(&inbound_payments_event_listener);
-		let outbound_payments_event_listener = Arc::clone(&outbound_payments_event_listener);
-		let fs_store_event_listener = Arc::clone(&fs_store_event_listener);
-		let peer_manager_event_listener = Arc::clone(&peer_manager_event_listener);
-		let output_sweeper_event_listener = Arc::clone(&output_sweeper_event_listener);
+		let event_handler_context = Arc::clone(&event_handler_context);
 		async move {
-			handle_ldk_events(
-				channel_manager_event_listener,
-				&bitcoind_client_event_listener,
-				&network_graph_event_listener,
-				&keys_manager_event_listener,
-				&on_chain_wallet_event_listener,
-				&bump_tx_event_handler,
-				peer_manager_event_listener,
-				inbound_payments_event_listener,
-				outbound_payments_event_listener,
-				fs_store_event_listener,
-				OutputSweeperWrapper(output_sweeper_event_listener),
-				network,
-				event,
-			)
-			.await;
+			handle_ldk_events(&event_handler_context, event).await;
 			Ok(())
 		}
 	};
@@ -1050,6 +1455,30 @@ async fn start_ldk() {
 	// Step 20: Persist ChannelManager and NetworkGraph
 	let persister = Arc::new(FilesystemStore::new(ldk_data_dir.clone().into()));
 
+	// Keep pulling incremental snapshots when syncing gossip from an RGS server.
+	if let Some(RgsSource::Http(url)) = &rgs_source {
+		let rgs_sync = Arc::clone(&rapid_gossip_sync);
+		let rgs_source = RgsSource::Http(url.clone());
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(rgs::RGS_SYNC_INTERVAL);
+			interval.tick().await;
+			loop {
+				interval.tick().await;
+				if let Err(e) = rgs::sync(&rgs_sync, &rgs_source).await {
+					println!("ERROR: failed to sync network graph via RGS: {}", e);
+				}
+			}
+		});
+	}
+
+	// Once we sync gossip via RGS the background processor must wait for the initial snapshot
+	// before pruning the graph, rather than treating it as P2P-synced.
+	let background_gossip_sync: BackgroundGossipSync = if rgs_source.is_some() {
+		GossipSync::Rapid(Arc::clone(&rapid_gossip_sync))
+	} else {
+		GossipSync::P2P(Arc::clone(&gossip_sync))
+	};
+
 	// Step 21: Background Processing
 	let (bp_exit, bp_exit_check) = tokio::sync::watch::channel(());
 	let mut background_processor = tokio::spawn(process_events_async(
@@ -1058,7 +1487,7 @@ async fn start_ldk() {
 		chain_monitor.clone(),
 		channel_manager.clone(),
 		Some(onion_messenger),
-		GossipSync::p2p(gossip_sync.clone()),
+		background_gossip_sync,
 		peer_manager.clone(),
 		logger.clone(),
 		Some(scorer.clone()),
@@ -1113,6 +1542,43 @@ async fn start_ldk() {
 		}
 	});
 
+	// Regularly mark unpaid invoices whose expiry has passed as expired.
+	tokio::spawn(invoice::run_invoice_expiry(Arc::clone(&inbound_payments), Arc::clone(&fs_store)));
+
+	// Regularly check whether unified payment requests were paid on any of their rails.
+	tokio::spawn(bip21::run_reconciliation(
+		Arc::clone(&unified_requests),
+		Arc::clone(&inbound_payments),
+		Arc::clone(&offers),
+		Arc::clone(&on_chain_wallet),
+		Arc::clone(&fs_store),
+	));
+
+	// Optionally serve LNURL-pay, so we can be paid at Lightning Addresses on our own domain.
+	if let Some(lnurl_config) = args.lnurl_server.clone() {
+		tokio::spawn(lnurl::run_server(
+			lnurl_config,
+			Arc::clone(&channel_manager),
+			Arc::clone(&keys_manager),
+			Arc::clone(&logger),
+			Arc::clone(&unpaid_lnurl_invoices),
+			args.network,
+		));
+	}
+
+	// Optionally probe well-connected nodes in the background to train the scorer.
+	if let Some(probing_config) = args.probing.clone() {
+		tokio::spawn(probing::run_background_prober(Arc::clone(&prober), probing_config));
+	}
+
+	// Taken before the announcement task below moves `args`.
+	let cli_rgs_source = args.rgs_source.clone();
+	let exchange_rate_source =
+		args.exchange_rate_source.as_deref().map(exchange_rate::parse_source);
+	// The resolver was already checked when the startup flags were parsed.
+	let dns_resolver =
+		args.dns_resolver.as_deref().map(|resolver| bip353::parse_resolver(resolver).unwrap());
+
 	// Regularly broadcast our node_announcement. This is only required (or possible) if we have
 	// some public channels.
 	let peer_man = Arc::clone(&peer_manager);
@@ -1139,6 +1605,13 @@ async fn start_ldk() {
 		}
 	});
 
+	// Regularly move a cold-storage sweep destination on to a fresh address once swept.
+	tokio::spawn(sweep::run_sweep_address_rotation(
+		Arc::clone(&sweep_destination),
+		Arc::clone(&output_sweeper),
+		Arc::clone(&logger),
+	));
+
 	tokio::spawn(sweep::migrate_deprecated_spendable_outputs(
 		ldk_data_dir.clone(),
 		Arc::clone(&keys_manager),
@@ -1154,21 +1627,44 @@ async fn start_ldk() {
 	let cli_logger = Arc::clone(&logger);
 	let cli_peer_manager = Arc::clone(&peer_manager);
 	let cli_on_chain_wallet = Arc::clone(&on_chain_wallet);
+	let cli_output_sweeper = Arc::clone(&output_sweeper);
+	let cli_bitcoind_client = Arc::clone(&bitcoind_client);
+	let cli_scorer = Arc::clone(&scorer);
+	let cli_prober = Arc::clone(&prober);
+	let cli_avoid_list = Arc::clone(&avoid_list);
+	let cli_rebalances = Arc::clone(&rebalances);
+	let cli_conversation_log = Arc::clone(&conversation_log);
+	let cli_offers = Arc::clone(&offers);
 	let cli_poll = tokio::task::spawn_blocking(move || {
-		cli::poll_for_user_input(
-			cli_peer_manager,
-			cli_channel_manager,
-			cli_chain_monitor,
+		cli::poll_for_user_input(cli::CliContext {
+			peer_manager: cli_peer_manager,
+			channel_manager: cli_channel_manager,
+			chain_monitor: cli_chain_monitor,
 			keys_manager,
-			cli_on_chain_wallet,
+			on_chain_wallet: cli_on_chain_wallet,
 			network_graph,
+			scorer: cli_scorer,
+			prober: cli_prober,
+			avoid_list: cli_avoid_list,
+			rapid_gossip_sync,
+			rgs_source: cli_rgs_source,
 			inbound_payments,
 			outbound_payments,
+			rebalances: cli_rebalances,
+			conversation_log: cli_conversation_log,
+			offers: cli_offers,
+			unified_requests,
+			pending_bolt12_invoices,
+			exchange_rate_source,
+			dns_resolver,
+			output_sweeper: cli_output_sweeper,
+			sweep_destination,
+			bitcoind_client: cli_bitcoind_client,
 			ldk_data_dir,
 			network,
-			cli_logger,
-			cli_persister,
-		)
+			logger: cli_logger,
+			fs_store: cli_persister,
+		})
 	});
 
 	// Exit if either CLI polling exits or the background processor exits (which shouldn't happen
@@ -0,0 +1,155 @@
+use crate::filesystem_store::FilesystemStore;
+use bitcoin::secp256k1::PublicKey;
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::channelmanager::PaymentId;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable};
+use std::io;
+use std::time::SystemTime;
+
+pub(crate) const CONVERSATION_LOG_KEY: &str = "messages";
+
+/// The keysend TLV type carrying a UTF-8 chat message, as used by Whatsat and most wallets that
+/// support keysend messaging.
+pub(crate) const MESSAGE_TLV_TYPE: u64 = 34349334;
+/// The (self-reported, unsigned) sender's node id.
+pub(crate) const SENDER_PUBKEY_TLV_TYPE: u64 = 34349339;
+/// The time the message was sent, as big-endian nanoseconds since the UNIX epoch.
+pub(crate) const TIMESTAMP_TLV_TYPE: u64 = 34349343;
+
+/// The TLV types making up a chat message, which we always accept on incoming payments.
+pub(crate) const MESSAGE_TLV_TYPES: [u64; 3] =
+	[MESSAGE_TLV_TYPE, SENDER_PUBKEY_TLV_TYPE, TIMESTAMP_TLV_TYPE];
+
+/// How much we send along with a message when no amount is given.
+pub(crate) const DEFAULT_MESSAGE_AMOUNT_MSAT: u64 = 1000;
+
+pub(crate) struct ChatMessage {
+	/// The node we sent the message to, or the node that says it sent it to us. Incoming messages
+	/// are unsigned, so this is only as trustworthy as the sender.
+	pub(crate) peer: Option<PublicKey>,
+	pub(crate) outgoing: bool,
+	pub(crate) text: String,
+	/// Seconds since the UNIX epoch.
+	pub(crate) timestamp: u64,
+	pub(crate) amt_msat: u64,
+	/// For outgoing messages, the keysend payment carrying the message.
+	pub(crate) payment_id: Option<PaymentId>,
+}
+
+impl_writeable_tlv_based!(ChatMessage, {
+	(0, peer, option),
+	(2, outgoing, required),
+	(4, text, required),
+	(6, timestamp, required),
+	(8, amt_msat, required),
+	(10, payment_id, option),
+});
+
+#[derive(Default)]
+pub(crate) struct ConversationLog {
+	pub(crate) messages: Vec<ChatMessage>,
+}
+
+impl_writeable_tlv_based!(ConversationLog, {
+	(0, messages, required_vec),
+});
+
+impl ConversationLog {
+	pub(crate) fn persist(&self, fs_store: &FilesystemStore) {
+		fs_store.write("", "", CONVERSATION_LOG_KEY, &self.encode()).unwrap();
+	}
+}
+
+pub(crate) fn read_conversation_log(fs_store: &FilesystemStore) -> ConversationLog {
+	match fs_store.read("", "", CONVERSATION_LOG_KEY) {
+		Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes)).unwrap_or_default(),
+		Err(_) => ConversationLog::default(),
+	}
+}
+
+/// The custom TLVs carrying `text` from `sender`.
+pub(crate) fn message_tlvs(sender: &PublicKey, text: &str) -> Vec<(u64, Vec<u8>)> {
+	let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+	vec![
+		(MESSAGE_TLV_TYPE, text.as_bytes().to_vec()),
+		(SENDER_PUBKEY_TLV_TYPE, sender.serialize().to_vec()),
+		(TIMESTAMP_TLV_TYPE, (now.as_nanos() as u64).to_be_bytes().to_vec()),
+	]
+}
+
+/// Extracts a chat message from the custom TLVs of a payment we received, if it carries one.
+pub(crate) fn message_from_tlvs(
+	custom_tlvs: &[(u64, Vec<u8>)], amt_msat: u64,
+) -> Option<ChatMessage> {
+	let tlv = |typ| custom_tlvs.iter().find(|(t, _)| *t == typ).map(|(_, value)| value);
+	let text = String::from_utf8_lossy(tlv(MESSAGE_TLV_TYPE)?).to_string();
+	let peer = tlv(SENDER_PUBKEY_TLV_TYPE).and_then(|pubkey| PublicKey::from_slice(pubkey).ok());
+	let sent_at_secs = tlv(TIMESTAMP_TLV_TYPE).and_then(|timestamp| {
+		if timestamp.len() != 8 {
+			return None;
+		}
+		let mut nanos = [0; 8];
+		nanos.copy_from_slice(timestamp);
+		Some(u64::from_be_bytes(nanos) / 1_000_000_000)
+	});
+	let timestamp = sent_at_secs.unwrap_or_else(|| {
+		SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+	});
+	Some(ChatMessage { peer, outgoing: false, text, timestamp, amt_msat, payment_id: None })
+}
+
+/// Splits the arguments of `sendmessage` after the pubkey into the message and optional amount.
+/// Messages containing spaces must be quoted.
+pub(crate) fn parse_message_args(args: &str) -> Option<(String, Option<&str>)> {
+	let args = args.trim();
+	if let Some(quoted) = args.strip_prefix('"') {
+		let end = quoted.find('"')?;
+		let amt = quoted[end + 1..].trim();
+		if amt.contains(char::is_whitespace) {
+			return None;
+		}
+		let amt = if amt.is_empty() { None } else { Some(amt) };
+		return Some((quoted[..end].to_string(), amt));
+	}
+	let mut words = args.split_whitespace();
+	let text = words.next()?.to_string();
+	let amt = words.next();
+	if words.next().is_some() {
+		return None;
+	}
+	Some((text, amt))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	#[test]
+	fn test_message_tlvs_round_trip() {
+		let secp_ctx = Secp256k1::new();
+		let secret_key = SecretKey::from_slice(&[42; 32]).unwrap();
+		let sender = PublicKey::from_secret_key(&secp_ctx, &secret_key);
+		let tlvs = message_tlvs(&sender, "gm ⚡");
+		let message = message_from_tlvs(&tlvs, 1000).unwrap();
+		assert_eq!(message.text, "gm ⚡");
+		assert_eq!(message.peer, Some(sender));
+		assert!(!message.outgoing);
+		assert!(message_from_tlvs(&tlvs[1..], 1000).is_none());
+	}
+
+	#[test]
+	fn test_parse_message_args() {
+		assert_eq!(parse_message_args("hello"), Some(("hello".to_string(), None)));
+		assert_eq!(parse_message_args("hello 5000"), Some(("hello".to_string(), Some("5000"))));
+		assert_eq!(
+			parse_message_args("\"hello there\" 5000"),
+			Some(("hello there".to_string(), Some("5000")))
+		);
+		assert_eq!(parse_message_args("\"hello there\""), Some(("hello there".to_string(), None)));
+		assert_eq!(parse_message_args("hello there friend"), None);
+		assert_eq!(parse_message_args("\"unterminated"), None);
+		assert_eq!(parse_message_args(""), None);
+	}
+}
@@ -0,0 +1,609 @@
+use crate::exchange_rate;
+use crate::filesystem_store::FilesystemStore;
+use crate::hex_utils;
+use crate::invoice;
+use crate::qr::QrOptions;
+use crate::ChannelManager;
+use bitcoin::secp256k1::PublicKey;
+use lightning::blinded_path::message::OffersContext;
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::channelmanager::PaymentId;
+use lightning::offers::invoice::Bolt12Invoice;
+use lightning::offers::offer::{Amount, Offer, OfferId, Quantity};
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable};
+use std::collections::HashMap;
+use std::io;
+use std::num::NonZeroU64;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+pub(crate) const OFFERS_KEY: &str = "offers";
+
+/// An offer we created, along with what it has been paid so far. The description, amount and other
+/// terms live in the offer itself.
+pub(crate) struct OfferInfo {
+	pub(crate) offer: String,
+	/// Single-use offers are disabled once they've been paid.
+	pub(crate) single_use: bool,
+	pub(crate) disabled: bool,
+	pub(crate) created_at: u64,
+	pub(crate) num_payments: u64,
+	pub(crate) total_received_msat: u64,
+	/// For offers created with `--currency`, the fiat price the amount was converted from, e.g.
+	/// `5.00 USD`. The offer itself is for the converted msat amount.
+	pub(crate) fiat_price: Option<String>,
+}
+
+impl_writeable_tlv_based!(OfferInfo, {
+	(0, offer, required),
+	(2, single_use, required),
+	(4, disabled, required),
+	(6, created_at, required),
+	(8, num_payments, required),
+	(10, total_received_msat, required),
+	(11, fiat_price, option),
+});
+
+impl OfferInfo {
+	pub(crate) fn decode(&self) -> Option<Offer> {
+		Offer::from_str(&self.offer).ok()
+	}
+}
+
+#[derive(Default)]
+pub(crate) struct OfferStorage {
+	/// Keyed by the bytes of the offer's `OfferId`, which can't be a map key itself.
+	pub(crate) offers: HashMap<[u8; 32], OfferInfo>,
+}
+
+impl_writeable_tlv_based!(OfferStorage, {
+	(0, offers, required),
+});
+
+impl OfferStorage {
+	pub(crate) fn persist(&self, fs_store: &FilesystemStore) {
+		fs_store.write("", "", OFFERS_KEY, &self.encode()).unwrap();
+	}
+
+	/// Whether we'll take a payment to `offer_id`. Offers we have no record of, such as ones created
+	/// before we kept track of them, are always accepted.
+	///
+	/// LDK answers invoice requests for any offer it created, so payments to disabled offers can
+	/// only be turned away once they arrive.
+	pub(crate) fn accepts_payment(&self, offer_id: &OfferId) -> bool {
+		self.offers.get(&offer_id.0).is_none_or(|offer| !offer.disabled)
+	}
+
+	/// Attributes a claimed payment to the offer it paid, returning whether we know the offer.
+	pub(crate) fn record_payment(&mut self, offer_id: &OfferId, amount_msat: u64) -> bool {
+		match self.offers.get_mut(&offer_id.0) {
+			Some(offer) => {
+				offer.num_payments += 1;
+				offer.total_received_msat += amount_msat;
+				if offer.single_use {
+					offer.disabled = true;
+				}
+				true
+			},
+			None => false,
+		}
+	}
+}
+
+pub(crate) fn read_offers(fs_store: &FilesystemStore) -> OfferStorage {
+	match fs_store.read("", "", OFFERS_KEY) {
+		Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes)).unwrap_or_default(),
+		Err(_) => OfferStorage::default(),
+	}
+}
+
+/// The options `createoffer` takes.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct OfferOptions {
+	/// `None` for an offer the payer may pay any amount to.
+	pub(crate) amt_msat: Option<u64>,
+	pub(crate) description: Option<String>,
+	pub(crate) issuer: Option<String>,
+	pub(crate) expiry_secs: Option<u64>,
+	/// The most items a single payment may be for; `None` for an offer of exactly one item.
+	pub(crate) quantity_max: Option<Quantity>,
+	pub(crate) single_use: bool,
+	/// A price given with `--currency` and `--amount`, as the currency code and the amount in its
+	/// smallest unit. It's converted to `amt_msat` at the current exchange rate before the offer
+	/// is built.
+	pub(crate) fiat_price: Option<(String, u64)>,
+	/// Where to show a QR code of the result, from `--qr` and `--qr-file <path>`.
+	pub(crate) qr: QrOptions,
+}
+
+impl OfferOptions {
+	/// Parses the arguments of `createoffer`. Descriptions and issuers containing spaces must be
+	/// quoted.
+	pub(crate) fn parse(args: &str) -> Result<Self, String> {
+		let mut args = invoice::split_quoted(args)?;
+		let qr = QrOptions::extract(&mut args)?;
+		let mut args = args.iter().map(|arg| arg.as_str()).peekable();
+		let mut options = OfferOptions { qr, ..OfferOptions::default() };
+		let (mut currency, mut fiat_amount) = (None, None);
+		match args.next_if(|arg| !arg.starts_with("--")) {
+			Some("any") | None => {},
+			Some(amt) => match amt.parse() {
+				Ok(amt_msat) => options.amt_msat = Some(amt_msat),
+				Err(e) => return Err(format!("couldn't parse amount {}: {}", amt, e)),
+			},
+		}
+		while let Some(flag) = args.next() {
+			if flag == "--single-use" {
+				options.single_use = true;
+				continue;
+			}
+			let value = args.next().ok_or(format!("{} requires a value", flag))?;
+			match flag {
+				"--description" => options.description = Some(value.to_string()),
+				"--issuer" => options.issuer = Some(value.to_string()),
+				"--expiry" => match value.parse() {
+					Ok(secs) => options.expiry_secs = Some(secs),
+					Err(e) => return Err(format!("couldn't parse --expiry {}: {}", value, e)),
+				},
+				"--quantity-max" if value == "unbounded" => {
+					options.quantity_max = Some(Quantity::Unbounded)
+				},
+				"--quantity-max" => match value.parse::<u64>() {
+					Ok(1) => options.quantity_max = Some(Quantity::One),
+					Ok(max) if max > 1 => {
+						let max = NonZeroU64::new(max).unwrap();
+						options.quantity_max = Some(Quantity::Bounded(max));
+					},
+					_ => return Err(format!("couldn't parse --quantity-max {}", value)),
+				},
+				"--currency"
+					if value.len() == 3 && value.chars().all(|c| c.is_ascii_alphabetic()) =>
+				{
+					currency = Some(value.to_ascii_uppercase())
+				},
+				"--currency" => return Err(format!("{} is not an ISO 4217 currency code", value)),
+				"--amount" => fiat_amount = Some(value),
+				_ => return Err(format!("unknown option {}", flag)),
+			}
+		}
+		match (currency, fiat_amount) {
+			(Some(_), _) | (_, Some(_)) if options.amt_msat.is_some() => {
+				return Err("give either an amount in msats or --currency and --amount".to_string())
+			},
+			(Some(currency), Some(amount)) => {
+				let amount = exchange_rate::parse_fiat_amount(&currency, amount)?;
+				options.fiat_price = Some((currency, amount));
+			},
+			(None, None) => {},
+			_ => return Err("--currency and --amount must be given together".to_string()),
+		}
+		Ok(options)
+	}
+}
+
+/// Builds an offer with the given options and starts keeping track of it.
+pub(crate) fn create_offer(
+	channel_manager: &ChannelManager, options: &OfferOptions, offers: &mut OfferStorage,
+	fs_store: &FilesystemStore,
+) -> Result<Offer, String> {
+	let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+	let absolute_expiry = options.expiry_secs.map(|secs| now + Duration::from_secs(secs));
+	let mut builder = channel_manager
+		.create_offer_builder(absolute_expiry)
+		.map_err(|e| format!("failed to initiate offer building: {:?}", e))?;
+	if let Some(amt_msat) = options.amt_msat {
+		builder = builder.amount_msats(amt_msat);
+	}
+	if let Some(description) = &options.description {
+		builder = builder.description(description.clone());
+	}
+	if let Some(issuer) = &options.issuer {
+		builder = builder.issuer(issuer.clone());
+	}
+	if let Some(quantity) = options.quantity_max {
+		builder = builder.supported_quantity(quantity);
+	}
+	let offer = builder.build().map_err(|e| format!("failed to build offer: {:?}", e))?;
+	offers.offers.insert(
+		offer.id().0,
+		OfferInfo {
+			offer: offer.to_string(),
+			single_use: options.single_use,
+			disabled: false,
+			created_at: now.as_secs(),
+			num_payments: 0,
+			total_received_msat: 0,
+			fiat_price: options.fiat_price.as_ref().map(|(currency, amount)| {
+				format!("{} {}", exchange_rate::format_fiat_amount(currency, *amount), currency)
+			}),
+		},
+	);
+	offers.persist(fs_store);
+	Ok(offer)
+}
+
+pub(crate) fn list_offers(offers: &OfferStorage) {
+	let mut offers = offers.offers.iter().collect::<Vec<_>>();
+	offers.sort_unstable_by_key(|(_, info)| info.created_at);
+	print!("[");
+	for (offer_id, info) in offers {
+		println!();
+		println!("\t{{");
+		println!("\t\toffer_id: {},", hex_utils::hex_str(offer_id));
+		println!("\t\toffer: {},", info.offer);
+		let offer = info.decode();
+		if let Some(offer) = &offer {
+			match offer.amount() {
+				Some(Amount::Bitcoin { amount_msats }) => {
+					println!("\t\tamount_millisatoshis: {},", amount_msats)
+				},
+				Some(Amount::Currency { iso4217_code, amount }) => {
+					println!("\t\tamount: {} {},", amount, String::from_utf8_lossy(&iso4217_code))
+				},
+				None => println!("\t\tamount_millisatoshis: any,"),
+			}
+			if let Some(fiat_price) = &info.fiat_price {
+				println!("\t\tconverted_from: {},", fiat_price);
+			}
+			if let Some(description) = offer.description() {
+				println!("\t\tdescription: {},", description);
+			}
+			if let Some(issuer) = offer.issuer() {
+				println!("\t\tissuer: {},", issuer);
+			}
+			match offer.supported_quantity() {
+				Quantity::One => {},
+				Quantity::Bounded(max) => println!("\t\tquantity_max: {},", max),
+				Quantity::Unbounded => println!("\t\tquantity_max: unbounded,"),
+			}
+			if let Some(expiry) = offer.absolute_expiry() {
+				println!("\t\texpires_at: {},", expiry.as_secs());
+			}
+		}
+		println!("\t\tsingle_use: {},", info.single_use);
+		let status = if info.disabled {
+			"disabled"
+		} else if offer.as_ref().is_some_and(|offer| offer.is_expired()) {
+			"expired"
+		} else {
+			"active"
+		};
+		println!("\t\tstatus: {},", status);
+		println!("\t\tnum_payments: {},", info.num_payments);
+		println!("\t\ttotal_received_msat: {},", info.total_received_msat);
+		println!("\t}},");
+	}
+	println!("]");
+}
+
+/// How long a refund can be claimed for when `createrefund` isn't given an expiry. Refunds are
+/// usually handed to customers to claim in their own time, so this is longer than for invoices.
+pub(crate) const DEFAULT_REFUND_EXPIRY_SECS: u64 = 24 * 60 * 60;
+
+/// The options `createrefund` takes.
+#[derive(Debug, PartialEq)]
+pub(crate) struct RefundOptions {
+	pub(crate) amt_msat: u64,
+	pub(crate) description: Option<String>,
+	pub(crate) expiry_secs: u64,
+}
+
+impl RefundOptions {
+	/// Parses the arguments of `createrefund`. Descriptions containing spaces must be quoted.
+	pub(crate) fn parse(args: &str) -> Result<Self, String> {
+		let args = invoice::split_quoted(args)?;
+		let mut args = args.iter().map(|arg| arg.as_str());
+		let amt_msat = match args.next() {
+			Some(amt) => {
+				amt.parse().map_err(|e| format!("couldn't parse amount {}: {}", amt, e))?
+			},
+			None => return Err("createrefund requires an amount in millisatoshis".to_string()),
+		};
+		let mut options =
+			RefundOptions { amt_msat, description: None, expiry_secs: DEFAULT_REFUND_EXPIRY_SECS };
+		while let Some(flag) = args.next() {
+			let value = args.next().ok_or(format!("{} requires a value", flag))?;
+			match flag {
+				"--description" => options.description = Some(value.to_string()),
+				"--expiry" => match value.parse() {
+					Ok(secs) => options.expiry_secs = secs,
+					Err(e) => return Err(format!("couldn't parse --expiry {}: {}", value, e)),
+				},
+				_ => return Err(format!("unknown option {}", flag)),
+			}
+		}
+		Ok(options)
+	}
+}
+
+/// Finds one of our offers by its id or the offer string itself.
+pub(crate) fn find_offer(offers: &OfferStorage, offer: &str) -> Option<OfferId> {
+	if let Some(id) = hex_utils::to_vec(offer).filter(|id| id.len() == 32) {
+		let mut offer_id = [0; 32];
+		offer_id.copy_from_slice(&id);
+		return offers.offers.get(&offer_id).map(|_| OfferId(offer_id));
+	}
+	let offer_id = Offer::from_str(offer).ok()?.id();
+	offers.offers.get(&offer_id.0).map(|_| offer_id)
+}
+
+/// With manual BOLT12 invoice handling enabled, decides which invoices for offers we pay are paid
+/// straight away. The rest wait for `approveinvoice` or `rejectinvoice`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Bolt12InvoicePolicy {
+	/// Invoices for at most this much may be paid without asking. If unset, every invoice waits.
+	pub(crate) auto_pay_max_msat: Option<u64>,
+	/// If not empty, only invoices signed by one of these keys may be paid without asking.
+	pub(crate) trusted_payees: Vec<PublicKey>,
+}
+
+impl Bolt12InvoicePolicy {
+	/// Returns why `invoice` can't be paid without asking, if it can't.
+	pub(crate) fn check(&self, invoice: &Bolt12Invoice) -> Result<(), String> {
+		let max_msat = self.auto_pay_max_msat.ok_or("no auto-pay limit is set")?;
+		if invoice.amount_msats() > max_msat {
+			return Err(format!(
+				"its amount of {} msat is above the auto-pay limit of {} msat",
+				invoice.amount_msats(),
+				max_msat
+			));
+		}
+		let payee = invoice.signing_pubkey();
+		if !self.trusted_payees.is_empty() && !self.trusted_payees.contains(&payee) {
+			return Err(format!("its payee {} is not one we trust", payee));
+		}
+		if invoice.is_expired() {
+			return Err("it has expired".to_string());
+		}
+		Ok(())
+	}
+}
+
+pub(crate) const PENDING_BOLT12_INVOICES_KEY: &str = "pending_bolt12_invoices";
+
+struct HeldBolt12Invoice {
+	invoice: Bolt12Invoice,
+	context: Option<OffersContext>,
+}
+
+impl_writeable_tlv_based!(HeldBolt12Invoice, {
+	(0, invoice, required),
+	(2, context, option),
+});
+
+#[derive(Default)]
+struct HeldBolt12Invoices {
+	invoices: HashMap<PaymentId, HeldBolt12Invoice>,
+}
+
+impl_writeable_tlv_based!(HeldBolt12Invoices, {
+	(0, invoices, required),
+});
+
+/// Received BOLT12 invoices waiting for the user to approve or reject them. LDK never gives up on
+/// a payment once it has handed us its invoice, so these are persisted to still be answerable
+/// after a restart.
+pub(crate) struct PendingBolt12Invoices {
+	held: Mutex<HeldBolt12Invoices>,
+	fs_store: Arc<FilesystemStore>,
+}
+
+pub(crate) fn read_pending_bolt12_invoices(
+	fs_store: Arc<FilesystemStore>,
+) -> PendingBolt12Invoices {
+	let held = match fs_store.read("", "", PENDING_BOLT12_INVOICES_KEY) {
+		Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes)).unwrap_or_default(),
+		Err(_) => HeldBolt12Invoices::default(),
+	};
+	PendingBolt12Invoices { held: Mutex::new(held), fs_store }
+}
+
+impl PendingBolt12Invoices {
+	fn persist(&self, held: &HeldBolt12Invoices) {
+		self.fs_store.write("", "", PENDING_BOLT12_INVOICES_KEY, &held.encode()).unwrap();
+	}
+
+	pub(crate) fn hold(
+		&self, payment_id: PaymentId, invoice: Bolt12Invoice, context: Option<OffersContext>,
+	) {
+		let mut held = self.held.lock().unwrap();
+		held.invoices.insert(payment_id, HeldBolt12Invoice { invoice, context });
+		self.persist(&held);
+	}
+
+	/// Drops the invoice held for `payment_id`, if any, once its payment has failed.
+	pub(crate) fn forget(&self, payment_id: &PaymentId) {
+		let mut held = self.held.lock().unwrap();
+		if held.invoices.remove(payment_id).is_some() {
+			self.persist(&held);
+		}
+	}
+
+	pub(crate) fn approve(
+		&self, channel_manager: &ChannelManager, payment_id: &PaymentId,
+	) -> Result<(), String> {
+		let mut held = self.held.lock().unwrap();
+		let HeldBolt12Invoice { invoice, context } = held
+			.invoices
+			.remove(payment_id)
+			.ok_or(format!("no invoice is waiting for approval for payment {}", payment_id))?;
+		self.persist(&held);
+		channel_manager
+			.send_payment_for_bolt12_invoice(&invoice, context.as_ref())
+			.map_err(|e| format!("failed to pay invoice: {:?}", e))
+	}
+
+	/// Rejects the invoice by abandoning its payment, which then fails as usual. The payment is
+	/// abandoned even if we aren't holding its invoice, so that it can't be left waiting on us.
+	pub(crate) fn reject(&self, channel_manager: &ChannelManager, payment_id: &PaymentId) {
+		let mut held = self.held.lock().unwrap();
+		if held.invoices.remove(payment_id).is_some() {
+			self.persist(&held);
+		}
+		channel_manager.abandon_payment(*payment_id);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::secp256k1::{Keypair, Secp256k1, SecretKey};
+	use lightning::blinded_path::payment::{
+		BlindedPaymentPath, Bolt12RefundContext, PaymentConstraints, PaymentContext, ReceiveTlvs,
+	};
+	use lightning::offers::invoice::UnsignedBolt12Invoice;
+	use lightning::offers::refund::RefundBuilder;
+	use lightning::sign::KeysManager;
+	use lightning::types::payment::{PaymentHash, PaymentSecret};
+
+	/// An invoice for `amount_msats` signed by the key derived from `payee_secret`, created
+	/// `created_secs_ago` seconds ago and expiring an hour after that.
+	fn bolt12_invoice(amount_msats: u64, payee_secret: u8, created_secs_ago: u64) -> Bolt12Invoice {
+		let secp_ctx = Secp256k1::new();
+		let payer =
+			PublicKey::from_secret_key(&secp_ctx, &SecretKey::from_slice(&[1; 32]).unwrap());
+		let payee = Keypair::from_secret_key(
+			&secp_ctx,
+			&SecretKey::from_slice(&[payee_secret; 32]).unwrap(),
+		);
+		let refund = RefundBuilder::new(vec![1; 32], payer, amount_msats).unwrap().build().unwrap();
+		let keys_manager = KeysManager::new(&[2; 32], 0, 0);
+		let tlvs = ReceiveTlvs {
+			payment_secret: PaymentSecret([3; 32]),
+			payment_constraints: PaymentConstraints {
+				max_cltv_expiry: u32::MAX,
+				htlc_minimum_msat: 1,
+			},
+			payment_context: PaymentContext::Bolt12Refund(Bolt12RefundContext {}),
+		};
+		let path =
+			BlindedPaymentPath::one_hop(payee.public_key(), tlvs, 144, &keys_manager, &secp_ctx)
+				.unwrap();
+		let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
+		refund
+			.respond_with_no_std(
+				vec![path],
+				PaymentHash([4; 32]),
+				payee.public_key(),
+				now - Duration::from_secs(created_secs_ago),
+			)
+			.unwrap()
+			.relative_expiry(3600)
+			.build()
+			.unwrap()
+			.sign(|message: &UnsignedBolt12Invoice| {
+				Ok(secp_ctx.sign_schnorr_no_aux_rand(message.as_ref().as_digest(), &payee))
+			})
+			.unwrap()
+	}
+
+	#[test]
+	fn test_bolt12_invoice_policy() {
+		let invoice = bolt12_invoice(5000, 42, 0);
+		assert!(Bolt12InvoicePolicy::default().check(&invoice).is_err());
+
+		let policy = Bolt12InvoicePolicy { auto_pay_max_msat: Some(5000), trusted_payees: vec![] };
+		assert!(policy.check(&invoice).is_ok());
+		assert!(policy.check(&bolt12_invoice(5001, 42, 0)).is_err());
+		assert!(policy.check(&bolt12_invoice(5000, 42, 7200)).is_err());
+
+		let trusted = invoice.signing_pubkey();
+		let policy =
+			Bolt12InvoicePolicy { auto_pay_max_msat: Some(5000), trusted_payees: vec![trusted] };
+		assert!(policy.check(&invoice).is_ok());
+		assert!(policy.check(&bolt12_invoice(5000, 43, 0)).is_err());
+	}
+
+	#[test]
+	fn test_pending_bolt12_invoices_survive_restart() {
+		let data_dir = std::env::temp_dir().join("ldk_pending_bolt12_invoices_test");
+		let _ = std::fs::remove_dir_all(&data_dir);
+		let fs_store = Arc::new(FilesystemStore::new(data_dir));
+		let pending = read_pending_bolt12_invoices(Arc::clone(&fs_store));
+		pending.hold(PaymentId([1; 32]), bolt12_invoice(5000, 42, 0), None);
+		pending.hold(PaymentId([2; 32]), bolt12_invoice(6000, 42, 0), None);
+		pending.forget(&PaymentId([2; 32]));
+
+		let reread = read_pending_bolt12_invoices(fs_store);
+		let held = reread.held.lock().unwrap();
+		assert_eq!(held.invoices.len(), 1);
+		assert_eq!(held.invoices[&PaymentId([1; 32])].invoice.amount_msats(), 5000);
+	}
+
+	#[test]
+	fn test_parse_offer_options() {
+		assert_eq!(OfferOptions::parse("").unwrap(), OfferOptions::default());
+		assert_eq!(OfferOptions::parse("any").unwrap(), OfferOptions::default());
+
+		let args = "5000 --description \"two coffees\" --issuer cafe --expiry 3600 \
+			--quantity-max 10 --single-use";
+		let options = OfferOptions::parse(args).unwrap();
+		assert_eq!(options.amt_msat, Some(5000));
+		assert_eq!(options.description.as_deref(), Some("two coffees"));
+		assert_eq!(options.issuer.as_deref(), Some("cafe"));
+		assert_eq!(options.expiry_secs, Some(3600));
+		assert_eq!(options.quantity_max, Some(Quantity::Bounded(NonZeroU64::new(10).unwrap())));
+		assert!(options.single_use);
+
+		let options = OfferOptions::parse("--quantity-max unbounded").unwrap();
+		assert_eq!(options.quantity_max, Some(Quantity::Unbounded));
+		let options = OfferOptions::parse("--quantity-max 1").unwrap();
+		assert_eq!(options.quantity_max, Some(Quantity::One));
+
+		assert!(OfferOptions::parse("lots").is_err());
+		assert!(OfferOptions::parse("--quantity-max 0").is_err());
+		assert!(OfferOptions::parse("--expiry").is_err());
+		assert!(OfferOptions::parse("--bogus 1").is_err());
+
+		let options = OfferOptions::parse("--currency usd --amount 5.00").unwrap();
+		assert_eq!(options.fiat_price, Some(("USD".to_string(), 500)));
+		assert_eq!(options.amt_msat, None);
+		assert!(OfferOptions::parse("--currency USD").is_err());
+		assert!(OfferOptions::parse("--amount 5.00").is_err());
+		assert!(OfferOptions::parse("5000 --currency USD --amount 5.00").is_err());
+		assert!(OfferOptions::parse("--currency dollars --amount 5.00").is_err());
+		assert!(OfferOptions::parse("--currency USD --amount 5.001").is_err());
+	}
+
+	#[test]
+	fn test_parse_refund_options() {
+		let options = RefundOptions::parse("5000").unwrap();
+		assert_eq!(options.amt_msat, 5000);
+		assert_eq!(options.description, None);
+		assert_eq!(options.expiry_secs, DEFAULT_REFUND_EXPIRY_SECS);
+
+		let options = RefundOptions::parse("5000 --description \"order 42\" --expiry 600").unwrap();
+		assert_eq!(options.description.as_deref(), Some("order 42"));
+		assert_eq!(options.expiry_secs, 600);
+
+		assert!(RefundOptions::parse("").is_err());
+		assert!(RefundOptions::parse("--expiry 600").is_err());
+		assert!(RefundOptions::parse("5000 --quantity-max 2").is_err());
+	}
+
+	#[test]
+	fn test_record_payment() {
+		let offer_id = OfferId([1; 32]);
+		let mut offers = OfferStorage::default();
+		offers.offers.insert(
+			offer_id.0,
+			OfferInfo {
+				offer: String::new(),
+				single_use: true,
+				disabled: false,
+				created_at: 0,
+				num_payments: 0,
+				total_received_msat: 0,
+				fiat_price: None,
+			},
+		);
+		assert!(offers.accepts_payment(&offer_id));
+		assert!(offers.record_payment(&offer_id, 1000));
+		assert_eq!(offers.offers[&offer_id.0].total_received_msat, 1000);
+		assert!(!offers.accepts_payment(&offer_id));
+		assert!(offers.accepts_payment(&OfferId([2; 32])));
+		assert!(!offers.record_payment(&OfferId([2; 32]), 1000));
+	}
+}
@@ -14,7 +14,7 @@ use bdk_wallet::{AddressInfo, Balance};
 use bitcoin::address::Address;
 use bitcoin::blockdata::constants::WITNESS_SCALE_FACTOR;
 use bitcoin::blockdata::locktime::absolute::LockTime;
-use bitcoin::blockdata::script::ScriptBuf;
+use bitcoin::blockdata::script::{Script, ScriptBuf};
 use bitcoin::blockdata::transaction::Transaction;
 use bitcoin::hashes::Hash;
 use bitcoin::key::XOnlyPublicKey;
@@ -267,6 +267,44 @@ where
 
 		balance
 	}
+
+	/// Gets the total received to `script` in confirmed transactions, whether or not it has
+	/// since been spent
+	pub fn received_to(&self, script: &Script) -> Amount {
+		let locked_wallet = self.inner.lock().unwrap();
+
+		locked_wallet
+			.list_output()
+			.filter(|output| output.chain_position.is_confirmed())
+			.filter(|output| output.txout.script_pubkey.as_script() == script)
+			.map(|output| output.txout.value)
+			.sum()
+	}
+
+	/// Creates a transaction paying `amount` to `script_pubkey`
+	///
+	/// The transaction is signed but not broadcast.
+	pub fn create_send_transaction(
+		&self, script_pubkey: ScriptBuf, amount: Amount, confirmation_target: ConfirmationTarget,
+	) -> Result<(Transaction, Amount), String> {
+		let mut wallet = self.inner.lock().unwrap();
+
+		let fee_rate = self.fee_estimator.get_est_sat_per_1000_weight(confirmation_target) as u64;
+		let fees = FeeRate::from_sat_per_kwu(fee_rate);
+
+		let mut tx_builder = wallet.build_tx();
+		tx_builder.add_recipient(script_pubkey, amount).fee_rate(fees);
+		let mut psbt =
+			tx_builder.finish().map_err(|e| format!("failed to build transaction: {}", e))?;
+
+		wallet
+			.sign(&mut psbt, SignOptions::default())
+			.map_err(|e| format!("failed to sign transaction: {}", e))?;
+
+		let fee = psbt.fee().map_err(|e| format!("failed to compute fee: {}", e))?;
+		let tx = psbt.extract_tx().map_err(|e| format!("failed to extract transaction: {}", e))?;
+		Ok((tx, fee))
+	}
 }
 
 /// Implementation of LDK's WalletSource trait
@@ -0,0 +1,279 @@
+use crate::filesystem_store::FilesystemStore;
+use crate::logger::FilesystemLogger;
+use crate::{ChannelManager, NetworkGraph};
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::channelmanager::{PaymentId, ProbeSendFailure};
+use lightning::routing::gossip::{NodeId, ReadOnlyNetworkGraph};
+use lightning::routing::router::{Path, RouteParameters};
+use lightning::util::logger::Logger;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable};
+use lightning::{log_debug, log_info};
+use rand::{thread_rng, Rng};
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How many of the best-connected nodes in the graph the background prober picks targets from.
+const POPULAR_DESTINATION_COUNT: usize = 100;
+
+/// The CLTV delta we ask for at the final hop of background probes, matching keysend.
+const PROBE_FINAL_CLTV_EXPIRY_DELTA: u32 = 40;
+
+/// Settings for the background prober, which probes well-connected nodes to train the scorer.
+#[derive(Clone, Debug)]
+pub(crate) struct ProbingConfig {
+	/// How long to wait between probes.
+	pub(crate) interval: Duration,
+	/// The amount each probe tries to deliver.
+	pub(crate) amount_msat: u64,
+	/// The most we allow to be tied up in background probe HTLCs at any one time.
+	pub(crate) max_locked_msat: u64,
+}
+
+impl Default for ProbingConfig {
+	fn default() -> Self {
+		Self {
+			interval: Duration::from_secs(10 * 60),
+			amount_msat: 50_000_000,
+			max_locked_msat: 500_000_000,
+		}
+	}
+}
+
+pub(crate) const BACKGROUND_PROBES_KEY: &str = "background_probes";
+
+/// Probes sent by the background prober that haven't resolved yet.
+///
+/// These are persisted so that probe HTLCs still in flight when we restart keep counting against
+/// the budget until their `ProbeSuccessful`/`ProbeFailed` event arrives.
+#[derive(Default)]
+struct BackgroundProbes {
+	payment_ids: HashSet<PaymentId>,
+	locked_msat: u64,
+}
+
+impl_writeable_tlv_based!(BackgroundProbes, {
+	(0, payment_ids, required),
+	(2, locked_msat, required),
+});
+
+impl BackgroundProbes {
+	fn has_budget_for(&self, config: &ProbingConfig) -> bool {
+		self.locked_msat + config.amount_msat <= config.max_locked_msat
+	}
+
+	fn sent(&mut self, payment_ids: impl Iterator<Item = PaymentId>, amount_msat: u64) {
+		let num_before = self.payment_ids.len();
+		self.payment_ids.extend(payment_ids);
+		if self.payment_ids.len() > num_before {
+			self.locked_msat += amount_msat;
+		}
+	}
+
+	/// Returns whether `payment_id` was one of ours.
+	fn finished(&mut self, payment_id: &PaymentId, value_msat: u64) -> bool {
+		if !self.payment_ids.remove(payment_id) {
+			return false;
+		}
+		self.locked_msat = self.locked_msat.saturating_sub(value_msat);
+		true
+	}
+}
+
+#[derive(Default)]
+struct ProbeState {
+	/// Probes sent from the `probe` command, whose outcome is reported on the console.
+	requested: HashSet<PaymentId>,
+	background: BackgroundProbes,
+}
+
+/// Sends probes and keeps track of which ones are still in flight.
+///
+/// Probe results are fed into the `ProbabilisticScorer` by the background processor, so all we
+/// need to do here is decide what to probe and report back on probes the user asked for.
+pub(crate) struct Prober {
+	channel_manager: Arc<ChannelManager>,
+	network_graph: Arc<NetworkGraph>,
+	logger: Arc<FilesystemLogger>,
+	fs_store: Arc<FilesystemStore>,
+	state: Mutex<ProbeState>,
+}
+
+impl Prober {
+	pub(crate) fn new(
+		channel_manager: Arc<ChannelManager>, network_graph: Arc<NetworkGraph>,
+		logger: Arc<FilesystemLogger>, fs_store: Arc<FilesystemStore>,
+	) -> Self {
+		let background = match fs_store.read("", "", BACKGROUND_PROBES_KEY) {
+			Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes)).unwrap_or_default(),
+			Err(_) => BackgroundProbes::default(),
+		};
+		let state = Mutex::new(ProbeState { requested: HashSet::new(), background });
+		Self { channel_manager, network_graph, logger, fs_store, state }
+	}
+
+	fn persist_background_probes(&self, background: &BackgroundProbes) {
+		self.fs_store.write("", "", BACKGROUND_PROBES_KEY, &background.encode()).unwrap();
+	}
+
+	/// Probes every path the router would use to pay `route_params`, returning how many probes
+	/// were sent.
+	pub(crate) fn probe(&self, route_params: RouteParameters) -> Result<usize, String> {
+		let probes = self
+			.channel_manager
+			.send_preflight_probes(route_params, None)
+			.map_err(|e| probe_send_failure_str(&e))?;
+		let mut state = self.state.lock().unwrap();
+		state.requested.extend(probes.iter().map(|(_, payment_id)| *payment_id));
+		Ok(probes.len())
+	}
+
+	/// Marks the probe as finished, returning whether it was one the user asked for (and so should
+	/// be reported) rather than a background probe.
+	pub(crate) fn probe_finished(&self, payment_id: &PaymentId, path: &Path) -> bool {
+		let mut state = self.state.lock().unwrap();
+		if state.background.finished(payment_id, path.final_value_msat()) {
+			self.persist_background_probes(&state.background);
+		}
+		state.requested.remove(payment_id)
+	}
+
+	/// Sends probes to a randomly chosen well-connected node, unless doing so would exceed the
+	/// locked-liquidity budget.
+	pub(crate) fn probe_popular_destination(&self, config: &ProbingConfig) {
+		{
+			let state = self.state.lock().unwrap();
+			if !state.background.has_budget_for(config) {
+				log_debug!(self.logger, "Skipping background probe, probe budget exhausted");
+				return;
+			}
+		}
+		let our_node_id = NodeId::from_pubkey(&self.channel_manager.get_our_node_id());
+		let destinations = popular_destinations(&self.network_graph.read_only(), &our_node_id);
+		let destination = match thread_rng().choose(&destinations) {
+			Some(destination) => *destination,
+			None => return,
+		};
+		let pubkey = match destination.as_pubkey() {
+			Ok(pubkey) => pubkey,
+			Err(_) => return,
+		};
+		match self.channel_manager.send_spontaneous_preflight_probes(
+			pubkey,
+			config.amount_msat,
+			PROBE_FINAL_CLTV_EXPIRY_DELTA,
+			None,
+		) {
+			Ok(probes) => {
+				log_info!(
+					self.logger,
+					"Sent {} background probe(s) for {} msat to {}",
+					probes.len(),
+					config.amount_msat,
+					pubkey
+				);
+				let mut state = self.state.lock().unwrap();
+				let payment_ids = probes.iter().map(|(_, payment_id)| *payment_id);
+				state.background.sent(payment_ids, config.amount_msat);
+				self.persist_background_probes(&state.background);
+			},
+			Err(e) => log_debug!(
+				self.logger,
+				"Failed to send background probe to {}: {}",
+				pubkey,
+				probe_send_failure_str(&e)
+			),
+		}
+	}
+}
+
+/// The nodes with the most channels in our network graph, excluding ourselves.
+fn popular_destinations(graph: &ReadOnlyNetworkGraph, our_node_id: &NodeId) -> Vec<NodeId> {
+	let mut nodes = graph
+		.nodes()
+		.unordered_iter()
+		.filter(|(node_id, _)| *node_id != our_node_id)
+		.map(|(node_id, node)| (*node_id, node.channels.len()))
+		.collect::<Vec<_>>();
+	nodes.sort_unstable_by_key(|(_, num_channels)| std::cmp::Reverse(*num_channels));
+	nodes.truncate(POPULAR_DESTINATION_COUNT);
+	nodes.into_iter().map(|(node_id, _)| node_id).collect()
+}
+
+fn probe_send_failure_str(failure: &ProbeSendFailure) -> String {
+	match failure {
+		ProbeSendFailure::RouteNotFound => "no route found".to_string(),
+		ProbeSendFailure::SendingFailed(e) => format!("failed to send probe: {:?}", e),
+	}
+}
+
+/// Periodically probes well-connected nodes so the scorer learns about liquidity before we need
+/// to route real payments through them.
+pub(crate) async fn run_background_prober(prober: Arc<Prober>, config: ProbingConfig) {
+	let mut interval = tokio::time::interval(config.interval);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+	// The first tick completes immediately; give ourselves a chance to connect to peers first.
+	interval.tick().await;
+	loop {
+		interval.tick().await;
+		prober.probe_popular_destination(&config);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{add_channel, node_key, test_network_graph};
+
+	#[test]
+	fn test_popular_destinations() {
+		// Node 1 (us) has the most channels, then node 2, then node 3; node 4 has just one.
+		let graph = test_network_graph();
+		let channels = [(1, 2), (1, 3), (1, 4), (1, 5), (2, 3), (2, 5), (3, 5)];
+		for (scid, (a, b)) in channels.iter().enumerate() {
+			add_channel(&graph, scid as u64 + 1, &node_key(*a), &node_key(*b), 0, 0, 40);
+		}
+		let our_node_id = NodeId::from_pubkey(&node_key(1));
+		let destinations = popular_destinations(&graph.read_only(), &our_node_id);
+		assert!(!destinations.contains(&our_node_id));
+		assert_eq!(destinations.len(), 4);
+		assert_eq!(destinations[3], NodeId::from_pubkey(&node_key(4)));
+		let node_ids_with_three = [2, 3, 5].iter().map(|n| NodeId::from_pubkey(&node_key(*n)));
+		assert_eq!(
+			destinations[..3].iter().copied().collect::<HashSet<_>>(),
+			node_ids_with_three.collect::<HashSet<_>>()
+		);
+	}
+
+	#[test]
+	fn test_background_probe_budget() {
+		let config =
+			ProbingConfig { amount_msat: 40_000, max_locked_msat: 100_000, ..Default::default() };
+		let mut probes = BackgroundProbes::default();
+		assert!(probes.has_budget_for(&config));
+
+		// A probe that went out over two paths locks up its amount once.
+		probes.sent(vec![PaymentId([1; 32]), PaymentId([2; 32])].into_iter(), config.amount_msat);
+		probes.sent(std::iter::once(PaymentId([3; 32])), config.amount_msat);
+		assert_eq!(probes.locked_msat, 80_000);
+		assert!(!probes.has_budget_for(&config));
+		// Failing to send anything doesn't use up any budget.
+		probes.sent(std::iter::empty(), config.amount_msat);
+		assert_eq!(probes.locked_msat, 80_000);
+
+		assert!(!probes.finished(&PaymentId([4; 32]), 40_000));
+		assert!(probes.finished(&PaymentId([1; 32]), 25_000));
+		assert!(probes.finished(&PaymentId([2; 32]), 15_000));
+		assert!(!probes.finished(&PaymentId([2; 32]), 15_000));
+		assert_eq!(probes.locked_msat, 40_000);
+		assert!(probes.has_budget_for(&config));
+
+		// Probes still in flight survive a restart.
+		let probes: BackgroundProbes =
+			Readable::read(&mut io::Cursor::new(probes.encode())).unwrap();
+		assert_eq!(probes.locked_msat, 40_000);
+		assert_eq!(probes.payment_ids, std::iter::once(PaymentId([3; 32])).collect());
+	}
+}
@@ -0,0 +1,198 @@
+use crate::bip21::UnifiedUri;
+use qrcode::render::{svg, unicode};
+use qrcode::QrCode;
+use std::path::{Path, PathBuf};
+
+/// The smallest size, in pixels, we render QR code images at.
+const QR_IMAGE_MIN_SIZE: u32 = 512;
+
+/// Where to show a QR code for something we print, as given by `--qr` and `--qr-file <path>`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct QrOptions {
+	/// Print the QR code in the terminal.
+	pub(crate) terminal: bool,
+	/// Write the QR code to a `.png` or `.svg` file.
+	pub(crate) file: Option<PathBuf>,
+}
+
+impl QrOptions {
+	/// Removes `--qr` and `--qr-file <path>` from already-split command arguments, leaving the
+	/// rest for the command's own parser.
+	pub(crate) fn extract(args: &mut Vec<String>) -> Result<Self, String> {
+		let mut options = QrOptions::default();
+		let mut remaining = Vec::with_capacity(args.len());
+		let mut args_iter = args.drain(..);
+		while let Some(arg) = args_iter.next() {
+			match arg.as_str() {
+				"--qr" => options.terminal = true,
+				"--qr-file" => {
+					let path = args_iter.next().ok_or("--qr-file requires a path")?;
+					match Path::new(&path).extension().and_then(|ext| ext.to_str()) {
+						Some("png") | Some("svg") => options.file = Some(PathBuf::from(path)),
+						_ => return Err(format!("--qr-file {} must end in .png or .svg", path)),
+					}
+				},
+				_ => remaining.push(arg),
+			}
+		}
+		drop(args_iter);
+		*args = remaining;
+		Ok(options)
+	}
+
+	pub(crate) fn is_requested(&self) -> bool {
+		self.terminal || self.file.is_some()
+	}
+}
+
+/// Whether `data` is a bech32 string, which may be uppercased without changing its meaning.
+fn is_bech32(data: &str) -> bool {
+	let lower = data.to_ascii_lowercase();
+	let is_bech32_prefix = ["ln", "bc1", "tb1", "bcrt1"].iter().any(|p| lower.starts_with(p));
+	is_bech32_prefix && data.contains('1') && data.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Uppercases the parts of `data` that are case-insensitive, so that QR codes can use the more
+/// compact alphanumeric mode for them. Invoices, offers and bech32 addresses are uppercased
+/// whole. In `bitcoin:` URIs the scheme, a bech32 address and the `lightning` and `lno` values are
+/// uppercased, while other parameters, such as a percent-encoded message, are left as they are.
+pub(crate) fn uppercase_for_qr(data: &str) -> String {
+	if is_bech32(data) {
+		return data.to_ascii_uppercase();
+	}
+	if !UnifiedUri::is_uri(data) {
+		return data.to_string();
+	}
+	let mut parts = data[8..].splitn(2, '?');
+	let address = parts.next().unwrap();
+	let mut uri = "BITCOIN:".to_string();
+	if is_bech32(address) {
+		uri.push_str(&address.to_ascii_uppercase());
+	} else {
+		uri.push_str(address);
+	}
+	if let Some(params) = parts.next() {
+		let params = params.split('&').map(|param| {
+			let mut kv = param.splitn(2, '=');
+			let key = kv.next().unwrap();
+			match (key.to_ascii_lowercase().as_str(), kv.next()) {
+				("lightning", Some(value)) | ("lno", Some(value)) if is_bech32(value) => {
+					format!("{}={}", key.to_ascii_uppercase(), value.to_ascii_uppercase())
+				},
+				_ => param.to_string(),
+			}
+		});
+		uri.push('?');
+		uri.push_str(&params.collect::<Vec<_>>().join("&"));
+	}
+	uri
+}
+
+fn encode(data: &str) -> Result<QrCode, String> {
+	QrCode::new(uppercase_for_qr(data).as_bytes())
+		.map_err(|e| format!("couldn't encode QR code: {}", e))
+}
+
+/// Renders `data` as a QR code of Unicode half-blocks, two modules per character cell.
+pub(crate) fn render_terminal(data: &str) -> Result<String, String> {
+	// Terminals usually draw light text on a dark background, so the colours are swapped to
+	// keep the code dark-on-light.
+	Ok(encode(data)?
+		.render::<unicode::Dense1x2>()
+		.dark_color(unicode::Dense1x2::Light)
+		.light_color(unicode::Dense1x2::Dark)
+		.quiet_zone(true)
+		.build())
+}
+
+/// Writes `data` as a QR code to a PNG or SVG file, picked by the extension of `path`.
+pub(crate) fn write_file(data: &str, path: &Path) -> Result<(), String> {
+	let code = encode(data)?;
+	match path.extension().and_then(|ext| ext.to_str()) {
+		Some("png") => code
+			.render::<image::Luma<u8>>()
+			.min_dimensions(QR_IMAGE_MIN_SIZE, QR_IMAGE_MIN_SIZE)
+			.build()
+			.save(path)
+			.map_err(|e| format!("failed to write {}: {}", path.display(), e)),
+		Some("svg") => {
+			let svg = code
+				.render::<svg::Color>()
+				.min_dimensions(QR_IMAGE_MIN_SIZE, QR_IMAGE_MIN_SIZE)
+				.build();
+			std::fs::write(path, svg)
+				.map_err(|e| format!("failed to write {}: {}", path.display(), e))
+		},
+		_ => Err(format!("{} must end in .png or .svg", path.display())),
+	}
+}
+
+/// Shows `data` as a QR code wherever `options` asks for.
+pub(crate) fn show(data: &str, options: &QrOptions) {
+	if options.terminal {
+		match render_terminal(data) {
+			Ok(qr) => println!("{}", qr),
+			Err(e) => println!("ERROR: {}", e),
+		}
+	}
+	if let Some(path) = &options.file {
+		match write_file(data, path) {
+			Ok(()) => println!("SUCCESS: wrote QR code to {}", path.display()),
+			Err(e) => println!("ERROR: {}", e),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn args(args: &[&str]) -> Vec<String> {
+		args.iter().map(|arg| arg.to_string()).collect()
+	}
+
+	#[test]
+	fn test_extract_qr_options() {
+		let mut parsed = args(&["5000", "--qr", "--description", "coffee", "--qr-file", "a.svg"]);
+		let options = QrOptions::extract(&mut parsed).unwrap();
+		assert!(options.terminal);
+		assert_eq!(options.file, Some(PathBuf::from("a.svg")));
+		assert_eq!(parsed, args(&["5000", "--description", "coffee"]));
+
+		let mut parsed = args(&["5000"]);
+		assert!(!QrOptions::extract(&mut parsed).unwrap().is_requested());
+		assert!(QrOptions::extract(&mut args(&["--qr-file"])).is_err());
+		assert!(QrOptions::extract(&mut args(&["--qr-file", "a.jpg"])).is_err());
+	}
+
+	#[test]
+	fn test_uppercase_for_qr() {
+		assert_eq!(uppercase_for_qr("lnbcrt1pabc"), "LNBCRT1PABC");
+		assert_eq!(uppercase_for_qr("lno1qgsqvg"), "LNO1QGSQVG");
+		assert_eq!(uppercase_for_qr("bcrt1qw508d6"), "BCRT1QW508D6");
+		// Base58 addresses are case-sensitive.
+		assert_eq!(
+			uppercase_for_qr("mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"),
+			"mipcBbFg9gMiCh81Kj8tqqdgoZub1ZJRfn"
+		);
+		assert_eq!(
+			uppercase_for_qr(
+				"bitcoin:tb1qabc?amount=0.001&message=Two%20coffees&lightning=lntb1pxyz"
+			),
+			"BITCOIN:TB1QABC?amount=0.001&message=Two%20coffees&LIGHTNING=LNTB1PXYZ"
+		);
+	}
+
+	#[test]
+	fn test_render() {
+		let qr = render_terminal("lnbcrt1pabc").unwrap();
+		assert!(qr.lines().count() > 10);
+		assert!(qr.contains('█') || qr.contains('▀') || qr.contains('▄'));
+
+		let path = std::env::temp_dir().join("ldk_qr_test.svg");
+		write_file("lnbcrt1pabc", &path).unwrap();
+		assert!(std::fs::read_to_string(&path).unwrap().contains("<svg"));
+		std::fs::remove_file(&path).unwrap();
+		assert!(write_file("lnbcrt1pabc", Path::new("qr.gif")).is_err());
+	}
+}
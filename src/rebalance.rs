@@ -0,0 +1,433 @@
+use crate::filesystem_store::FilesystemStore;
+use crate::graph;
+use crate::logger::FilesystemLogger;
+use crate::routing::AvoidList;
+use crate::{ChannelManager, HTLCStatus, NetworkGraph, Scorer};
+use bitcoin::secp256k1::PublicKey;
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::channel_state::ChannelDetails;
+use lightning::ln::channelmanager::{PaymentId, RecipientOnionFields};
+use lightning::ln::types::ChannelId;
+use lightning::routing::gossip::{NodeId, ReadOnlyNetworkGraph, RoutingFees};
+use lightning::routing::router::{
+	find_route, Path, PaymentParameters, Route, RouteHop, RouteParameters,
+};
+use lightning::routing::scoring::ProbabilisticScoringFeeParameters;
+use lightning::types::features::{ChannelFeatures, NodeFeatures};
+use lightning::types::payment::PaymentHash;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable};
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+
+pub(crate) const REBALANCES_KEY: &str = "rebalances";
+
+/// The CLTV delta we require on the final hop back to ourselves.
+const REBALANCE_FINAL_CLTV_EXPIRY_DELTA: u32 = 40;
+
+/// How long the self-generated payment hash for a rebalance stays valid.
+const REBALANCE_INVOICE_EXPIRY_SECS: u32 = 60 * 60;
+
+pub(crate) struct RebalanceInfo {
+	pub(crate) from_channel: ChannelId,
+	pub(crate) to_channel: ChannelId,
+	pub(crate) payment_hash: PaymentHash,
+	pub(crate) amt_msat: u64,
+	/// The routing fee we expected to pay when we built the route.
+	pub(crate) expected_fee_msat: u64,
+	/// The routing fee actually paid, once the rebalance succeeds.
+	pub(crate) fee_paid_msat: Option<u64>,
+	pub(crate) status: HTLCStatus,
+	pub(crate) created_at: u64,
+}
+
+impl_writeable_tlv_based!(RebalanceInfo, {
+	(0, from_channel, required),
+	(2, to_channel, required),
+	(4, payment_hash, required),
+	(6, amt_msat, required),
+	(8, expected_fee_msat, required),
+	(10, fee_paid_msat, option),
+	(12, status, required),
+	(14, created_at, required),
+});
+
+/// Circular payments to ourselves, kept apart from `inbound_payments`/`outbound_payments` so they
+/// don't show up as money sent or received and their cost can be accounted for on its own.
+#[derive(Default)]
+pub(crate) struct RebalanceStorage {
+	pub(crate) rebalances: HashMap<PaymentId, RebalanceInfo>,
+}
+
+impl_writeable_tlv_based!(RebalanceStorage, {
+	(0, rebalances, required),
+});
+
+impl RebalanceStorage {
+	pub(crate) fn persist(&self, fs_store: &FilesystemStore) {
+		fs_store.write("", "", REBALANCES_KEY, &self.encode()).unwrap();
+	}
+
+	/// Whether `payment_hash` is the self-generated hash of one of our rebalances.
+	pub(crate) fn is_rebalance(&self, payment_hash: &PaymentHash) -> bool {
+		self.rebalances.values().any(|rebalance| rebalance.payment_hash == *payment_hash)
+	}
+}
+
+pub(crate) fn read_rebalances(fs_store: &FilesystemStore) -> RebalanceStorage {
+	match fs_store.read("", "", REBALANCES_KEY) {
+		Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes)).unwrap_or_default(),
+		Err(_) => RebalanceStorage::default(),
+	}
+}
+
+fn fee_msat(fees: &RoutingFees, amt_msat: u64) -> u64 {
+	fees.base_msat as u64 + amt_msat * fees.proportional_millionths as u64 / 1_000_000
+}
+
+/// The fees and CLTV delta `node` charges for forwarding over the public channel `scid`.
+fn forwarding_policy(
+	graph: &ReadOnlyNetworkGraph, scid: u64, node: &PublicKey,
+) -> Result<(RoutingFees, u32), String> {
+	let channel = graph.channel(scid).ok_or(format!("unknown channel {}", scid))?;
+	let node_id = NodeId::from_pubkey(node);
+	let update = if channel.node_one == node_id {
+		channel.one_to_two.as_ref()
+	} else {
+		channel.two_to_one.as_ref()
+	};
+	update
+		.map(|update| (update.fees, update.cltv_expiry_delta as u32))
+		.ok_or(format!("no forwarding policy known for {} on channel {}", node, scid))
+}
+
+fn node_features(graph: &ReadOnlyNetworkGraph, node: &PublicKey) -> NodeFeatures {
+	graph
+		.node(&NodeId::from_pubkey(node))
+		.and_then(|node| node.announcement_info.as_ref())
+		.map(|info| info.features().clone())
+		.unwrap_or_else(NodeFeatures::empty)
+}
+
+fn channel_features(graph: &ReadOnlyNetworkGraph, scid: u64) -> ChannelFeatures {
+	graph.channel(scid).map(|chan| chan.features.clone()).unwrap_or_else(ChannelFeatures::empty)
+}
+
+/// Builds circular routes from us, across the network and back to us.
+pub(crate) struct CircularRouter<'a> {
+	pub(crate) our_node_id: PublicKey,
+	pub(crate) our_node_features: NodeFeatures,
+	/// All of our own channels, which the middle of a route must stay off.
+	pub(crate) own_scids: Vec<u64>,
+	pub(crate) network_graph: &'a NetworkGraph,
+	pub(crate) scorer: &'a RwLock<Scorer>,
+	pub(crate) logger: Arc<FilesystemLogger>,
+	pub(crate) avoid_list: &'a AvoidList,
+}
+
+impl<'a> CircularRouter<'a> {
+	pub(crate) fn new(
+		channel_manager: &ChannelManager, network_graph: &'a NetworkGraph,
+		scorer: &'a RwLock<Scorer>, logger: Arc<FilesystemLogger>, avoid_list: &'a AvoidList,
+	) -> Self {
+		CircularRouter {
+			our_node_id: channel_manager.get_our_node_id(),
+			our_node_features: channel_manager.node_features(),
+			own_scids: channel_manager
+				.list_channels()
+				.iter()
+				.filter_map(|chan| chan.short_channel_id)
+				.collect(),
+			network_graph,
+			scorer,
+			logger,
+			avoid_list,
+		}
+	}
+
+	/// Builds a route that leaves through `from`, crosses the network to the peer of `to` and
+	/// comes back to us over `to`, staying clear of everything on the avoid-list.
+	///
+	/// The router refuses to route to ourselves, so we ask it for a route between the two peers
+	/// instead and add our own channels on either end.
+	pub(crate) fn build_route(
+		&self, from: &ChannelDetails, to: &ChannelDetails, amt_msat: u64,
+		random_seed_bytes: &[u8; 32],
+	) -> Result<Route, String> {
+		let out_peer = from.counterparty.node_id;
+		let in_peer = to.counterparty.node_id;
+		let out_scid =
+			from.get_outbound_payment_scid().ok_or("the outgoing channel isn't usable")?;
+		let in_scid = to.get_inbound_payment_scid().ok_or("the incoming channel isn't usable")?;
+		for (peer, scid) in [(&out_peer, out_scid), (&in_peer, in_scid)] {
+			if self.avoid_list.nodes.contains(&NodeId::from_pubkey(peer))
+				|| self.avoid_list.channels.contains(&scid)
+			{
+				return Err(format!(
+					"channel {} or its peer {} is on the avoid-list",
+					graph::scid_str(scid),
+					peer
+				));
+			}
+		}
+		let in_policy = to
+			.counterparty
+			.forwarding_info
+			.as_ref()
+			.ok_or("we don't know the incoming peer's forwarding policy yet")?;
+		let in_fees = RoutingFees {
+			base_msat: in_policy.fee_base_msat,
+			proportional_millionths: in_policy.fee_proportional_millionths,
+		};
+		let in_peer_fee_msat = fee_msat(&in_fees, amt_msat);
+
+		let graph = self.network_graph.read_only();
+		let mut hops = Vec::new();
+		if out_peer == in_peer {
+			hops.push(RouteHop {
+				pubkey: out_peer,
+				node_features: node_features(&graph, &out_peer),
+				short_channel_id: out_scid,
+				channel_features: channel_features(&graph, out_scid),
+				fee_msat: in_peer_fee_msat,
+				cltv_expiry_delta: in_policy.cltv_expiry_delta as u32,
+				maybe_announced_channel: from.is_announced,
+			});
+		} else {
+			// Route from the outgoing peer to the incoming one, delivering enough for the incoming
+			// peer's fee and keeping the route off our own channels and anything we avoid.
+			let payment_params = PaymentParameters::from_node_id(
+				in_peer,
+				in_policy.cltv_expiry_delta as u32 + REBALANCE_FINAL_CLTV_EXPIRY_DELTA,
+			);
+			let mut route_params = RouteParameters::from_payment_params_and_value(
+				payment_params,
+				amt_msat + in_peer_fee_msat,
+			);
+			route_params.payment_params.max_path_count = 1;
+			let failed_channels = &mut route_params.payment_params.previously_failed_channels;
+			failed_channels.extend(self.own_scids.iter().copied());
+			failed_channels.extend(self.avoid_list.excluded_channels(&graph, []));
+			let middle = find_route(
+				&out_peer,
+				&route_params,
+				self.network_graph,
+				None,
+				Arc::clone(&self.logger),
+				&*self.scorer.read().unwrap(),
+				&ProbabilisticScoringFeeParameters::default(),
+				random_seed_bytes,
+			)
+			.map_err(|e| format!("no route between the two peers: {}", e.err))?;
+			let mut middle_hops = middle.paths.into_iter().next().ok_or("no route found")?.hops;
+
+			let into_middle_msat = middle_hops.iter().map(|hop| hop.fee_msat).sum::<u64>();
+			let (out_fees, out_cltv_delta) =
+				forwarding_policy(&graph, middle_hops[0].short_channel_id, &out_peer)?;
+			hops.push(RouteHop {
+				pubkey: out_peer,
+				node_features: node_features(&graph, &out_peer),
+				short_channel_id: out_scid,
+				channel_features: channel_features(&graph, out_scid),
+				fee_msat: fee_msat(&out_fees, into_middle_msat),
+				cltv_expiry_delta: out_cltv_delta,
+				maybe_announced_channel: from.is_announced,
+			});
+			// The router treated the incoming peer as the recipient; it now forwards to us instead.
+			let last = middle_hops.last_mut().unwrap();
+			last.fee_msat = in_peer_fee_msat;
+			last.cltv_expiry_delta = in_policy.cltv_expiry_delta as u32;
+			hops.extend(middle_hops);
+		}
+		hops.push(RouteHop {
+			pubkey: self.our_node_id,
+			node_features: self.our_node_features.clone(),
+			short_channel_id: in_scid,
+			channel_features: channel_features(&graph, in_scid),
+			fee_msat: amt_msat,
+			cltv_expiry_delta: REBALANCE_FINAL_CLTV_EXPIRY_DELTA,
+			maybe_announced_channel: to.is_announced,
+		});
+
+		let route_params = RouteParameters::from_payment_params_and_value(
+			PaymentParameters::from_node_id(self.our_node_id, REBALANCE_FINAL_CLTV_EXPIRY_DELTA),
+			amt_msat,
+		);
+		Ok(Route {
+			paths: vec![Path { hops, blinded_tail: None }],
+			route_params: Some(route_params),
+		})
+	}
+}
+
+/// Sends `route` (from [`CircularRouter::build_route`]) as a payment to ourselves and records it as a
+/// rebalance. Returns the payment id.
+pub(crate) fn send_rebalance(
+	channel_manager: &ChannelManager, route: &Route, from: &ChannelDetails, to: &ChannelDetails,
+	rebalances: &mut RebalanceStorage, fs_store: &FilesystemStore, random_bytes: [u8; 32],
+) -> Result<PaymentId, String> {
+	let amt_msat = route.get_total_amount();
+	let (payment_hash, payment_secret) = channel_manager
+		.create_inbound_payment(
+			Some(amt_msat),
+			REBALANCE_INVOICE_EXPIRY_SECS,
+			Some(REBALANCE_FINAL_CLTV_EXPIRY_DELTA as u16),
+		)
+		.map_err(|()| "failed to create a payment hash for the rebalance".to_string())?;
+	let payment_id = PaymentId(random_bytes);
+	rebalances.rebalances.insert(
+		payment_id,
+		RebalanceInfo {
+			from_channel: from.channel_id,
+			to_channel: to.channel_id,
+			payment_hash,
+			amt_msat,
+			expected_fee_msat: route.get_total_fees(),
+			fee_paid_msat: None,
+			status: HTLCStatus::Pending,
+			created_at: SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs(),
+		},
+	);
+	rebalances.persist(fs_store);
+	// `send_payment` always asks the router for a route, which can't express a payment from us
+	// back to ourselves over two chosen channels, so we send the route we built directly.
+	#[allow(deprecated)]
+	let sent = channel_manager.send_payment_with_route(
+		route.clone(),
+		payment_hash,
+		RecipientOnionFields::secret_only(payment_secret),
+		payment_id,
+	);
+	if let Err(e) = sent {
+		rebalances.rebalances.get_mut(&payment_id).unwrap().status = HTLCStatus::Failed;
+		rebalances.persist(fs_store);
+		return Err(format!("failed to send rebalance: {:?}", e));
+	}
+	Ok(payment_id)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::{add_channel, node_key, test_network_graph};
+	use lightning::ln::channel_state::{ChannelCounterparty, CounterpartyForwardingInfo};
+	use lightning::routing::scoring::ProbabilisticScoringDecayParameters;
+	use lightning::types::features::InitFeatures;
+
+	#[allow(deprecated)]
+	fn our_channel(
+		peer: PublicKey, scid: u64, forwarding_info: Option<CounterpartyForwardingInfo>,
+	) -> ChannelDetails {
+		ChannelDetails {
+			channel_id: ChannelId([scid as u8; 32]),
+			counterparty: ChannelCounterparty {
+				node_id: peer,
+				features: InitFeatures::empty(),
+				unspendable_punishment_reserve: 0,
+				forwarding_info,
+				outbound_htlc_minimum_msat: None,
+				outbound_htlc_maximum_msat: None,
+			},
+			funding_txo: None,
+			feerate_sat_per_1000_weight: None,
+			channel_type: None,
+			short_channel_id: Some(scid),
+			outbound_scid_alias: None,
+			inbound_scid_alias: None,
+			channel_value_satoshis: 1_000_000,
+			unspendable_punishment_reserve: None,
+			user_channel_id: 0,
+			balance_msat: 500_000_000,
+			outbound_capacity_msat: 500_000_000,
+			next_outbound_htlc_limit_msat: 500_000_000,
+			next_outbound_htlc_minimum_msat: 1,
+			inbound_capacity_msat: 500_000_000,
+			confirmations_required: None,
+			confirmations: Some(6),
+			force_close_spend_delay: None,
+			is_outbound: true,
+			is_channel_ready: true,
+			channel_shutdown_state: None,
+			is_usable: true,
+			is_announced: true,
+			inbound_htlc_minimum_msat: None,
+			inbound_htlc_maximum_msat: None,
+			config: None,
+			pending_inbound_htlcs: Vec::new(),
+			pending_outbound_htlcs: Vec::new(),
+		}
+	}
+
+	#[test]
+	fn test_circular_route_fees_and_avoid_list() {
+		// We have channels to A (scid 1) and C (scid 4). A reaches C through B, or more cheaply
+		// through D.
+		let (us, a, b, c, d) = (node_key(1), node_key(2), node_key(3), node_key(4), node_key(5));
+		let network_graph = test_network_graph();
+		add_channel(&network_graph, 2, &a, &b, 1000, 100, 10);
+		add_channel(&network_graph, 3, &b, &c, 2000, 200, 20);
+		add_channel(&network_graph, 5, &a, &d, 0, 0, 10);
+		add_channel(&network_graph, 6, &d, &c, 0, 0, 10);
+		let logger = Arc::new(FilesystemLogger::new("test_dir".to_string()));
+		let scorer = RwLock::new(Scorer::new(
+			ProbabilisticScoringDecayParameters::default(),
+			Arc::clone(&network_graph),
+			Arc::clone(&logger),
+		));
+		let from = our_channel(a, 1, None);
+		let in_policy = CounterpartyForwardingInfo {
+			fee_base_msat: 3000,
+			fee_proportional_millionths: 300,
+			cltv_expiry_delta: 30,
+		};
+		let to = our_channel(c, 4, Some(in_policy));
+
+		let mut avoid_list = AvoidList::default();
+		avoid_list.nodes.insert(NodeId::from_pubkey(&d));
+		let router = CircularRouter {
+			our_node_id: us,
+			our_node_features: NodeFeatures::empty(),
+			own_scids: vec![1, 4],
+			network_graph: &network_graph,
+			scorer: &scorer,
+			logger: Arc::clone(&logger),
+			avoid_list: &avoid_list,
+		};
+		let route = router.build_route(&from, &to, 1_000_000, &[42; 32]).unwrap();
+		let hops = &route.paths[0].hops;
+		let hop_summary = hops
+			.iter()
+			.map(|hop| (hop.pubkey, hop.short_channel_id, hop.fee_msat, hop.cltv_expiry_delta))
+			.collect::<Vec<_>>();
+		// C charges 3000 + 300ppm to forward 1_000_000 msat back to us, B 2000 + 200ppm to
+		// forward the resulting 1_003_300 msat to C and A 1000 + 100ppm to forward 1_005_500
+		// msat on to B.
+		assert_eq!(
+			hop_summary,
+			vec![
+				(a, 1, 1100, 10),
+				(b, 2, 2200, 20),
+				(c, 3, 3300, 30),
+				(us, 4, 1_000_000, REBALANCE_FINAL_CLTV_EXPIRY_DELTA),
+			]
+		);
+		assert_eq!(route.get_total_fees(), 6600);
+		assert_eq!(route.get_total_amount(), 1_000_000);
+
+		// Without the avoid-list we take the free route through D.
+		let avoid_list = AvoidList::default();
+		let router = CircularRouter { avoid_list: &avoid_list, ..router };
+		let route = router.build_route(&from, &to, 1_000_000, &[42; 32]).unwrap();
+		let scids = route.paths[0].hops.iter().map(|hop| hop.short_channel_id).collect::<Vec<_>>();
+		assert_eq!(scids, vec![1, 5, 6, 4]);
+		assert_eq!(route.get_total_fees(), 3300);
+
+		// A channel of our own or peer we avoid can't be used at all.
+		let mut avoid_list = AvoidList::default();
+		avoid_list.nodes.insert(NodeId::from_pubkey(&a));
+		let router = CircularRouter { avoid_list: &avoid_list, ..router };
+		assert!(router.build_route(&from, &to, 1_000_000, &[42; 32]).is_err());
+	}
+}
@@ -0,0 +1,152 @@
+use crate::filesystem_store::FilesystemStore;
+use crate::http;
+use crate::logger::FilesystemLogger;
+use crate::NetworkGraph;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub(crate) type RapidGossipSync =
+	lightning_rapid_gossip_sync::RapidGossipSync<Arc<NetworkGraph>, Arc<FilesystemLogger>>;
+
+/// How often we pull an incremental snapshot when syncing from an RGS server.
+pub(crate) const RGS_SYNC_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Where a Rapid Gossip Sync snapshot is loaded from.
+pub(crate) enum RgsSource {
+	/// A snapshot file on local disk, applied as-is.
+	File(PathBuf),
+	/// The base URL of an RGS server. Snapshots are fetched from `<url>/<last_sync_timestamp>`, so
+	/// after the first sync the server only sends us what changed since.
+	Http(String),
+}
+
+impl RgsSource {
+	pub(crate) fn parse(source: &str) -> Self {
+		if source.starts_with("http://") || source.starts_with("https://") {
+			RgsSource::Http(source.trim_end_matches('/').to_string())
+		} else {
+			RgsSource::File(PathBuf::from(source))
+		}
+	}
+}
+
+pub(crate) async fn fetch_snapshot(
+	source: &RgsSource, last_sync_timestamp: u32,
+) -> Result<Vec<u8>, String> {
+	match source {
+		RgsSource::File(path) => std::fs::read(path)
+			.map_err(|e| format!("failed to read RGS snapshot {}: {}", path.display(), e)),
+		RgsSource::Http(url) => http::get(&format!("{}/{}", url, last_sync_timestamp)).await,
+	}
+}
+
+/// Fetches a snapshot from `source` and applies it to the network graph, returning the snapshot's
+/// timestamp.
+///
+/// The graph records the timestamp of the last snapshot applied to it and is persisted along with
+/// it, so incremental syncs always pick up from what the graph on disk has seen.
+pub(crate) async fn sync(
+	rapid_gossip_sync: &RapidGossipSync, source: &RgsSource,
+) -> Result<u32, String> {
+	let last_sync_timestamp =
+		rapid_gossip_sync.network_graph().get_last_rapid_gossip_sync_timestamp().unwrap_or(0);
+	let snapshot = fetch_snapshot(source, last_sync_timestamp).await?;
+	rapid_gossip_sync
+		.update_network_graph(&snapshot)
+		.map_err(|e| format!("failed to apply RGS snapshot: {:?}", e))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::test_utils::stand_in_http_server;
+	use bitcoin::constants::ChainHash;
+	use bitcoin::Network;
+	use lightning::util::ser::{ReadableArgs, Writeable};
+	use std::sync::Mutex;
+	use std::time::SystemTime;
+
+	/// Serves `body` to every HTTP request, returning its URL and the paths requested.
+	async fn serve(body: Vec<u8>) -> (String, Arc<Mutex<Vec<String>>>) {
+		let (host, requests) = stand_in_http_server(move |_, _| body.clone()).await;
+		(format!("http://{}", host), requests)
+	}
+
+	/// An RGS v1 snapshot with a single incremental update for a channel we don't know, which is
+	/// skipped. LDK only records the snapshot timestamp in the graph if there are any updates.
+	fn snapshot(network: Network, timestamp: u32) -> Vec<u8> {
+		let mut snapshot = vec![76, 68, 75, 1];
+		snapshot.extend_from_slice(ChainHash::using_genesis_block(network).as_bytes());
+		snapshot.extend_from_slice(&timestamp.to_be_bytes());
+		snapshot.extend_from_slice(&0u32.to_be_bytes()); // node_id_count
+		snapshot.extend_from_slice(&0u32.to_be_bytes()); // announcement_count
+		snapshot.extend_from_slice(&1u32.to_be_bytes()); // update_count
+		snapshot.extend_from_slice(&[0; 26]); // default update values
+		snapshot.extend_from_slice(&[1, 0b_1000_0000]); // scid delta and incremental flag
+		snapshot
+	}
+
+	#[test]
+	fn test_parse_rgs_source() {
+		match RgsSource::parse("https://rapidsync.lightningdevkit.org/snapshot/") {
+			RgsSource::Http(url) => {
+				assert_eq!(url, "https://rapidsync.lightningdevkit.org/snapshot")
+			},
+			RgsSource::File(_) => panic!("expected an HTTP source"),
+		}
+		match RgsSource::parse("test_data/rgs_snapshot") {
+			RgsSource::File(path) => assert_eq!(path, PathBuf::from("test_data/rgs_snapshot")),
+			RgsSource::Http(_) => panic!("expected a file source"),
+		}
+	}
+
+	#[tokio::test]
+	async fn test_fetch_snapshot_requests_since_last_sync() {
+		let (url, requests) = serve(vec![1, 2, 3]).await;
+		let snapshot = fetch_snapshot(&RgsSource::Http(url), 1_700_000_000).await.unwrap();
+		assert_eq!(snapshot, vec![1, 2, 3]);
+		assert_eq!(*requests.lock().unwrap(), vec!["/1700000000".to_string()]);
+	}
+
+	#[tokio::test]
+	async fn test_sync_applies_snapshot_and_records_timestamp() {
+		let logger = Arc::new(FilesystemLogger::new("test_dir".to_string()));
+		let network_graph = Arc::new(NetworkGraph::new(Network::Regtest, Arc::clone(&logger)));
+		let rapid_gossip_sync =
+			RapidGossipSync::new(Arc::clone(&network_graph), Arc::clone(&logger));
+
+		let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+		let (url, requests) = serve(snapshot(Network::Regtest, now as u32)).await;
+		let timestamp = sync(&rapid_gossip_sync, &RgsSource::Http(url)).await.unwrap();
+
+		assert_eq!(timestamp, now as u32);
+		assert_eq!(*requests.lock().unwrap(), vec!["/0".to_string()]);
+		assert_eq!(network_graph.get_last_rapid_gossip_sync_timestamp(), Some(now as u32));
+
+		// The next sync only asks for what changed since, even after the graph is reloaded.
+		let mut encoded_graph = Vec::new();
+		network_graph.write(&mut encoded_graph).unwrap();
+		let network_graph: Arc<NetworkGraph> =
+			Arc::new(ReadableArgs::read(&mut &encoded_graph[..], Arc::clone(&logger)).unwrap());
+		let rapid_gossip_sync = RapidGossipSync::new(network_graph, logger);
+		let (url, requests) = serve(snapshot(Network::Regtest, now as u32 + 1)).await;
+		sync(&rapid_gossip_sync, &RgsSource::Http(url)).await.unwrap();
+		assert_eq!(*requests.lock().unwrap(), vec![format!("/{}", now)]);
+	}
+
+	#[tokio::test]
+	async fn test_sync_rejects_snapshot_for_other_network() {
+		let logger = Arc::new(FilesystemLogger::new("test_dir".to_string()));
+		let network_graph = Arc::new(NetworkGraph::new(Network::Regtest, Arc::clone(&logger)));
+		let rapid_gossip_sync = RapidGossipSync::new(Arc::clone(&network_graph), logger);
+
+		let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+		let (url, _) = serve(snapshot(Network::Bitcoin, now as u32)).await;
+		assert!(sync(&rapid_gossip_sync, &RgsSource::Http(url)).await.is_err());
+		assert_eq!(network_graph.get_last_rapid_gossip_sync_timestamp(), None);
+	}
+}
@@ -0,0 +1,626 @@
+use crate::filesystem_store::FilesystemStore;
+use crate::graph;
+use crate::hex_utils;
+use crate::logger::FilesystemLogger;
+use crate::{ChannelManager, NetworkGraph, Scorer};
+use bitcoin::secp256k1::{self, PublicKey, Secp256k1};
+use lightning::blinded_path::message::{BlindedMessagePath, MessageContext, MessageForwardNode};
+use lightning::blinded_path::payment::{BlindedPaymentPath, ReceiveTlvs};
+use lightning::impl_writeable_tlv_based;
+use lightning::ln::bolt11_payment::{
+	payment_parameters_from_invoice, payment_parameters_from_zero_amount_invoice,
+};
+use lightning::ln::channel_state::ChannelDetails;
+use lightning::ln::channelmanager::Retry;
+use lightning::ln::msgs::LightningError;
+use lightning::ln::types::ChannelId;
+use lightning::onion_message::messenger::{Destination, MessageRouter, OnionMessagePath};
+use lightning::routing::gossip::{NodeId, ReadOnlyNetworkGraph};
+use lightning::routing::router::{
+	find_route, CandidateRouteHop, DefaultRouter, InFlightHtlcs, PaymentParameters,
+	PublicHopCandidate, Route, RouteParameters, Router,
+};
+use lightning::routing::scoring::{ChannelUsage, ProbabilisticScoringFeeParameters, ScoreLookUp};
+use lightning::sign::KeysManager;
+use lightning::util::persist::KVStore;
+use lightning::util::ser::{Readable, Writeable};
+use lightning_invoice::Bolt11Invoice;
+use std::collections::HashSet;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+pub(crate) const AVOID_LIST_KEY: &str = "avoid_list";
+
+/// Nodes and channels that no payment we send may route through.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct AvoidList {
+	pub(crate) nodes: HashSet<NodeId>,
+	pub(crate) channels: HashSet<u64>,
+}
+
+impl_writeable_tlv_based!(AvoidList, {
+	(0, nodes, required),
+	(2, channels, required),
+});
+
+pub(crate) fn read_avoid_list(fs_store: &FilesystemStore) -> AvoidList {
+	match fs_store.read("", "", AVOID_LIST_KEY) {
+		Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes)).unwrap_or_default(),
+		Err(_) => AvoidList::default(),
+	}
+}
+
+impl AvoidList {
+	pub(crate) fn persist(&self, fs_store: &FilesystemStore) -> Result<(), String> {
+		fs_store
+			.write("", "", AVOID_LIST_KEY, &self.encode())
+			.map_err(|e| format!("failed to persist avoid-list: {}", e))
+	}
+
+	/// Every channel a payment must not use: the avoided channels plus all of the avoided nodes'
+	/// channels we know of, including our own channels with them.
+	pub(crate) fn excluded_channels<'a, I: IntoIterator<Item = &'a ChannelDetails>>(
+		&self, graph: &ReadOnlyNetworkGraph, first_hops: I,
+	) -> Vec<u64> {
+		let mut excluded = self.channels.iter().copied().collect::<Vec<_>>();
+		for node_id in self.nodes.iter() {
+			if let Some(node) = graph.node(node_id) {
+				excluded.extend(node.channels.iter());
+			}
+		}
+		excluded.extend(
+			first_hops
+				.into_iter()
+				.filter(|chan| self.nodes.contains(&NodeId::from_pubkey(&chan.counterparty.node_id)))
+				.filter_map(|chan| chan.get_outbound_payment_scid()),
+		);
+		excluded
+	}
+}
+
+/// Keeps a payment we're about to route (or probe) away from everything on `avoid_list`.
+pub(crate) fn apply_avoid_list(
+	channel_manager: &ChannelManager, network_graph: &NetworkGraph, avoid_list: &AvoidList,
+	route_params: &mut RouteParameters,
+) {
+	let excluded = avoid_list
+		.excluded_channels(&network_graph.read_only(), &channel_manager.list_usable_channels());
+	route_params.payment_params.previously_failed_channels.extend(excluded);
+}
+
+type InnerRouter = DefaultRouter<
+	Arc<NetworkGraph>,
+	Arc<FilesystemLogger>,
+	Arc<KeysManager>,
+	Arc<RwLock<Scorer>>,
+	ProbabilisticScoringFeeParameters,
+	Scorer,
+>;
+
+/// The router `ChannelManager` uses for every payment and probe, which keeps routes away from
+/// whatever is on the global avoid-list at the time, including payments (like BOLT12 ones) whose
+/// route parameters we don't build ourselves.
+pub(crate) struct AvoidListRouter {
+	inner: InnerRouter,
+	network_graph: Arc<NetworkGraph>,
+	avoid_list: Arc<Mutex<AvoidList>>,
+}
+
+impl AvoidListRouter {
+	pub(crate) fn new(
+		network_graph: Arc<NetworkGraph>, logger: Arc<FilesystemLogger>,
+		keys_manager: Arc<KeysManager>, scorer: Arc<RwLock<Scorer>>,
+		avoid_list: Arc<Mutex<AvoidList>>,
+	) -> Self {
+		let inner = DefaultRouter::new(
+			Arc::clone(&network_graph),
+			logger,
+			keys_manager,
+			scorer,
+			ProbabilisticScoringFeeParameters::default(),
+		);
+		AvoidListRouter { inner, network_graph, avoid_list }
+	}
+}
+
+impl Router for AvoidListRouter {
+	fn find_route(
+		&self, payer: &PublicKey, route_params: &RouteParameters,
+		first_hops: Option<&[&ChannelDetails]>, inflight_htlcs: InFlightHtlcs,
+	) -> Result<Route, LightningError> {
+		let excluded = self.avoid_list.lock().unwrap().excluded_channels(
+			&self.network_graph.read_only(),
+			first_hops.unwrap_or(&[]).iter().copied(),
+		);
+		let mut route_params = route_params.clone();
+		route_params.payment_params.previously_failed_channels.extend(excluded);
+		self.inner.find_route(payer, &route_params, first_hops, inflight_htlcs)
+	}
+
+	fn create_blinded_payment_paths<T: secp256k1::Signing + secp256k1::Verification>(
+		&self, recipient: PublicKey, first_hops: Vec<ChannelDetails>, tlvs: ReceiveTlvs,
+		amount_msats: u64, secp_ctx: &Secp256k1<T>,
+	) -> Result<Vec<BlindedPaymentPath>, ()> {
+		self.inner.create_blinded_payment_paths(recipient, first_hops, tlvs, amount_msats, secp_ctx)
+	}
+}
+
+impl MessageRouter for AvoidListRouter {
+	fn find_path(
+		&self, sender: PublicKey, peers: Vec<PublicKey>, destination: Destination,
+	) -> Result<OnionMessagePath, ()> {
+		self.inner.find_path(sender, peers, destination)
+	}
+
+	fn create_blinded_paths<T: secp256k1::Signing + secp256k1::Verification>(
+		&self, recipient: PublicKey, context: MessageContext, peers: Vec<PublicKey>,
+		secp_ctx: &Secp256k1<T>,
+	) -> Result<Vec<BlindedMessagePath>, ()> {
+		self.inner.create_blinded_paths(recipient, context, peers, secp_ctx)
+	}
+
+	fn create_compact_blinded_paths<T: secp256k1::Signing + secp256k1::Verification>(
+		&self, recipient: PublicKey, context: MessageContext, peers: Vec<MessageForwardNode>,
+		secp_ctx: &Secp256k1<T>,
+	) -> Result<Vec<BlindedMessagePath>, ()> {
+		self.inner.create_compact_blinded_paths(recipient, context, peers, secp_ctx)
+	}
+}
+
+/// Finds one of our channels by its channel id or (real or alias) short channel id.
+pub(crate) fn find_first_hop<'a>(
+	first_hops: &'a [ChannelDetails], channel: &str,
+) -> Option<&'a ChannelDetails> {
+	if let Some(id) = hex_utils::to_vec(channel).filter(|id| id.len() == 32) {
+		let mut channel_id = [0; 32];
+		channel_id.copy_from_slice(&id);
+		return first_hops.iter().find(|chan| chan.channel_id == ChannelId(channel_id));
+	}
+	let scid = graph::parse_scid(channel)?;
+	first_hops.iter().find(|chan| {
+		chan.short_channel_id == Some(scid) || chan.outbound_scid_alias == Some(scid)
+	})
+}
+
+/// How long we keep retrying a payment when neither `--retries` nor `--timeout` is given.
+pub(crate) const DEFAULT_PAYMENT_RETRY: Retry = Retry::Timeout(Duration::from_secs(10));
+
+/// Per-payment limits given as options on `sendpayment` and `keysend`.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct PaymentOptions {
+	/// The most we'll pay in routing fees, as an absolute amount.
+	pub(crate) max_fee_msat: Option<u64>,
+	/// The most we'll pay in routing fees, relative to the amount sent.
+	pub(crate) max_fee_ppm: Option<u64>,
+	pub(crate) max_total_cltv_expiry_delta: Option<u32>,
+	pub(crate) retry: Option<Retry>,
+	pub(crate) max_path_count: Option<u8>,
+	/// Limits each path to using at most 1/2^n of a channel's capacity.
+	pub(crate) max_channel_saturation_power_of_half: Option<u8>,
+	/// The channel (by channel id or short channel id) the payment must leave through.
+	pub(crate) via: Option<String>,
+	/// Nodes and channels to keep this payment away from, on top of the global avoid-list.
+	pub(crate) avoid: AvoidList,
+	/// Filled in by `resolve_excluded_channels` from `via`, `avoid` and the global avoid-list.
+	pub(crate) excluded_channels: Vec<u64>,
+	/// Custom TLV records to include in the onion for the recipient.
+	pub(crate) custom_tlvs: Vec<(u64, Vec<u8>)>,
+	/// For offers, the number of items to pay for.
+	pub(crate) quantity: Option<u64>,
+	/// For offers, a note for the recipient, included in our invoice request.
+	pub(crate) payer_note: Option<String>,
+	/// For Lightning Addresses, a comment for the recipient, sent with the invoice request.
+	pub(crate) comment: Option<String>,
+}
+
+/// Custom TLV types must be in the range reserved for experimental and application use.
+const MIN_CUSTOM_TLV_TYPE: u64 = 1 << 16;
+
+/// The TLV type keysend uses for the payment preimage, which LDK sets itself.
+const KEYSEND_PREIMAGE_TLV_TYPE: u64 = 5482373484;
+
+/// Parses a `<type>=<hex>` custom TLV record.
+pub(crate) fn parse_custom_tlv(tlv: &str) -> Result<(u64, Vec<u8>), String> {
+	let mut parts = tlv.splitn(2, '=');
+	let typ = parts.next().unwrap();
+	let value = parts.next().ok_or(format!("custom TLV {} must be given as <type>=<hex>", tlv))?;
+	let typ = typ.parse::<u64>().map_err(|e| format!("couldn't parse TLV type {}: {}", typ, e))?;
+	if typ < MIN_CUSTOM_TLV_TYPE || typ == KEYSEND_PREIMAGE_TLV_TYPE {
+		return Err(format!("TLV type {} is not available for custom records", typ));
+	}
+	let value = hex_utils::to_vec(value).ok_or(format!("couldn't parse TLV value {}", value))?;
+	Ok((typ, value))
+}
+
+impl PaymentOptions {
+	pub(crate) fn parse<'a, I: Iterator<Item = &'a str>>(mut args: I) -> Result<Self, String> {
+		let mut options = PaymentOptions::default();
+		while let Some(flag) = args.next() {
+			let value = args.next().ok_or(format!("{} requires a value", flag))?;
+			match flag {
+				"--max-fee-msat" => options.max_fee_msat = Some(parse_option(flag, value)?),
+				"--max-fee-ppm" => options.max_fee_ppm = Some(parse_option(flag, value)?),
+				"--max-cltv" => {
+					options.max_total_cltv_expiry_delta = Some(parse_option(flag, value)?)
+				},
+				"--retries" | "--timeout" if options.retry.is_some() => {
+					return Err("only one of --retries and --timeout may be given".to_string())
+				},
+				"--retries" => options.retry = Some(Retry::Attempts(parse_option(flag, value)?)),
+				"--timeout" => {
+					let secs = parse_option(flag, value)?;
+					options.retry = Some(Retry::Timeout(Duration::from_secs(secs)));
+				},
+				"--max-paths" => options.max_path_count = Some(parse_option(flag, value)?),
+				"--max-channel-saturation" => {
+					options.max_channel_saturation_power_of_half = Some(parse_option(flag, value)?)
+				},
+				"--via" if options.via.is_some() => {
+					return Err("--via may only be given once".to_string())
+				},
+				"--via" => options.via = Some(value.to_string()),
+				"--avoid-node" => match hex_utils::to_compressed_pubkey(value) {
+					Some(pubkey) => {
+						options.avoid.nodes.insert(NodeId::from_pubkey(&pubkey));
+					},
+					None => return Err(format!("couldn't parse --avoid-node pubkey {}", value)),
+				},
+				"--tlv" => {
+					let (typ, value) = parse_custom_tlv(value)?;
+					if options.custom_tlvs.iter().any(|(existing, _)| *existing == typ) {
+						return Err(format!("TLV type {} was given more than once", typ));
+					}
+					options.custom_tlvs.push((typ, value));
+				},
+				"--quantity" => options.quantity = Some(parse_option(flag, value)?),
+				"--note" => options.payer_note = Some(value.to_string()),
+				"--comment" => options.comment = Some(value.to_string()),
+				"--avoid-channel" => match graph::parse_scid(value) {
+					Some(scid) => {
+						options.avoid.channels.insert(scid);
+					},
+					None => return Err(format!("couldn't parse --avoid-channel {}", value)),
+				},
+				_ => return Err(format!("unknown option {}", flag)),
+			}
+		}
+		Ok(options)
+	}
+
+	pub(crate) fn retry(&self) -> Retry {
+		self.retry.unwrap_or(DEFAULT_PAYMENT_RETRY)
+	}
+
+	/// The routing fee cap for sending `amount_msat`, taking the tighter of the absolute and
+	/// proportional limits if both were given.
+	pub(crate) fn max_total_routing_fee_msat(&self, amount_msat: u64) -> Option<u64> {
+		let ppm_limit = self
+			.max_fee_ppm
+			.map(|ppm| (amount_msat as u128 * ppm as u128 / 1_000_000) as u64);
+		match (self.max_fee_msat, ppm_limit) {
+			(Some(msat), Some(ppm)) => Some(msat.min(ppm)),
+			(msat, ppm) => msat.or(ppm),
+		}
+	}
+
+	/// Works out which channels the router must not use for this payment. LDK's router has no
+	/// notion of excluded nodes or required first hops, so both are expressed as excluded channels:
+	/// all channels of avoided nodes, and all of our channels other than the `--via` one.
+	pub(crate) fn resolve_excluded_channels(
+		&mut self, channel_manager: &ChannelManager, network_graph: &NetworkGraph,
+		global_avoid_list: &AvoidList,
+	) -> Result<(), String> {
+		let first_hops = channel_manager.list_usable_channels();
+		let graph = network_graph.read_only();
+		self.excluded_channels = self.avoid.excluded_channels(&graph, &first_hops);
+		self.excluded_channels.extend(global_avoid_list.excluded_channels(&graph, &first_hops));
+		if let Some(via) = &self.via {
+			let via_channel = find_first_hop(&first_hops, via)
+				.ok_or(format!("--via {} is not one of our usable channels", via))?;
+			self.excluded_channels.extend(
+				first_hops
+					.iter()
+					.filter(|chan| chan.channel_id != via_channel.channel_id)
+					.filter_map(|chan| chan.get_outbound_payment_scid()),
+			);
+		}
+		Ok(())
+	}
+
+	/// Whether any option was given that LDK's BOLT12 offer payments can't take, as they're
+	/// routed and built by LDK itself.
+	pub(crate) fn unsupported_for_offers(&self) -> bool {
+		self.max_total_cltv_expiry_delta.is_some()
+			|| self.max_path_count.is_some()
+			|| self.max_channel_saturation_power_of_half.is_some()
+			|| self.via.is_some()
+			|| !self.avoid.nodes.is_empty()
+			|| !self.avoid.channels.is_empty()
+			|| !self.custom_tlvs.is_empty()
+	}
+
+	/// Whether any options were given that only make sense when paying an offer.
+	pub(crate) fn offers_only(&self) -> bool {
+		self.quantity.is_some() || self.payer_note.is_some()
+	}
+
+	/// Whether any options were given that only make sense when paying a Lightning Address.
+	pub(crate) fn lightning_address_only(&self) -> bool {
+		self.comment.is_some()
+	}
+
+	pub(crate) fn apply(&self, route_params: &mut RouteParameters) {
+		if let Some(max_fee) = self.max_total_routing_fee_msat(route_params.final_value_msat) {
+			route_params.max_total_routing_fee_msat = Some(max_fee);
+		}
+		let payment_params = &mut route_params.payment_params;
+		if let Some(max_cltv) = self.max_total_cltv_expiry_delta {
+			payment_params.max_total_cltv_expiry_delta = max_cltv;
+		}
+		if let Some(max_paths) = self.max_path_count {
+			payment_params.max_path_count = max_paths;
+		}
+		if let Some(saturation) = self.max_channel_saturation_power_of_half {
+			payment_params.max_channel_saturation_power_of_half = saturation;
+		}
+		payment_params.previously_failed_channels.extend(self.excluded_channels.iter());
+	}
+}
+
+fn parse_option<T: FromStr>(flag: &str, value: &str) -> Result<T, String>
+where
+	T::Err: std::fmt::Display,
+{
+	value.parse().map_err(|e| format!("couldn't parse {}: {}", flag, e))
+}
+
+/// Builds the route parameters for paying `destination`, which is either a BOLT11 invoice or a
+/// node pubkey (paid via keysend). An amount is required for pubkeys and zero-amount invoices.
+pub(crate) fn route_params_for_destination(
+	destination: &str, amt_msat: Option<u64>,
+) -> Result<RouteParameters, String> {
+	if let Some(pubkey) = hex_utils::to_compressed_pubkey(destination) {
+		let amt_msat = amt_msat.ok_or("an amount is required when paying a pubkey")?;
+		return Ok(RouteParameters::from_payment_params_and_value(
+			PaymentParameters::for_keysend(pubkey, 40, false),
+			amt_msat,
+		));
+	}
+	let invoice = Bolt11Invoice::from_str(destination)
+		.map_err(|e| format!("{} is neither a pubkey nor a valid invoice: {:?}", destination, e))?;
+	let params = match (invoice.amount_milli_satoshis(), amt_msat) {
+		(None, Some(amt_msat)) | (Some(0), Some(amt_msat)) => {
+			payment_parameters_from_zero_amount_invoice(&invoice, amt_msat)
+		},
+		(None, None) | (Some(0), None) => {
+			return Err("an amount is required for a zero-amount invoice".to_string())
+		},
+		(Some(invoice_amt), Some(amt_msat)) if invoice_amt != amt_msat => {
+			return Err(format!("amount didn't match invoice value of {}msat", invoice_amt))
+		},
+		_ => payment_parameters_from_invoice(&invoice),
+	};
+	let (_payment_hash, _recipient_onion, route_params) =
+		params.map_err(|e| format!("failed to parse invoice: {:?}", e))?;
+	Ok(route_params)
+}
+
+/// Resolves a node pubkey or short channel id to the set of channels a payment must not use.
+///
+/// LDK's router has no notion of excluded nodes, so avoiding a node is done by excluding every
+/// channel it has in our network graph.
+pub(crate) fn channels_to_avoid(
+	graph: &ReadOnlyNetworkGraph, node_or_scid: &str,
+) -> Result<Vec<u64>, String> {
+	if let Some(pubkey) = hex_utils::to_compressed_pubkey(node_or_scid) {
+		return match graph.node(&NodeId::from_pubkey(&pubkey)) {
+			Some(node) => Ok(node.channels.clone()),
+			None => Err(format!("node {} is not in the network graph", pubkey)),
+		};
+	}
+	match graph::parse_scid(node_or_scid) {
+		Some(scid) => Ok(vec![scid]),
+		None => Err(format!("{} is neither a node pubkey nor a short channel id", node_or_scid)),
+	}
+}
+
+/// Runs the router the same way `ChannelManager` would for a real payment, using our usable
+/// channels as first hops and the live scorer, but without sending anything.
+pub(crate) fn query_route(
+	channel_manager: &ChannelManager, network_graph: &NetworkGraph, scorer: &RwLock<Scorer>,
+	logger: Arc<FilesystemLogger>, route_params: &RouteParameters, random_seed_bytes: &[u8; 32],
+) -> Result<Route, String> {
+	let first_hops = channel_manager.list_usable_channels();
+	let first_hop_refs = first_hops.iter().collect::<Vec<_>>();
+	let scorer = scorer.read().unwrap();
+	find_route(
+		&channel_manager.get_our_node_id(),
+		route_params,
+		network_graph,
+		Some(&first_hop_refs),
+		logger,
+		&*scorer,
+		&ProbabilisticScoringFeeParameters::default(),
+		random_seed_bytes,
+	)
+	.map_err(|e| e.err)
+}
+
+/// What the scorer charges, on top of fees, for sending `amount_msat` over a channel towards
+/// `target` when choosing routes. Channels that aren't in the graph, such as those from route
+/// hints, aren't penalized.
+fn hop_penalty_msat(
+	graph: &ReadOnlyNetworkGraph, scorer: &Scorer,
+	score_params: &ProbabilisticScoringFeeParameters, short_channel_id: u64, target: &NodeId,
+	amount_msat: u64,
+) -> u64 {
+	let channel =
+		graph.channel(short_channel_id).and_then(|channel| channel.as_directed_to(target));
+	let (info, _source) = match channel {
+		Some(channel) => channel,
+		None => return 0,
+	};
+	let usage = ChannelUsage {
+		amount_msat,
+		inflight_htlc_msat: 0,
+		effective_capacity: info.effective_capacity(),
+	};
+	let candidate = CandidateRouteHop::PublicHop(PublicHopCandidate { info, short_channel_id });
+	scorer.channel_penalty_msat(&candidate, usage, score_params)
+}
+
+pub(crate) fn print_route(route: &Route, network_graph: &NetworkGraph, scorer: &RwLock<Scorer>) {
+	let graph = network_graph.read_only();
+	let scorer = scorer.read().unwrap();
+	let score_params = ProbabilisticScoringFeeParameters::default();
+	println!("\t{{");
+	println!("\t\ttotal_amount_msat: {},", route.get_total_amount());
+	println!("\t\ttotal_fees_msat: {},", route.get_total_fees());
+	println!("\t\tpaths: [");
+	for path in route.paths.iter() {
+		println!("\t\t\t{{");
+		println!("\t\t\t\tamount_msat: {},", path.final_value_msat());
+		println!("\t\t\t\tfee_msat: {},", path.fee_msat());
+		let total_cltv = path.hops.iter().map(|hop| hop.cltv_expiry_delta).sum::<u32>();
+		println!("\t\t\t\ttotal_cltv_expiry_delta: {},", total_cltv);
+		let mut path_penalty_msat = 0u64;
+		let mut path_success_probability = Some(1.0);
+		println!("\t\t\t\thops: [");
+		for (idx, hop) in path.hops.iter().enumerate() {
+			// Each hop's `fee_msat` is what that node keeps (or, for the last hop, the amount
+			// delivered), so the amount sent over the channel into a hop is the sum of the fees
+			// from that hop on.
+			let amount_msat = path.hops[idx..].iter().map(|hop| hop.fee_msat).sum::<u64>();
+			let target = NodeId::from_pubkey(&hop.pubkey);
+			println!("\t\t\t\t\t{{");
+			println!("\t\t\t\t\t\tpubkey: {},", hop.pubkey);
+			if let Some(alias) = graph.node(&target).and_then(graph::node_alias) {
+				println!("\t\t\t\t\t\talias: {},", alias);
+			}
+			println!("\t\t\t\t\t\tshort_channel_id: {},", graph::scid_str(hop.short_channel_id));
+			println!("\t\t\t\t\t\tamount_msat: {},", amount_msat);
+			if idx + 1 < path.hops.len() {
+				println!("\t\t\t\t\t\tfee_msat: {},", hop.fee_msat);
+			}
+			println!("\t\t\t\t\t\tcltv_expiry_delta: {},", hop.cltv_expiry_delta);
+			// The router never penalizes our own channels, so the first hop costs nothing.
+			let penalty_msat = if idx == 0 {
+				0
+			} else {
+				hop_penalty_msat(
+					&graph,
+					&scorer,
+					&score_params,
+					hop.short_channel_id,
+					&target,
+					amount_msat,
+				)
+			};
+			path_penalty_msat = path_penalty_msat.saturating_add(penalty_msat);
+			println!("\t\t\t\t\t\tpenalty_msat: {},", penalty_msat);
+			if let Some((min, max)) =
+				scorer.estimated_channel_liquidity_range(hop.short_channel_id, &target)
+			{
+				println!("\t\t\t\t\t\testimated_liquidity_msat: {}-{},", min, max);
+			}
+			match scorer.historical_estimated_payment_success_probability(
+				hop.short_channel_id,
+				&target,
+				amount_msat,
+				&score_params,
+			) {
+				Some(probability) => {
+					path_success_probability = path_success_probability.map(|p| p * probability);
+					println!("\t\t\t\t\t\tsuccess_probability: {:.3},", probability);
+				},
+				None => {
+					path_success_probability = None;
+					println!("\t\t\t\t\t\tsuccess_probability: unknown (no history),");
+				},
+			}
+			println!("\t\t\t\t\t}},");
+		}
+		println!("\t\t\t\t],");
+		println!("\t\t\t\tpenalty_msat: {},", path_penalty_msat);
+		match path_success_probability {
+			Some(probability) => println!("\t\t\t\tsuccess_probability: {:.3},", probability),
+			None => println!("\t\t\t\tsuccess_probability: unknown (some hops have no history),"),
+		}
+		println!("\t\t\t}},");
+	}
+	println!("\t\t],");
+	println!("\t}},");
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_parse_payment_options() {
+		let args = "--max-fee-ppm 5000 --retries 3 --max-paths 1 --max-cltv 1008".split(' ');
+		let options = PaymentOptions::parse(args).unwrap();
+		assert_eq!(options.max_fee_ppm, Some(5000));
+		assert_eq!(options.retry(), Retry::Attempts(3));
+		assert_eq!(options.max_path_count, Some(1));
+		assert_eq!(options.max_total_cltv_expiry_delta, Some(1008));
+
+		assert_eq!(PaymentOptions::parse(std::iter::empty()).unwrap(), PaymentOptions::default());
+		assert_eq!(PaymentOptions::default().retry(), DEFAULT_PAYMENT_RETRY);
+		assert!(PaymentOptions::parse("--retries 3 --timeout 60".split(' ')).is_err());
+		assert!(PaymentOptions::parse("--max-paths".split(' ')).is_err());
+		assert!(PaymentOptions::parse("--max-paths 300".split(' ')).is_err());
+		assert!(PaymentOptions::parse("--bogus 1".split(' ')).is_err());
+
+		let args = "--via 800000x1x0 --avoid-channel 700000x2x1 --avoid-channel 42".split(' ');
+		let options = PaymentOptions::parse(args).unwrap();
+		assert_eq!(options.via.as_deref(), Some("800000x1x0"));
+		let expected_channels =
+			vec![graph::parse_scid("700000x2x1").unwrap(), 42].into_iter().collect();
+		assert_eq!(options.avoid.channels, expected_channels);
+		assert!(PaymentOptions::parse("--via 1 --via 2".split(' ')).is_err());
+		assert!(PaymentOptions::parse("--avoid-node 02abcd".split(' ')).is_err());
+
+		let options = PaymentOptions::parse("--tlv 65537=00ff --tlv 7629169=6869".split(' '));
+		assert_eq!(
+			options.unwrap().custom_tlvs,
+			vec![(65537, vec![0x00, 0xff]), (7629169, b"hi".to_vec())]
+		);
+		assert!(PaymentOptions::parse("--tlv 65537=00 --tlv 65537=01".split(' ')).is_err());
+
+		let args = vec!["--quantity", "3", "--note", "for the team"];
+		let options = PaymentOptions::parse(args.into_iter()).unwrap();
+		assert_eq!(options.quantity, Some(3));
+		assert_eq!(options.payer_note.as_deref(), Some("for the team"));
+		assert!(options.offers_only());
+		assert!(!options.lightning_address_only());
+		assert!(PaymentOptions::parse("--quantity many".split(' ')).is_err());
+
+		let options = PaymentOptions::parse(vec!["--comment", "for lunch"].into_iter()).unwrap();
+		assert_eq!(options.comment.as_deref(), Some("for lunch"));
+		assert!(options.lightning_address_only());
+		assert!(!options.offers_only());
+	}
+
+	#[test]
+	fn test_parse_custom_tlv() {
+		assert_eq!(parse_custom_tlv("34349334=6869"), Ok((34349334, b"hi".to_vec())));
+		assert!(parse_custom_tlv("34349334").is_err());
+		assert!(parse_custom_tlv("34349334=zz").is_err());
+		assert!(parse_custom_tlv("1000=00").is_err());
+		assert!(parse_custom_tlv("5482373484=00").is_err());
+	}
+
+	#[test]
+	fn test_max_total_routing_fee_takes_tighter_limit() {
+		let mut options = PaymentOptions { max_fee_ppm: Some(1000), ..Default::default() };
+		assert_eq!(options.max_total_routing_fee_msat(10_000_000), Some(10_000));
+		options.max_fee_msat = Some(5_000);
+		assert_eq!(options.max_total_routing_fee_msat(10_000_000), Some(5_000));
+		options.max_fee_msat = Some(50_000);
+		assert_eq!(options.max_total_routing_fee_msat(10_000_000), Some(10_000));
+		assert_eq!(PaymentOptions::default().max_total_routing_fee_msat(10_000_000), None);
+	}
+}
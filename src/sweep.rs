@@ -3,16 +3,27 @@ use crate::filesystem_store::FilesystemStore;
 use crate::logger::FilesystemLogger;
 use std::io::{BufReader, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{fs, io};
 
-use lightning::sign::{EntropySource, KeysManager, SpendableOutputDescriptor};
+use bdk_wallet::miniscript::descriptor::{Descriptor, DescriptorPublicKey};
+use bitcoin::bip32::Xpub;
+use bitcoin::blockdata::locktime::absolute::LockTime;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::{OutPoint, ScriptBuf, Transaction};
+use lightning::impl_writeable_tlv_based;
+use lightning::sign::{
+	ChangeDestinationSource, EntropySource, KeysManager, OutputSpender, SpendableOutputDescriptor,
+};
 use lightning::util::logger::Logger;
 use lightning::util::persist::KVStore;
 use lightning::util::ser::{Readable, WithoutLength, Writeable};
+use lightning::util::sweep::{OutputSpendStatus, TrackedSpendableOutput};
 
 use crate::hex_utils;
-use crate::OutputSweeper;
+use crate::{LdkOnChainWallet, OutputSweeper};
 
 const DEPRECATED_PENDING_SPENDABLE_OUTPUT_DIR: &'static str = "pending_spendable_outputs";
 
@@ -101,3 +112,306 @@ pub(crate) async fn migrate_deprecated_spendable_outputs(
 		outputs.len()
 	);
 }
+
+pub(crate) const SWEEP_DESCRIPTOR_INDEX_KEY: &str = "sweep_descriptor_index";
+pub(crate) const BUMPED_SWEEPS_KEY: &str = "bumped_sweeps";
+/// How often we check whether a descriptor sweep destination should move on to its next address.
+const SWEEP_ADDRESS_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The descriptor index we currently sweep to.
+pub(crate) struct SweepAddress {
+	index: u32,
+	/// Whether the script at `index` was handed out for a sweep, in which case we move on to the
+	/// next index once no unconfirmed sweep pays to it anymore.
+	handed_out: bool,
+	/// How often a script was handed out since startup, used to notice sweeps built while we
+	/// were checking whether to move on.
+	handouts: u64,
+}
+
+impl_writeable_tlv_based!(SweepAddress, {
+	(0, index, required),
+	(2, handed_out, required),
+	(_unused, handouts, (static_value, 0)),
+});
+
+/// Where the [`OutputSweeper`] sends funds it claims from closed channels.
+///
+/// By default this is our own [`LdkOnChainWallet`], but a user may instead provide a watch-only
+/// descriptor (or bare xpub) so that swept funds go straight to cold storage.
+pub(crate) enum SweepDestination {
+	Wallet(Arc<LdkOnChainWallet>),
+	Descriptor {
+		descriptor: Box<Descriptor<DescriptorPublicKey>>,
+		address: Mutex<SweepAddress>,
+		persister: Arc<FilesystemStore>,
+	},
+}
+
+impl SweepDestination {
+	/// Builds a [`SweepDestination::Descriptor`] from a descriptor string. A bare xpub is treated
+	/// as `wpkh(<xpub>/0/*)`.
+	pub(crate) fn from_descriptor_str(
+		descriptor_str: &str, persister: Arc<FilesystemStore>,
+	) -> Result<Self, String> {
+		let descriptor_str = match Xpub::from_str(descriptor_str) {
+			Ok(xpub) => format!("wpkh({}/0/*)", xpub),
+			Err(_) => descriptor_str.to_string(),
+		};
+		let descriptor = Descriptor::<DescriptorPublicKey>::from_str(&descriptor_str)
+			.map_err(|e| format!("invalid sweep descriptor: {}", e))?;
+		if !descriptor.has_wildcard() {
+			// Reusing a single address for every sweep would needlessly link our channels.
+			return Err("sweep descriptor must contain a wildcard derivation step".to_string());
+		}
+		let address = match persister.read("", "", SWEEP_DESCRIPTOR_INDEX_KEY) {
+			Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes))
+				.map_err(|e| format!("failed to read sweep descriptor index: {:?}", e))?,
+			Err(e) if e.kind() == lightning::io::ErrorKind::NotFound => {
+				SweepAddress { index: 0, handed_out: false, handouts: 0 }
+			},
+			Err(e) => return Err(format!("failed to read sweep descriptor index: {}", e)),
+		};
+		let descriptor = Box::new(descriptor);
+		Ok(SweepDestination::Descriptor { descriptor, address: Mutex::new(address), persister })
+	}
+
+	pub(crate) fn describe(&self) -> String {
+		match self {
+			SweepDestination::Wallet(_) => "on-chain wallet".to_string(),
+			SweepDestination::Descriptor { descriptor, .. } => descriptor.to_string(),
+		}
+	}
+
+	/// Moves a descriptor destination on to its next index once the sweep paying the current one
+	/// has confirmed.
+	///
+	/// The sweeper asks for a destination script every block until its sweep confirms, and
+	/// handing out a fresh one each time would quickly run past any wallet's gap limit. We thus
+	/// keep handing out the same script and only advance from here. This can't happen in
+	/// [`ChangeDestinationSource::get_change_destination_script`] itself, as the sweeper calls
+	/// it with its state locked.
+	pub(crate) fn advance_if_swept(&self, sweeper: &OutputSweeper) -> Result<(), String> {
+		let (descriptor, address, persister) = match self {
+			SweepDestination::Wallet(_) => return Ok(()),
+			SweepDestination::Descriptor { descriptor, address, persister } => {
+				(descriptor, address, persister)
+			},
+		};
+		let handouts = {
+			let address = address.lock().unwrap();
+			if !address.handed_out {
+				return Ok(());
+			}
+			address.handouts
+		};
+		let tracked_outputs = sweeper.tracked_spendable_outputs();
+		let mut address = address.lock().unwrap();
+		if address.handouts != handouts {
+			// The sweeper built a new sweep since we looked at its outputs, check again later.
+			return Ok(());
+		}
+		let script = descriptor
+			.at_derivation_index(address.index)
+			.map_err(|e| format!("failed to derive sweep script: {}", e))?
+			.script_pubkey();
+		let sweep_pending = tracked_outputs.iter().any(|output| match &output.status {
+			OutputSpendStatus::PendingFirstConfirmation { latest_spending_tx, .. } => {
+				latest_spending_tx.output.iter().any(|txout| txout.script_pubkey == script)
+			},
+			_ => false,
+		});
+		if sweep_pending {
+			return Ok(());
+		}
+		address.index += 1;
+		address.handed_out = false;
+		persister
+			.write("", "", SWEEP_DESCRIPTOR_INDEX_KEY, &address.encode())
+			.map_err(|e| format!("failed to persist sweep descriptor index: {}", e))
+	}
+}
+
+/// Regularly moves a descriptor sweep destination on to a fresh address once its sweep confirms.
+pub(crate) async fn run_sweep_address_rotation(
+	destination: Arc<SweepDestination>, sweeper: Arc<OutputSweeper>, logger: Arc<FilesystemLogger>,
+) {
+	let mut interval = tokio::time::interval(SWEEP_ADDRESS_CHECK_INTERVAL);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+	loop {
+		interval.tick().await;
+		if let Err(e) = destination.advance_if_swept(&sweeper) {
+			lightning::log_error!(&*logger, "{}", e);
+		}
+	}
+}
+
+impl ChangeDestinationSource for SweepDestination {
+	fn get_change_destination_script(&self) -> Result<ScriptBuf, ()> {
+		match self {
+			SweepDestination::Wallet(wallet) => wallet.get_change_destination_script(),
+			SweepDestination::Descriptor { descriptor, address, persister } => {
+				let mut address = address.lock().unwrap();
+				let script =
+					descriptor.at_derivation_index(address.index).map_err(|_| ())?.script_pubkey();
+				address.handouts += 1;
+				if !address.handed_out {
+					// Persist that the script is in use before handing it out so we move on from
+					// it after a restart.
+					address.handed_out = true;
+					persister
+						.write("", "", SWEEP_DESCRIPTOR_INDEX_KEY, &address.encode())
+						.map_err(|_| ())?;
+				}
+				Ok(script)
+			},
+		}
+	}
+}
+
+/// Replacement sweeps broadcast by `bumpsweep`, which the [`OutputSweeper`] doesn't know about.
+#[derive(Default)]
+pub(crate) struct BumpedSweeps {
+	pub(crate) txs: Vec<Transaction>,
+}
+
+impl_writeable_tlv_based!(BumpedSweeps, {
+	(0, txs, optional_vec),
+});
+
+impl BumpedSweeps {
+	pub(crate) fn read(persister: &FilesystemStore) -> Self {
+		match persister.read("", "", BUMPED_SWEEPS_KEY) {
+			Ok(bytes) => Readable::read(&mut io::Cursor::new(bytes)).unwrap_or_default(),
+			Err(_) => BumpedSweeps::default(),
+		}
+	}
+
+	/// Records a replacement sweep, forgetting any earlier ones for outputs that have since
+	/// confirmed or been pruned by the sweeper.
+	pub(crate) fn record(
+		persister: &FilesystemStore, sweeper: &OutputSweeper, tx: Transaction,
+	) -> Result<(), String> {
+		let unconfirmed_outputs = sweeper
+			.tracked_spendable_outputs()
+			.into_iter()
+			.filter(|output| {
+				!matches!(output.status, OutputSpendStatus::PendingThresholdConfirmations { .. })
+			})
+			.collect::<Vec<_>>();
+		let mut bumped = Self::read(persister);
+		bumped.txs.retain(|tx| unconfirmed_outputs.iter().any(|output| output.is_spent_in(tx)));
+		bumped.txs.push(tx);
+		persister
+			.write("", "", BUMPED_SWEEPS_KEY, &bumped.encode())
+			.map_err(|e| format!("failed to persist bumped sweep: {}", e))
+	}
+}
+
+/// Returns the outpoint a tracked output descriptor spends from.
+pub(crate) fn spendable_output_outpoint(descriptor: &SpendableOutputDescriptor) -> OutPoint {
+	let outpoint = match descriptor {
+		SpendableOutputDescriptor::StaticOutput { outpoint, .. } => outpoint,
+		SpendableOutputDescriptor::DelayedPaymentOutput(descriptor) => &descriptor.outpoint,
+		SpendableOutputDescriptor::StaticPaymentOutput(descriptor) => &descriptor.outpoint,
+	};
+	outpoint.into_bitcoin_outpoint()
+}
+
+/// Returns the transaction most recently broadcast to spend the given output, if any.
+///
+/// While unconfirmed, that may be a replacement from `bumpsweep` rather than the sweeper's own
+/// transaction. The mempool keeps whichever of them pays the higher absolute fee, so given the
+/// values of the outputs being swept we pick that one.
+pub(crate) fn latest_sweep_tx<'a, F: Fn(&Transaction) -> u64>(
+	output: &'a TrackedSpendableOutput, bumped: &'a BumpedSweeps, fee_sats: F,
+) -> Option<&'a Transaction> {
+	match &output.status {
+		OutputSpendStatus::PendingInitialBroadcast { .. } => None,
+		OutputSpendStatus::PendingFirstConfirmation { latest_spending_tx, .. } => bumped
+			.txs
+			.iter()
+			.filter(|tx| output.is_spent_in(tx))
+			.chain(std::iter::once(latest_spending_tx))
+			.max_by_key(|tx| fee_sats(tx)),
+		OutputSpendStatus::PendingThresholdConfirmations { latest_spending_tx, .. } => {
+			Some(latest_spending_tx)
+		},
+	}
+}
+
+/// Re-spends all swept outputs that have not yet confirmed in a single transaction paying the given
+/// feerate, replacing whatever the sweeper last broadcast.
+///
+/// The [`OutputSweeper`] only rebroadcasts at the current fee estimate, so this lets a user push a
+/// stuck sweep through. The sweeper recognizes the replacement once it confirms, as it spends the
+/// same outputs.
+pub(crate) fn bump_sweep(
+	sweeper: &OutputSweeper, keys_manager: &KeysManager, destination: &SweepDestination,
+	feerate_sat_per_1000_weight: u32,
+) -> Result<Transaction, String> {
+	let cur_height = sweeper.current_best_block().height;
+	let tracked_outputs = sweeper.tracked_spendable_outputs();
+	let descriptors = tracked_outputs
+		.iter()
+		.filter(|output| match output.status {
+			OutputSpendStatus::PendingInitialBroadcast { delayed_until_height } => {
+				delayed_until_height.is_none_or(|height| height <= cur_height)
+			},
+			OutputSpendStatus::PendingFirstConfirmation { .. } => true,
+			OutputSpendStatus::PendingThresholdConfirmations { .. } => false,
+		})
+		.map(|output| &output.descriptor)
+		.collect::<Vec<_>>();
+	if descriptors.is_empty() {
+		return Err("no unconfirmed sweeps to bump".to_string());
+	}
+
+	let change_script = destination
+		.get_change_destination_script()
+		.map_err(|()| "failed to get sweep destination script".to_string())?;
+	let locktime = LockTime::from_height(cur_height).unwrap_or(LockTime::ZERO);
+	keys_manager
+		.spend_spendable_outputs(
+			&descriptors,
+			Vec::new(),
+			change_script,
+			feerate_sat_per_1000_weight,
+			Some(locktime),
+			&Secp256k1::new(),
+		)
+		.map_err(|()| "failed to build sweep transaction".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::bip32::Xpriv;
+	use bitcoin::Network;
+
+	#[test]
+	fn test_descriptor_destination_reuses_script_until_swept() {
+		let data_dir = std::env::temp_dir().join("ldk_sweep_destination_test");
+		let _ = std::fs::remove_dir_all(&data_dir);
+		let fs_store = Arc::new(FilesystemStore::new(data_dir));
+		let xpriv = Xpriv::new_master(Network::Testnet, &[42; 32]).unwrap();
+		let xpub = Xpub::from_priv(&Secp256k1::new(), &xpriv).to_string();
+
+		let destination =
+			SweepDestination::from_descriptor_str(&xpub, Arc::clone(&fs_store)).unwrap();
+		let script = destination.get_change_destination_script().unwrap();
+		assert_eq!(destination.get_change_destination_script().unwrap(), script);
+
+		// After a restart we still know the script was handed out and keep using it.
+		let destination = SweepDestination::from_descriptor_str(&xpub, fs_store).unwrap();
+		match &destination {
+			SweepDestination::Descriptor { address, .. } => {
+				let address = address.lock().unwrap();
+				assert_eq!(address.index, 0);
+				assert!(address.handed_out);
+			},
+			SweepDestination::Wallet(_) => panic!("expected a descriptor destination"),
+		}
+		assert_eq!(destination.get_change_destination_script().unwrap(), script);
+	}
+}
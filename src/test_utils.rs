@@ -0,0 +1,87 @@
+//! Helpers shared by the unit tests of several modules.
+use crate::logger::FilesystemLogger;
+use crate::NetworkGraph;
+use bitcoin::constants::ChainHash;
+use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use bitcoin::Network;
+use lightning::ln::msgs::UnsignedChannelUpdate;
+use lightning::types::features::ChannelFeatures;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// The public key of a test node, derived from a secret key made of `n` repeated.
+pub(crate) fn node_key(n: u8) -> PublicKey {
+	PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[n; 32]).unwrap())
+}
+
+pub(crate) fn test_network_graph() -> Arc<NetworkGraph> {
+	let logger = Arc::new(FilesystemLogger::new("test_dir".to_string()));
+	Arc::new(NetworkGraph::new(Network::Regtest, logger))
+}
+
+/// Adds a public channel between `node_a` and `node_b` to `graph`, with both ends charging the
+/// given fees and CLTV delta for forwarding over it.
+pub(crate) fn add_channel(
+	graph: &NetworkGraph, scid: u64, node_a: &PublicKey, node_b: &PublicKey, fee_base_msat: u32,
+	fee_proportional_millionths: u32, cltv_expiry_delta: u16,
+) {
+	let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs();
+	graph
+		.add_channel_from_partial_announcement(
+			scid,
+			now,
+			ChannelFeatures::empty(),
+			*node_a,
+			*node_b,
+		)
+		.unwrap();
+	for channel_flags in [0, 1] {
+		graph
+			.update_channel_unsigned(&UnsignedChannelUpdate {
+				chain_hash: ChainHash::using_genesis_block(Network::Regtest),
+				short_channel_id: scid,
+				timestamp: now as u32,
+				message_flags: 1,
+				channel_flags,
+				cltv_expiry_delta,
+				htlc_minimum_msat: 1,
+				htlc_maximum_msat: 100_000_000,
+				fee_base_msat,
+				fee_proportional_millionths,
+				excess_data: Vec::new(),
+			})
+			.unwrap();
+	}
+}
+
+/// Stands in for an HTTP server, answering each request with `handler(host, path)` and recording
+/// the paths requested. Returns the `host:port` it listens on along with the recorded paths.
+pub(crate) async fn stand_in_http_server<F, B>(handler: F) -> (String, Arc<Mutex<Vec<String>>>)
+where
+	F: Fn(&str, &str) -> B + Send + Sync + 'static,
+	B: AsRef<[u8]> + Send,
+{
+	let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let host = listener.local_addr().unwrap().to_string();
+	let requests = Arc::new(Mutex::new(Vec::new()));
+	let (server_host, server_requests) = (host.clone(), Arc::clone(&requests));
+	tokio::spawn(async move {
+		loop {
+			let (mut stream, _) = listener.accept().await.unwrap();
+			let mut request = vec![0; 4096];
+			let len = stream.read(&mut request).await.unwrap();
+			let request = String::from_utf8_lossy(&request[..len]).to_string();
+			let path = request.split(' ').nth(1).unwrap_or("/").to_string();
+			let body = handler(&server_host, &path);
+			server_requests.lock().unwrap().push(path);
+			let header = format!(
+				"HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+				body.as_ref().len()
+			);
+			stream.write_all(header.as_bytes()).await.unwrap();
+			stream.write_all(body.as_ref()).await.unwrap();
+		}
+	});
+	(host, requests)
+}